@@ -3,6 +3,7 @@
 use reqwest::blocking::get;
 use std::fs;
 use std::path::Path;
+use std::process::Command;
 
 const URLS: [&str; 3] = ["https://github.com/doronz88/DeveloperDiskImage/raw/refs/heads/main/PersonalizedImages/Xcode_iOS_DDI_Personalized/BuildManifest.plist", "https://github.com/doronz88/DeveloperDiskImage/raw/refs/heads/main/PersonalizedImages/Xcode_iOS_DDI_Personalized/Image.dmg", "https://github.com/doronz88/DeveloperDiskImage/raw/refs/heads/main/PersonalizedImages/Xcode_iOS_DDI_Personalized/Image.dmg.trustcache"];
 const OUTPUT_DIR: &str = "DDI";
@@ -15,6 +16,24 @@ const OUTPUT_FILES: [&str; 3] = [
 fn main() {
     println!("cargo:rerun-if-changed=build.rs");
 
+    // Reported by /version, builds outside of a checkout just don't have one
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    if let Ok(output) = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+    {
+        if output.status.success() {
+            let hash = String::from_utf8_lossy(&output.stdout);
+            println!("cargo:rustc-env=JITSTREAMER_GIT_HASH={}", hash.trim());
+        }
+    }
+
+    // The gRPC service, this needs protoc installed
+    tonic_build::configure()
+        .build_client(false)
+        .compile_protos(&["proto/jitstreamer.proto"], &["proto"])
+        .expect("Failed to compile protos");
+
     // Ensure output directory exists
     if !Path::new(OUTPUT_DIR).exists() {
         fs::create_dir_all(OUTPUT_DIR).expect("Failed to create DDI directory");
@@ -0,0 +1,148 @@
+// Jackson Coxson
+// CIDR allowlists for groups of routes
+
+use std::{net::IpAddr, str::FromStr, sync::Arc};
+
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_client_ip::SecureClientIp;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::{
+    config::SharedConfig,
+    error::{ErrorCode, JitError},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (s, None),
+        };
+        let addr = IpAddr::from_str(addr.trim()).map_err(|_| format!("invalid address: {s}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .trim()
+                .parse::<u8>()
+                .map_err(|_| format!("invalid prefix: {s}"))?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(format!("prefix too long: {s}"));
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl Cidr {
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A list of networks allowed to reach a group of routes. An empty list allows everyone.
+#[derive(Clone, Default)]
+pub struct Allowlist(Arc<Vec<Cidr>>);
+
+impl Allowlist {
+    /// Parses a comma separated list of CIDRs
+    pub fn parse(list: &str) -> Result<Self, String> {
+        let list = list
+            .split(',')
+            .filter(|c| !c.trim().is_empty())
+            .map(|c| c.parse::<Cidr>())
+            .collect::<Result<Vec<Cidr>, String>>()?;
+        Ok(Self(Arc::new(list)))
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        self.0.is_empty() || self.contains(ip)
+    }
+
+    /// Whether a network in the list contains the address, false for an empty list
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|c| c.contains(ip))
+    }
+}
+
+/// Middleware rejecting clients outside of DEVICE_ALLOWLIST
+pub async fn enforce_device(
+    State(config): State<SharedConfig>,
+    ip: SecureClientIp,
+    request: Request,
+    next: Next,
+) -> Response {
+    let allowlist = config.load().device_allowlist.clone();
+    enforce(&allowlist, ip, request, next).await
+}
+
+/// Middleware rejecting clients outside of REGISTER_ALLOWLIST
+pub async fn enforce_register(
+    State(config): State<SharedConfig>,
+    ip: SecureClientIp,
+    request: Request,
+    next: Next,
+) -> Response {
+    let allowlist = config.load().register_allowlist.clone();
+    enforce(&allowlist, ip, request, next).await
+}
+
+#[derive(Serialize)]
+struct ForbiddenReturn {
+    ok: bool,
+    #[serde(flatten)]
+    error: JitError,
+}
+
+async fn enforce(
+    allowlist: &Allowlist,
+    ip: SecureClientIp,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !allowlist.allows(ip.0) {
+        warn!("Rejecting {} for {}", ip.0, request.uri().path());
+        return (
+            StatusCode::FORBIDDEN,
+            Json(ForbiddenReturn {
+                ok: false,
+                error: JitError::new(ErrorCode::Forbidden, "forbidden"),
+            }),
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
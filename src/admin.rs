@@ -0,0 +1,1198 @@
+// Jackson Coxson
+// Admin operations run across many devices at once
+
+use std::{net::IpAddr, str::FromStr, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Path, Query, Request, State},
+    http::{header::AUTHORIZATION, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{sync::Semaphore, task::JoinSet};
+use tracing::{info, warn, Instrument};
+
+use crate::{
+    api_keys::{self, ApiKey, Rejection, Scope},
+    bans::{Ban, BanKind, BanList},
+    common,
+    config::Config,
+    device,
+    flood::Block,
+    heartbeat::{self, HeartbeatSummary},
+    history::LaunchRecord,
+    invites::{self, Invite},
+    launch_limit::RunningLaunch,
+    mount,
+    netmuxd::{self, MuxerDevice},
+    privacy, provider, register, retention,
+    scheduler::{Job, JobStatus},
+    stats::{self, Breakdown},
+    status::{self, Incident},
+    JitStreamerState,
+};
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchOperation {
+    /// Start mounting the developer disk image
+    Mount,
+    /// Check that the device can be reached over lockdown
+    Probe,
+    /// Issue the device a new Wireguard peer
+    RegenerateConfig,
+}
+
+#[derive(Deserialize)]
+pub struct BatchRequest {
+    operation: BatchOperation,
+    /// The devices to operate on, every registered device if omitted
+    udids: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+pub struct BatchResult {
+    udid: String,
+    ok: bool,
+    detail: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct BatchReturn {
+    ok: bool,
+    results: Vec<BatchResult>,
+    error: Option<String>,
+}
+
+/// Compares hashes of the tokens, so how long it takes says nothing about the admin token
+fn token_matches(given: &str, token: &str) -> bool {
+    Sha256::digest(given) == Sha256::digest(token)
+}
+
+/// Middleware rejecting requests without the admin bearer token or an admin API key
+pub async fn authorize(
+    State((state, token)): State<(JitStreamerState, Arc<String>)>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let bearer = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+    let authorized = match bearer {
+        Some(t) if token_matches(t, &token) => true,
+        Some(t) => {
+            match api_keys::check(&state, Some(t), Scope::Admin, request.uri().path()).await {
+                Ok(()) => true,
+                Err(rejection @ Rejection::RateLimited(_)) => return rejection.into_response(),
+                Err(_) => false,
+            }
+        }
+        None => false,
+    };
+    if !authorized {
+        warn!(
+            "Rejecting unauthorized admin request for {}",
+            request.uri().path()
+        );
+        return (StatusCode::UNAUTHORIZED, "unauthorized").into_response();
+    }
+    next.run(request).await
+}
+
+/// Runs an operation across a set of devices, at most ADMIN_CONCURRENCY at a time
+pub async fn batch(
+    State(state): State<JitStreamerState>,
+    Json(request): Json<BatchRequest>,
+) -> Json<BatchReturn> {
+    let devices = match sqlx::query_as::<_, (String, String)>("SELECT udid, ip FROM devices")
+        .fetch_all(&state.db)
+        .await
+    {
+        Ok(d) => d,
+        Err(e) => {
+            tracing::error!("Failed to query database: {e:?}");
+            return Json(BatchReturn {
+                ok: false,
+                results: Vec::new(),
+                error: Some("Failed to query database".to_string()),
+            });
+        }
+    };
+
+    let targets: Vec<(String, Option<String>)> = match request.udids {
+        Some(udids) => {
+            let mut targets = Vec::with_capacity(udids.len());
+            for udid in udids {
+                let udid = privacy::reveal(&state, &udid).await;
+                let ip = devices
+                    .iter()
+                    .find(|(u, _)| *u == udid)
+                    .map(|(_, ip)| ip.clone());
+                targets.push((udid, ip));
+            }
+            targets
+        }
+        None => devices
+            .into_iter()
+            .map(|(udid, ip)| (udid, Some(ip)))
+            .collect(),
+    };
+    info!(
+        "Running {:?} across {} devices",
+        request.operation,
+        targets.len()
+    );
+
+    let permits = Arc::new(Semaphore::new(state.config().admin_concurrency));
+    let mut tasks = JoinSet::new();
+    for (index, (udid, ip)) in targets.into_iter().enumerate() {
+        let state = state.clone();
+        let permits = permits.clone();
+        let operation = request.operation;
+        tasks.spawn(
+            async move {
+                let _permit = permits.acquire_owned().await;
+                let res = match ip {
+                    Some(ip) => run(&state, operation, &udid, &ip).await,
+                    None => Err("Device is not registered".to_string()),
+                };
+                (index, udid, res)
+            }
+            .instrument(tracing::Span::current()),
+        );
+    }
+
+    let mut results = Vec::new();
+    while let Some(res) = tasks.join_next().await {
+        match res {
+            Ok(r) => results.push(r),
+            Err(e) => warn!("Batch task failed: {e:?}"),
+        }
+    }
+    results.sort_by_key(|(index, _, _)| *index);
+
+    Json(BatchReturn {
+        ok: true,
+        results: results
+            .into_iter()
+            .map(|(_, udid, res)| match res {
+                Ok(detail) => BatchResult {
+                    udid,
+                    ok: true,
+                    detail: Some(detail),
+                    error: None,
+                },
+                Err(e) => BatchResult {
+                    udid,
+                    ok: false,
+                    detail: None,
+                    error: Some(e),
+                },
+            })
+            .collect(),
+        error: None,
+    })
+}
+
+async fn run(
+    state: &JitStreamerState,
+    operation: BatchOperation,
+    udid: &str,
+    ip: &str,
+) -> Result<String, String> {
+    let ip = IpAddr::from_str(ip)
+        .map_err(|_| format!("Stored IP {ip} is invalid"))?
+        .to_canonical();
+    match operation {
+        BatchOperation::Mount => match mount::start_mount(state, udid, ip).await? {
+            true => Ok("mounting".to_string()),
+            false => Ok("mounted".to_string()),
+        },
+        BatchOperation::Probe => probe(state, udid, ip).await,
+        BatchOperation::RegenerateConfig => {
+            let res =
+                register::regenerate_config(&state.db, &state.db_writer, &state.config(), udid)
+                    .await;
+            // The device gets a new address if its old one left the interface's network
+            state.udid_cache.invalidate(udid, &ip.to_string()).await;
+            res
+        }
+    }
+}
+
+async fn probe(state: &JitStreamerState, udid: &str, ip: IpAddr) -> Result<String, String> {
+    let pairing_file = common::get_pairing_file(udid, &state.config().pairing_store).await?;
+
+    let (provider, start) = provider::start(state, udid, ip, pairing_file)
+        .await
+        .map_err(|e| e.to_string())?;
+    let info = device::get_device_info(&state.device_info_cache, udid, &provider).await;
+    state.heartbeats.release(udid).await.ok();
+
+    let info = info.map_err(|e| format!("Failed to get device info: {e:?}"))?;
+    Ok(format!(
+        "{} {} (heartbeat reused: {}, woke device: {})",
+        info.device_class,
+        info.product_version.as_deref().unwrap_or("unknown version"),
+        start.reused,
+        start.woke
+    ))
+}
+
+#[derive(Serialize)]
+pub struct AdminDevice {
+    udid: String,
+    ip: String,
+    /// The IPv4 address of a dual stack Wireguard peer
+    ipv4: Option<String>,
+    last_used: String,
+    heartbeat: Option<heartbeat::HeartbeatStatus>,
+}
+
+#[derive(Serialize)]
+pub struct DevicesReturn {
+    ok: bool,
+    devices: Vec<AdminDevice>,
+    error: Option<String>,
+}
+
+/// Lists every registered device
+pub async fn list_devices(State(state): State<JitStreamerState>) -> Json<DevicesReturn> {
+    let devices = match sqlx::query_as::<_, (String, String, Option<String>, String)>(
+        "SELECT udid, ip, ipv4, CAST(last_used AS TEXT) FROM devices ORDER BY last_used DESC",
+    )
+    .fetch_all(&state.db)
+    .await
+    {
+        Ok(d) => d,
+        Err(e) => {
+            tracing::error!("Failed to query database: {e:?}");
+            return Json(DevicesReturn {
+                ok: false,
+                devices: Vec::new(),
+                error: Some("Failed to query database".to_string()),
+            });
+        }
+    };
+
+    let heartbeats = state.heartbeats.list().await.unwrap_or_default();
+    Json(DevicesReturn {
+        ok: true,
+        devices: devices
+            .into_iter()
+            .map(|(udid, ip, ipv4, last_used)| AdminDevice {
+                heartbeat: heartbeats
+                    .iter()
+                    .find(|h| h.udid == udid)
+                    .map(|h| h.status.clone()),
+                udid,
+                ip,
+                ipv4,
+                last_used,
+            })
+            .collect(),
+        error: None,
+    })
+}
+
+#[derive(Serialize)]
+pub struct CachedTunnel {
+    udid: String,
+    age_secs: u64,
+}
+
+#[derive(Serialize)]
+pub struct SessionsReturn {
+    ok: bool,
+    heartbeats: Vec<HeartbeatSummary>,
+    /// Devices whose RSD handshake is cached, so their next tunnel skips it
+    tunnels: Vec<CachedTunnel>,
+    /// Devices with a developer disk image mount in progress
+    mounting: Vec<String>,
+    /// Launches in progress, oldest first
+    launches: Vec<RunningLaunch>,
+}
+
+/// Shows the live heartbeats, cached tunnels, mounts and launches in progress
+pub async fn sessions(State(state): State<JitStreamerState>) -> Json<SessionsReturn> {
+    Json(SessionsReturn {
+        ok: true,
+        heartbeats: state.heartbeats.list().await.unwrap_or_default(),
+        tunnels: state
+            .rsd_cache
+            .entries()
+            .await
+            .into_iter()
+            .map(|(udid, age_secs)| CachedTunnel { udid, age_secs })
+            .collect(),
+        mounting: state
+            .mount_cache
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, m)| !m.ended())
+            .map(|(udid, _)| udid.clone())
+            .collect(),
+        launches: state.launch_limiter.running(),
+    })
+}
+
+/// Ends one of the device's sessions. `kind` is `heartbeats`, `tunnels` or `launches`.
+pub async fn end_session(
+    Path((kind, udid)): Path<(String, String)>,
+    State(state): State<JitStreamerState>,
+) -> Json<AdminReturn> {
+    let udid = privacy::reveal(&state, &udid).await;
+    let ended = match kind.as_str() {
+        "heartbeats" => match state.heartbeats.kill(&udid).await {
+            Ok(killed) => killed,
+            Err(e) => {
+                return Json(AdminReturn {
+                    ok: false,
+                    error: Some(e.to_string()),
+                })
+            }
+        },
+        "tunnels" => {
+            let cached = state
+                .rsd_cache
+                .entries()
+                .await
+                .iter()
+                .any(|(u, _)| *u == udid);
+            state.rsd_cache.invalidate(&udid).await;
+            cached
+        }
+        "launches" => state.launch_limiter.stop(&udid),
+        _ => {
+            return Json(AdminReturn {
+                ok: false,
+                error: Some(format!("{kind} is not a kind of session")),
+            })
+        }
+    };
+    match ended {
+        true => {
+            info!("Ended {udid}'s {kind} session on request");
+            Json(AdminReturn {
+                ok: true,
+                error: None,
+            })
+        }
+        false => Json(AdminReturn {
+            ok: false,
+            error: Some(format!("{udid} has no {kind} session")),
+        }),
+    }
+}
+
+#[derive(Serialize)]
+pub struct AdminReturn {
+    ok: bool,
+    error: Option<String>,
+}
+
+/// Kills the device's heartbeat and launch in progress, and forgets its cached tunnel and
+/// unfinished launch
+pub async fn kill_sessions(
+    Path(udid): Path<String>,
+    State(state): State<JitStreamerState>,
+) -> Json<AdminReturn> {
+    let udid = privacy::reveal(&state, &udid).await;
+    info!("Killing sessions for {udid}");
+    state.launch_limiter.stop(&udid);
+    state.heartbeats.kill(&udid).await.ok();
+    state.rsd_cache.invalidate(&udid).await;
+    state.launch_checkpoints.lock().await.remove(&udid);
+    Json(AdminReturn {
+        ok: true,
+        error: None,
+    })
+}
+
+#[derive(Serialize)]
+pub struct PurgeReturn {
+    ok: bool,
+    /// How many mounts in progress were forgotten
+    mounts: usize,
+    /// How many unfinished launches were forgotten
+    launches: usize,
+}
+
+/// Forgets every mount in progress and unfinished launch, so stuck ones start over
+pub async fn purge(State(state): State<JitStreamerState>) -> Json<PurgeReturn> {
+    let mounts = std::mem::take(&mut *state.mount_cache.lock().await).len();
+    let launches = std::mem::take(&mut *state.launch_checkpoints.lock().await).len();
+    info!("Purged {mounts} mounts in progress and {launches} unfinished launches");
+    Json(PurgeReturn {
+        ok: true,
+        mounts,
+        launches,
+    })
+}
+
+/// Deletes the device's registration
+pub async fn delete_device(
+    Path(udid): Path<String>,
+    State(state): State<JitStreamerState>,
+) -> Json<AdminReturn> {
+    let udid = privacy::reveal(&state, &udid).await;
+    match register::remove_device(&state, &udid).await {
+        Ok(()) => Json(AdminReturn {
+            ok: true,
+            error: None,
+        }),
+        Err(e) => Json(AdminReturn {
+            ok: false,
+            error: Some(e),
+        }),
+    }
+}
+
+#[derive(Serialize)]
+pub struct LaunchesReturn {
+    ok: bool,
+    launches: Vec<LaunchRecord>,
+}
+
+/// Lists the most recent launches across all devices, newest first
+pub async fn launches(State(state): State<JitStreamerState>) -> Json<LaunchesReturn> {
+    Json(LaunchesReturn {
+        ok: true,
+        launches: state.launch_history.recent().await,
+    })
+}
+
+#[derive(Serialize)]
+pub struct ReloadReturn {
+    ok: bool,
+    /// Settings that changed but only take effect after a restart
+    restart_required: Vec<&'static str>,
+    error: Option<String>,
+}
+
+/// Re-reads the config, keeping the current one if the new one is invalid
+pub async fn reload(State(state): State<JitStreamerState>) -> Json<ReloadReturn> {
+    info!("Reloading config");
+    match Config::reload(&state.config) {
+        Ok(restart_required) => Json(ReloadReturn {
+            ok: true,
+            restart_required,
+            error: None,
+        }),
+        Err(errors) => Json(ReloadReturn {
+            ok: false,
+            restart_required: Vec::new(),
+            error: Some(
+                errors
+                    .iter()
+                    .map(|e| e.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", "),
+            ),
+        }),
+    }
+}
+
+#[derive(Serialize)]
+pub struct BansReturn {
+    ok: bool,
+    bans: Vec<Ban>,
+    error: Option<String>,
+}
+
+/// Lists every ban, newest first
+pub async fn list_bans(State(state): State<JitStreamerState>) -> Json<BansReturn> {
+    match BanList::list(&state.db).await {
+        Ok(bans) => Json(BansReturn {
+            ok: true,
+            bans,
+            error: None,
+        }),
+        Err(e) => {
+            tracing::error!("Failed to query database: {e:?}");
+            Json(BansReturn {
+                ok: false,
+                bans: Vec::new(),
+                error: Some("Failed to query database".to_string()),
+            })
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BanRequest {
+    kind: BanKind,
+    /// A UDID, or an IP address or CIDR range
+    value: String,
+    reason: Option<String>,
+}
+
+/// Bans a device or network, killing the device's sessions
+pub async fn ban(
+    State(state): State<JitStreamerState>,
+    Json(request): Json<BanRequest>,
+) -> Json<AdminReturn> {
+    let value = privacy::reveal(&state, request.value.trim()).await;
+    let value = value.as_str();
+    if let Err(e) = state
+        .bans
+        .ban(
+            &state.db_writer,
+            request.kind,
+            value,
+            request.reason.as_deref(),
+        )
+        .await
+    {
+        return Json(AdminReturn {
+            ok: false,
+            error: Some(e),
+        });
+    }
+    if request.kind == BanKind::Udid {
+        state.heartbeats.kill(value).await.ok();
+    }
+    Json(AdminReturn {
+        ok: true,
+        error: None,
+    })
+}
+
+#[derive(Deserialize)]
+pub struct UnbanRequest {
+    kind: BanKind,
+    value: String,
+}
+
+/// Lifts a ban
+pub async fn unban(
+    State(state): State<JitStreamerState>,
+    Json(request): Json<UnbanRequest>,
+) -> Json<AdminReturn> {
+    let value = privacy::reveal(&state, request.value.trim()).await;
+    match state
+        .bans
+        .unban(&state.db_writer, request.kind, &value)
+        .await
+    {
+        Ok(true) => Json(AdminReturn {
+            ok: true,
+            error: None,
+        }),
+        Ok(false) => Json(AdminReturn {
+            ok: false,
+            error: Some(format!("{} is not banned", request.value)),
+        }),
+        Err(e) => Json(AdminReturn {
+            ok: false,
+            error: Some(e),
+        }),
+    }
+}
+
+#[derive(Serialize)]
+pub struct BlocksReturn {
+    ok: bool,
+    blocks: Vec<Block>,
+    error: Option<String>,
+}
+
+/// Client IPs and devices blocked for making too many failed requests
+pub async fn list_blocks(State(state): State<JitStreamerState>) -> Json<BlocksReturn> {
+    Json(BlocksReturn {
+        ok: true,
+        blocks: state.flood_guard.list().await,
+        error: None,
+    })
+}
+
+/// Lifts a block before it expires
+pub async fn lift_block(
+    State(state): State<JitStreamerState>,
+    Json(request): Json<UnbanRequest>,
+) -> Json<AdminReturn> {
+    let value = privacy::reveal(&state, request.value.trim()).await;
+    match state.flood_guard.lift(request.kind, &value).await {
+        true => Json(AdminReturn {
+            ok: true,
+            error: None,
+        }),
+        false => Json(AdminReturn {
+            ok: false,
+            error: Some(format!("{} is not blocked", request.value)),
+        }),
+    }
+}
+
+#[derive(Serialize)]
+pub struct InvitesReturn {
+    ok: bool,
+    invites: Vec<Invite>,
+    error: Option<String>,
+}
+
+/// Lists every invite code and who used it, newest first
+pub async fn list_invites(State(state): State<JitStreamerState>) -> Json<InvitesReturn> {
+    match invites::list(&state.db).await {
+        Ok(invites) => Json(InvitesReturn {
+            ok: true,
+            invites,
+            error: None,
+        }),
+        Err(e) => {
+            tracing::error!("Failed to query database: {e:?}");
+            Json(InvitesReturn {
+                ok: false,
+                invites: Vec::new(),
+                error: Some("Failed to query database".to_string()),
+            })
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct MintRequest {
+    /// How many codes to mint, one if omitted
+    count: Option<u32>,
+    /// Who the codes are for
+    note: Option<String>,
+    /// Codes never expire if omitted
+    expires_in_hours: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct MintReturn {
+    ok: bool,
+    codes: Vec<String>,
+    error: Option<String>,
+}
+
+/// Mints one-time invite codes for ALLOW_REGISTRATION=3
+pub async fn mint_invites(
+    State(state): State<JitStreamerState>,
+    Json(request): Json<MintRequest>,
+) -> Json<MintReturn> {
+    let count = request.count.unwrap_or(1);
+    if count == 0 || count > invites::MAX_MINT {
+        return Json(MintReturn {
+            ok: false,
+            codes: Vec::new(),
+            error: Some(format!("count must be between 1 and {}", invites::MAX_MINT)),
+        });
+    }
+    match invites::mint(
+        &state.db_writer,
+        count,
+        request.note.as_deref(),
+        request.expires_in_hours,
+    )
+    .await
+    {
+        Ok(codes) => Json(MintReturn {
+            ok: true,
+            codes,
+            error: None,
+        }),
+        Err(e) => Json(MintReturn {
+            ok: false,
+            codes: Vec::new(),
+            error: Some(e),
+        }),
+    }
+}
+
+/// Deletes an invite code so it can't be used
+pub async fn revoke_invite(
+    Path(code): Path<String>,
+    State(state): State<JitStreamerState>,
+) -> Json<AdminReturn> {
+    match invites::revoke(&state.db_writer, &code).await {
+        Ok(true) => Json(AdminReturn {
+            ok: true,
+            error: None,
+        }),
+        Ok(false) => Json(AdminReturn {
+            ok: false,
+            error: Some(format!("{code} is not an invite code")),
+        }),
+        Err(e) => {
+            tracing::error!("Failed to remove invite: {e:?}");
+            Json(AdminReturn {
+                ok: false,
+                error: Some("Failed to remove invite".to_string()),
+            })
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ApiKeysReturn {
+    ok: bool,
+    api_keys: Vec<ApiKey>,
+    error: Option<String>,
+}
+
+/// Lists every API key, without the keys themselves
+pub async fn list_api_keys(State(state): State<JitStreamerState>) -> Json<ApiKeysReturn> {
+    match api_keys::list(&state.db).await {
+        Ok(keys) => Json(ApiKeysReturn {
+            ok: true,
+            api_keys: keys,
+            error: None,
+        }),
+        Err(e) => {
+            tracing::error!("Failed to query database: {e:?}");
+            Json(ApiKeysReturn {
+                ok: false,
+                api_keys: Vec::new(),
+                error: Some("Failed to query database".to_string()),
+            })
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct MintApiKeyRequest {
+    /// Who or what the key is for
+    name: String,
+    /// `launch` or `admin`, just launch if omitted
+    scopes: Option<Vec<String>>,
+    /// Requests per minute, unlimited if omitted
+    rate_limit: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub struct MintApiKeyReturn {
+    ok: bool,
+    /// Only shown now, the server keeps a hash
+    key: Option<String>,
+    error: Option<String>,
+}
+
+/// Mints an API key for REQUIRE_API_KEY or the admin routes
+pub async fn mint_api_key(
+    State(state): State<JitStreamerState>,
+    Json(request): Json<MintApiKeyRequest>,
+) -> Json<MintApiKeyReturn> {
+    let scopes = match request.scopes {
+        Some(scopes) => scopes
+            .iter()
+            .map(|s| s.parse::<Scope>())
+            .collect::<Result<Vec<Scope>, String>>(),
+        None => Ok(vec![Scope::Launch]),
+    };
+    let res = match scopes {
+        Ok(scopes) => {
+            api_keys::mint(
+                &state.db_writer,
+                &request.name,
+                &scopes,
+                request.rate_limit.unwrap_or(0),
+            )
+            .await
+        }
+        Err(e) => Err(e),
+    };
+    match res {
+        Ok(key) => Json(MintApiKeyReturn {
+            ok: true,
+            key: Some(key),
+            error: None,
+        }),
+        Err(e) => Json(MintApiKeyReturn {
+            ok: false,
+            key: None,
+            error: Some(e),
+        }),
+    }
+}
+
+/// Deletes an API key, rejecting it from the next request on
+pub async fn revoke_api_key(
+    Path(id): Path<i64>,
+    State(state): State<JitStreamerState>,
+) -> Json<AdminReturn> {
+    match api_keys::revoke(&state.db_writer, id).await {
+        Ok(true) => Json(AdminReturn {
+            ok: true,
+            error: None,
+        }),
+        Ok(false) => Json(AdminReturn {
+            ok: false,
+            error: Some(format!("{id} is not an API key")),
+        }),
+        Err(e) => {
+            tracing::error!("Failed to remove API key: {e:?}");
+            Json(AdminReturn {
+                ok: false,
+                error: Some("Failed to remove API key".to_string()),
+            })
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct WaitlistEntry {
+    udid: String,
+    ip: String,
+    created_at: String,
+}
+
+#[derive(Serialize)]
+pub struct WaitlistReturn {
+    ok: bool,
+    /// Oldest first, the order they were turned away in
+    waitlist: Vec<WaitlistEntry>,
+    error: Option<String>,
+}
+
+/// Lists the devices turned away while the server was full
+pub async fn waitlist(State(state): State<JitStreamerState>) -> Json<WaitlistReturn> {
+    match sqlx::query_as::<_, (String, String, String)>(
+        "SELECT udid, ip, CAST(created_at AS TEXT) FROM waitlist ORDER BY created_at",
+    )
+    .fetch_all(&state.db)
+    .await
+    {
+        Ok(rows) => Json(WaitlistReturn {
+            ok: true,
+            waitlist: rows
+                .into_iter()
+                .map(|(udid, ip, created_at)| WaitlistEntry {
+                    udid,
+                    ip,
+                    created_at,
+                })
+                .collect(),
+            error: None,
+        }),
+        Err(e) => {
+            tracing::error!("Failed to query database: {e:?}");
+            Json(WaitlistReturn {
+                ok: false,
+                waitlist: Vec::new(),
+                error: Some("Failed to query database".to_string()),
+            })
+        }
+    }
+}
+
+/// Takes a device off the waitlist, once it's been let in or turned down
+pub async fn remove_from_waitlist(
+    Path(udid): Path<String>,
+    State(state): State<JitStreamerState>,
+) -> Json<AdminReturn> {
+    let udid = privacy::reveal(&state, &udid).await;
+    match state
+        .db_writer
+        .execute(sqlx::query("DELETE FROM waitlist WHERE udid = ?").bind(&udid))
+        .await
+    {
+        Ok(r) if r.rows_affected() > 0 => Json(AdminReturn {
+            ok: true,
+            error: None,
+        }),
+        Ok(_) => Json(AdminReturn {
+            ok: false,
+            error: Some(format!("{udid} is not on the waitlist")),
+        }),
+        Err(e) => {
+            tracing::error!("Failed to enact the statement: {e:?}");
+            Json(AdminReturn {
+                ok: false,
+                error: Some("Failed to remove from the waitlist".to_string()),
+            })
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct IncidentsReturn {
+    ok: bool,
+    /// Newest first
+    incidents: Vec<Incident>,
+    error: Option<String>,
+}
+
+/// Lists every incident, including ones no longer on the status page
+pub async fn list_incidents(State(state): State<JitStreamerState>) -> Json<IncidentsReturn> {
+    match status::list(&state.db).await {
+        Ok(incidents) => Json(IncidentsReturn {
+            ok: true,
+            incidents,
+            error: None,
+        }),
+        Err(e) => {
+            tracing::error!("Failed to query database: {e:?}");
+            Json(IncidentsReturn {
+                ok: false,
+                incidents: Vec::new(),
+                error: Some("Failed to query database".to_string()),
+            })
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PostIncidentRequest {
+    /// Shown to users as is, such as `Launches fail on iOS 18.4, a fix is on the way`
+    message: String,
+}
+
+#[derive(Serialize)]
+pub struct PostIncidentReturn {
+    ok: bool,
+    id: Option<i64>,
+    error: Option<String>,
+}
+
+/// Puts an ongoing incident on the status page
+pub async fn post_incident(
+    State(state): State<JitStreamerState>,
+    Json(request): Json<PostIncidentRequest>,
+) -> Json<PostIncidentReturn> {
+    let message = request.message.trim();
+    if message.is_empty() {
+        return Json(PostIncidentReturn {
+            ok: false,
+            id: None,
+            error: Some("An incident needs a message".to_string()),
+        });
+    }
+    match status::post(&state.db_writer, message).await {
+        Ok(id) => {
+            info!("Posted incident {id}: {message}");
+            Json(PostIncidentReturn {
+                ok: true,
+                id: Some(id),
+                error: None,
+            })
+        }
+        Err(e) => {
+            tracing::error!("Failed to save incident: {e:?}");
+            Json(PostIncidentReturn {
+                ok: false,
+                id: None,
+                error: Some("Failed to save incident".to_string()),
+            })
+        }
+    }
+}
+
+/// Marks an incident resolved, it stays on the status page for a week
+pub async fn resolve_incident(
+    Path(id): Path<i64>,
+    State(state): State<JitStreamerState>,
+) -> Json<AdminReturn> {
+    match status::resolve(&state.db_writer, id).await {
+        Ok(true) => Json(AdminReturn {
+            ok: true,
+            error: None,
+        }),
+        Ok(false) => Json(AdminReturn {
+            ok: false,
+            error: Some(format!("{id} is not an ongoing incident")),
+        }),
+        Err(e) => {
+            tracing::error!("Failed to resolve incident: {e:?}");
+            Json(AdminReturn {
+                ok: false,
+                error: Some("Failed to resolve incident".to_string()),
+            })
+        }
+    }
+}
+
+/// Deletes an incident, such as one posted by mistake
+pub async fn remove_incident(
+    Path(id): Path<i64>,
+    State(state): State<JitStreamerState>,
+) -> Json<AdminReturn> {
+    match status::remove(&state.db_writer, id).await {
+        Ok(true) => Json(AdminReturn {
+            ok: true,
+            error: None,
+        }),
+        Ok(false) => Json(AdminReturn {
+            ok: false,
+            error: Some(format!("{id} is not an incident")),
+        }),
+        Err(e) => {
+            tracing::error!("Failed to remove incident: {e:?}");
+            Json(AdminReturn {
+                ok: false,
+                error: Some("Failed to remove incident".to_string()),
+            })
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct JobsReturn {
+    ok: bool,
+    jobs: Vec<JobStatus>,
+}
+
+/// Shows each periodic job's interval, when it last ran and how that went
+pub async fn jobs(State(state): State<JitStreamerState>) -> Json<JobsReturn> {
+    Json(JobsReturn {
+        ok: true,
+        jobs: state.scheduler.status(&state).await,
+    })
+}
+
+/// Starts a job now instead of waiting for its interval, even if it's off
+pub async fn run_job(
+    Path(name): Path<String>,
+    State(state): State<JitStreamerState>,
+) -> Json<AdminReturn> {
+    let Ok(job) = name.parse::<Job>() else {
+        return Json(AdminReturn {
+            ok: false,
+            error: Some(format!("{name} is not a job")),
+        });
+    };
+    match state.scheduler.run_now(&state, job).await {
+        true => {
+            info!("Running the {name} job on request");
+            Json(AdminReturn {
+                ok: true,
+                error: None,
+            })
+        }
+        false => Json(AdminReturn {
+            ok: false,
+            error: Some(format!("The {name} job is already running")),
+        }),
+    }
+}
+
+#[derive(Serialize)]
+pub struct MuxerDevicesReturn {
+    ok: bool,
+    devices: Vec<MuxerDevice>,
+    error: Option<String>,
+}
+
+/// Lists the devices netmuxd knows about, to debug devices that can't be found
+pub async fn muxer_devices() -> Json<MuxerDevicesReturn> {
+    match netmuxd::list_devices().await {
+        Ok(devices) => Json(MuxerDevicesReturn {
+            ok: true,
+            devices,
+            error: None,
+        }),
+        Err(e) => {
+            warn!("Failed to list muxer devices: {e}");
+            Json(MuxerDevicesReturn {
+                ok: false,
+                devices: Vec::new(),
+                error: Some(e.to_string()),
+            })
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct StatsOptions {
+    /// How far back to count, defaults to 30 days
+    days: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub struct StatsReturn {
+    ok: bool,
+    days: u32,
+    #[serde(flatten)]
+    breakdown: Option<Breakdown>,
+    error: Option<String>,
+}
+
+/// Breaks the launch stats down by failure code, bundle ID and iOS version
+pub async fn stats(
+    Query(options): Query<StatsOptions>,
+    State(state): State<JitStreamerState>,
+) -> Json<StatsReturn> {
+    let days = options.days.unwrap_or(30);
+    match stats::breakdown(&state, days).await {
+        Ok(breakdown) => Json(StatsReturn {
+            ok: true,
+            days,
+            breakdown: Some(breakdown),
+            error: None,
+        }),
+        Err(e) => {
+            tracing::error!("Failed to query database: {e:?}");
+            Json(StatsReturn {
+                ok: false,
+                days,
+                breakdown: None,
+                error: Some("Failed to query database".to_string()),
+            })
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct StaleOptions {
+    /// Defaults to DEVICE_RETENTION_DAYS
+    days: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct StaleDevice {
+    udid: String,
+    last_used: String,
+}
+
+#[derive(Serialize)]
+pub struct StaleReturn {
+    ok: bool,
+    /// Least recently used first
+    devices: Vec<StaleDevice>,
+    error: Option<String>,
+}
+
+/// Lists the devices the retention sweep would remove, without removing them
+pub async fn stale_devices(
+    Query(options): Query<StaleOptions>,
+    State(state): State<JitStreamerState>,
+) -> Json<StaleReturn> {
+    let retention = match options.days {
+        Some(days) => Duration::from_secs(days * 24 * 60 * 60),
+        None => state.config().device_retention,
+    };
+    if retention.is_zero() {
+        return Json(StaleReturn {
+            ok: false,
+            devices: Vec::new(),
+            error: Some("DEVICE_RETENTION_DAYS is off, pass ?days= to preview".to_string()),
+        });
+    }
+
+    match retention::stale(&state, retention).await {
+        Ok(devices) => Json(StaleReturn {
+            ok: true,
+            devices: devices
+                .into_iter()
+                .map(|(udid, last_used)| StaleDevice { udid, last_used })
+                .collect(),
+            error: None,
+        }),
+        Err(e) => {
+            tracing::error!("Failed to query database: {e:?}");
+            Json(StaleReturn {
+                ok: false,
+                devices: Vec::new(),
+                error: Some("Failed to query database".to_string()),
+            })
+        }
+    }
+}
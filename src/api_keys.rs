@@ -0,0 +1,342 @@
+// Jackson Coxson
+// API keys for private instances, so access isn't decided only by VPN membership
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{
+        header::{AUTHORIZATION, RETRY_AFTER},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use sha2::Digest;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::{
+    db::{DbPool, Writer},
+    error::{ErrorCode, JitError},
+    JitStreamerState,
+};
+
+/// Minted keys start with this, so they're recognizable in a config or a leak
+const KEY_PREFIX: &str = "jse_";
+/// Query parameter for clients that can't set headers, such as browser websockets
+const KEY_PARAM: &str = "api_key";
+/// How often a key's last use is written
+const LAST_USED_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What a key may be used for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scope {
+    /// Registering and every route operating on a device
+    Launch,
+    /// The admin routes, in place of ADMIN_TOKEN, and everything a launch key can do
+    Admin,
+}
+
+impl Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Scope::Launch => "launch",
+            Scope::Admin => "admin",
+        })
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "launch" => Ok(Scope::Launch),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(format!("{s} is not a scope, use launch or admin")),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ApiKey {
+    pub id: i64,
+    pub name: String,
+    pub scopes: Vec<String>,
+    /// Requests per minute, 0 for unlimited
+    pub rate_limit: u32,
+    pub created_at: String,
+    pub last_used: Option<String>,
+}
+
+/// When this server last wrote each key's last use, by key ID, so requests in between
+/// don't wait on the database writer
+#[derive(Clone, Default)]
+pub struct LastUsed(Arc<Mutex<HashMap<i64, Instant>>>);
+
+impl LastUsed {
+    /// Whether the key's last use should be written, marking it written if so
+    async fn due(&self, id: i64) -> bool {
+        let now = Instant::now();
+        let mut lock = self.0.lock().await;
+        match lock.get(&id) {
+            Some(at) if now.duration_since(*at) < LAST_USED_INTERVAL => false,
+            _ => {
+                lock.insert(id, now);
+                true
+            }
+        }
+    }
+}
+
+/// Only the hash is stored, the key itself is shown once when it's minted
+fn hash_key(key: &str) -> String {
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(key.as_bytes());
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+fn generate_key() -> String {
+    let random = rand::random::<[u8; 24]>()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+    format!("{KEY_PREFIX}{random}")
+}
+
+/// Mints a key, returning it
+pub async fn mint(
+    db: &Writer,
+    name: &str,
+    scopes: &[Scope],
+    rate_limit: u32,
+) -> Result<String, String> {
+    if scopes.is_empty() {
+        return Err("A key needs at least one scope".to_string());
+    }
+    let key = generate_key();
+    let scopes = scopes
+        .iter()
+        .map(|s| s.to_string())
+        .collect::<Vec<String>>()
+        .join(",");
+    db.execute(
+        sqlx::query(
+            "INSERT INTO api_keys (key_hash, name, scopes, rate_limit, created_at) VALUES (?, ?, ?, ?, CURRENT_TIMESTAMP)",
+        )
+        .bind(hash_key(&key))
+        .bind(name)
+        .bind(&scopes)
+        .bind(rate_limit),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to save API key: {e:?}");
+        "Failed to save API key".to_string()
+    })?;
+    info!("Minted API key {name} with scopes {scopes}");
+    Ok(key)
+}
+
+pub async fn list(db: &DbPool) -> Result<Vec<ApiKey>, sqlx::Error> {
+    let rows = sqlx::query_as::<_, (i64, String, String, u32, String, Option<String>)>(
+        "SELECT id, name, scopes, rate_limit, CAST(created_at AS TEXT), CAST(last_used AS TEXT) FROM api_keys ORDER BY id",
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(
+            |(id, name, scopes, rate_limit, created_at, last_used)| ApiKey {
+                id,
+                name,
+                scopes: scopes.split(',').map(|s| s.to_string()).collect(),
+                rate_limit,
+                created_at,
+                last_used,
+            },
+        )
+        .collect())
+}
+
+/// Deletes a key, returning whether there was one
+pub async fn revoke(db: &Writer, id: i64) -> Result<bool, sqlx::Error> {
+    let res = db
+        .execute(sqlx::query("DELETE FROM api_keys WHERE id = ?").bind(id))
+        .await?;
+    Ok(res.rows_affected() > 0)
+}
+
+/// The key sent as `Authorization: Bearer <key>`, or the `api_key` query parameter
+pub fn from_request(headers: &HeaderMap, query: Option<&str>) -> Option<String> {
+    let header = headers
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+    let param = query.and_then(|q| {
+        q.split('&')
+            .find_map(|p| p.strip_prefix(KEY_PARAM)?.strip_prefix('='))
+    });
+    header
+        .or(param)
+        .map(|k| k.trim().to_string())
+        .filter(|k| !k.is_empty())
+}
+
+/// Why a request's key wasn't accepted
+#[derive(Debug)]
+pub enum Rejection {
+    Missing,
+    Unknown,
+    /// The key exists but doesn't have the scope
+    Scope(Scope),
+    /// The key is over its rate limit, retry after the seconds
+    RateLimited(u64),
+    Database,
+}
+
+impl Rejection {
+    pub fn error(&self) -> JitError {
+        match self {
+            Rejection::Missing => JitError::new(
+                ErrorCode::Forbidden,
+                "This server requires an API key, send it as a bearer token",
+            ),
+            Rejection::Unknown => JitError::new(ErrorCode::Forbidden, "Unknown API key"),
+            Rejection::Scope(scope) => JitError::new(
+                ErrorCode::Forbidden,
+                format!("This API key doesn't have the {scope} scope"),
+            ),
+            Rejection::RateLimited(retry_after) => JitError::new(
+                ErrorCode::RateLimited,
+                format!("Too many requests with this API key, try again in {retry_after} seconds"),
+            ),
+            Rejection::Database => JitError::internal("Failed to query database"),
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            Rejection::Missing | Rejection::Unknown => StatusCode::UNAUTHORIZED,
+            Rejection::Scope(_) => StatusCode::FORBIDDEN,
+            Rejection::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            Rejection::Database => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RejectedReturn {
+    ok: bool,
+    #[serde(flatten)]
+    error: JitError,
+}
+
+impl IntoResponse for Rejection {
+    fn into_response(self) -> Response {
+        let mut response = (
+            self.status(),
+            Json(RejectedReturn {
+                ok: false,
+                error: self.error(),
+            }),
+        )
+            .into_response();
+        if let Rejection::RateLimited(retry_after) = self {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        response
+    }
+}
+
+/// Checks the key has the scope and is under its rate limit. Admin keys have every scope.
+/// `what` is the route or call being made, for the log.
+pub async fn check(
+    state: &JitStreamerState,
+    key: Option<&str>,
+    scope: Scope,
+    what: &str,
+) -> Result<(), Rejection> {
+    let key = key.ok_or(Rejection::Missing)?;
+    let (id, name, scopes, rate_limit) = sqlx::query_as::<_, (i64, String, String, u32)>(
+        "SELECT id, name, scopes, rate_limit FROM api_keys WHERE key_hash = ?",
+    )
+    .bind(hash_key(key))
+    .fetch_optional(&state.db)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to query database: {e:?}");
+        Rejection::Database
+    })?
+    .ok_or_else(|| {
+        warn!("Rejecting unknown API key for {what}");
+        Rejection::Unknown
+    })?;
+
+    let scopes = scopes
+        .split(',')
+        .filter_map(|s| s.parse::<Scope>().ok())
+        .collect::<Vec<Scope>>();
+    if !scopes.contains(&scope) && !scopes.contains(&Scope::Admin) {
+        warn!("Rejecting API key {name} without the {scope} scope for {what}");
+        return Err(Rejection::Scope(scope));
+    }
+    if rate_limit > 0 {
+        if let Err(wait) = state.key_rate_limiter.take(id, rate_limit).await {
+            let retry_after = wait.as_secs() + 1;
+            warn!("Rate limiting API key {name} on {what}, retry in {retry_after}s");
+            return Err(Rejection::RateLimited(retry_after));
+        }
+    }
+
+    // Written at most once a minute, not on every request. Other nodes write it too, so
+    // the database skips it as well if one just did.
+    if !state.api_key_last_used.due(id).await {
+        return Ok(());
+    }
+    if let Err(e) = state
+        .db_writer
+        .execute(
+            sqlx::query(
+                "UPDATE api_keys SET last_used = CURRENT_TIMESTAMP WHERE id = ? AND (last_used IS NULL OR last_used < datetime('now', '-1 minute'))",
+            )
+            .bind(id),
+        )
+        .await
+    {
+        warn!("Failed to update API key {name}'s last use: {e:?}");
+    }
+    Ok(())
+}
+
+/// Middleware rejecting requests without a launch key when REQUIRE_API_KEY is set
+pub async fn enforce(
+    State(state): State<JitStreamerState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !state.config().require_api_key {
+        return next.run(request).await;
+    }
+    let key = from_request(request.headers(), request.uri().query());
+    if let Err(rejection) = check(&state, key.as_deref(), Scope::Launch, request.uri().path()).await
+    {
+        return rejection.into_response();
+    }
+    next.run(request).await
+}
@@ -0,0 +1,175 @@
+// Jackson Coxson
+// Caches each device's debuggable apps, since shortcuts list them on every run
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use idevice::{installation_proxy::InstallationProxyClient, IdeviceService};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::info;
+
+use crate::{
+    error::{ErrorCode, JitError},
+    provider::DeviceProvider,
+};
+
+/// The longest bundle ID kept for an app
+const MAX_BUNDLE_ID_LEN: usize = 255;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AppDetails {
+    pub name: String,
+    pub version: Option<String>,
+    pub executable: Option<String>,
+    /// Signed with get-task-allow, so debugserver can attach to it
+    pub is_debuggable: bool,
+}
+
+impl AppDetails {
+    /// Reads an app from its installation proxy entry
+    pub fn from_plist(bundle_id: &str, app: &plist::Value) -> Self {
+        let app = app.as_dictionary();
+        let string = |key: &str| {
+            app.and_then(|a| a.get(key))
+                .and_then(|v| v.as_string())
+                .map(|v| v.to_string())
+        };
+        Self {
+            name: string("CFBundleName").unwrap_or_else(|| bundle_id.to_string()),
+            version: string("CFBundleShortVersionString").or_else(|| string("CFBundleVersion")),
+            executable: string("CFBundleExecutable"),
+            is_debuggable: app
+                .and_then(|a| a.get("Entitlements"))
+                .and_then(|e| e.as_dictionary())
+                .and_then(|e| e.get("get-task-allow"))
+                .and_then(|g| g.as_boolean())
+                .unwrap_or(false),
+        }
+    }
+}
+
+/// A device's app list, as returned by `/get_apps`
+#[derive(Clone, Debug)]
+pub struct AppList {
+    /// App names to bundle IDs
+    pub bundle_ids: HashMap<String, String>,
+    /// Details by bundle ID
+    pub details: HashMap<String, AppDetails>,
+    /// Base64 PNGs by bundle ID, if they were requested
+    pub icons: Option<HashMap<String, String>>,
+}
+
+impl AppList {
+    /// Looks up an app's bundle ID by the name shown in the app list, ignoring case
+    pub fn bundle_id(&self, name: &str) -> Option<&String> {
+        self.bundle_ids
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, b)| b)
+    }
+}
+
+/// Letters, digits, dots, hyphens and underscores, what installed apps' bundle IDs are made of
+pub fn valid_bundle_id(bundle_id: &str) -> bool {
+    !bundle_id.is_empty()
+        && bundle_id.len() <= MAX_BUNDLE_ID_LEN
+        && bundle_id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'-' || b == b'_')
+}
+
+/// Lists the device's user apps, and system apps if asked, by bundle ID
+pub async fn fetch(
+    provider: &DeviceProvider,
+    system: bool,
+) -> Result<HashMap<String, AppDetails>, JitError> {
+    let mut instproxy_client = InstallationProxyClient::connect(provider)
+        .await
+        .map_err(|e| {
+            JitError::new(
+                ErrorCode::ServiceFailed,
+                format!("Failed to start instproxy: {e:?}"),
+            )
+        })?;
+
+    let application_type = match system {
+        true => "Any",
+        false => "User",
+    };
+    let apps = instproxy_client
+        .get_apps(Some(application_type.to_string()), None)
+        .await
+        .map_err(|e| {
+            info!("Failed to get apps: {:?}", e);
+            JitError::new(
+                ErrorCode::ServiceFailed,
+                format!("Failed to get apps: {:?}", e),
+            )
+        })?;
+    Ok(apps
+        .iter()
+        .map(|(bundle_id, app)| (bundle_id.clone(), AppDetails::from_plist(bundle_id, app)))
+        .collect())
+}
+
+/// App names to bundle IDs, for the shortcut's menu
+pub fn names(details: &HashMap<String, AppDetails>) -> HashMap<String, String> {
+    details
+        .iter()
+        .map(|(bundle_id, app)| (app.name.clone(), bundle_id.clone()))
+        .collect()
+}
+
+#[derive(Clone)]
+struct CachedApps {
+    apps: AppList,
+    fetched: Instant,
+}
+
+#[derive(Clone)]
+pub struct AppsCache {
+    inner: Arc<Mutex<HashMap<String, CachedApps>>>,
+    ttl: Duration,
+}
+
+impl AppsCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(HashMap::new())),
+            ttl,
+        }
+    }
+
+    /// The cached list of debuggable user apps, if it's fresh and has icons when they're wanted
+    pub async fn get(&self, udid: &str, icons: bool) -> Option<AppList> {
+        let mut lock = self.inner.lock().await;
+        match lock.get(udid) {
+            Some(c) if c.fetched.elapsed() < self.ttl => {
+                (!icons || c.apps.icons.is_some()).then(|| c.apps.clone())
+            }
+            Some(_) => {
+                lock.remove(udid);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub async fn insert(&self, udid: &str, apps: AppList) {
+        self.inner.lock().await.insert(
+            udid.to_string(),
+            CachedApps {
+                apps,
+                fetched: Instant::now(),
+            },
+        );
+    }
+
+    pub async fn invalidate(&self, udid: &str) {
+        self.inner.lock().await.remove(udid);
+    }
+}
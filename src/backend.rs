@@ -0,0 +1,402 @@
+// Jackson Coxson
+// What launching, attaching and listing apps need from a device, so they can run against a mock
+
+use std::{collections::HashMap, future::Future, net::IpAddr, pin::Pin, sync::Arc};
+
+use tracing::{debug, info, warn};
+
+use crate::{
+    apps::{self, AppDetails},
+    common::{self, get_pairing_file},
+    config::Config,
+    device,
+    error::{ErrorCode, JitError},
+    heartbeat::HeartbeatStart,
+    launcher::{self, LaunchOptions},
+    lockdown_jit, pipeline,
+    processes::{self, RunningProcess},
+    progress::{LaunchEvent, Progress},
+    provider, rsd, tunnel, wake, JitStreamerState,
+};
+
+pub type BackendFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, JitError>> + Send + 'a>>;
+
+/// Each call connects to the device at `ip` and leaves its heartbeat running, release it
+/// when done
+pub trait DeviceBackend: Send + Sync {
+    /// Launches the app, sending each phase to `progress`. Returns the PID.
+    fn launch<'a>(
+        &'a self,
+        state: &'a JitStreamerState,
+        udid: &'a str,
+        ip: IpAddr,
+        bundle_id: String,
+        options: LaunchOptions,
+        progress: &'a Progress,
+    ) -> BackendFuture<'a, (u64, HeartbeatStart)>;
+
+    /// Attaches debugserver to the process and detaches
+    fn attach<'a>(
+        &'a self,
+        state: &'a JitStreamerState,
+        udid: &'a str,
+        ip: IpAddr,
+        pid: u64,
+    ) -> BackendFuture<'a, ()>;
+
+    /// The installed apps by bundle ID, system apps too if `system`
+    fn apps<'a>(
+        &'a self,
+        state: &'a JitStreamerState,
+        udid: &'a str,
+        ip: IpAddr,
+        system: bool,
+    ) -> BackendFuture<'a, HashMap<String, AppDetails>>;
+
+    /// Base64 PNGs of the apps' home screen icons by bundle ID, skipping any that fail
+    fn icons<'a>(
+        &'a self,
+        state: &'a JitStreamerState,
+        udid: &'a str,
+        ip: IpAddr,
+        bundle_ids: Vec<String>,
+    ) -> BackendFuture<'a, HashMap<String, String>>;
+
+    /// Opens a tunnel for the launch that usually follows listing apps, see `rsd::prewarm`
+    fn prewarm<'a>(
+        &'a self,
+        state: &'a JitStreamerState,
+        udid: &'a str,
+        ip: IpAddr,
+    ) -> BackendFuture<'a, ()>;
+
+    /// The running processes, with the bundle IDs of apps
+    fn processes<'a>(
+        &'a self,
+        state: &'a JitStreamerState,
+        udid: &'a str,
+        ip: IpAddr,
+    ) -> BackendFuture<'a, Vec<RunningProcess>>;
+
+    /// Attaches debugserver to the running process with the name and detaches
+    fn attach_name<'a>(
+        &'a self,
+        state: &'a JitStreamerState,
+        udid: &'a str,
+        ip: IpAddr,
+        name: &'a str,
+    ) -> BackendFuture<'a, ()>;
+
+    /// The connected device, for the routes that use its services directly
+    fn provider<'a>(
+        &'a self,
+        state: &'a JitStreamerState,
+        udid: &'a str,
+        ip: IpAddr,
+    ) -> BackendFuture<'a, provider::DeviceProvider>;
+}
+
+/// Real devices, unless the server was built with the mock feature and MOCK_DEVICES is on
+pub fn from_config(config: &Config) -> Arc<dyn DeviceBackend> {
+    if config.mock_devices {
+        #[cfg(feature = "mock")]
+        {
+            warn!("MOCK_DEVICES is on, no real device will be contacted");
+            return Arc::new(crate::mock::MockBackend::default());
+        }
+        #[cfg(not(feature = "mock"))]
+        warn!("MOCK_DEVICES needs a build with the mock feature, using real devices");
+    }
+    Arc::new(IdeviceBackend)
+}
+
+/// Enables JIT the way the device's iOS version needs, once it's connected. Returns the PID.
+pub async fn launch_on(
+    state: &JitStreamerState,
+    udid: &str,
+    provider: &provider::DeviceProvider,
+    bundle_id: String,
+    options: LaunchOptions,
+    progress: &Progress,
+) -> Result<u64, JitError> {
+    let method = match device::get_device_info(&state.device_info_cache, udid, provider).await {
+        Ok(info) => {
+            if let Err(e) = info.device_class.check_supported() {
+                return Err(JitError::new(ErrorCode::UnsupportedDevice, e));
+            }
+            info.jit_method
+        }
+        Err(e) => {
+            debug!("Failed to get device info for {udid}: {e:?}");
+            launcher::JitMethod::for_version(None)
+        }
+    };
+    // A locked device fails deep in the launch with a service error that means nothing to
+    // the user, so catch it first
+    match device::passcode_locked(provider).await {
+        Ok(true) => {
+            return Err(JitError::new(
+                ErrorCode::DeviceLocked,
+                "Your device is locked. Unlock it and try again.",
+            ))
+        }
+        Ok(false) => {}
+        Err(e) => debug!("Failed to check whether {udid} is locked: {e:?}"),
+    }
+    debug!("Enabling JIT on {udid} with {method:?}");
+
+    let launch_timeout = state.config().device_timeouts.launch;
+    let res = match method {
+        launcher::JitMethod::Lockdown => {
+            common::timeout(
+                launch_timeout,
+                "launching the app",
+                lockdown_jit::launch(provider, bundle_id, &options, progress),
+            )
+            .await
+        }
+        launcher::JitMethod::RemoteXpc => {
+            let tunnels = tunnel::for_device(state, udid, provider).await?;
+            let target = rsd::TunnelTarget {
+                provider,
+                udid,
+                tunnels: tunnels.as_ref(),
+                cache: &state.rsd_cache,
+            };
+            let pipeline = pipeline::LaunchPipeline::new(
+                target,
+                &state.launch_checkpoints,
+                progress,
+                bundle_id,
+                options,
+                state.config().retry,
+            )
+            .await;
+            common::timeout(launch_timeout, "launching the app", pipeline.run()).await
+        }
+    };
+    match res {
+        Err(e) if e.code == ErrorCode::DdiNotMounted => {
+            Err(device::explain_missing_services(provider, e).await)
+        }
+        res => res,
+    }
+}
+
+/// Talks to devices with idevice
+pub struct IdeviceBackend;
+
+impl IdeviceBackend {
+    async fn connect(
+        state: &JitStreamerState,
+        udid: &str,
+        ip: IpAddr,
+    ) -> Result<(provider::DeviceProvider, HeartbeatStart), JitError> {
+        debug!("Getting pairing file for {udid}");
+        let pairing_file = get_pairing_file(udid, &state.config().pairing_store)
+            .await
+            .inspect_err(|e| info!("Failed to get pairing file: {:?}", e))?;
+        provider::start(state, udid, ip, pairing_file).await
+    }
+}
+
+impl DeviceBackend for IdeviceBackend {
+    fn launch<'a>(
+        &'a self,
+        state: &'a JitStreamerState,
+        udid: &'a str,
+        ip: IpAddr,
+        bundle_id: String,
+        options: LaunchOptions,
+        progress: &'a Progress,
+    ) -> BackendFuture<'a, (u64, HeartbeatStart)> {
+        Box::pin(async move {
+            if let Some(woke) = wake::nudge(state, udid, ip).await {
+                progress.send(LaunchEvent::Nudge { woke });
+            }
+            let (provider, heartbeat_start) = Self::connect(state, udid, ip).await?;
+            progress.send(LaunchEvent::Heartbeat {
+                reused: heartbeat_start.reused,
+                woke: heartbeat_start.woke,
+            });
+
+            let pid = launch_on(state, udid, &provider, bundle_id, options, progress).await?;
+            Ok((pid, heartbeat_start))
+        })
+    }
+
+    fn attach<'a>(
+        &'a self,
+        state: &'a JitStreamerState,
+        udid: &'a str,
+        ip: IpAddr,
+        pid: u64,
+    ) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            let (provider, _) = Self::connect(state, udid, ip).await?;
+            common::timeout(state.config().device_timeouts.attach, "attaching", async {
+                let (adapter, _) = crate::jit::developer_service(
+                    state,
+                    udid,
+                    &provider,
+                    idevice::debug_proxy::SERVICE_NAME,
+                    pipeline::DEBUG_PROXY_MISSING,
+                )
+                .await?;
+                crate::jit::attach_pid(adapter, pid).await
+            })
+            .await
+        })
+    }
+
+    fn apps<'a>(
+        &'a self,
+        state: &'a JitStreamerState,
+        udid: &'a str,
+        ip: IpAddr,
+        system: bool,
+    ) -> BackendFuture<'a, HashMap<String, AppDetails>> {
+        Box::pin(async move {
+            let (provider, _) = Self::connect(state, udid, ip).await?;
+            debug!("Connecting to device {udid} to get apps");
+            common::timeout(
+                state.config().device_timeouts.get_apps,
+                "listing apps",
+                apps::fetch(&provider, system),
+            )
+            .await
+        })
+    }
+
+    fn icons<'a>(
+        &'a self,
+        state: &'a JitStreamerState,
+        udid: &'a str,
+        ip: IpAddr,
+        bundle_ids: Vec<String>,
+    ) -> BackendFuture<'a, HashMap<String, String>> {
+        Box::pin(async move {
+            let (provider, _) = Self::connect(state, udid, ip).await?;
+            Ok(crate::app_icons(&provider, bundle_ids.iter()).await)
+        })
+    }
+
+    fn prewarm<'a>(
+        &'a self,
+        state: &'a JitStreamerState,
+        udid: &'a str,
+        ip: IpAddr,
+    ) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            let (provider, _) = Self::connect(state, udid, ip).await?;
+            // Older devices launch over lockdown, with no tunnel to warm
+            match device::get_device_info(&state.device_info_cache, udid, &provider).await {
+                Ok(info) if info.jit_method == launcher::JitMethod::RemoteXpc => {}
+                _ => return Ok(()),
+            }
+            let tunnels = tunnel::for_device(state, udid, &provider).await?;
+            let target = rsd::TunnelTarget {
+                provider: &provider,
+                udid,
+                tunnels: tunnels.as_ref(),
+                cache: &state.rsd_cache,
+            };
+            common::timeout(
+                state.config().device_timeouts.launch,
+                "opening a tunnel",
+                async {
+                    rsd::prewarm(target)
+                        .await
+                        .map_err(|e| JitError::new(ErrorCode::TunnelFailed, e))
+                },
+            )
+            .await
+        })
+    }
+
+    fn processes<'a>(
+        &'a self,
+        state: &'a JitStreamerState,
+        udid: &'a str,
+        ip: IpAddr,
+    ) -> BackendFuture<'a, Vec<RunningProcess>> {
+        Box::pin(async move {
+            let (provider, _) = Self::connect(state, udid, ip).await?;
+            let (adapter, _) = crate::jit::developer_service(
+                state,
+                udid,
+                &provider,
+                idevice::dvt::SERVICE_NAME,
+                pipeline::DVT_MISSING,
+            )
+            .await?;
+            let (mut processes, _) = processes::running(adapter)
+                .await
+                .map_err(|e| JitError::new(ErrorCode::ServiceFailed, e))?;
+            processes::fill_bundle_ids(&provider, &mut processes).await;
+            Ok(processes)
+        })
+    }
+
+    fn attach_name<'a>(
+        &'a self,
+        state: &'a JitStreamerState,
+        udid: &'a str,
+        ip: IpAddr,
+        name: &'a str,
+    ) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            let (provider, _) = Self::connect(state, udid, ip).await?;
+            common::timeout(state.config().device_timeouts.attach, "attaching", async {
+                let (adapter, services) = crate::jit::developer_service(
+                    state,
+                    udid,
+                    &provider,
+                    idevice::dvt::SERVICE_NAME,
+                    pipeline::DVT_MISSING,
+                )
+                .await?;
+                let (processes, mut adapter) = processes::running(adapter)
+                    .await
+                    .map_err(|e| JitError::new(ErrorCode::ServiceFailed, e))?;
+                let pid = match processes::find(&processes, name) {
+                    Some(p) => p.pid,
+                    None => {
+                        return Err(JitError::new(
+                            ErrorCode::ProcessNotFound,
+                            format!("No running process is named {name}"),
+                        ))
+                    }
+                };
+                debug!("Found {name} running as {pid}");
+
+                let port = services
+                    .port(idevice::debug_proxy::SERVICE_NAME)
+                    .ok_or_else(|| {
+                        JitError::new(ErrorCode::DdiNotMounted, pipeline::DEBUG_PROXY_MISSING)
+                    })?;
+                if let Err(e) = adapter.connect(port).await {
+                    warn!("Failed to connect to debug proxy port: {e:?}");
+                    return Err(JitError::new(
+                        ErrorCode::TunnelFailed,
+                        "Failed to connect to debug proxy port",
+                    ));
+                }
+                crate::jit::attach_pid(adapter, pid).await
+            })
+            .await
+        })
+    }
+
+    fn provider<'a>(
+        &'a self,
+        state: &'a JitStreamerState,
+        udid: &'a str,
+        ip: IpAddr,
+    ) -> BackendFuture<'a, provider::DeviceProvider> {
+        Box::pin(async move {
+            let (provider, _) = Self::connect(state, udid, ip).await?;
+            Ok(provider)
+        })
+    }
+}
@@ -0,0 +1,240 @@
+// Jackson Coxson
+// CLI subcommands that export and restore registrations, for moving to a new server
+
+use std::path::PathBuf;
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use clap::Subcommand;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::Config,
+    db::{DbPool, Writer},
+    legacy,
+    pairing_store::PairingStore,
+};
+
+/// Bumped when the export format changes in a way older servers can't read
+const EXPORT_VERSION: u32 = 1;
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Writes every registration to a JSON file
+    Export {
+        /// Where to write the export
+        output: PathBuf,
+        /// Also include each device's pairing file, which is needed to launch apps
+        #[arg(long)]
+        pairing_files: bool,
+    },
+    /// Copies the database and every pairing file into a folder
+    Backup {
+        /// A folder that doesn't exist yet
+        output: PathBuf,
+    },
+    /// Restores registrations from a file written by export
+    Import { input: PathBuf },
+    /// Imports registrations from the original JitStreamer
+    ImportLegacy {
+        /// The original JitStreamer's database
+        database: PathBuf,
+        /// The folder of <udid>.plist pairing files, devices without one are skipped
+        #[arg(long, value_name = "PATH")]
+        pairing_files: Option<PathBuf>,
+        /// Its Wireguard config, to copy the devices' peers into the first interface
+        #[arg(long, value_name = "PATH")]
+        wireguard_conf: Option<PathBuf>,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Export {
+    version: u32,
+    devices: Vec<ExportedDevice>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExportedDevice {
+    udid: String,
+    ip: String,
+    ipv4: Option<String>,
+    wireguard_interface: Option<String>,
+    last_used: String,
+    /// Base64, only exported with --pairing-files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pairing_file: Option<String>,
+}
+
+/// Runs the subcommand instead of the server
+pub async fn run(
+    command: Command,
+    config: &Config,
+    db: &DbPool,
+    writer: &Writer,
+) -> Result<(), String> {
+    match command {
+        Command::Export {
+            output,
+            pairing_files,
+        } => export(config, db, output, pairing_files).await,
+        Command::Backup { output } => backup(config, db, output).await,
+        Command::Import { input } => import(config, writer, input).await,
+        Command::ImportLegacy {
+            database,
+            pairing_files,
+            wireguard_conf,
+        } => {
+            legacy::import(
+                config,
+                writer,
+                &database,
+                pairing_files.as_deref(),
+                wireguard_conf.as_deref(),
+            )
+            .await
+        }
+    }
+}
+
+async fn devices(db: &DbPool) -> Result<Vec<ExportedDevice>, String> {
+    let rows = sqlx::query_as::<
+        _,
+        (
+            String,
+            String,
+            Option<String>,
+            Option<String>,
+            String,
+        ),
+    >(
+        "SELECT udid, ip, ipv4, wireguard_interface, CAST(last_used AS TEXT) FROM devices ORDER BY udid",
+    )
+    .fetch_all(db)
+    .await
+    .map_err(|e| format!("Failed to read devices: {e}"))?;
+    Ok(rows
+        .into_iter()
+        .map(
+            |(udid, ip, ipv4, wireguard_interface, last_used)| ExportedDevice {
+                udid,
+                ip,
+                ipv4,
+                wireguard_interface,
+                last_used,
+                pairing_file: None,
+            },
+        )
+        .collect())
+}
+
+async fn export(
+    config: &Config,
+    db: &DbPool,
+    output: PathBuf,
+    pairing_files: bool,
+) -> Result<(), String> {
+    let mut devices = devices(db).await?;
+    if pairing_files {
+        for device in &mut devices {
+            match config.pairing_store.get(&device.udid).await? {
+                Some(bytes) => device.pairing_file = Some(BASE64_STANDARD.encode(bytes)),
+                None => eprintln!("{} has no pairing file", device.udid),
+            }
+        }
+    }
+
+    let export = Export {
+        version: EXPORT_VERSION,
+        devices,
+    };
+    let json = serde_json::to_vec_pretty(&export).unwrap();
+    tokio::fs::write(&output, json)
+        .await
+        .map_err(|e| format!("Failed to write {}: {e}", output.display()))?;
+    println!(
+        "Exported {} devices to {}",
+        export.devices.len(),
+        output.display()
+    );
+    Ok(())
+}
+
+async fn backup(config: &Config, db: &DbPool, output: PathBuf) -> Result<(), String> {
+    if output.exists() {
+        return Err(format!("{} already exists", output.display()));
+    }
+    let pairing = output.join("pairing");
+    tokio::fs::create_dir_all(&pairing)
+        .await
+        .map_err(|e| format!("Failed to create {}: {e}", pairing.display()))?;
+
+    // A consistent copy, even while the server is writing to it
+    let database = output.join("jitstreamer.db");
+    sqlx::query("VACUUM INTO ?")
+        .bind(database.to_string_lossy().into_owned())
+        .execute(db)
+        .await
+        .map_err(|e| format!("Failed to copy the database: {e}"))?;
+
+    let devices = devices(db).await?;
+    let mut copied = 0;
+    for device in &devices {
+        match config.pairing_store.get(&device.udid).await? {
+            Some(bytes) => {
+                let file = pairing.join(format!("{}.plist", device.udid));
+                tokio::fs::write(&file, bytes)
+                    .await
+                    .map_err(|e| format!("Failed to write {}: {e}", file.display()))?;
+                copied += 1;
+            }
+            None => eprintln!("{} has no pairing file", device.udid),
+        }
+    }
+    println!(
+        "Backed up the database and {copied} of {} pairing files to {}",
+        devices.len(),
+        output.display()
+    );
+    Ok(())
+}
+
+async fn import(config: &Config, db: &Writer, input: PathBuf) -> Result<(), String> {
+    let bytes = tokio::fs::read(&input)
+        .await
+        .map_err(|e| format!("Failed to read {}: {e}", input.display()))?;
+    let export: Export = serde_json::from_slice(&bytes)
+        .map_err(|e| format!("{} isn't an export: {e}", input.display()))?;
+    if export.version > EXPORT_VERSION {
+        return Err(format!(
+            "The export is version {}, this server reads up to {EXPORT_VERSION}",
+            export.version
+        ));
+    }
+
+    for device in &export.devices {
+        if let Some(pairing_file) = &device.pairing_file {
+            let bytes = BASE64_STANDARD
+                .decode(pairing_file)
+                .map_err(|e| format!("{}'s pairing file is invalid: {e}", device.udid))?;
+            config.pairing_store.put(&device.udid, &bytes).await?;
+        }
+        db.execute(
+            sqlx::query(
+                "INSERT OR REPLACE INTO devices (udid, ip, ipv4, wireguard_interface, last_used) VALUES (?, ?, ?, ?, ?)",
+            )
+            .bind(&device.udid)
+            .bind(&device.ip)
+            .bind(&device.ipv4)
+            .bind(&device.wireguard_interface)
+            .bind(&device.last_used),
+        )
+        .await
+        .map_err(|e| format!("Failed to import {}: {e}", device.udid))?;
+    }
+    println!(
+        "Imported {} devices from {}",
+        export.devices.len(),
+        input.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,245 @@
+// Jackson Coxson
+// Bans on abusive devices and networks, kept in memory and checked before every handler
+
+use std::{collections::HashSet, net::IpAddr, sync::Arc};
+
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_client_ip::SecureClientIp;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::{
+    acl::Cidr,
+    common::{self, DeviceSelector},
+    db::{DbPool, Writer},
+    error::{ErrorCode, JitError},
+    JitStreamerState,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BanKind {
+    Udid,
+    /// An address or a CIDR range
+    Ip,
+}
+
+impl BanKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BanKind::Udid => "udid",
+            BanKind::Ip => "ip",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Bans {
+    udids: HashSet<String>,
+    networks: Vec<(String, Cidr)>,
+}
+
+impl Bans {
+    fn insert(&mut self, kind: BanKind, value: &str) -> Result<(), String> {
+        match kind {
+            BanKind::Udid => {
+                self.udids.insert(value.to_string());
+            }
+            BanKind::Ip => {
+                let cidr = value.parse::<Cidr>()?;
+                self.networks.retain(|(v, _)| v != value);
+                self.networks.push((value.to_string(), cidr));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The ban table, cached so enforcing it doesn't cost a query per request
+#[derive(Clone, Default)]
+pub struct BanList(Arc<Mutex<Bans>>);
+
+#[derive(Serialize)]
+pub struct Ban {
+    pub kind: BanKind,
+    pub value: String,
+    pub reason: Option<String>,
+    pub created_at: String,
+}
+
+impl BanList {
+    pub async fn load(db: &DbPool) -> Result<Self, sqlx::Error> {
+        let rows = sqlx::query_as::<_, (String, String)>("SELECT kind, value FROM bans")
+            .fetch_all(db)
+            .await?;
+        let mut bans = Bans::default();
+        for (kind, value) in rows {
+            let kind = match kind.as_str() {
+                "udid" => BanKind::Udid,
+                _ => BanKind::Ip,
+            };
+            if let Err(e) = bans.insert(kind, &value) {
+                warn!("Skipping invalid ban {value}: {e}");
+            }
+        }
+        info!(
+            "Loaded {} banned devices and {} banned networks",
+            bans.udids.len(),
+            bans.networks.len()
+        );
+        Ok(Self(Arc::new(Mutex::new(bans))))
+    }
+
+    pub async fn udid_banned(&self, udid: &str) -> bool {
+        self.0.lock().await.udids.contains(udid)
+    }
+
+    pub async fn ip_banned(&self, ip: IpAddr) -> bool {
+        self.0
+            .lock()
+            .await
+            .networks
+            .iter()
+            .any(|(_, c)| c.contains(ip))
+    }
+
+    pub async fn list(db: &DbPool) -> Result<Vec<Ban>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, (String, String, Option<String>, String)>(
+            "SELECT kind, value, reason, CAST(created_at AS TEXT) FROM bans ORDER BY created_at DESC",
+        )
+        .fetch_all(db)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(kind, value, reason, created_at)| Ban {
+                kind: match kind.as_str() {
+                    "udid" => BanKind::Udid,
+                    _ => BanKind::Ip,
+                },
+                value,
+                reason,
+                created_at,
+            })
+            .collect())
+    }
+
+    pub async fn ban(
+        &self,
+        db: &Writer,
+        kind: BanKind,
+        value: &str,
+        reason: Option<&str>,
+    ) -> Result<(), String> {
+        if kind == BanKind::Ip {
+            value.parse::<Cidr>()?;
+        }
+        db.execute(
+            sqlx::query(
+                "INSERT OR REPLACE INTO bans (kind, value, reason, created_at) VALUES (?, ?, ?, CURRENT_TIMESTAMP)",
+            )
+            .bind(kind.as_str())
+            .bind(value)
+            .bind(reason),
+        )
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to save ban: {e:?}");
+            "Failed to save ban".to_string()
+        })?;
+        self.0.lock().await.insert(kind, value)?;
+        info!("Banned {} {value}", kind.as_str());
+        Ok(())
+    }
+
+    /// Lifts a ban, returning whether there was one
+    pub async fn unban(&self, db: &Writer, kind: BanKind, value: &str) -> Result<bool, String> {
+        let res = db
+            .execute(
+                sqlx::query("DELETE FROM bans WHERE kind = ? AND value = ?")
+                    .bind(kind.as_str())
+                    .bind(value),
+            )
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to remove ban: {e:?}");
+                "Failed to remove ban".to_string()
+            })?;
+        let mut bans = self.0.lock().await;
+        match kind {
+            BanKind::Udid => {
+                bans.udids.remove(value);
+            }
+            BanKind::Ip => bans.networks.retain(|(v, _)| v != value),
+        }
+        info!("Unbanned {} {value}", kind.as_str());
+        Ok(res.rows_affected() > 0)
+    }
+}
+
+#[derive(Serialize)]
+struct BannedReturn {
+    ok: bool,
+    #[serde(flatten)]
+    error: JitError,
+}
+
+fn banned() -> JitError {
+    JitError::new(
+        ErrorCode::Banned,
+        "This device has been banned from this server",
+    )
+}
+
+/// Rejects banned client IPs, and banned devices the client names or resolves to.
+/// `what` is the route or call being made, for the log.
+pub async fn check(
+    state: &JitStreamerState,
+    ip: IpAddr,
+    selector: &DeviceSelector,
+    what: &str,
+) -> Result<(), JitError> {
+    if state.bans.ip_banned(ip).await {
+        warn!("Rejecting banned IP {ip} for {what}");
+        return Err(banned());
+    }
+
+    for udid in [&selector.device, &selector.udid].into_iter().flatten() {
+        if state.bans.udid_banned(udid).await {
+            warn!("Rejecting banned device {udid} for {what}");
+            return Err(banned());
+        }
+    }
+    // Unregistered clients have nothing to look up, registering checks the UDID itself
+    if let Ok(udid) = common::get_udid(state, ip.to_string(), selector).await {
+        if state.bans.udid_banned(&udid).await {
+            warn!("Rejecting banned device {udid} for {what}");
+            return Err(banned());
+        }
+    }
+    Ok(())
+}
+
+/// Middleware rejecting banned client IPs, and banned devices the client names or resolves to
+pub async fn enforce(
+    State(state): State<JitStreamerState>,
+    ip: SecureClientIp,
+    selector: DeviceSelector,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Err(error) = check(&state, ip.0, &selector, request.uri().path()).await {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(BannedReturn { ok: false, error }),
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
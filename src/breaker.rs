@@ -0,0 +1,103 @@
+// Jackson Coxson
+// Stops reaching for devices that keep failing to connect, until they've had time to come back
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::error::{ErrorCode, JitError};
+
+/// Circuits are only pruned once there are this many
+const PRUNE_THRESHOLD: usize = 1024;
+/// A circuit that hasn't failed for this long is forgotten
+const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+
+struct Circuit {
+    /// Connection failures in a row
+    failures: u32,
+    /// Attempts are refused until then
+    open_until: Option<Instant>,
+    updated: Instant,
+}
+
+/// Consecutive connection failures per UDID. Once a device fails CIRCUIT_BREAKER_FAILURES
+/// times in a row, attempts are refused for the cooldown instead of setting up another
+/// heartbeat that will time out. The first attempt after the cooldown goes through, and
+/// opens the circuit again if it fails too.
+#[derive(Clone, Default)]
+pub struct CircuitBreaker(Arc<Mutex<HashMap<String, Circuit>>>);
+
+impl CircuitBreaker {
+    /// Refuses the attempt while the device's circuit is open
+    pub async fn check(&self, udid: &str) -> Result<(), JitError> {
+        let lock = self.0.lock().await;
+        let open_until = match lock.get(udid).and_then(|c| c.open_until) {
+            Some(o) => o,
+            None => return Ok(()),
+        };
+        let remaining = open_until.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(());
+        }
+        Err(JitError::new(
+            ErrorCode::DeviceUnreachable,
+            format!(
+                "Your device has been unreachable for its last {} attempts, check that the VPN is connected. Try again in {} seconds.",
+                lock[udid].failures,
+                remaining.as_secs() + 1
+            ),
+        ))
+    }
+
+    /// Records the outcome of connecting to the device. `threshold` of 0 never opens it.
+    pub async fn record(
+        &self,
+        udid: &str,
+        error: Option<&JitError>,
+        threshold: u32,
+        cooldown: Duration,
+    ) {
+        let mut lock = self.0.lock().await;
+        match error.map(|e| e.code) {
+            None => {
+                if lock.remove(udid).is_some_and(|c| c.open_until.is_some()) {
+                    info!("{udid} is reachable again, closing its circuit");
+                }
+                return;
+            }
+            Some(
+                ErrorCode::DeviceUnreachable | ErrorCode::VpnNoHandshake | ErrorCode::DeviceTimeout,
+            ) => {}
+            // Such as a rejected pairing file, which says nothing about the connection
+            Some(_) => return,
+        }
+
+        let now = Instant::now();
+        if lock.len() >= PRUNE_THRESHOLD {
+            lock.retain(|_, c| now.duration_since(c.updated) < FORGET_AFTER);
+        }
+        let circuit = lock.entry(udid.to_string()).or_insert(Circuit {
+            failures: 0,
+            open_until: None,
+            updated: now,
+        });
+        circuit.failures += 1;
+        circuit.updated = now;
+        if threshold > 0 && circuit.failures >= threshold {
+            warn!(
+                "{udid} failed to connect {} times in a row, refusing attempts for {cooldown:?}",
+                circuit.failures
+            );
+            circuit.open_until = Some(now + cooldown);
+        }
+    }
+
+    pub async fn reset(&self, udid: &str) {
+        self.0.lock().await.remove(udid);
+    }
+}
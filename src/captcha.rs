@@ -0,0 +1,118 @@
+// Jackson Coxson
+// Optional CAPTCHA on registering, so bots can't fill the devices table with junk peers
+
+use std::{net::IpAddr, str::FromStr, time::Duration};
+
+use serde::Deserialize;
+use tracing::warn;
+
+/// Clients that can't use the form send the token in this header
+pub const CAPTCHA_HEADER: &str = "x-jitstreamer-captcha";
+/// The form fields the widgets put their token in
+pub const FORM_FIELDS: [&str; 2] = ["cf-turnstile-response", "h-captcha-response"];
+/// How long the provider gets to answer
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Provider {
+    Turnstile,
+    HCaptcha,
+}
+
+impl FromStr for Provider {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "turnstile" => Ok(Provider::Turnstile),
+            "hcaptcha" => Ok(Provider::HCaptcha),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Provider {
+    fn verify_url(&self) -> &'static str {
+        match self {
+            Provider::Turnstile => "https://challenges.cloudflare.com/turnstile/v0/siteverify",
+            Provider::HCaptcha => "https://api.hcaptcha.com/siteverify",
+        }
+    }
+}
+
+/// The CAPTCHA registering new devices needs
+#[derive(Clone, PartialEq)]
+pub struct Captcha {
+    pub provider: Provider,
+    /// Shown to the upload page's widget
+    pub site_key: String,
+    pub secret: String,
+}
+
+impl std::fmt::Debug for Captcha {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Captcha")
+            .field("provider", &self.provider)
+            .field("site_key", &self.site_key)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Deserialize)]
+struct VerifyResponse {
+    success: bool,
+    #[serde(default, rename = "error-codes")]
+    error_codes: Vec<String>,
+}
+
+impl Captcha {
+    /// Asks the provider whether the token was solved by the client at `ip`
+    pub async fn verify(&self, token: &str, ip: IpAddr) -> Result<(), String> {
+        let client = reqwest::Client::builder()
+            .timeout(VERIFY_TIMEOUT)
+            .build()
+            .map_err(|e| format!("Failed to build the HTTP client: {e}"))?;
+        let response = client
+            .post(self.provider.verify_url())
+            .form(&[
+                ("secret", self.secret.as_str()),
+                ("response", token),
+                ("remoteip", ip.to_canonical().to_string().as_str()),
+            ])
+            .send()
+            .await
+            .map_err(|e| {
+                warn!("Failed to reach the CAPTCHA provider: {e}");
+                "Failed to verify the CAPTCHA, try again".to_string()
+            })?
+            .json::<VerifyResponse>()
+            .await
+            .map_err(|e| {
+                warn!("The CAPTCHA provider answered with something unexpected: {e}");
+                "Failed to verify the CAPTCHA, try again".to_string()
+            })?;
+        match response.success {
+            true => Ok(()),
+            false => Err(format!(
+                "The CAPTCHA wasn't solved ({})",
+                response.error_codes.join(", ")
+            )),
+        }
+    }
+
+    /// The widget for the upload form, which adds its token to the form when solved
+    pub fn widget(&self) -> String {
+        let (script, class) = match self.provider {
+            Provider::Turnstile => (
+                "https://challenges.cloudflare.com/turnstile/v0/api.js",
+                "cf-turnstile",
+            ),
+            Provider::HCaptcha => ("https://js.hcaptcha.com/1/api.js", "h-captcha"),
+        };
+        format!(
+            r#"<script src="{script}" async defer></script>
+        <div class="{class}" data-sitekey="{}"></div>"#,
+            self.site_key
+        )
+    }
+}
@@ -0,0 +1,217 @@
+// Jackson Coxson
+// GET /check, which walks through everything a launch needs and says which step is broken
+
+use axum::{extract::State, Json};
+use axum_client_ip::SecureClientIp;
+use serde::Serialize;
+use tracing::debug;
+
+use crate::{
+    common, device,
+    error::{ErrorCode, JitError},
+    launcher, lockdown_jit, mount, pipeline,
+    provider::DeviceProvider,
+    JitStreamerState,
+};
+
+/// The checks, in the order a launch needs them
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Step {
+    Registration,
+    PairingFile,
+    Reachable,
+    DdiMounted,
+    DeveloperMode,
+    DebugServices,
+}
+
+const STEPS: [Step; 6] = [
+    Step::Registration,
+    Step::PairingFile,
+    Step::Reachable,
+    Step::DdiMounted,
+    Step::DeveloperMode,
+    Step::DebugServices,
+];
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Pass,
+    Fail,
+    /// An earlier step failed, or the step doesn't apply to the device
+    Skipped,
+}
+
+#[derive(Serialize)]
+pub struct CheckItem {
+    step: Step,
+    status: Status,
+    /// Why the step failed
+    #[serde(flatten)]
+    error: Option<JitError>,
+}
+
+#[derive(Serialize)]
+pub struct CheckReturn {
+    /// Every step passed or doesn't apply
+    ok: bool,
+    udid: Option<String>,
+    checks: Vec<CheckItem>,
+    /// The first failure, which is the one to fix
+    #[serde(flatten)]
+    error: Option<JitError>,
+}
+
+#[derive(Default)]
+struct Checklist(Vec<CheckItem>);
+
+impl Checklist {
+    fn pass(&mut self, step: Step) {
+        self.push(step, Status::Pass, None);
+    }
+
+    fn fail(&mut self, step: Step, error: JitError) {
+        self.push(step, Status::Fail, Some(error));
+    }
+
+    fn skip(&mut self, step: Step) {
+        self.push(step, Status::Skipped, None);
+    }
+
+    fn push(&mut self, step: Step, status: Status, error: Option<JitError>) {
+        self.0.push(CheckItem {
+            step,
+            status,
+            error,
+        });
+    }
+
+    fn finish(mut self, udid: Option<String>) -> Json<CheckReturn> {
+        for step in STEPS {
+            if !self.0.iter().any(|c| c.step == step) {
+                self.skip(step);
+            }
+        }
+        let error = self.0.iter().find_map(|c| c.error.clone());
+        Json(CheckReturn {
+            ok: error.is_none(),
+            udid,
+            checks: self.0,
+            error,
+        })
+    }
+}
+
+/// Checks the caller's device step by step, without mounting or launching anything. Once the
+/// device is reached, the remaining steps all run so each one's state is reported.
+pub async fn handler(
+    ip: SecureClientIp,
+    selector: common::DeviceSelector,
+    State(state): State<JitStreamerState>,
+) -> Json<CheckReturn> {
+    let mut checklist = Checklist::default();
+    let (udid, device_ip) =
+        match common::get_device(&state, ip.0, &selector, state.config().allow_udid_override).await
+        {
+            Ok(d) => d,
+            Err(e) => {
+                checklist.fail(Step::Registration, e);
+                return checklist.finish(None);
+            }
+        };
+    checklist.pass(Step::Registration);
+
+    let provider = match state.backend.provider(&state, &udid, device_ip).await {
+        Ok(p) => p,
+        // A missing file is known up front, only the device can tell that one is stale
+        Err(e)
+            if matches!(
+                e.code,
+                ErrorCode::PairingMissing | ErrorCode::PairingInvalid
+            ) =>
+        {
+            checklist.fail(Step::PairingFile, e);
+            return checklist.finish(Some(udid));
+        }
+        Err(e) => {
+            checklist.pass(Step::PairingFile);
+            checklist.fail(Step::Reachable, e);
+            return checklist.finish(Some(udid));
+        }
+    };
+    checklist.pass(Step::PairingFile);
+    checklist.pass(Step::Reachable);
+
+    let timeout = state.config().device_timeouts.attach;
+    let res = common::timeout(timeout, "checking", async {
+        device_checks(&state, &udid, &provider, &mut checklist).await;
+        Ok(())
+    })
+    .await;
+    state.heartbeats.release(&udid).await.ok();
+    if let Err(e) = res {
+        // Blame the step that hung
+        let step = STEPS
+            .into_iter()
+            .find(|s| !checklist.0.iter().any(|c| c.step == *s));
+        if let Some(step) = step {
+            checklist.fail(step, e);
+        }
+    }
+    checklist.finish(Some(udid))
+}
+
+async fn device_checks(
+    state: &JitStreamerState,
+    udid: &str,
+    provider: &DeviceProvider,
+    checklist: &mut Checklist,
+) {
+    match mount::ddi_mounted(provider).await {
+        Ok(true) => checklist.pass(Step::DdiMounted),
+        Ok(false) => checklist.fail(
+            Step::DdiMounted,
+            JitError::new(
+                ErrorCode::DdiNotMounted,
+                "The developer disk image isn't mounted. Mount it and try again.",
+            ),
+        ),
+        Err(e) => checklist.fail(Step::DdiMounted, e),
+    }
+
+    match device::developer_mode_enabled(provider).await {
+        Ok(true) => checklist.pass(Step::DeveloperMode),
+        Ok(false) => checklist.fail(
+            Step::DeveloperMode,
+            device::developer_mode_disabled(provider).await,
+        ),
+        // Before iOS 16 there's no Developer Mode to turn on
+        Err(e) => {
+            debug!("Failed to get the Developer Mode status of {udid}: {e:?}");
+            checklist.skip(Step::DeveloperMode);
+        }
+    }
+
+    let method = match device::get_device_info(&state.device_info_cache, udid, provider).await {
+        Ok(info) => info.jit_method,
+        Err(_) => launcher::JitMethod::for_version(None),
+    };
+    let res = match method {
+        launcher::JitMethod::Lockdown => lockdown_jit::debugserver_available(provider).await,
+        launcher::JitMethod::RemoteXpc => crate::jit::developer_service(
+            state,
+            udid,
+            provider,
+            idevice::debug_proxy::SERVICE_NAME,
+            pipeline::DEBUG_PROXY_MISSING,
+        )
+        .await
+        .map(|_| ()),
+    };
+    match res {
+        Ok(()) => checklist.pass(Step::DebugServices),
+        Err(e) => checklist.fail(Step::DebugServices, e),
+    }
+}
@@ -0,0 +1,43 @@
+// Jackson Coxson
+// The subcommands that run instead of the server
+
+use clap::Subcommand;
+
+use crate::{backup, config::Config, db, doctor, ops};
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    #[command(flatten)]
+    Backup(backup::Command),
+    #[command(flatten)]
+    Ops(ops::Command),
+    /// Checks for the tools, permissions and ports the server needs, printing how to fix
+    /// anything that's missing
+    Doctor,
+}
+
+/// Runs the subcommand instead of the server
+pub async fn run(command: Command, config: &Config) -> Result<(), String> {
+    match command {
+        // Opening the database is one of the checks
+        Command::Doctor => doctor::run(config).await,
+        Command::Backup(command) => {
+            let (db, writer) = open(config).await?;
+            backup::run(command, config, &db, &writer).await
+        }
+        Command::Ops(command) => {
+            let (db, writer) = open(config).await?;
+            ops::run(command, config, &db, &writer).await
+        }
+    }
+}
+
+async fn open(config: &Config) -> Result<(db::DbPool, db::Writer), String> {
+    let db = db::connect(&config.database_path)
+        .await
+        .map_err(|e| format!("Failed to open database: {e}"))?;
+    let writer = db::Writer::spawn(&db)
+        .await
+        .map_err(|e| format!("Failed to open database: {e}"))?;
+    Ok((db, writer))
+}
@@ -0,0 +1,96 @@
+// Jackson Coxson
+// Which app is calling, from X-Client, so stats can tell clients apart and old ones get the fields they read
+
+use std::convert::Infallible;
+
+use axum::{
+    extract::FromRequestParts,
+    http::{request::Parts, HeaderMap},
+};
+use serde::{Deserialize, Serialize};
+
+pub const CLIENT_HEADER: &str = "x-client";
+/// Longer values are cut, they're stored with every launch
+const MAX_CLIENT_LEN: usize = 64;
+
+/// The response shape a client expects
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Profile {
+    /// The original JitStreamer's, with `launching`, `position` and `mounting`. Clients
+    /// that don't send X-Client, such as the shortcut, get this.
+    Legacy,
+    /// Only the fields this server fills in
+    Current,
+}
+
+/// The `X-Client` header, such as `SideStore/0.6.1` or `StikJIT/2.0`
+#[derive(Clone, Debug, Default)]
+pub struct Client(Option<String>);
+
+impl Client {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let client = headers
+            .get(CLIENT_HEADER)
+            .and_then(|h| h.to_str().ok())
+            .map(|h| h.trim())
+            .filter(|h| !h.is_empty())
+            .map(|h| h.chars().take(MAX_CLIENT_LEN).collect());
+        Self(client)
+    }
+
+    pub fn new(client: Option<&str>) -> Self {
+        Self(
+            client
+                .map(|c| c.trim())
+                .filter(|c| !c.is_empty())
+                .map(|c| c.chars().take(MAX_CLIENT_LEN).collect()),
+        )
+    }
+
+    /// The header as sent, for stats
+    pub fn name(&self) -> Option<&str> {
+        self.0.as_deref()
+    }
+
+    pub fn profile(&self) -> Profile {
+        let product = match &self.0 {
+            Some(c) => c.split('/').next().unwrap_or_default().to_lowercase(),
+            None => return Profile::Legacy,
+        };
+        match product.replace([' ', '-', '_'], "").as_str() {
+            "sidestore" | "stikjit" | "stikdebug" => Profile::Current,
+            _ => Profile::Legacy,
+        }
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Client {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self::from_headers(&parts.headers))
+    }
+}
+
+/// Fields the original JitStreamer returned from /launch_app. They say nothing, but old
+/// shortcuts check them.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LegacyLaunchFields {
+    launching: bool,
+    position: Option<usize>,
+    mounting: bool,
+}
+
+impl Profile {
+    /// The legacy fields for a launch that succeeded or not, None for current clients
+    pub fn launch_fields(self, ok: bool) -> Option<LegacyLaunchFields> {
+        match self {
+            Profile::Legacy => Some(LegacyLaunchFields {
+                launching: ok,
+                position: ok.then_some(0),
+                mounting: false,
+            }),
+            Profile::Current => None,
+        }
+    }
+}
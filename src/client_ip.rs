@@ -0,0 +1,99 @@
+// Jackson Coxson
+// Finds the real client address when running behind a reverse proxy
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+use tracing::debug;
+
+use crate::{acl::Allowlist, config::SharedConfig};
+
+/// Where the client's address is read from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClientIpSource {
+    /// The address of the TCP connection
+    ConnectInfo,
+    /// The X-Forwarded-For header, skipping trusted proxies from the right
+    XForwardedFor,
+    /// The CF-Connecting-IP header set by Cloudflare
+    CfConnectingIp,
+}
+
+impl FromStr for ClientIpSource {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().replace('-', "_").as_str() {
+            "connect_info" => Ok(Self::ConnectInfo),
+            "x_forwarded_for" => Ok(Self::XForwardedFor),
+            "cf_connecting_ip" => Ok(Self::CfConnectingIp),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Middleware replacing the connection's address with the client's, so every
+/// handler and allowlist sees the device instead of the proxy.
+/// Headers are only believed when the connection comes from a trusted proxy.
+pub async fn middleware(
+    State(config): State<SharedConfig>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let config = config.load_full();
+    if config.client_ip_source == ClientIpSource::ConnectInfo {
+        return next.run(request).await;
+    }
+    let Some(ConnectInfo(peer)) = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .copied()
+    else {
+        return next.run(request).await;
+    };
+    if !config.trusted_proxies.contains(peer.ip()) {
+        debug!("Ignoring forwarded address from untrusted peer {peer}");
+        return next.run(request).await;
+    }
+
+    let client = match config.client_ip_source {
+        ClientIpSource::ConnectInfo => None,
+        ClientIpSource::XForwardedFor => forwarded_for(request.headers(), &config.trusted_proxies),
+        ClientIpSource::CfConnectingIp => request
+            .headers()
+            .get("cf-connecting-ip")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| IpAddr::from_str(h.trim()).ok()),
+    };
+    if let Some(ip) = client {
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::new(ip, peer.port())));
+    }
+    next.run(request).await
+}
+
+/// The rightmost address that isn't a trusted proxy, since anything left of it
+/// could have been sent by the client
+fn forwarded_for(headers: &HeaderMap, trusted: &Allowlist) -> Option<IpAddr> {
+    let hops = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .filter_map(|a| IpAddr::from_str(a.trim()).ok())
+        .collect::<Vec<IpAddr>>();
+    hops.iter()
+        .rev()
+        .find(|ip| !trusted.contains(**ip))
+        .or(hops.first())
+        .copied()
+}
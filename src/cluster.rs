@@ -0,0 +1,258 @@
+// Jackson Coxson
+// Coordinates several servers behind a load balancer, so only one reaches each device at a time
+
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use redis::{aio::ConnectionManager, Script};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::{
+    error::{ErrorCode, JitError},
+    heartbeat::HeartbeatStatus,
+    JitStreamerState,
+};
+
+/// How long a lease lasts without being renewed, so a node that dies frees its devices
+const LEASE_TTL: Duration = Duration::from_secs(30);
+/// How often a node renews the leases of the heartbeats it holds
+const RENEW_INTERVAL: Duration = Duration::from_secs(10);
+/// Where heartbeat stores and kills are published
+const HEARTBEAT_CHANNEL: &str = "jitstreamer:heartbeats";
+/// How long to wait before subscribing again after losing the connection
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(5);
+
+/// Takes the lease if it's free or already ours, returning the owner
+const CLAIM: &str = r#"
+local owner = redis.call('GET', KEYS[1])
+if owner == false or owner == ARGV[1] then
+    redis.call('SET', KEYS[1], ARGV[1], 'PX', ARGV[2])
+    return ARGV[1]
+end
+return owner
+"#;
+
+/// Drops the lease only if it's ours
+const RELEASE: &str = r#"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+"#;
+
+fn lease_key(udid: &str) -> String {
+    format!("jitstreamer:lease:{udid}")
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeartbeatOp {
+    /// The node started a heartbeat to the device, so no other node should have one
+    Store,
+    Kill,
+}
+
+/// A heartbeat change on one node, published to the others
+#[derive(Serialize, Deserialize)]
+struct HeartbeatMessage {
+    node: String,
+    op: HeartbeatOp,
+    udid: String,
+}
+
+#[derive(Clone)]
+struct Redis {
+    /// Subscribing needs a connection of its own
+    client: redis::Client,
+    connection: ConnectionManager,
+}
+
+/// This node's name, and the Redis server the nodes share when there are several
+#[derive(Clone)]
+pub struct Cluster {
+    node: String,
+    redis: Option<Redis>,
+}
+
+impl Cluster {
+    pub async fn connect(node: String, redis_url: Option<&str>) -> Result<Self, redis::RedisError> {
+        let redis = match redis_url {
+            Some(url) => {
+                let client = redis::Client::open(url)?;
+                let connection = client.get_connection_manager().await?;
+                info!("Joined the cluster as node {node}");
+                Some(Redis { client, connection })
+            }
+            None => None,
+        };
+        Ok(Self { node, redis })
+    }
+
+    pub fn node(&self) -> &str {
+        &self.node
+    }
+
+    /// Takes or renews the lease on the device, failing if another node holds it.
+    /// Always succeeds without REDIS_URL, there's no one to share with.
+    pub async fn claim(&self, udid: &str) -> Result<(), JitError> {
+        let mut redis = match &self.redis {
+            Some(r) => r.connection.clone(),
+            None => return Ok(()),
+        };
+        let owner: String = Script::new(CLAIM)
+            .key(lease_key(udid))
+            .arg(&self.node)
+            .arg(LEASE_TTL.as_millis() as u64)
+            .invoke_async(&mut redis)
+            .await
+            .map_err(|e| {
+                warn!("Failed to claim {udid}: {e}");
+                JitError::internal("Failed to reach the cluster's Redis server")
+            })?;
+        match owner == self.node {
+            true => Ok(()),
+            false => Err(JitError::new(
+                ErrorCode::Busy,
+                format!("Your device is connected through node {owner}, retry shortly"),
+            )),
+        }
+    }
+
+    /// Frees the device for other nodes, if this node holds it
+    pub async fn release(&self, udid: &str) {
+        let mut redis = match &self.redis {
+            Some(r) => r.connection.clone(),
+            None => return,
+        };
+        if let Err(e) = Script::new(RELEASE)
+            .key(lease_key(udid))
+            .arg(&self.node)
+            .invoke_async::<i64>(&mut redis)
+            .await
+        {
+            warn!("Failed to release {udid}: {e}");
+        }
+    }
+
+    /// Tells the other nodes about a heartbeat change in the background. A kill also
+    /// frees the device's lease.
+    pub fn publish(&self, op: HeartbeatOp, udid: &str) {
+        let mut redis = match &self.redis {
+            Some(r) => r.connection.clone(),
+            None => return,
+        };
+        let message = serde_json::to_string(&HeartbeatMessage {
+            node: self.node.clone(),
+            op,
+            udid: udid.to_string(),
+        })
+        .unwrap();
+        let cluster = self.clone();
+        let udid = udid.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = redis::cmd("PUBLISH")
+                .arg(HEARTBEAT_CHANNEL)
+                .arg(message)
+                .query_async::<i64>(&mut redis)
+                .await
+            {
+                warn!("Failed to publish heartbeat change: {e}");
+            }
+            if let HeartbeatOp::Kill = op {
+                cluster.release(&udid).await;
+            }
+        });
+    }
+
+    /// Checks the Redis server answers, for /healthz. None without REDIS_URL.
+    pub async fn ping(&self) -> Option<Result<(), String>> {
+        let mut redis = self.redis.as_ref()?.connection.clone();
+        Some(
+            redis::cmd("PING")
+                .query_async::<String>(&mut redis)
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+        )
+    }
+}
+
+/// Renews the leases of every heartbeat this node holds, so they outlive LEASE_TTL.
+/// Leases of heartbeats that died or failed expire on their own.
+pub async fn renew_leases(state: JitStreamerState) {
+    if state.cluster.redis.is_none() {
+        return;
+    }
+    let mut interval = tokio::time::interval(RENEW_INTERVAL);
+    loop {
+        interval.tick().await;
+        let heartbeats = match state.heartbeats.list().await {
+            Ok(h) => h,
+            Err(_) => return,
+        };
+        for heartbeat in heartbeats {
+            if matches!(heartbeat.status, HeartbeatStatus::Failed(_)) {
+                continue;
+            }
+            match state.cluster.claim(&heartbeat.udid).await {
+                // Another node took it after the lease lapsed, let it have the device
+                Err(e) if e.code == ErrorCode::Busy => {
+                    warn!("Lost the lease on {}: {}", heartbeat.udid, e.message);
+                    state.heartbeats.evict(&heartbeat.udid).await.ok();
+                }
+                // Redis is down, the lease is renewed once it's back
+                Err(_) | Ok(()) => {}
+            }
+        }
+    }
+}
+
+/// Applies heartbeat changes published by other nodes: a device another node stored or
+/// killed a heartbeat for shouldn't keep one here either
+pub async fn listen(state: JitStreamerState) {
+    let client = match &state.cluster.redis {
+        Some(r) => r.client.clone(),
+        None => return,
+    };
+    loop {
+        let mut pubsub = match client.get_async_pubsub().await {
+            Ok(p) => p,
+            Err(e) => {
+                warn!("Failed to connect to Redis for heartbeat changes: {e}");
+                tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+                continue;
+            }
+        };
+        if let Err(e) = pubsub.subscribe(HEARTBEAT_CHANNEL).await {
+            warn!("Failed to subscribe to heartbeat changes: {e}");
+            tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+            continue;
+        }
+
+        let mut messages = pubsub.into_on_message();
+        while let Some(message) = messages.next().await {
+            let message = match message
+                .get_payload::<String>()
+                .ok()
+                .and_then(|p| serde_json::from_str::<HeartbeatMessage>(&p).ok())
+            {
+                Some(m) => m,
+                None => continue,
+            };
+            if message.node == state.cluster.node {
+                continue;
+            }
+            debug!(
+                "Node {} sent {:?} for {}",
+                message.node, message.op, message.udid
+            );
+            state.heartbeats.evict(&message.udid).await.ok();
+            if let HeartbeatOp::Kill = message.op {
+                state.cluster.release(&message.udid).await;
+            }
+        }
+        warn!("Lost the subscription to heartbeat changes, subscribing again");
+        tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+    }
+}
@@ -1,50 +1,373 @@
 // Jackson Coxson
 
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    future::Future,
+    net::IpAddr,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{extract::FromRequestParts, http::request::Parts};
 use idevice::pairing_file::PairingFile;
-use log::info;
-
-pub async fn get_udid_from_ip(ip: String) -> Result<String, String> {
-    tokio::task::spawn_blocking(move || {
-        let db = match sqlite::open("jitstreamer.db") {
-            Ok(db) => db,
-            Err(e) => {
-                info!("Failed to open database: {:?}", e);
-                return Err(format!("Failed to open database: {:?}", e));
-            }
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::{
+    config::Config,
+    db::{DbPool, Writer},
+    error::{ErrorCode, JitError},
+    identity,
+    pairing_store::{Backend, PairingStore},
+    JitStreamerState,
+};
+
+pub const DEVICE_TOKEN_HEADER: &str = "x-jitstreamer-token";
+pub const DEVICE_HEADER: &str = "x-jitstreamer-device";
+pub const UDID_OVERRIDE_HEADER: &str = "x-jitstreamer-udid";
+
+/// How the client picks which registered device it means, beyond its IP.
+/// Read from headers, or query parameters for clients that can't set headers, such as websockets.
+#[derive(Clone, Default)]
+pub struct DeviceSelector {
+    /// The token issued at registration, sent by devices whose public IP is shared or changes.
+    /// `X-JitStreamer-Token` header or `token` query parameter.
+    pub token: Option<String>,
+    /// The UDID of one of the devices registered from the client's IP.
+    /// `X-JitStreamer-Device` header or `device` query parameter.
+    pub device: Option<String>,
+    /// Skips the IP lookup entirely for clients behind proxies, if the server allows it.
+    /// `X-JitStreamer-UDID` header or `udid` query parameter.
+    pub udid: Option<String>,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for DeviceSelector {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let read = |header: &str, param: &str| {
+            let header = parts
+                .headers
+                .get(header)
+                .and_then(|h| h.to_str().ok())
+                .map(|h| h.to_string());
+            let query = parts
+                .uri
+                .query()
+                .and_then(|q| {
+                    q.split('&')
+                        .find_map(|p| p.strip_prefix(param)?.strip_prefix('='))
+                })
+                .map(|t| t.to_string());
+            header.or(query).filter(|t| !t.is_empty())
         };
+        Ok(Self {
+            token: read(DEVICE_TOKEN_HEADER, "token"),
+            device: read(DEVICE_HEADER, "device"),
+            udid: read(UDID_OVERRIDE_HEADER, "udid"),
+        })
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Lookup {
+    Ip(String),
+    /// A device picked from the ones registered from an IP
+    Selected(String, String),
+    Token(String),
+}
+
+/// Caches which UDID an IP or token resolves to, saving a database round trip per request
+#[derive(Clone)]
+pub struct UdidCache {
+    inner: Arc<Mutex<HashMap<Lookup, (String, Instant)>>>,
+    ttl: Duration,
+}
+
+impl UdidCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(HashMap::new())),
+            ttl,
+        }
+    }
+
+    async fn get(&self, lookup: &Lookup) -> Option<String> {
+        let mut lock = self.inner.lock().await;
+        match lock.get(lookup) {
+            Some((udid, fetched)) if fetched.elapsed() < self.ttl => Some(udid.clone()),
+            Some(_) => {
+                lock.remove(lookup);
+                None
+            }
+            None => None,
+        }
+    }
+
+    async fn insert(&self, lookup: Lookup, udid: &str) {
+        self.inner
+            .lock()
+            .await
+            .insert(lookup, (udid.to_string(), Instant::now()));
+    }
+
+    /// Forgets every lookup for the device and the IP it registered from
+    pub async fn invalidate(&self, udid: &str, ip: &str) {
+        self.inner
+            .lock()
+            .await
+            .retain(|lookup, (u, _)| u != udid && !matches!(lookup, Lookup::Ip(i) if i == ip));
+    }
+}
+
+/// Identifies the calling device by its token if it sent one, otherwise by its IP
+pub async fn get_udid(
+    state: &JitStreamerState,
+    ip: String,
+    selector: &DeviceSelector,
+) -> Result<String, JitError> {
+    let lookup = match (&selector.token, &selector.device) {
+        (Some(t), _) => Lookup::Token(t.clone()),
+        (None, Some(d)) => Lookup::Selected(ip.clone(), d.clone()),
+        (None, None) => Lookup::Ip(ip.clone()),
+    };
+    if let Some(udid) = state.udid_cache.get(&lookup).await {
+        return Ok(udid);
+    }
+
+    let udid = match &selector.token {
+        Some(t) => get_udid_from_token(&state.db, &state.config(), t, &ip).await?,
+        None => get_udid_from_ip(&state.db, ip, selector.device.as_deref()).await?,
+    };
+    state.udid_cache.insert(lookup, &udid).await;
+    Ok(udid)
+}
 
-        // Get the device from the database
-        let query = "SELECT udid FROM devices WHERE ip = ?";
-        let mut statement = match crate::db::db_prepare(&db, query) {
-            Some(s) => s,
+/// Identifies the calling device and the address to reach it at.
+/// With overrides allowed, an explicit UDID is trusted and the device is reached at its registered address.
+pub async fn get_device(
+    state: &JitStreamerState,
+    ip: IpAddr,
+    selector: &DeviceSelector,
+    allow_override: bool,
+) -> Result<(String, IpAddr), JitError> {
+    let udid = match &selector.udid {
+        Some(udid) => udid,
+        None => return Ok((get_udid(state, ip.to_string(), selector).await?, ip)),
+    };
+    if !allow_override {
+        return Err(JitError::new(
+            ErrorCode::Forbidden,
+            "This server doesn't allow choosing a device by UDID",
+        ));
+    }
+
+    match sqlx::query_scalar::<_, String>("SELECT ip FROM devices WHERE udid = ?")
+        .bind(udid)
+        .fetch_optional(&state.db)
+        .await
+    {
+        Ok(Some(registered)) => match IpAddr::from_str(&registered) {
+            Ok(registered) => {
+                info!("Using UDID override {udid} from {:?}", ip);
+                Ok((udid.clone(), registered.to_canonical()))
+            }
+            Err(_) => Err(JitError::internal(format!(
+                "Device {udid} has an invalid registered IP"
+            ))),
+        },
+        Ok(None) => Err(JitError::new(
+            ErrorCode::NotRegistered,
+            format!("Device {udid} is not registered"),
+        )),
+        Err(e) => {
+            tracing::error!("Failed to query database: {e:?}");
+            Err(JitError::internal("Failed to open database"))
+        }
+    }
+}
+
+/// Finds the device the token was issued to, once its signature checks out
+async fn get_udid_from_token(
+    db: &DbPool,
+    config: &Config,
+    token: &str,
+    ip: &str,
+) -> Result<String, JitError> {
+    let unknown = || {
+        JitError::new(
+            ErrorCode::NotRegistered,
+            "Unknown device token, register again to get a new one",
+        )
+    };
+    let Some(udid) = identity::verify(config, token) else {
+        info!("Invalid device token from {:?}", ip);
+        return Err(unknown());
+    };
+    match sqlx::query_scalar::<_, String>("SELECT udid FROM devices WHERE udid = ?")
+        .bind(udid)
+        .fetch_optional(db)
+        .await
+    {
+        Ok(Some(udid)) => {
+            info!("Found device with udid {} by token", udid);
+            Ok(udid)
+        }
+        Ok(None) => {
+            info!("Device {udid} from {:?} is no longer registered", ip);
+            Err(unknown())
+        }
+        Err(e) => {
+            tracing::error!("Failed to query database: {e:?}");
+            Err(JitError::internal("Failed to open database"))
+        }
+    }
+}
+
+async fn get_udid_from_ip(
+    db: &DbPool,
+    ip: String,
+    device: Option<&str>,
+) -> Result<String, JitError> {
+    let udids = get_devices_for_ip(db, &ip)
+        .await?
+        .into_iter()
+        .map(|(udid, _)| udid)
+        .collect::<Vec<String>>();
+
+    if let Some(device) = device {
+        return match udids.iter().find(|u| u.as_str() == device) {
+            Some(udid) => {
+                info!("Found selected device with udid {}", udid);
+                Ok(udid.clone())
+            }
             None => {
-                log::error!("Failed to prepare query!");
-                return Err("Failed to open database".to_string());
+                info!("Device {device} is not registered from IP {:?}", ip);
+                Err(JitError::new(
+                    ErrorCode::NotRegistered,
+                    format!("Device {device} is not registered from IP {:?}", ip),
+                ))
             }
         };
-        statement.bind((1, ip.as_str())).unwrap();
-        let udid = if let Some(sqlite::State::Row) = crate::db::statement_next(&mut statement) {
-            let udid = statement.read::<String, _>("udid").unwrap();
+    }
+
+    match udids.as_slice() {
+        [udid] => {
             info!("Found device with udid {}", udid);
-            udid
-        } else {
+            Ok(udid.clone())
+        }
+        [] => {
             info!("No device found for IP {:?}", ip);
-            return Err(format!("No device found for IP {:?}", ip));
-        };
-        Ok(udid)
-    })
+            Err(JitError::new(
+                ErrorCode::NotRegistered,
+                format!("No device found for IP {:?}", ip),
+            ))
+        }
+        _ => {
+            info!("{} devices share IP {:?}", udids.len(), ip);
+            Err(JitError::new(
+                ErrorCode::DeviceAmbiguous,
+                format!(
+                    "Multiple devices are registered from IP {:?}. Pick one from /devices with the X-JitStreamer-Device header, or send the token you got at registration in the X-JitStreamer-Token header.",
+                    ip
+                ),
+            ))
+        }
+    }
+}
+
+/// Gets the UDID and last used time of every device registered from the IP, either of a
+/// dual stack device's addresses
+pub async fn get_devices_for_ip(db: &DbPool, ip: &str) -> Result<Vec<(String, String)>, JitError> {
+    sqlx::query_as::<_, (String, String)>(
+        "SELECT udid, CAST(last_used AS TEXT) FROM devices WHERE ip = ? OR ipv4 = ? ORDER BY last_used DESC",
+    )
+    .bind(ip)
+    .bind(ip)
+    .fetch_all(db)
     .await
-    .unwrap()
+    .map_err(|e| {
+        tracing::error!("Failed to query database: {e:?}");
+        JitError::internal("Failed to open database")
+    })
+}
+
+/// Marks the device as used now, keeping it from expiring
+pub async fn touch_device(db: &Writer, udid: &str) {
+    if let Err(e) = db
+        .execute(
+            sqlx::query("UPDATE devices SET last_used = CURRENT_TIMESTAMP WHERE udid = ?")
+                .bind(udid),
+        )
+        .await
+    {
+        tracing::error!("Failed to update last_used: {e:?}");
+    }
 }
 
 /// Gets the pairing file
-pub async fn get_pairing_file(
-    udid: &str,
-    pairing_file_storage: &str,
-) -> Result<PairingFile, idevice::IdeviceError> {
-    // All pairing files are stored at /var/lib/lockdown/<udid>.plist
-    let path = format!("{pairing_file_storage}/{udid}.plist");
-    let pairing_file = tokio::fs::read(path).await?;
-
-    PairingFile::from_bytes(&pairing_file)
+pub async fn get_pairing_file(udid: &str, store: &Backend) -> Result<PairingFile, JitError> {
+    let pairing_file = match store.get(udid).await {
+        Ok(Some(p)) => p,
+        Ok(None) => {
+            info!("No pairing file stored for {udid}");
+            return Err(JitError::new(
+                ErrorCode::PairingMissing,
+                "No pairing file is stored for this device. Register again to upload it.",
+            ));
+        }
+        Err(e) => {
+            info!("Failed to read pairing file for {udid}: {e:?}");
+            return Err(JitError::new(
+                ErrorCode::PairingMissing,
+                format!("Failed to get pairing file: {e}. Register again to upload it."),
+            ));
+        }
+    };
+
+    PairingFile::from_bytes(&pairing_file).map_err(|e| {
+        JitError::new(
+            ErrorCode::PairingInvalid,
+            format!("Failed to get pairing file: {e}. Regenerate it with jitterbug pair."),
+        )
+    })
+}
+
+/// A random UUID in uppercase, the form lockdown and Apple profiles use
+pub fn random_uuid() -> String {
+    let b = rand::random::<[u8; 16]>();
+    let hex =
+        |r: std::ops::Range<usize>| b[r].iter().map(|b| format!("{b:02X}")).collect::<String>();
+    format!(
+        "{}-{}-{}-{}-{}",
+        hex(0..4),
+        hex(4..6),
+        hex(6..8),
+        hex(8..10),
+        hex(10..16)
+    )
+}
+
+/// Fails with DEVICE_TIMEOUT if the device operation doesn't finish in time, so a hung
+/// connection can't stall the request forever. `what` finishes "didn't finish ...".
+pub async fn timeout<T>(
+    limit: Duration,
+    what: &str,
+    operation: impl Future<Output = Result<T, JitError>>,
+) -> Result<T, JitError> {
+    match tokio::time::timeout(limit, operation).await {
+        Ok(res) => res,
+        Err(_) => {
+            warn!("Device timed out {what} after {limit:?}");
+            Err(JitError::new(
+                ErrorCode::DeviceTimeout,
+                format!(
+                    "The device didn't finish {what} within {} seconds, make sure it's awake and connected",
+                    limit.as_secs()
+                ),
+            ))
+        }
+    }
 }
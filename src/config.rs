@@ -0,0 +1,1368 @@
+// Jackson Coxson
+// Server configuration, validated at startup and again on every reload.
+// Each setting is read from CLI flags, then the environment, then the TOML config file.
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::{IpAddr, Ipv6Addr},
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use arc_swap::ArcSwap;
+use axum::http::{HeaderName, HeaderValue, Method};
+use clap::Parser;
+use tracing::{info, warn};
+
+use crate::{
+    acl::{Allowlist, Cidr},
+    captcha::{self, Captcha},
+    cli,
+    client_ip::ClientIpSource,
+    heartbeat::HeartbeatConfig,
+    identity,
+    mobileconfig::ProfileSigning,
+    pairing_store,
+    register::Ipv6Allocation,
+    retry::RetryPolicy,
+    scheduler::Job,
+    shortcut::ShortcutRelease,
+    tunnel::{self, TunnelKind, VersionTunnel},
+};
+
+const DEFAULT_CONFIG_FILE: &str = "jitstreamer.toml";
+/// Where the generated device token secret is kept, inside DATA_DIR
+const DEVICE_TOKEN_SECRET_FILE: &str = "device_token_secret";
+
+#[derive(Parser, Debug)]
+#[command(version, about = "JIT enabler for iOS devices over the network")]
+struct Cli {
+    /// TOML file to read settings from, keys are the variable names in lowercase
+    #[arg(short, long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// The port to bind to (JITSTREAMER_PORT)
+    #[arg(short, long)]
+    port: Option<u16>,
+    /// Registration mode, 0 disables registering (ALLOW_REGISTRATION)
+    #[arg(long)]
+    allow_registration: Option<u8>,
+    /// Where pairing files are stored (PLIST_STORAGE)
+    #[arg(long, value_name = "PATH")]
+    plist_storage: Option<String>,
+    /// The folder the database, pairing files and Wireguard configs are kept in (DATA_DIR)
+    #[arg(long, value_name = "PATH")]
+    data_dir: Option<String>,
+    /// Sets any other variable, such as --set MAX_HEARTBEATS=500
+    #[arg(short, long = "set", value_name = "VAR=VALUE")]
+    set: Vec<String>,
+    #[command(subcommand)]
+    command: Option<cli::Command>,
+}
+
+/// The subcommand to run instead of the server, if any
+pub fn command() -> Option<cli::Command> {
+    Cli::parse().command
+}
+
+#[derive(Debug)]
+pub struct ConfigError {
+    pub var: String,
+    pub value: String,
+    pub expected: &'static str,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} is set to {:?}, expected {}",
+            self.var, self.value, self.expected
+        )
+    }
+}
+
+/// The machine's hostname, so each node in a cluster gets a different name by default
+fn default_node_id() -> String {
+    std::fs::read_to_string("/etc/hostname")
+        .ok()
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "jitstreamer".to_string())
+}
+
+/// The live config, swapped out whole when it's reloaded
+pub type SharedConfig = Arc<ArcSwap<Config>>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WireguardConfig {
+    pub config_name: String,
+    /// The folder with the interfaces' config files
+    pub conf_dir: String,
+    pub port: u16,
+    pub server_address: String,
+    pub endpoint: String,
+    pub server_allowed_ips: String,
+    /// Also gives each peer an IPv4 address from this subnet, for networks that mangle
+    /// IPv6 inside the tunnel. The server takes the subnet's first address.
+    pub ipv4_subnet: Option<Cidr>,
+    /// Runs the interface in-process with boringtun instead of the kernel module
+    pub embedded: bool,
+}
+
+impl WireguardConfig {
+    pub fn conf_path(&self) -> String {
+        format!("{}/{}.conf", self.conf_dir, self.config_name)
+    }
+
+    /// The network peers get their IPv6 address from, the first of the server's allowed IPs.
+    /// Only its first 64 bits are used.
+    pub fn ipv6_network(&self) -> Ipv6Addr {
+        let network = self
+            .server_allowed_ips
+            .split(',')
+            .next()
+            .and_then(|n| n.trim().parse::<Cidr>().ok())
+            .map(|n| n.addr());
+        match network {
+            Some(IpAddr::V6(n)) => n,
+            _ => unreachable!("the config only accepts IPv6 networks"),
+        }
+    }
+}
+
+/// How long each device operation may take before failing with DEVICE_TIMEOUT
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceTimeouts {
+    /// Connecting to the device and starting its heartbeat, including waking it
+    pub heartbeat: Duration,
+    /// Tunneling, launching and attaching, after the heartbeat
+    pub launch: Duration,
+    pub get_apps: Duration,
+    pub attach: Duration,
+}
+
+/// How the server terminates HTTPS itself, instead of relying on a reverse proxy
+#[derive(Clone, Debug, PartialEq)]
+pub enum TlsConfig {
+    /// A certificate chain and private key in PEM files
+    Files { cert: PathBuf, key: PathBuf },
+    /// Certificates issued and renewed by Let's Encrypt
+    Acme {
+        domains: Vec<String>,
+        email: Option<String>,
+        cache: PathBuf,
+        staging: bool,
+    },
+}
+
+/// What a node in a deployment serves, so registering and launching can run on different
+/// hosts sharing one database
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    All,
+    /// Registering, which needs the Wireguard interface, but no device routes
+    RegisterOnly,
+    /// The device routes, launching and tunneling, but no registering
+    JitOnly,
+}
+
+impl Role {
+    pub fn registers(self) -> bool {
+        self != Role::JitOnly
+    }
+
+    pub fn serves_devices(self) -> bool {
+        self != Role::RegisterOnly
+    }
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Role::All => "all",
+            Role::RegisterOnly => "register-only",
+            Role::JitOnly => "jit-only",
+        })
+    }
+}
+
+impl FromStr for Role {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "all" => Ok(Role::All),
+            "register-only" => Ok(Role::RegisterOnly),
+            "jit-only" => Ok(Role::JitOnly),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Config {
+    pub allow_registration: u8,
+    pub port: u16,
+    /// Listen on JITSTREAMER_PORT, can be turned off when only serving on a Unix socket
+    pub listen_tcp: bool,
+    pub unix_socket: Option<PathBuf>,
+    /// Permissions of the Unix socket file
+    pub unix_socket_mode: u32,
+    pub pairing_store: pairing_store::Backend,
+    /// Tailnet ranges devices registering by address must come from, when TAILSCALE is on
+    pub tailnet: Option<Allowlist>,
+    /// Trust the X-JitStreamer-UDID header instead of looking devices up by IP
+    pub allow_udid_override: bool,
+    /// Lets clients launch with a pairing file in the request, on devices that aren't registered
+    pub allow_inline_launch: bool,
+    /// Lets clients uninstall apps from their device
+    pub allow_uninstall: bool,
+    /// Lets clients open a port proxied to their device's debugserver
+    pub allow_lldb_proxy: bool,
+    /// How long an lldb port waits for its connection
+    pub lldb_proxy_timeout: Duration,
+    /// The longest a debug session stays attached, and how long it does by default
+    pub debug_session_timeout: Duration,
+    /// Reach devices plugged into the host through usbmuxd instead of over the network
+    pub usb_devices: bool,
+    /// Launch, attach and list apps on pretend devices, with the mock feature
+    pub mock_devices: bool,
+    /// Serve SideJITServer's routes too
+    pub sidejit_compat: bool,
+    /// Where the built-in muxer serves registered devices, off when unset
+    pub muxer_socket: Option<String>,
+    /// The port the gRPC service listens on, off when unset
+    pub grpc_port: Option<u16>,
+    /// The sqlite database, which every node in a cluster must share
+    pub database_path: String,
+    /// This server's name in logs and in a cluster
+    pub node_id: String,
+    /// Which of the server's routes and jobs this node runs
+    pub role: Role,
+    /// Advertise the server over mDNS for apps on the same network
+    pub mdns: bool,
+    /// The name the server is advertised under
+    pub mdns_name: String,
+    /// The Redis server nodes coordinate through, a single node when unset
+    pub redis_url: Option<String>,
+    /// Where alerts are posted
+    pub webhook_urls: Vec<reqwest::Url>,
+    /// Failed launches in a row that raise an alert, never when zero
+    pub webhook_launch_failures: u32,
+    /// The most devices that can be registered, unlimited when zero
+    pub max_devices: usize,
+    /// Devices turned away by MAX_DEVICES are kept for the admin to review
+    pub waitlist: bool,
+    /// Devices unused for longer are removed, never when zero
+    pub device_retention: Duration,
+    /// Launch stats older than this are rolled up into daily counts, never when zero
+    pub stats_retention: Duration,
+    /// How often each periodic job runs, only when asked to when zero
+    pub job_intervals: HashMap<Job, Duration>,
+    pub rsd_cache_ttl: Duration,
+    /// Open the device's tunnel after listing its apps, since a launch usually follows
+    pub prewarm_tunnels: bool,
+    /// How many of the busiest devices are kept connected during the hours they're usually
+    /// used, none when zero
+    pub standby_devices: usize,
+    /// Launches at an hour of the day before it counts as one the device is usually used
+    pub standby_min_launches: u32,
+    /// Launches older than this many days don't count towards a device's usual hours
+    pub standby_history_days: u32,
+    pub apps_cache_ttl: Duration,
+    pub udid_cache_ttl: Duration,
+    pub heartbeat: HeartbeatConfig,
+    pub device_timeouts: DeviceTimeouts,
+    /// How long a launch nudges a device that doesn't answer before connecting, zero for never
+    pub wake_timeout: Duration,
+    /// How transient failures connecting to and tunneling into devices are retried
+    pub retry: RetryPolicy,
+    /// Connection failures in a row before a device is refused for the cooldown, 0 for never
+    pub circuit_breaker_failures: u32,
+    pub circuit_breaker_cooldown: Duration,
+    /// Failed requests a client IP or device may make in a minute before it's blocked, 0 for never
+    pub flood_errors_per_minute: u32,
+    pub flood_block: Duration,
+    /// How tunnels to the developer services are made
+    pub tunnel_provider: TunnelKind,
+    /// Replaces TUNNEL_PROVIDER for devices on these iOS major versions
+    pub tunnel_provider_by_version: Vec<VersionTunnel>,
+    pub tunneld_url: String,
+    pub device_allowlist: Allowlist,
+    pub register_allowlist: Allowlist,
+    /// Requests per minute each client IP may make, 0 for unlimited
+    pub rate_limit_register: u32,
+    /// The largest registration body taken, in bytes
+    pub register_max_body: usize,
+    /// How long after registering a new device an IP has to wait to register another
+    pub register_cooldown: Duration,
+    /// Registering a new device needs a solved CAPTCHA, none when unset
+    pub captcha: Option<Captcha>,
+    pub rate_limit_launch: u32,
+    pub rate_limit_get_apps: u32,
+    pub rate_limit_capacity: u32,
+    /// Successful launches each device may make, 0 for unlimited
+    pub quota_launches_per_hour: u32,
+    pub quota_launches_per_day: u32,
+    pub client_ip_source: ClientIpSource,
+    /// Proxies whose forwarded client address is believed
+    pub trusted_proxies: Allowlist,
+    /// Interfaces peers are spread over, WIREGUARD_CONFIG_NAME first and then the ones in
+    /// WIREGUARD_INTERFACES. Never empty.
+    pub wireguard: Vec<WireguardConfig>,
+    pub ipv6_allocation: Ipv6Allocation,
+    /// Origins allowed to call the API from a browser, any origin when empty
+    pub cors_origins: Vec<HeaderValue>,
+    pub cors_methods: Vec<Method>,
+    /// Request headers allowed on top of the ones the API reads
+    pub cors_headers: Vec<HeaderName>,
+    /// Bearer token for the admin routes, which are disabled when unset
+    pub admin_token: Option<String>,
+    /// Whether the device and registration routes need a key from /admin/api_keys
+    pub require_api_key: bool,
+    pub admin_concurrency: usize,
+    /// How many launches can run at once across all devices
+    pub launch_concurrency: usize,
+    /// Collector to export request spans to, disabled when unset
+    pub otlp_endpoint: Option<String>,
+    /// Hash UDIDs and IP addresses with this in logs, stats and the admin API, shown as is
+    /// when unset
+    pub privacy_salt: Option<String>,
+    /// Signs device tokens, generated and kept in DATA_DIR when unset
+    pub device_token_secret: String,
+    /// Tokens signed with it are still accepted while devices move to the new secret
+    pub device_token_previous_secret: Option<String>,
+    /// Serve HTTPS, plain HTTP when unset
+    pub tls: Option<TlsConfig>,
+    /// Sign the VPN profiles handed out at registration, unsigned when unset
+    pub profile_signing: Option<ProfileSigning>,
+    /// The shortcut release /shortcut points to, none when unset
+    pub shortcut: Option<ShortcutRelease>,
+}
+
+/// Reads settings from every source, collecting every invalid variable
+/// instead of stopping at the first one
+#[derive(Default)]
+struct SettingsReader {
+    cli: HashMap<String, String>,
+    file: HashMap<String, String>,
+    read: Vec<&'static str>,
+    errors: Vec<ConfigError>,
+    /// Relative paths and the default data files are inside it, the working directory
+    /// without one
+    data_dir: Option<PathBuf>,
+}
+
+impl SettingsReader {
+    fn new(cli: Cli) -> Self {
+        let mut reader = Self::default();
+
+        for (var, value) in [
+            ("JITSTREAMER_PORT", cli.port.map(|p| p.to_string())),
+            (
+                "ALLOW_REGISTRATION",
+                cli.allow_registration.map(|a| a.to_string()),
+            ),
+            ("PLIST_STORAGE", cli.plist_storage),
+            ("DATA_DIR", cli.data_dir),
+        ] {
+            if let Some(value) = value {
+                reader.cli.insert(var.to_string(), value);
+            }
+        }
+        for set in cli.set {
+            match set.split_once('=') {
+                Some((var, value)) => {
+                    reader
+                        .cli
+                        .insert(var.trim().to_uppercase(), value.to_string());
+                }
+                None => reader.error("--set", set, "VAR=VALUE"),
+            }
+        }
+
+        // It says where the config file is, so it can't be set in it
+        reader.data_dir = reader
+            .cli
+            .get("DATA_DIR")
+            .cloned()
+            .or_else(|| std::env::var("DATA_DIR").ok())
+            // systemd's StateDirectory=, the one folder a DynamicUser service can keep data in
+            .or_else(|| {
+                std::env::var("STATE_DIRECTORY")
+                    .ok()
+                    .and_then(|d| d.split(':').next().map(|d| d.to_string()))
+            })
+            .filter(|d| !d.is_empty())
+            .map(PathBuf::from);
+
+        let (path, explicit) = match cli.config {
+            Some(p) => (p, true),
+            None => (reader.resolve(DEFAULT_CONFIG_FILE), false),
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => match contents.parse::<toml::Table>() {
+                Ok(table) => {
+                    reader.file = table
+                        .into_iter()
+                        .map(|(k, v)| {
+                            let v = match v {
+                                toml::Value::String(s) => s,
+                                v => v.to_string(),
+                            };
+                            (k.to_uppercase(), v)
+                        })
+                        .collect()
+                }
+                Err(e) => reader.error(
+                    "--config",
+                    format!("{} ({})", path.display(), e.message()),
+                    "a valid TOML file",
+                ),
+            },
+            Err(e) if explicit || e.kind() != std::io::ErrorKind::NotFound => reader.error(
+                "--config",
+                format!("{} ({e})", path.display()),
+                "a readable file",
+            ),
+            Err(_) => {}
+        }
+        reader
+    }
+
+    fn error(&mut self, var: &str, value: String, expected: &'static str) {
+        self.errors.push(ConfigError {
+            var: var.to_string(),
+            value,
+            expected,
+        });
+    }
+
+    fn lookup(&mut self, var: &'static str) -> Option<String> {
+        self.read.push(var);
+        self.cli
+            .get(var)
+            .cloned()
+            .or_else(|| std::env::var(var).ok())
+            .or_else(|| self.file.get(var).cloned())
+    }
+
+    fn string(&mut self, var: &'static str, default: &str) -> String {
+        self.lookup(var).unwrap_or(default.to_string())
+    }
+
+    /// The path inside DATA_DIR, absolute paths are kept as they are
+    fn resolve(&self, path: &str) -> PathBuf {
+        match &self.data_dir {
+            Some(dir) => dir.join(path),
+            None => PathBuf::from(path),
+        }
+    }
+
+    /// DEVICE_TOKEN_SECRET, or the one generated the first time the server ran without it
+    fn device_token_secret(&mut self) -> String {
+        if let Some(secret) = self.lookup("DEVICE_TOKEN_SECRET").filter(|s| !s.is_empty()) {
+            return secret;
+        }
+        let path = self.resolve(DEVICE_TOKEN_SECRET_FILE);
+        match identity::stored_secret(&path) {
+            Ok(secret) => secret,
+            Err(e) => {
+                self.error(
+                    "DEVICE_TOKEN_SECRET",
+                    format!("unset, and {} can't be saved ({e})", path.display()),
+                    "a secret, or a writable DATA_DIR to keep a generated one in",
+                );
+                String::new()
+            }
+        }
+    }
+
+    /// A file or folder setting, relative to DATA_DIR. `default` is used inside DATA_DIR,
+    /// and `standalone` without one.
+    fn path(&mut self, var: &'static str, default: &str, standalone: &str) -> String {
+        let fallback = match self.data_dir {
+            Some(_) => default,
+            None => standalone,
+        };
+        let path = self.string(var, fallback);
+        self.resolve(&path).to_string_lossy().into_owned()
+    }
+
+    fn parse<T: FromStr>(&mut self, var: &'static str, default: T, expected: &'static str) -> T {
+        match self.lookup(var) {
+            Some(v) => match v.trim().parse::<T>() {
+                Ok(v) => v,
+                Err(_) => {
+                    self.error(var, v, expected);
+                    default
+                }
+            },
+            None => default,
+        }
+    }
+
+    /// Parses a comma separated list, empty entries are skipped
+    fn list<T: FromStr>(
+        &mut self,
+        var: &'static str,
+        default: &str,
+        expected: &'static str,
+    ) -> Vec<T> {
+        let value = self.string(var, default);
+        let list = value
+            .split(',')
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty())
+            .map(|v| v.parse::<T>())
+            .collect::<Result<Vec<T>, _>>();
+        match list {
+            Ok(l) => l,
+            Err(_) => {
+                self.error(var, value, expected);
+                Vec::new()
+            }
+        }
+    }
+
+    /// A positive number of seconds
+    fn seconds(&mut self, var: &'static str, default: u64) -> Duration {
+        let seconds = self.parse(var, default, "a positive number of seconds");
+        if seconds == 0 {
+            self.error(var, seconds.to_string(), "a positive number of seconds");
+            return Duration::from_secs(default);
+        }
+        Duration::from_secs(seconds)
+    }
+
+    fn allowlist(&mut self, var: &'static str, default: &str) -> Allowlist {
+        let value = self.string(var, default);
+        match Allowlist::parse(&value) {
+            Ok(a) => a,
+            Err(_) => {
+                self.error(
+                    var,
+                    value,
+                    "a comma separated list of CIDRs such as fd00::/64,10.0.0.0/8",
+                );
+                Allowlist::default()
+            }
+        }
+    }
+
+    /// Reads the extra interfaces, separated by semicolons. Each is its name, port, IPv6
+    /// /64 and optionally an IPv4 subnet, separated by spaces. The endpoint is shared.
+    fn wireguard_interfaces(&mut self, primary: WireguardConfig) -> Vec<WireguardConfig> {
+        const VAR: &str = "WIREGUARD_INTERFACES";
+        const EXPECTED: &str =
+            "interfaces separated by semicolons, such as jitstreamer2 51870 fd01::/64 10.8.0.0/16";
+        let value = self.string(VAR, "");
+        let mut interfaces = vec![primary];
+        for spec in value.split(';').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            let fields = spec.split_whitespace().collect::<Vec<&str>>();
+            let (name, port, network, ipv4) = match fields.as_slice() {
+                [name, port, network] => (name, port, network, None),
+                [name, port, network, ipv4] => (name, port, network, Some(ipv4)),
+                _ => {
+                    self.error(VAR, spec.to_string(), EXPECTED);
+                    continue;
+                }
+            };
+            let port = port.parse::<u16>().ok();
+            let network = network
+                .parse::<Cidr>()
+                .ok()
+                .filter(|n| n.addr().is_ipv6() && n.prefix() <= 64);
+            let ipv4 = match ipv4.map(|i| i.parse::<Cidr>()) {
+                Some(Ok(i)) if i.addr().is_ipv4() && i.prefix() <= 29 => Some(Some(i)),
+                Some(_) => None,
+                None => Some(None),
+            };
+            let (port, network, ipv4_subnet) = match (port, network, ipv4) {
+                (Some(p), Some(n), Some(i)) => (p, n, i),
+                _ => {
+                    self.error(VAR, spec.to_string(), EXPECTED);
+                    continue;
+                }
+            };
+            if interfaces
+                .iter()
+                .any(|w| w.config_name == *name || w.port == port)
+            {
+                self.error(
+                    VAR,
+                    spec.to_string(),
+                    "a name and port no other interface uses",
+                );
+                continue;
+            }
+            interfaces.push(WireguardConfig {
+                config_name: name.to_string(),
+                conf_dir: interfaces[0].conf_dir.clone(),
+                port,
+                server_address: format!("{}/128", network.addr()),
+                endpoint: interfaces[0].endpoint.clone(),
+                server_allowed_ips: format!("{}/{}", network.addr(), network.prefix()),
+                ipv4_subnet,
+                embedded: interfaces[0].embedded,
+            });
+        }
+        interfaces
+    }
+
+    fn ipv4_subnet(&mut self, var: &'static str) -> Option<Cidr> {
+        let value = self.string(var, "");
+        if value.is_empty() {
+            return None;
+        }
+        match value.parse::<Cidr>() {
+            // Room for the network, the server and a broadcast address at least
+            Ok(c) if c.addr().is_ipv4() && c.prefix() <= 29 => Some(c),
+            _ => {
+                self.error(
+                    var,
+                    value,
+                    "an IPv4 subnet of /29 or larger, such as 10.7.0.0/16",
+                );
+                None
+            }
+        }
+    }
+
+    fn pairing_store(&mut self) -> pairing_store::Backend {
+        let default_storage = match std::env::consts::OS {
+            "macos" => "/var/db/lockdown",
+            "linux" => "/var/lib/lockdown",
+            "windows" => "C:/ProgramData/Apple/Lockdown",
+            _ => "",
+        };
+        let filesystem =
+            |path: String| pairing_store::Backend::Filesystem(pairing_store::Filesystem::new(path));
+
+        let store = self.string("PAIRING_STORE", "filesystem");
+        match store.as_str() {
+            "filesystem" => {
+                let path = self.path("PLIST_STORAGE", "lockdown", default_storage);
+                if path.is_empty() {
+                    self.error(
+                        "PLIST_STORAGE",
+                        String::new(),
+                        "a path, there is no default on this OS without DATA_DIR",
+                    );
+                }
+                filesystem(path)
+            }
+            "s3" => {
+                let bucket = self.string("S3_BUCKET", "");
+                let region = self.string("S3_REGION", "us-east-1");
+                let endpoint = Some(self.string("S3_ENDPOINT", "")).filter(|e| !e.is_empty());
+                let access_key = self.string("S3_ACCESS_KEY_ID", "");
+                let secret_key = self.string("S3_SECRET_ACCESS_KEY", "");
+                let prefix = self.string("S3_PREFIX", "");
+                for (var, value) in [
+                    ("S3_BUCKET", &bucket),
+                    ("S3_ACCESS_KEY_ID", &access_key),
+                    ("S3_SECRET_ACCESS_KEY", &secret_key),
+                ] {
+                    if value.is_empty() {
+                        self.error(var, String::new(), "to be set when PAIRING_STORE is s3");
+                    }
+                }
+                match pairing_store::S3::new(
+                    &bucket,
+                    &region,
+                    endpoint,
+                    &access_key,
+                    &secret_key,
+                    prefix,
+                ) {
+                    Ok(s) => pairing_store::Backend::S3(s),
+                    Err(e) => {
+                        warn!("Invalid S3 settings: {e}");
+                        self.error(
+                            "S3_REGION",
+                            region,
+                            "an AWS region, or any name with S3_ENDPOINT set",
+                        );
+                        filesystem(default_storage.to_string())
+                    }
+                }
+            }
+            _ => {
+                self.error("PAIRING_STORE", store, "filesystem or s3");
+                filesystem(default_storage.to_string())
+            }
+        }
+    }
+
+    fn tls(&mut self) -> Option<TlsConfig> {
+        let cert = self.string("TLS_CERT", "");
+        let key = self.string("TLS_KEY", "");
+        let domains = self
+            .string("ACME_DOMAINS", "")
+            .split(',')
+            .map(|d| d.trim().to_string())
+            .filter(|d| !d.is_empty())
+            .collect::<Vec<String>>();
+        let email = Some(self.string("ACME_EMAIL", "")).filter(|e| !e.is_empty());
+        let cache = self.path("ACME_CACHE", "acme", "acme");
+        let staging = self.parse("ACME_STAGING", false, "true or false");
+
+        match (cert.is_empty(), key.is_empty(), domains.is_empty()) {
+            (true, true, true) => None,
+            (false, false, true) => Some(TlsConfig::Files {
+                cert: cert.into(),
+                key: key.into(),
+            }),
+            (true, true, false) => Some(TlsConfig::Acme {
+                domains,
+                email,
+                cache: cache.into(),
+                staging,
+            }),
+            (_, _, false) => {
+                self.error(
+                    "ACME_DOMAINS",
+                    domains.join(","),
+                    "to be unset when TLS_CERT or TLS_KEY is set",
+                );
+                None
+            }
+            (true, _, _) => {
+                self.error("TLS_CERT", cert, "a path when TLS_KEY is set");
+                None
+            }
+            (_, true, _) => {
+                self.error("TLS_KEY", key, "a path when TLS_CERT is set");
+                None
+            }
+        }
+    }
+
+    fn profile_signing(&mut self) -> Option<ProfileSigning> {
+        let cert = self.string("PROFILE_SIGNING_CERT", "");
+        let key = self.string("PROFILE_SIGNING_KEY", "");
+        match (cert.is_empty(), key.is_empty()) {
+            (true, true) => None,
+            (false, false) => Some(ProfileSigning {
+                cert: cert.into(),
+                key: key.into(),
+            }),
+            (true, false) => {
+                self.error(
+                    "PROFILE_SIGNING_CERT",
+                    cert,
+                    "a path when PROFILE_SIGNING_KEY is set",
+                );
+                None
+            }
+            (false, true) => {
+                self.error(
+                    "PROFILE_SIGNING_KEY",
+                    key,
+                    "a path when PROFILE_SIGNING_CERT is set",
+                );
+                None
+            }
+        }
+    }
+
+    fn captcha(&mut self) -> Option<Captcha> {
+        let provider = self.string("CAPTCHA_PROVIDER", "");
+        if provider.is_empty() {
+            return None;
+        }
+        let Ok(provider) = provider.parse::<captcha::Provider>() else {
+            self.error("CAPTCHA_PROVIDER", provider, "turnstile or hcaptcha");
+            return None;
+        };
+        let site_key = self.string("CAPTCHA_SITE_KEY", "");
+        let secret = self.string("CAPTCHA_SECRET", "");
+        if site_key.is_empty() {
+            self.error(
+                "CAPTCHA_SITE_KEY",
+                site_key,
+                "a key when CAPTCHA_PROVIDER is set",
+            );
+            return None;
+        }
+        if secret.is_empty() {
+            self.error(
+                "CAPTCHA_SECRET",
+                secret,
+                "a key when CAPTCHA_PROVIDER is set",
+            );
+            return None;
+        }
+        Some(Captcha {
+            provider,
+            site_key,
+            secret,
+        })
+    }
+
+    fn shortcut(&mut self) -> Option<ShortcutRelease> {
+        let version = self.string("SHORTCUT_VERSION", "");
+        let url = self.string("SHORTCUT_URL", "");
+        let changelog = Some(self.string("SHORTCUT_CHANGELOG", "")).filter(|c| !c.is_empty());
+        match (version.is_empty(), url.is_empty()) {
+            (true, true) => None,
+            (false, false) => Some(ShortcutRelease {
+                version,
+                url,
+                changelog,
+            }),
+            (true, false) => {
+                self.error(
+                    "SHORTCUT_VERSION",
+                    version,
+                    "a version when SHORTCUT_URL is set",
+                );
+                None
+            }
+            (false, true) => {
+                self.error("SHORTCUT_URL", url, "a URL when SHORTCUT_VERSION is set");
+                None
+            }
+        }
+    }
+
+    /// Flags settings from the file or --set that don't exist, most likely typos
+    fn check_unknown(&mut self) {
+        let unknown = self
+            .cli
+            .keys()
+            .chain(self.file.keys())
+            .filter(|k| !self.read.contains(&k.as_str()))
+            .cloned()
+            .collect::<Vec<String>>();
+        for var in unknown {
+            let value = self.cli.get(&var).or(self.file.get(&var)).cloned();
+            self.error(&var, value.unwrap_or_default(), "a known setting");
+        }
+    }
+}
+
+impl Config {
+    /// Devices get a Wireguard peer when registering, with or without an invite code
+    pub fn wireguard_registration(&self) -> bool {
+        matches!(self.allow_registration, 1 | 3)
+    }
+
+    /// Devices are reached at the address they registered from
+    pub fn address_registration(&self) -> bool {
+        matches!(self.allow_registration, 2 | 4)
+    }
+
+    /// The server is on the devices' home network, see `lan`
+    pub fn lan_mode(&self) -> bool {
+        self.allow_registration == 4
+    }
+
+    /// How often the job runs, zero when it only runs when asked to. Jobs for another
+    /// node's role never run on their own.
+    pub fn job_interval(&self, job: Job) -> Duration {
+        if job.needs_registering() && !self.role.registers() {
+            return Duration::ZERO;
+        }
+        if job.needs_serving() && !self.role.serves_devices() {
+            return Duration::ZERO;
+        }
+        self.job_intervals.get(&job).copied().unwrap_or_default()
+    }
+
+    /// The Wireguard interface with the name, the first one if it's no longer configured
+    pub fn wireguard_interface(&self, name: Option<&str>) -> &WireguardConfig {
+        name.and_then(|n| self.wireguard.iter().find(|w| w.config_name == n))
+            .unwrap_or(&self.wireguard[0])
+    }
+
+    /// Reads the config from the CLI, environment and config file, returning every invalid variable
+    pub fn load() -> Result<Self, Vec<ConfigError>> {
+        Self::load_from(Cli::parse())
+    }
+
+    /// Reads the config with these arguments in place of the process's, for tests
+    #[cfg(all(test, feature = "mock"))]
+    pub fn load_args(args: &[&str]) -> Result<Self, Vec<ConfigError>> {
+        Self::load_from(Cli::parse_from(
+            std::iter::once("jitstreamer-eb").chain(args.iter().copied()),
+        ))
+    }
+
+    fn load_from(cli: Cli) -> Result<Self, Vec<ConfigError>> {
+        let mut settings = SettingsReader::new(cli);
+
+        let allow_registration = settings.parse("ALLOW_REGISTRATION", 1u8, "0, 1, 2, 3 or 4");
+        if allow_registration > 4 {
+            settings.error(
+                "ALLOW_REGISTRATION",
+                allow_registration.to_string(),
+                "0, 1, 2, 3 or 4",
+            );
+        }
+
+        let port = settings.parse("JITSTREAMER_PORT", 9172u16, "a port number (1-65535)");
+        let listen_tcp = settings.parse("JITSTREAMER_TCP", true, "true or false");
+        let unix_socket = Some(settings.string("UNIX_SOCKET", ""))
+            .filter(|s| !s.is_empty())
+            .map(PathBuf::from);
+        let mode = settings.string("UNIX_SOCKET_MODE", "660");
+        let unix_socket_mode = match u32::from_str_radix(mode.trim(), 8) {
+            Ok(m) if m <= 0o777 => m,
+            _ => {
+                settings.error("UNIX_SOCKET_MODE", mode, "octal permissions such as 660");
+                0o660
+            }
+        };
+        if !listen_tcp && unix_socket.is_none() {
+            settings.error(
+                "JITSTREAMER_TCP",
+                listen_tcp.to_string(),
+                "true unless UNIX_SOCKET is set",
+            );
+        }
+        if unix_socket.is_some() && !cfg!(unix) {
+            settings.error(
+                "UNIX_SOCKET",
+                unix_socket
+                    .as_ref()
+                    .map(|p| p.display().to_string())
+                    .unwrap_or_default(),
+                "to be unset, Unix sockets aren't supported on this OS",
+            );
+        }
+
+        let pairing_store = settings.pairing_store();
+
+        let tailscale = settings.parse("TAILSCALE", false, "true or false");
+        let tailnet_ranges =
+            settings.allowlist("TAILNET_RANGES", "100.64.0.0/10,fd7a:115c:a1e0::/48");
+        if tailscale && allow_registration != 2 {
+            settings.error(
+                "TAILSCALE",
+                tailscale.to_string(),
+                "false unless ALLOW_REGISTRATION is 2",
+            );
+        }
+        let tailnet = Some(tailnet_ranges).filter(|_| tailscale);
+
+        // Devices on a home network can't be told apart by their address changing, so LAN
+        // mode trusts the UDID they send
+        let allow_udid_override = settings.parse("ALLOW_UDID_OVERRIDE", false, "true or false")
+            || allow_registration == 4;
+        let allow_inline_launch = settings.parse("ALLOW_INLINE_LAUNCH", false, "true or false");
+        let allow_uninstall = settings.parse("ALLOW_UNINSTALL", false, "true or false");
+        let allow_lldb_proxy = settings.parse("ALLOW_LLDB_PROXY", false, "true or false");
+        let lldb_proxy_timeout = settings.seconds("LLDB_PROXY_TIMEOUT", 60);
+        let debug_session_timeout = settings.seconds("DEBUG_SESSION_TIMEOUT", 600);
+        let usb_devices = settings.parse("USB_DEVICES", false, "true or false");
+        let mock_devices = settings.parse("MOCK_DEVICES", false, "true or false");
+        let sidejit_compat = settings.parse("SIDEJIT_COMPAT", false, "true or false");
+        let muxer_socket = Some(settings.string("MUXER_SOCKET", "")).filter(|s| !s.is_empty());
+        let webhook_urls = settings.list("WEBHOOK_URLS", "", "a comma separated list of URLs");
+        let webhook_launch_failures =
+            settings.parse("WEBHOOK_LAUNCH_FAILURES", 3u32, "a number of launches");
+        let grpc_port = Some(settings.parse("GRPC_PORT", 0u16, "a port number, or 0 for off"))
+            .filter(|p| *p != 0);
+        let database_path = settings.path("DATABASE_PATH", "jitstreamer.db", "jitstreamer.db");
+        let node_id = Some(settings.string("NODE_ID", ""))
+            .filter(|n| !n.is_empty())
+            .unwrap_or_else(default_node_id);
+        let role = settings.parse("ROLE", Role::All, "all, register-only or jit-only");
+        let mdns = settings.parse("MDNS", allow_registration == 4, "true or false");
+        let mdns_name = Some(settings.string("MDNS_NAME", ""))
+            .filter(|n| !n.is_empty())
+            .unwrap_or_else(|| node_id.clone());
+        let redis_url = Some(settings.string("REDIS_URL", "")).filter(|u| !u.is_empty());
+        let max_devices = settings.parse("MAX_DEVICES", 0usize, "a number of devices");
+        let waitlist = settings.parse("WAITLIST", false, "true or false");
+        let device_retention_days =
+            settings.parse("DEVICE_RETENTION_DAYS", 0u64, "a number of days");
+        let stats_retention_days = settings.parse("STATS_RETENTION_DAYS", 0u64, "a number of days");
+        let job_intervals = Job::ALL
+            .into_iter()
+            .map(|job| {
+                let interval =
+                    settings.parse(job.var(), job.default_interval(), "a number of seconds");
+                (job, Duration::from_secs(interval))
+            })
+            .collect();
+
+        let rsd_cache_ttl = settings.parse("RSD_CACHE_TTL", 300u64, "a number of seconds");
+        let prewarm_tunnels = settings.parse("PREWARM_TUNNELS", true, "true or false");
+        let standby_devices = settings.parse("STANDBY_DEVICES", 0usize, "a number of devices");
+        let standby_min_launches =
+            settings.parse("STANDBY_MIN_LAUNCHES", 3u32, "a number of launches");
+        let standby_history_days =
+            settings.parse("STANDBY_HISTORY_DAYS", 30u32, "a number of days");
+        let apps_cache_ttl = settings.parse("APPS_CACHE_TTL", 300u64, "a number of seconds");
+        let udid_cache_ttl = settings.parse("UDID_CACHE_TTL", 60u64, "a number of seconds");
+        let heartbeat = HeartbeatConfig {
+            grace_period: Duration::from_secs(settings.parse(
+                "HEARTBEAT_GRACE_PERIOD",
+                30u64,
+                "a number of seconds",
+            )),
+            max_heartbeats: settings.parse("MAX_HEARTBEATS", 200usize, "a positive number"),
+            max_lifetime: Duration::from_secs(settings.parse(
+                "HEARTBEAT_MAX_LIFETIME",
+                600u64,
+                "a number of seconds",
+            )),
+            interval: settings.seconds("HEARTBEAT_INTERVAL", 30),
+            polo_timeout: settings.seconds("HEARTBEAT_POLO_TIMEOUT", 10),
+        };
+
+        let device_timeouts = DeviceTimeouts {
+            heartbeat: settings.seconds("TIMEOUT_HEARTBEAT", 30),
+            launch: settings.seconds("TIMEOUT_LAUNCH", 60),
+            get_apps: settings.seconds("TIMEOUT_GET_APPS", 60),
+            attach: settings.seconds("TIMEOUT_ATTACH", 30),
+        };
+        let wake_timeout =
+            Duration::from_secs(settings.parse("WAKE_TIMEOUT", 10u64, "a number of seconds"));
+
+        let retry = RetryPolicy {
+            attempts: settings.parse("RETRY_ATTEMPTS", 3u32, "a positive number"),
+            backoff: Duration::from_millis(settings.parse(
+                "RETRY_BACKOFF_MS",
+                500u64,
+                "a number of milliseconds",
+            )),
+        };
+        if retry.attempts == 0 {
+            settings.error(
+                "RETRY_ATTEMPTS",
+                retry.attempts.to_string(),
+                "a positive number",
+            );
+        }
+
+        let circuit_breaker_failures =
+            settings.parse("CIRCUIT_BREAKER_FAILURES", 5u32, "a number of failures");
+        let circuit_breaker_cooldown = settings.seconds("CIRCUIT_BREAKER_COOLDOWN", 60);
+        let flood_errors_per_minute = settings.parse(
+            "FLOOD_ERRORS_PER_MINUTE",
+            120u32,
+            "a number of errors per minute",
+        );
+        let flood_block = settings.seconds("FLOOD_BLOCK", 10 * 60);
+
+        let tunnel_provider = settings.parse(
+            "TUNNEL_PROVIDER",
+            TunnelKind::Software,
+            "software, tunneld or kernel",
+        );
+        let tunnel_provider_by_version = settings.list(
+            "TUNNEL_PROVIDER_BY_VERSION",
+            "",
+            "a comma separated list of versions and providers such as 17=software,18=kernel",
+        );
+        let tunneld_url = settings.string("TUNNELD_URL", tunnel::DEFAULT_TUNNELD_URL);
+
+        let device_allowlist = settings.allowlist("DEVICE_ALLOWLIST", "");
+        let register_allowlist = settings.allowlist("REGISTER_ALLOWLIST", "");
+
+        let rate_limit_register = settings.parse(
+            "RATE_LIMIT_REGISTER",
+            5u32,
+            "a number of requests per minute",
+        );
+        let register_max_body =
+            settings.parse("REGISTER_MAX_BODY", 64 * 1024usize, "a number of bytes");
+        let register_cooldown = settings.parse("REGISTER_COOLDOWN", 0u64, "a number of seconds");
+        let captcha = settings.captcha();
+        let rate_limit_launch = settings.parse(
+            "RATE_LIMIT_LAUNCH",
+            20u32,
+            "a number of requests per minute",
+        );
+        let rate_limit_get_apps = settings.parse(
+            "RATE_LIMIT_GET_APPS",
+            30u32,
+            "a number of requests per minute",
+        );
+        let rate_limit_capacity = settings.parse(
+            "RATE_LIMIT_CAPACITY",
+            10u32,
+            "a number of requests per minute",
+        );
+
+        let quota_launches_per_hour = settings.parse(
+            "QUOTA_LAUNCHES_PER_HOUR",
+            0u32,
+            "a number of launches per hour",
+        );
+        let quota_launches_per_day = settings.parse(
+            "QUOTA_LAUNCHES_PER_DAY",
+            0u32,
+            "a number of launches per day",
+        );
+
+        let client_ip_source = settings.parse(
+            "CLIENT_IP_SOURCE",
+            ClientIpSource::ConnectInfo,
+            "connect_info, x_forwarded_for or cf_connecting_ip",
+        );
+        let trusted_proxies = settings.allowlist("TRUSTED_PROXIES", "127.0.0.0/8,::1/128");
+
+        let wireguard = WireguardConfig {
+            config_name: settings.string("WIREGUARD_CONFIG_NAME", "jitstreamer"),
+            conf_dir: settings.path("WIREGUARD_CONFIG_DIR", "wireguard", "/etc/wireguard"),
+            port: settings.parse("WIREGUARD_PORT", 51869u16, "a port number (1-65535)"),
+            server_address: settings.string("WIREGUARD_SERVER_ADDRESS", "fd00::/128"),
+            endpoint: settings.string("WIREGUARD_ENDPOINT", "jitstreamer.jkcoxson.com"),
+            server_allowed_ips: settings.string("WIREGUARD_SERVER_ALLOWED_IPS", "fd00::/64"),
+            ipv4_subnet: settings.ipv4_subnet("WIREGUARD_IPV4_SUBNET"),
+            embedded: settings.parse("WIREGUARD_EMBEDDED", false, "true or false"),
+        };
+        // Peers get their addresses from this network, so it has to be one
+        let network = wireguard
+            .server_allowed_ips
+            .split(',')
+            .next()
+            .and_then(|n| n.trim().parse::<Cidr>().ok());
+        if !network.is_some_and(|n| n.addr().is_ipv6() && n.prefix() <= 64) {
+            settings.error(
+                "WIREGUARD_SERVER_ALLOWED_IPS",
+                wireguard.server_allowed_ips.clone(),
+                "an IPv6 /64 or larger first, such as fd00::/64",
+            );
+        }
+        let wireguard = settings.wireguard_interfaces(wireguard);
+        let ipv6_allocation = settings.parse(
+            "WIREGUARD_IPV6_ALLOCATION",
+            Ipv6Allocation::Random,
+            "random or sequential",
+        );
+
+        let cors_origins = settings
+            .list::<String>("CORS_ORIGINS", "*", "a comma separated list of origins")
+            .into_iter()
+            .filter(|o| o != "*")
+            .map(|o| HeaderValue::from_str(&o).map_err(|_| o))
+            .collect::<Result<Vec<HeaderValue>, String>>()
+            .unwrap_or_else(|o| {
+                settings.error("CORS_ORIGINS", o, "origins such as https://example.com");
+                Vec::new()
+            });
+        let cors_methods = settings.list(
+            "CORS_METHODS",
+            "GET,POST,DELETE,OPTIONS",
+            "a comma separated list of HTTP methods",
+        );
+        let cors_headers =
+            settings.list("CORS_HEADERS", "", "a comma separated list of header names");
+
+        let admin_token = Some(settings.string("ADMIN_TOKEN", "")).filter(|t| !t.is_empty());
+        let require_api_key = settings.parse("REQUIRE_API_KEY", false, "true or false");
+        if require_api_key && admin_token.is_none() {
+            // Keys are minted through the admin routes
+            settings.error(
+                "REQUIRE_API_KEY",
+                require_api_key.to_string(),
+                "false without ADMIN_TOKEN",
+            );
+        }
+        let otlp_endpoint =
+            Some(settings.string("OTEL_EXPORTER_OTLP_ENDPOINT", "")).filter(|e| !e.is_empty());
+        let privacy_salt = Some(settings.string("PRIVACY_SALT", "")).filter(|s| !s.is_empty());
+        let device_token_secret = settings.device_token_secret();
+        let device_token_previous_secret =
+            Some(settings.string("DEVICE_TOKEN_PREVIOUS_SECRET", "")).filter(|s| !s.is_empty());
+        let admin_concurrency = settings.parse("ADMIN_CONCURRENCY", 8usize, "a positive number");
+        if admin_concurrency == 0 {
+            settings.error(
+                "ADMIN_CONCURRENCY",
+                admin_concurrency.to_string(),
+                "a positive number",
+            );
+        }
+
+        let tls = settings.tls();
+        let profile_signing = settings.profile_signing();
+        let shortcut = settings.shortcut();
+
+        let launch_concurrency = settings.parse("LAUNCH_CONCURRENCY", 32usize, "a positive number");
+        if launch_concurrency == 0 {
+            settings.error(
+                "LAUNCH_CONCURRENCY",
+                launch_concurrency.to_string(),
+                "a positive number",
+            );
+        }
+
+        settings.check_unknown();
+        if !settings.errors.is_empty() {
+            return Err(settings.errors);
+        }
+
+        Ok(Self {
+            allow_registration,
+            port,
+            listen_tcp,
+            unix_socket,
+            unix_socket_mode,
+            pairing_store,
+            tailnet,
+            allow_udid_override,
+            allow_inline_launch,
+            allow_uninstall,
+            allow_lldb_proxy,
+            lldb_proxy_timeout,
+            debug_session_timeout,
+            usb_devices,
+            mock_devices,
+            sidejit_compat,
+            muxer_socket,
+            grpc_port,
+            database_path,
+            node_id,
+            role,
+            mdns,
+            mdns_name,
+            redis_url,
+            webhook_urls,
+            webhook_launch_failures,
+            max_devices,
+            waitlist,
+            device_retention: Duration::from_secs(device_retention_days * 24 * 60 * 60),
+            stats_retention: Duration::from_secs(stats_retention_days * 24 * 60 * 60),
+            job_intervals,
+            rsd_cache_ttl: Duration::from_secs(rsd_cache_ttl),
+            prewarm_tunnels,
+            standby_devices,
+            standby_min_launches,
+            standby_history_days,
+            apps_cache_ttl: Duration::from_secs(apps_cache_ttl),
+            udid_cache_ttl: Duration::from_secs(udid_cache_ttl),
+            heartbeat,
+            device_timeouts,
+            wake_timeout,
+            retry,
+            circuit_breaker_failures,
+            circuit_breaker_cooldown,
+            flood_errors_per_minute,
+            flood_block,
+            tunnel_provider,
+            tunnel_provider_by_version,
+            tunneld_url,
+            device_allowlist,
+            register_allowlist,
+            rate_limit_register,
+            register_max_body,
+            register_cooldown: Duration::from_secs(register_cooldown),
+            captcha,
+            rate_limit_launch,
+            rate_limit_get_apps,
+            rate_limit_capacity,
+            quota_launches_per_hour,
+            quota_launches_per_day,
+            client_ip_source,
+            trusted_proxies,
+            wireguard,
+            ipv6_allocation,
+            cors_origins,
+            cors_methods,
+            cors_headers,
+            admin_token,
+            require_api_key,
+            admin_concurrency,
+            launch_concurrency,
+            otlp_endpoint,
+            privacy_salt,
+            device_token_secret,
+            device_token_previous_secret,
+            tls,
+            profile_signing,
+            shortcut,
+        })
+    }
+
+    /// Re-reads every source and swaps in the new config if it's valid.
+    /// Returns the settings that changed but only take effect after a restart.
+    pub fn reload(shared: &SharedConfig) -> Result<Vec<&'static str>, Vec<ConfigError>> {
+        dotenvy::dotenv_override().ok();
+        let new = Self::load()?;
+        let old = shared.load();
+
+        let mut restart_required = Vec::new();
+        for (var, changed) in [
+            ("JITSTREAMER_PORT", old.port != new.port),
+            ("JITSTREAMER_TCP", old.listen_tcp != new.listen_tcp),
+            (
+                "UNIX_SOCKET",
+                old.unix_socket != new.unix_socket || old.unix_socket_mode != new.unix_socket_mode,
+            ),
+            ("RSD_CACHE_TTL", old.rsd_cache_ttl != new.rsd_cache_ttl),
+            ("APPS_CACHE_TTL", old.apps_cache_ttl != new.apps_cache_ttl),
+            ("UDID_CACHE_TTL", old.udid_cache_ttl != new.udid_cache_ttl),
+            ("HEARTBEAT_*", old.heartbeat != new.heartbeat),
+            ("MUXER_SOCKET", old.muxer_socket != new.muxer_socket),
+            ("GRPC_PORT", old.grpc_port != new.grpc_port),
+            ("DATABASE_PATH", old.database_path != new.database_path),
+            (
+                "WIREGUARD_CONFIG_DIR",
+                old.wireguard[0].conf_dir != new.wireguard[0].conf_dir,
+            ),
+            ("NODE_ID", old.node_id != new.node_id),
+            ("ROLE", old.role != new.role),
+            ("REDIS_URL", old.redis_url != new.redis_url),
+            (
+                "WIREGUARD_EMBEDDED",
+                old.wireguard[0].embedded != new.wireguard[0].embedded,
+            ),
+            (
+                "LAUNCH_CONCURRENCY",
+                old.launch_concurrency != new.launch_concurrency,
+            ),
+            ("ADMIN_TOKEN", old.admin_token != new.admin_token),
+            ("MOCK_DEVICES", old.mock_devices != new.mock_devices),
+            (
+                "MDNS",
+                old.mdns != new.mdns || old.mdns_name != new.mdns_name,
+            ),
+            ("SIDEJIT_COMPAT", old.sidejit_compat != new.sidejit_compat),
+            (
+                "CORS_*",
+                old.cors_origins != new.cors_origins
+                    || old.cors_methods != new.cors_methods
+                    || old.cors_headers != new.cors_headers,
+            ),
+            (
+                "OTEL_EXPORTER_OTLP_ENDPOINT",
+                old.otlp_endpoint != new.otlp_endpoint,
+            ),
+            ("PRIVACY_SALT", old.privacy_salt != new.privacy_salt),
+        ] {
+            if changed {
+                warn!("{var} changed, restart the server to apply it");
+                restart_required.push(var);
+            }
+        }
+
+        if new.wireguard_registration() && new.role.registers() {
+            for wireguard in new
+                .wireguard
+                .iter()
+                .filter(|w| !old.wireguard_registration() || !old.wireguard.contains(w))
+            {
+                crate::register::check_wireguard(wireguard);
+            }
+        }
+        if old.allow_registration != new.allow_registration {
+            info!(
+                "Registration mode changed from {} to {}",
+                old.allow_registration, new.allow_registration
+            );
+        }
+
+        shared.store(Arc::new(new));
+        info!("Configuration reloaded");
+        Ok(restart_required)
+    }
+}
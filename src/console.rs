@@ -0,0 +1,224 @@
+// Jackson Coxson
+// Streams what debugserver sees of a running app, so a crash after JIT shows its cause
+
+use axum::extract::{
+    ws::{Message, WebSocket},
+    Path, State, WebSocketUpgrade,
+};
+use axum_client_ip::SecureClientIp;
+use idevice::debug_proxy::DebugProxyClient;
+use serde::Serialize;
+use tracing::{debug, info, warn};
+
+use crate::{
+    common::DeviceSelector,
+    error::{ErrorCode, JitError},
+    i18n::Language,
+    pipeline,
+    tunnel::Tunnel,
+    JitStreamerState,
+};
+
+const SIGTRAP: u8 = 5;
+const SIGSTOP: u8 = 17;
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ConsoleEvent {
+    /// Debugserver is attached and the app is running
+    Attached { pid: u64 },
+    /// Output from the app, only captured for some processes
+    Output { text: String },
+    /// The app stopped on a signal, such as a crash. It's resumed with the same signal.
+    Stopped { signal: u8, packet: String },
+    /// The app exited with a status, or was killed by a signal
+    Exited {
+        status: Option<u8>,
+        signal: Option<u8>,
+    },
+    Error {
+        #[serde(flatten)]
+        error: JitError,
+    },
+}
+
+impl ConsoleEvent {
+    fn to_ws_message(&self) -> Message {
+        Message::text(serde_json::to_string(&self).unwrap())
+    }
+}
+
+/// Attaches debugserver to an app launched earlier, and keeps the session open while
+/// the socket is, sending the app's output and stop packets
+pub async fn handler(
+    ws: WebSocketUpgrade,
+    ip: SecureClientIp,
+    selector: DeviceSelector,
+    Path(pid): Path<u64>,
+    language: Language,
+    State(state): State<JitStreamerState>,
+) -> axum::response::Response {
+    ws.on_upgrade(move |mut socket| async move {
+        info!("Got request to stream the console of {pid} from {:?}", ip.0);
+        let (udid, provider) = match crate::jit::JitSession::new(&state, ip.0, &selector)
+            .connect()
+            .await
+        {
+            Ok(d) => d,
+            Err(e) => {
+                let event = ConsoleEvent::Error {
+                    error: language.localize(e),
+                };
+                socket.send(event.to_ws_message()).await.ok();
+                return;
+            }
+        };
+
+        let res = match crate::jit::developer_service(
+            &state,
+            &udid,
+            &provider,
+            idevice::debug_proxy::SERVICE_NAME,
+            pipeline::DEBUG_PROXY_MISSING,
+        )
+        .await
+        {
+            Ok((adapter, _)) => stream(adapter, pid, &mut socket).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            let event = ConsoleEvent::Error {
+                error: language.localize(e),
+            };
+            socket.send(event.to_ws_message()).await.ok();
+        }
+        socket.close().await.ok();
+
+        state.heartbeats.release(&udid).await.ok();
+    })
+}
+
+/// Forwards packets until the app exits or the client leaves. Dropping the connection
+/// makes debugserver detach, leaving the app running.
+async fn stream(adapter: Tunnel, pid: u64, socket: &mut WebSocket) -> Result<(), JitError> {
+    let mut dp = attach(adapter, pid).await?;
+    socket
+        .send(ConsoleEvent::Attached { pid }.to_ws_message())
+        .await
+        .ok();
+
+    let mut command = Some("vCont;c".to_string());
+    loop {
+        let packet = match command.take() {
+            Some(c) => send(&mut dp, c).await?,
+            None => {
+                // Keep the same read going across client messages, dropping it could lose a packet
+                let read = read(&mut dp);
+                tokio::pin!(read);
+                loop {
+                    tokio::select! {
+                        res = &mut read => break res?,
+                        msg = socket.recv() => {
+                            if let Some(Ok(Message::Close(_))) | Some(Err(_)) | None = msg {
+                                debug!("Console client for {pid} left, detaching");
+                                return Ok(());
+                            }
+                        }
+                    }
+                }
+            }
+        };
+        let packet = match packet {
+            Some(p) => p,
+            None => continue,
+        };
+
+        let event = match packet.as_bytes().first() {
+            Some(b'O') => ConsoleEvent::Output {
+                text: String::from_utf8_lossy(&decode_hex(&packet[1..])).to_string(),
+            },
+            Some(b'T') | Some(b'S') => {
+                let signal = hex_byte(&packet[1..]).unwrap_or(0);
+                command = Some(resume_command(signal));
+                ConsoleEvent::Stopped { signal, packet }
+            }
+            Some(b'W') => ConsoleEvent::Exited {
+                status: hex_byte(&packet[1..]),
+                signal: None,
+            },
+            Some(b'X') => ConsoleEvent::Exited {
+                status: None,
+                signal: hex_byte(&packet[1..]),
+            },
+            _ => {
+                debug!("Ignoring debugserver packet {packet}");
+                continue;
+            }
+        };
+        let exited = matches!(event, ConsoleEvent::Exited { .. });
+        if socket.send(event.to_ws_message()).await.is_err() || exited {
+            return Ok(());
+        }
+    }
+}
+
+/// Attaches debugserver to the process, leaving it stopped. Dropping the client detaches
+/// and leaves the app running.
+pub async fn attach(adapter: Tunnel, pid: u64) -> Result<DebugProxyClient<Tunnel>, JitError> {
+    let mut dp = DebugProxyClient::new(adapter);
+    if let Some(res) = send(&mut dp, format!("vAttach;{pid:02X}"))
+        .await?
+        .filter(|r| r.starts_with('E'))
+    {
+        return Err(JitError::new(
+            ErrorCode::AttachFailed,
+            format!("Failed to attach to {pid}: {res}"),
+        ));
+    }
+    // Without this, debugserver kills the app when the connection drops
+    send(&mut dp, "QSetDetachOnError:1".to_string()).await?;
+    Ok(dp)
+}
+
+/// Resumes the app after it stopped on the signal. The signal is passed on so the app
+/// behaves as it would without a debugger, except SIGTRAP and SIGSTOP, which are the
+/// debugger's own.
+pub fn resume_command(signal: u8) -> String {
+    match signal {
+        SIGTRAP | SIGSTOP => "vCont;c".to_string(),
+        _ => format!("vCont;C{signal:02x}"),
+    }
+}
+
+pub async fn send(
+    dp: &mut DebugProxyClient<Tunnel>,
+    command: String,
+) -> Result<Option<String>, JitError> {
+    dp.send_command(command.into()).await.map_err(|e| {
+        warn!("Failed to send command to debug server: {e:?}");
+        JitError::new(
+            ErrorCode::TunnelFailed,
+            format!("Failed to send command to debug server: {e:?}"),
+        )
+    })
+}
+
+pub async fn read(dp: &mut DebugProxyClient<Tunnel>) -> Result<Option<String>, JitError> {
+    dp.read_response().await.map_err(|e| {
+        warn!("Failed to read from debug server: {e:?}");
+        JitError::new(
+            ErrorCode::TunnelFailed,
+            format!("Failed to read from debug server: {e:?}"),
+        )
+    })
+}
+
+pub fn hex_byte(s: &str) -> Option<u8> {
+    s.get(..2).and_then(|b| u8::from_str_radix(b, 16).ok())
+}
+
+fn decode_hex(s: &str) -> Vec<u8> {
+    (0..s.len() / 2)
+        .filter_map(|i| s.get(i * 2..).and_then(hex_byte))
+        .collect()
+}
@@ -0,0 +1,320 @@
+// Jackson Coxson
+// One websocket for every operation, so clients don't reconnect for each request
+
+use std::net::IpAddr;
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        Json, Query, State, WebSocketUpgrade,
+    },
+    response::Response,
+};
+use axum_client_ip::SecureClientIp;
+use futures_util::{SinkExt, StreamExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tracing::debug;
+
+use crate::{
+    bans,
+    client::Client,
+    common::{self, DeviceSelector},
+    error::{ErrorCode, JitError},
+    i18n::Language,
+    jit, launcher, mount, progress,
+    rate_limit::{self, Budget},
+    JitStreamerState,
+};
+
+/// A call from the client. The id is echoed back on everything sent for it.
+#[derive(Deserialize)]
+struct Call {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Reply {
+    Result {
+        id: Value,
+        result: Value,
+    },
+    Error {
+        id: Value,
+        error: JitError,
+    },
+    /// Progress of a call that hasn't finished yet
+    Event {
+        id: Value,
+        event: &'static str,
+        data: Value,
+    },
+}
+
+#[derive(Deserialize)]
+struct AuthParams {
+    token: Option<String>,
+    device: Option<String>,
+    udid: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AttachParams {
+    pid: u16,
+}
+
+fn params<T: DeserializeOwned>(params: Value) -> Result<T, JitError> {
+    let params = match params {
+        Value::Null => Value::Object(Default::default()),
+        p => p,
+    };
+    serde_json::from_value(params)
+        .map_err(|e| JitError::new(ErrorCode::BadRequest, format!("invalid params: {e}")))
+}
+
+/// Takes calls for the caller's device over one websocket. Calls run concurrently, and
+/// `auth` picks the device for the calls after it, like the X-JitStreamer-* headers.
+pub async fn handler(
+    ws: WebSocketUpgrade,
+    ip: SecureClientIp,
+    selector: DeviceSelector,
+    language: Language,
+    client: Client,
+    State(state): State<JitStreamerState>,
+) -> Response {
+    ws.on_upgrade(move |socket| connection(socket, ip.0, selector, language, client, state))
+}
+
+async fn connection(
+    socket: WebSocket,
+    ip: IpAddr,
+    mut selector: DeviceSelector,
+    language: Language,
+    client: Client,
+    state: JitStreamerState,
+) {
+    let (mut sink, mut stream) = socket.split();
+    let (sender, mut replies) = unbounded_channel::<Reply>();
+    tokio::spawn(async move {
+        while let Some(reply) = replies.recv().await {
+            let text = serde_json::to_string(&reply).unwrap();
+            if sink.send(Message::text(text)).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    while let Some(Ok(message)) = stream.next().await {
+        let text = match message {
+            Message::Text(t) => t,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let call = match serde_json::from_str::<Call>(text.as_str()) {
+            Ok(c) => c,
+            Err(e) => {
+                let error = JitError::new(ErrorCode::BadRequest, format!("invalid call: {e}"));
+                sender
+                    .send(Reply::Error {
+                        id: Value::Null,
+                        error: language.localize(error),
+                    })
+                    .ok();
+                continue;
+            }
+        };
+        debug!("Control channel call {} from {ip}", call.method);
+
+        // Handled in order, so the calls after it use the new device
+        if call.method == "auth" {
+            let reply = match auth(&state, ip, call.params).await {
+                Ok((new, udid)) => {
+                    selector = new;
+                    Reply::Result {
+                        id: call.id,
+                        result: json!({ "udid": udid }),
+                    }
+                }
+                Err(e) => Reply::Error {
+                    id: call.id,
+                    error: language.localize(e),
+                },
+            };
+            sender.send(reply).ok();
+            continue;
+        }
+
+        // Keep running if the client goes away, a launch would leave the app suspended
+        let session = Session {
+            state: state.clone(),
+            ip,
+            selector: selector.clone(),
+            client: client.clone(),
+            sender: sender.clone(),
+        };
+        tokio::spawn(async move {
+            let id = call.id.clone();
+            let reply = match session.run(call).await {
+                Ok(result) => Reply::Result { id, result },
+                Err(e) => Reply::Error {
+                    id,
+                    error: language.localize(e),
+                },
+            };
+            session.sender.send(reply).ok();
+        });
+    }
+}
+
+/// Checks the device the client picked is registered and not banned
+async fn auth(
+    state: &JitStreamerState,
+    ip: IpAddr,
+    params: Value,
+) -> Result<(DeviceSelector, String), JitError> {
+    let params = self::params::<AuthParams>(params)?;
+    let selector = DeviceSelector {
+        token: params.token,
+        device: params.device,
+        udid: params.udid,
+    };
+    bans::check(state, ip, &selector, "/ws auth").await?;
+    let (udid, _) =
+        common::get_device(state, ip, &selector, state.config().allow_udid_override).await?;
+    Ok((selector, udid))
+}
+
+/// What a call needs from its connection
+struct Session {
+    state: JitStreamerState,
+    ip: IpAddr,
+    selector: DeviceSelector,
+    /// X-Client from the upgrade request
+    client: Client,
+    sender: UnboundedSender<Reply>,
+}
+
+impl Session {
+    async fn run(&self, call: Call) -> Result<Value, JitError> {
+        match call.method.as_str() {
+            "get_apps" => self.get_apps(call.params).await,
+            "launch" => self.launch(&call.id, call.params).await,
+            "attach" => self.attach(call.params).await,
+            "mount" => self.mount(&call.id).await,
+            m => Err(JitError::new(
+                ErrorCode::BadRequest,
+                format!("unknown method {m}"),
+            )),
+        }
+    }
+
+    /// The caller's device, for launching and attaching
+    fn jit(&self) -> jit::JitSession<'_> {
+        jit::JitSession::new(&self.state, self.ip, &self.selector).client(self.client.name())
+    }
+
+    fn event(&self, id: &Value, event: &'static str, data: Value) {
+        self.sender
+            .send(Reply::Event {
+                id: id.clone(),
+                event,
+                data,
+            })
+            .ok();
+    }
+
+    /// The same as `/get_apps`
+    async fn get_apps(&self, params: Value) -> Result<Value, JitError> {
+        let options = self::params::<crate::GetAppsOptions>(params)?;
+        rate_limit::check(&self.state, Budget::GetApps, self.ip, "/ws get_apps")
+            .await
+            .map_err(|(e, _)| e)?;
+        let Json(res) = crate::get_apps(
+            SecureClientIp(self.ip),
+            self.selector.clone(),
+            Query(options),
+            State(self.state.clone()),
+        )
+        .await;
+        match res.error {
+            Some(e) => Err(e),
+            None => Ok(serde_json::to_value(res).unwrap()),
+        }
+    }
+
+    /// Takes the same body as `/v2/launch_app`, sending each phase as a `launch` event
+    async fn launch(&self, id: &Value, params: Value) -> Result<Value, JitError> {
+        let (bundle_id, options) = self::params::<launcher::LaunchRequest>(params)?.into_parts();
+        rate_limit::check(&self.state, Budget::Launch, self.ip, "/ws launch")
+            .await
+            .map_err(|(e, _)| e)?;
+
+        let (progress, mut events) = progress::Progress::channel();
+        let session = self.jit().progress(&progress);
+        let launch = session.launch(bundle_id, options);
+        tokio::pin!(launch);
+        let res = loop {
+            tokio::select! {
+                res = &mut launch => break res,
+                Some(event) = events.recv() => {
+                    self.event(id, "launch", serde_json::to_value(event).unwrap());
+                }
+            }
+        };
+        while let Ok(event) = events.try_recv() {
+            self.event(id, "launch", serde_json::to_value(event).unwrap());
+        }
+
+        res.map(|pid| json!({ "pid": pid }))
+    }
+
+    /// The same as `/attach/{pid}`
+    async fn attach(&self, params: Value) -> Result<Value, JitError> {
+        let params = self::params::<AttachParams>(params)?;
+        self.jit()
+            .attach(params.pid as u64)
+            .await
+            .map(|()| json!({ "message": "" }))
+    }
+
+    /// Mounts the developer disk image, sending its progress as `mount` events
+    async fn mount(&self, id: &Value) -> Result<Value, JitError> {
+        let udid = common::get_udid(&self.state, self.ip.to_string(), &self.selector).await?;
+        if !mount::start_mount(&self.state, &udid, self.ip).await? {
+            return Ok(json!({ "mounted": true }));
+        }
+
+        let receiver = self
+            .state
+            .mount_cache
+            .lock()
+            .await
+            .get(&udid)
+            .map(|m| m.progress.clone());
+        if let Some(mut receiver) = receiver {
+            loop {
+                let status = receiver.borrow_and_update().clone();
+                match status {
+                    Ok((done, total, complete)) => {
+                        let percentage = done as f32 / total as f32;
+                        self.event(id, "mount", json!({ "percentage": percentage }));
+                        if complete {
+                            break;
+                        }
+                    }
+                    Err(e) => return Err(JitError::new(ErrorCode::DdiMountFailed, e)),
+                }
+                if receiver.changed().await.is_err() {
+                    break;
+                }
+            }
+        }
+        Ok(json!({ "mounted": true }))
+    }
+}
@@ -1,28 +1,198 @@
 // Jackson Coxson
-// Code to retry a few times until the database isn't locked.
+// Shared connection pool for the database
 
-use sqlite::{Connection, State, Statement};
+use std::{
+    ops::{Deref, DerefMut},
+    path::Path,
+    time::Duration,
+};
 
-pub fn db_prepare<'a>(db: &'a Connection, query: &str) -> Option<Statement<'a>> {
-    for _ in 0..50 {
-        match db.prepare(query) {
-            Ok(s) => return Some(s),
-            Err(_) => {
-                std::thread::sleep(std::time::Duration::from_millis(100));
+use sqlx::{
+    query::Query,
+    sqlite::{
+        SqliteArguments, SqliteConnectOptions, SqliteConnection, SqliteJournalMode, SqlitePool,
+        SqlitePoolOptions, SqliteQueryResult, SqliteSynchronous,
+    },
+    Sqlite,
+};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, info};
+
+pub type DbPool = SqlitePool;
+
+/// How long a statement waits for another connection's lock before failing
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Ordered schema migrations, a migration's schema version is its index + 1.
+/// Never edit a migration that has shipped, add a new one instead.
+const MIGRATIONS: &[&str] = &[
+    include_str!("sql/up.sql"),
+    include_str!("sql/0002_device_tokens.sql"),
+    include_str!("sql/0003_bans.sql"),
+    include_str!("sql/0004_device_ipv4.sql"),
+    include_str!("sql/0005_device_wireguard_interface.sql"),
+    include_str!("sql/0006_invites.sql"),
+    include_str!("sql/0007_waitlist.sql"),
+    include_str!("sql/0008_drop_launch_queue.sql"),
+    include_str!("sql/0009_launch_stats.sql"),
+    include_str!("sql/0010_api_keys.sql"),
+    include_str!("sql/0011_launch_stats_device_index.sql"),
+    include_str!("sql/0012_launch_stats_client.sql"),
+    include_str!("sql/0013_device_ipv6_unique.sql"),
+    include_str!("sql/0014_incidents.sql"),
+    include_str!("sql/0015_launch_stats_daily.sql"),
+    include_str!("sql/0016_mount_stats.sql"),
+    include_str!("sql/0017_launch_stats_error.sql"),
+    include_str!("sql/0018_device_usage.sql"),
+    include_str!("sql/0019_favorites.sql"),
+    include_str!("sql/0020_subscriptions.sql"),
+    include_str!("sql/0021_drop_device_tokens.sql"),
+];
+
+/// Opens the database pool, creating the database and its folder if they don't exist yet.
+/// WAL lets the pool read while the writer writes.
+pub async fn connect(path: &str) -> Result<DbPool, sqlx::Error> {
+    if let Some(dir) = Path::new(path)
+        .parent()
+        .filter(|d| !d.as_os_str().is_empty())
+    {
+        std::fs::create_dir_all(dir)?;
+    }
+    let options = SqliteConnectOptions::new()
+        .filename(path)
+        .create_if_missing(true)
+        .journal_mode(SqliteJournalMode::Wal)
+        .synchronous(SqliteSynchronous::Normal)
+        .busy_timeout(BUSY_TIMEOUT);
+    let pool = SqlitePoolOptions::new()
+        .max_connections(8)
+        .connect_with(options)
+        .await?;
+
+    migrate(&pool).await?;
+    Ok(pool)
+}
+
+/// Brings the schema up to date, applying each pending migration in its own transaction
+async fn migrate(pool: &DbPool) -> Result<(), sqlx::Error> {
+    sqlx::query("CREATE TABLE IF NOT EXISTS schema_version (version integer not null)")
+        .execute(pool)
+        .await?;
+
+    let version = sqlx::query_scalar::<_, Option<i64>>("SELECT MAX(version) FROM schema_version")
+        .fetch_one(pool)
+        .await?;
+    let version = match version {
+        Some(v) => v,
+        None => {
+            // Databases created before migrations existed already have the first schema
+            let legacy = sqlx::query_scalar::<_, i64>(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'devices'",
+            )
+            .fetch_one(pool)
+            .await?;
+            if legacy > 0 {
+                sqlx::query("INSERT INTO schema_version (version) VALUES (1)")
+                    .execute(pool)
+                    .await?;
+                1
+            } else {
+                0
             }
         }
+    };
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let version = i as i64 + 1;
+        info!("Applying database migration {version}");
+        let mut tx = pool.begin().await?;
+        sqlx::raw_sql(migration).execute(&mut *tx).await?;
+        sqlx::query("INSERT INTO schema_version (version) VALUES (?)")
+            .bind(version)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
     }
-    None
+    Ok(())
 }
 
-pub fn statement_next(statement: &mut Statement) -> Option<State> {
-    for _ in 0..50 {
-        match statement.next() {
-            Ok(s) => return Some(s),
-            Err(_) => {
-                std::thread::sleep(std::time::Duration::from_millis(100));
+/// Funnels the server's writes through one connection owned by a task, so they queue up
+/// in order instead of fighting over SQLite's write lock. Reads keep using the pool.
+#[derive(Clone)]
+pub struct Writer {
+    sender: mpsc::UnboundedSender<oneshot::Sender<WriteConnection>>,
+}
+
+impl Writer {
+    /// Takes a connection out of the pool for the writer task
+    pub async fn spawn(pool: &DbPool) -> Result<Self, sqlx::Error> {
+        let mut conn = pool.acquire().await?.detach();
+        let (sender, mut receiver) = mpsc::unbounded_channel::<oneshot::Sender<WriteConnection>>();
+        tokio::spawn(async move {
+            while let Some(request) = receiver.recv().await {
+                let (back, returned) = oneshot::channel();
+                // A caller that gave up drops the lease, which hands the connection back
+                request
+                    .send(WriteConnection {
+                        conn: Some(conn),
+                        back: Some(back),
+                    })
+                    .ok();
+                conn = match returned.await {
+                    Ok(c) => c,
+                    Err(_) => {
+                        error!("The database writer lost its connection");
+                        break;
+                    }
+                };
             }
+        });
+        Ok(Self { sender })
+    }
+
+    /// Waits for the write connection. Hold it only as long as the write takes, every
+    /// other write waits for it to be dropped.
+    pub async fn acquire(&self) -> Result<WriteConnection, sqlx::Error> {
+        let (request, lease) = oneshot::channel();
+        self.sender
+            .send(request)
+            .map_err(|_| sqlx::Error::PoolClosed)?;
+        lease.await.map_err(|_| sqlx::Error::PoolClosed)
+    }
+
+    /// Runs a single statement
+    pub async fn execute<'q>(
+        &self,
+        query: Query<'q, Sqlite, SqliteArguments<'q>>,
+    ) -> Result<SqliteQueryResult, sqlx::Error> {
+        query.execute(&mut *self.acquire().await?).await
+    }
+}
+
+/// The write connection, returned to the writer task when dropped
+pub struct WriteConnection {
+    conn: Option<SqliteConnection>,
+    back: Option<oneshot::Sender<SqliteConnection>>,
+}
+
+impl Deref for WriteConnection {
+    type Target = SqliteConnection;
+
+    fn deref(&self) -> &Self::Target {
+        self.conn.as_ref().expect("only taken on drop")
+    }
+}
+
+impl DerefMut for WriteConnection {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.conn.as_mut().expect("only taken on drop")
+    }
+}
+
+impl Drop for WriteConnection {
+    fn drop(&mut self) {
+        if let (Some(conn), Some(back)) = (self.conn.take(), self.back.take()) {
+            back.send(conn).ok();
         }
     }
-    None
 }
@@ -0,0 +1,327 @@
+// Jackson Coxson
+// Keeps debugserver attached to an app until it's released, for apps that lose JIT when detached
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use axum_client_ip::SecureClientIp;
+use idevice::debug_proxy::DebugProxyClient;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{oneshot, Mutex};
+use tracing::{debug, info};
+
+use crate::{
+    common::{self, DeviceSelector},
+    console,
+    error::{ErrorCode, JitError},
+    pipeline,
+    tunnel::Tunnel,
+    JitStreamerState,
+};
+
+struct Session {
+    udid: String,
+    pid: u64,
+    expires: tokio::time::Instant,
+    /// Detaches when sent to or dropped
+    release: oneshot::Sender<()>,
+}
+
+/// The sessions running on this node, by ID
+#[derive(Clone, Default)]
+pub struct DebugSessions(Arc<Mutex<HashMap<String, Session>>>);
+
+/// The device's session on the process in the map, if it has one
+fn find(sessions: &HashMap<String, Session>, udid: &str, pid: u64) -> Option<DebugSessionInfo> {
+    let now = tokio::time::Instant::now();
+    sessions
+        .iter()
+        .find(|(_, s)| s.udid == udid && s.pid == pid)
+        .map(|(id, s)| DebugSessionInfo {
+            id: id.clone(),
+            pid: s.pid,
+            expires_in: s.expires.saturating_duration_since(now).as_secs(),
+        })
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct DebugSessionInfo {
+    pub id: String,
+    pub pid: u64,
+    /// Seconds until the session is released on its own
+    pub expires_in: u64,
+}
+
+#[derive(Deserialize)]
+pub struct DebugSessionRequest {
+    pid: u64,
+    /// Seconds to stay attached, capped at DEBUG_SESSION_TIMEOUT
+    timeout: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct DebugSessionReturn {
+    ok: bool,
+    session: Option<DebugSessionInfo>,
+    #[serde(flatten)]
+    error: Option<JitError>,
+}
+
+impl DebugSessionReturn {
+    fn fail(error: JitError) -> Json<Self> {
+        Json(Self {
+            ok: false,
+            session: None,
+            error: Some(error),
+        })
+    }
+
+    fn existing(session: DebugSessionInfo) -> Json<Self> {
+        info!(
+            "{} already has debug session {}, returning it",
+            session.pid, session.id
+        );
+        Json(Self {
+            ok: true,
+            session: Some(session),
+            error: None,
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct DebugSessionsReturn {
+    ok: bool,
+    sessions: Vec<DebugSessionInfo>,
+    #[serde(flatten)]
+    error: Option<JitError>,
+}
+
+/// Attaches debugserver to the process and keeps it attached, resuming the app whenever it
+/// stops, until the session is released, times out or the app exits. A process that
+/// already has a session gets that one back, debugserver can only attach once.
+pub async fn create(
+    ip: SecureClientIp,
+    selector: DeviceSelector,
+    State(state): State<JitStreamerState>,
+    Json(request): Json<DebugSessionRequest>,
+) -> Json<DebugSessionReturn> {
+    info!(
+        "Got request to keep debugserver attached to {} from {:?}",
+        request.pid, ip.0
+    );
+    let max = state.config().debug_session_timeout;
+    let timeout = request
+        .timeout
+        .map(Duration::from_secs)
+        .unwrap_or(max)
+        .min(max);
+
+    let udid = match caller(&state, ip, &selector).await {
+        Ok(u) => u,
+        Err(e) => return DebugSessionReturn::fail(e),
+    };
+    if let Some(session) = find(&*state.debug_sessions.0.lock().await, &udid, request.pid) {
+        return DebugSessionReturn::existing(session);
+    }
+
+    let (udid, provider) = match crate::jit::JitSession::new(&state, ip.0, &selector)
+        .connect()
+        .await
+    {
+        Ok(d) => d,
+        Err(e) => return DebugSessionReturn::fail(e),
+    };
+    let res = async {
+        let (adapter, _) = crate::jit::developer_service(
+            &state,
+            &udid,
+            &provider,
+            idevice::debug_proxy::SERVICE_NAME,
+            pipeline::DEBUG_PROXY_MISSING,
+        )
+        .await?;
+        console::attach(adapter, request.pid).await
+    }
+    .await;
+    let dp = match res {
+        Ok(dp) => dp,
+        Err(e) => {
+            state.heartbeats.release(&udid).await.ok();
+            return DebugSessionReturn::fail(e);
+        }
+    };
+
+    let id = rand::random::<[u8; 8]>()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+    let (release, released) = oneshot::channel();
+    let expires = tokio::time::Instant::now() + timeout;
+    {
+        let mut sessions = state.debug_sessions.0.lock().await;
+        // Another request attached while this one was connecting
+        if let Some(session) = find(&sessions, &udid, request.pid) {
+            drop(sessions);
+            drop(dp);
+            state.heartbeats.release(&udid).await.ok();
+            return DebugSessionReturn::existing(session);
+        }
+        sessions.insert(
+            id.clone(),
+            Session {
+                udid: udid.clone(),
+                pid: request.pid,
+                expires,
+                release,
+            },
+        );
+    }
+    info!("Started debug session {id} for {} on {udid}", request.pid);
+    tokio::spawn(run(
+        state,
+        id.clone(),
+        udid,
+        request.pid,
+        dp,
+        released,
+        expires,
+    ));
+
+    Json(DebugSessionReturn {
+        ok: true,
+        session: Some(DebugSessionInfo {
+            id,
+            pid: request.pid,
+            expires_in: timeout.as_secs(),
+        }),
+        error: None,
+    })
+}
+
+/// Lists the sessions on the caller's device
+pub async fn list(
+    ip: SecureClientIp,
+    selector: DeviceSelector,
+    State(state): State<JitStreamerState>,
+) -> Json<DebugSessionsReturn> {
+    let udid = match caller(&state, ip, &selector).await {
+        Ok(u) => u,
+        Err(e) => {
+            return Json(DebugSessionsReturn {
+                ok: false,
+                sessions: Vec::new(),
+                error: Some(e),
+            })
+        }
+    };
+    let now = tokio::time::Instant::now();
+    let sessions = state
+        .debug_sessions
+        .0
+        .lock()
+        .await
+        .iter()
+        .filter(|(_, s)| s.udid == udid)
+        .map(|(id, s)| DebugSessionInfo {
+            id: id.clone(),
+            pid: s.pid,
+            expires_in: s.expires.saturating_duration_since(now).as_secs(),
+        })
+        .collect();
+    Json(DebugSessionsReturn {
+        ok: true,
+        sessions,
+        error: None,
+    })
+}
+
+/// Detaches debugserver, leaving the app running
+pub async fn release(
+    ip: SecureClientIp,
+    selector: DeviceSelector,
+    Path(id): Path<String>,
+    State(state): State<JitStreamerState>,
+) -> Json<DebugSessionReturn> {
+    let udid = match caller(&state, ip, &selector).await {
+        Ok(u) => u,
+        Err(e) => return DebugSessionReturn::fail(e),
+    };
+    let mut lock = state.debug_sessions.0.lock().await;
+    // Another device's session is as good as missing
+    match lock.get(&id).filter(|s| s.udid == udid) {
+        Some(_) => {
+            let session = lock.remove(&id).unwrap();
+            session.release.send(()).ok();
+            info!("Releasing debug session {id} for {}", session.pid);
+            Json(DebugSessionReturn {
+                ok: true,
+                session: None,
+                error: None,
+            })
+        }
+        None => DebugSessionReturn::fail(JitError::new(
+            ErrorCode::BadRequest,
+            format!("No debug session {id} on your device"),
+        )),
+    }
+}
+
+async fn caller(
+    state: &JitStreamerState,
+    ip: SecureClientIp,
+    selector: &DeviceSelector,
+) -> Result<String, JitError> {
+    common::get_device(state, ip.0, selector, state.config().allow_udid_override)
+        .await
+        .map(|(udid, _)| udid)
+}
+
+/// Keeps the app running under debugserver until the session ends, then detaches by
+/// dropping the connection
+async fn run(
+    state: JitStreamerState,
+    id: String,
+    udid: String,
+    pid: u64,
+    mut dp: DebugProxyClient<Tunnel>,
+    mut released: oneshot::Receiver<()>,
+    expires: tokio::time::Instant,
+) {
+    let deadline = tokio::time::sleep_until(expires);
+    tokio::pin!(deadline);
+
+    let mut command = Some("vCont;c".to_string());
+    let reason = loop {
+        let packet = match command.take() {
+            Some(c) => console::send(&mut dp, c).await,
+            None => tokio::select! {
+                res = console::read(&mut dp) => res,
+                _ = &mut released => break "released".to_string(),
+                _ = &mut deadline => break "timed out".to_string(),
+            },
+        };
+        let packet = match packet {
+            Ok(Some(p)) => p,
+            Ok(None) => continue,
+            Err(e) => break format!("lost debugserver: {}", e.message),
+        };
+        match packet.as_bytes().first() {
+            Some(b'T') | Some(b'S') => {
+                let signal = console::hex_byte(&packet[1..]).unwrap_or(0);
+                debug!("{pid} stopped on signal {signal} in debug session {id}");
+                command = Some(console::resume_command(signal));
+            }
+            Some(b'W') | Some(b'X') => break "the app exited".to_string(),
+            _ => debug!("Ignoring debugserver packet {packet}"),
+        }
+    };
+    drop(dp);
+    info!("Debug session {id} for {pid} on {udid} ended: {reason}");
+
+    state.debug_sessions.0.lock().await.remove(&id);
+    state.heartbeats.release(&udid).await.ok();
+}
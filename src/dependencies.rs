@@ -0,0 +1,170 @@
+// Jackson Coxson
+// Probes what the server leans on, WireGuard, usbmuxd and tunneld, so a missing one turns off
+// only the features that need it instead of the whole server
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::{
+    config::Config,
+    error::{ErrorCode, JitError},
+    health, netmuxd, notify, tunnel, JitStreamerState,
+};
+
+/// How often missing dependencies are checked again, to turn their features back on
+const PROBE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Dependency {
+    Wireguard,
+    Usbmuxd,
+    Tunneld,
+}
+
+impl Dependency {
+    pub const ALL: [Dependency; 3] = [
+        Dependency::Wireguard,
+        Dependency::Usbmuxd,
+        Dependency::Tunneld,
+    ];
+
+    /// Its component in /healthz
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Dependency::Wireguard => "wireguard",
+            Dependency::Usbmuxd => "usbmuxd",
+            Dependency::Tunneld => "tunneld",
+        }
+    }
+
+    /// What's turned off while it's missing
+    pub fn disables(self) -> &'static str {
+        match self {
+            Dependency::Wireguard => "Wireguard registration",
+            Dependency::Usbmuxd => "reaching devices over USB",
+            Dependency::Tunneld => "developer services through tunneld",
+        }
+    }
+
+    /// Whether this node uses it with the current configuration
+    pub fn configured(self, config: &Config) -> bool {
+        match self {
+            Dependency::Wireguard => config.wireguard_registration() && config.role.registers(),
+            Dependency::Usbmuxd => config.usb_devices && config.role.serves_devices(),
+            Dependency::Tunneld => tunnel::uses_tunneld(config) && config.role.serves_devices(),
+        }
+    }
+
+    pub async fn check(self, config: &Config) -> Result<(), String> {
+        match self {
+            Dependency::Wireguard => health::wireguard_up(config).await,
+            Dependency::Usbmuxd => netmuxd::list_devices()
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+            Dependency::Tunneld => tunnel::tunneld_reachable(&config.tunneld_url).await,
+        }
+    }
+}
+
+/// The dependencies that are missing, with why. Their features refuse requests with
+/// DEGRADED until a check finds them again.
+#[derive(Clone, Default)]
+pub struct Dependencies(Arc<RwLock<HashMap<Dependency, String>>>);
+
+impl Dependencies {
+    /// Why the dependency is missing, None if it's there
+    pub async fn missing(&self, dependency: Dependency) -> Option<String> {
+        self.0.read().await.get(&dependency).cloned()
+    }
+
+    /// Refuses the request while the dependency is missing
+    pub async fn require(&self, dependency: Dependency) -> Result<(), JitError> {
+        match self.missing(dependency).await {
+            Some(e) => Err(JitError::new(
+                ErrorCode::Degraded,
+                format!(
+                    "{} is off on this server right now, {} isn't available: {e}",
+                    dependency.disables(),
+                    dependency.as_str()
+                ),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Records the outcome of checking the dependency, turning its features off or back on.
+/// The operator is alerted when usbmuxd goes or comes back.
+pub async fn record(state: &JitStreamerState, dependency: Dependency, res: &Result<(), String>) {
+    let mut missing = state.dependencies.0.write().await;
+    let was_missing = missing.contains_key(&dependency);
+    match res {
+        Ok(()) => {
+            if missing.remove(&dependency).is_none() {
+                return;
+            }
+            info!(
+                "{} is back, turning {} on again",
+                dependency.as_str(),
+                dependency.disables()
+            );
+        }
+        Err(e) => {
+            missing.insert(dependency, e.clone());
+            if was_missing {
+                return;
+            }
+            warn!(
+                "{} is unavailable, turning {} off until it's back: {e}",
+                dependency.as_str(),
+                dependency.disables()
+            );
+        }
+    }
+    if dependency == Dependency::Usbmuxd {
+        state.notifier.alert(match res {
+            Ok(()) => notify::Alert::MuxerRecovered,
+            Err(e) => notify::Alert::MuxerUnavailable { error: e.clone() },
+        });
+    }
+}
+
+/// Checks every dependency the configuration uses, so the server starts without the
+/// missing ones instead of failing requests deep inside
+pub async fn probe(state: &JitStreamerState) {
+    let config = state.config();
+    for dependency in Dependency::ALL {
+        if dependency.configured(&config) {
+            record(state, dependency, &dependency.check(&config).await).await;
+        }
+    }
+}
+
+/// Checks the missing dependencies again every minute. Ones the configuration no longer
+/// uses are forgotten.
+pub async fn run(state: JitStreamerState) {
+    let mut interval = tokio::time::interval(PROBE_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let missing = state
+            .dependencies
+            .0
+            .read()
+            .await
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        let config = state.config();
+        for dependency in missing {
+            let res = match dependency.configured(&config) {
+                true => dependency.check(&config).await,
+                false => Ok(()),
+            };
+            record(&state, dependency, &res).await;
+        }
+    }
+}
@@ -0,0 +1,177 @@
+// Jackson Coxson
+// Device class detection from lockdown values
+
+use std::{collections::HashMap, sync::Arc};
+
+use idevice::{amfi::AmfiClient, lockdownd::LockdowndClient, IdeviceError, IdeviceService};
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tracing::debug;
+
+use crate::{
+    error::{ErrorCode, JitError},
+    launcher::JitMethod,
+    provider::DeviceProvider,
+};
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceClass {
+    IPhone,
+    IPad,
+    IPod,
+    AppleTv,
+    Watch,
+    Vision,
+    Unknown,
+}
+
+impl From<&str> for DeviceClass {
+    fn from(value: &str) -> Self {
+        match value {
+            "iPhone" => Self::IPhone,
+            "iPad" => Self::IPad,
+            "iPod" => Self::IPod,
+            "AppleTV" => Self::AppleTv,
+            "Watch" => Self::Watch,
+            "RealityDevice" => Self::Vision,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+impl std::fmt::Display for DeviceClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::IPhone => "iPhone",
+                Self::IPad => "iPad",
+                Self::IPod => "iPod",
+                Self::AppleTv => "Apple TV",
+                Self::Watch => "Apple Watch",
+                Self::Vision => "Apple Vision",
+                Self::Unknown => "unknown device",
+            }
+        )
+    }
+}
+
+impl DeviceClass {
+    /// Whether the bundled iOS developer disk image can be mounted
+    pub fn can_mount_ddi(&self) -> bool {
+        matches!(self, Self::IPhone | Self::IPad | Self::IPod)
+    }
+
+    /// Returns an explanation if JIT can't be enabled on this class of device
+    pub fn check_supported(&self) -> Result<(), String> {
+        match self {
+            Self::Watch => Err(format!("JIT is not supported on {self}")),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct DeviceInfo {
+    pub device_class: DeviceClass,
+    pub product_type: Option<String>,
+    pub product_version: Option<String>,
+    /// How launches enable JIT on the device
+    pub jit_method: JitMethod,
+}
+
+pub type DeviceInfoCache = Arc<Mutex<HashMap<String, DeviceInfo>>>;
+
+/// Reads the device class and version from lockdown, caching the result per UDID
+pub async fn get_device_info(
+    cache: &DeviceInfoCache,
+    udid: &str,
+    provider: &DeviceProvider,
+) -> Result<DeviceInfo, IdeviceError> {
+    if let Some(i) = cache.lock().await.get(udid) {
+        return Ok(i.clone());
+    }
+
+    debug!("Getting device info for {udid}");
+    let mut lockdown_client = LockdowndClient::connect(provider).await?;
+    lockdown_client
+        .start_session(provider.pairing_file())
+        .await?;
+
+    let device_class = match lockdown_client.get_value("DeviceClass").await? {
+        plist::Value::String(c) => DeviceClass::from(c.as_str()),
+        _ => DeviceClass::Unknown,
+    };
+    let product_type = match lockdown_client.get_value("ProductType").await {
+        Ok(plist::Value::String(p)) => Some(p),
+        _ => None,
+    };
+    let product_version = match lockdown_client.get_value("ProductVersion").await {
+        Ok(plist::Value::String(p)) => Some(p),
+        _ => None,
+    };
+
+    let info = DeviceInfo {
+        device_class,
+        product_type,
+        jit_method: JitMethod::for_version(product_version.as_deref()),
+        product_version,
+    };
+    cache.lock().await.insert(udid.to_string(), info.clone());
+    Ok(info)
+}
+
+/// Whether the device is locked with its passcode. Not cached, it changes all the time.
+pub async fn passcode_locked(provider: &DeviceProvider) -> Result<bool, IdeviceError> {
+    let mut lockdown_client = LockdowndClient::connect(provider).await?;
+    lockdown_client
+        .start_session(provider.pairing_file())
+        .await?;
+    Ok(matches!(
+        lockdown_client.get_value("PasswordProtected").await?,
+        plist::Value::Boolean(true)
+    ))
+}
+
+/// Developer services are also missing when Developer Mode is off, which iOS updates tend to
+/// do. If it is, shows its switch in Settings, where it's hidden until something asks for
+/// it, and says how to turn it on. Otherwise returns `error` as it is.
+pub async fn explain_missing_services(provider: &DeviceProvider, error: JitError) -> JitError {
+    match developer_mode_enabled(provider).await {
+        Ok(false) => developer_mode_disabled(provider).await,
+        Ok(true) => error,
+        Err(e) => {
+            debug!("Failed to get the Developer Mode status: {e:?}");
+            error
+        }
+    }
+}
+
+/// Whether Developer Mode is on. Fails before iOS 16, which has no AMFI service or
+/// Developer Mode.
+pub async fn developer_mode_enabled(provider: &DeviceProvider) -> Result<bool, IdeviceError> {
+    AmfiClient::connect(provider)
+        .await?
+        .get_developer_mode_status()
+        .await
+}
+
+/// Shows the Developer Mode switch and explains how to turn it on
+pub async fn developer_mode_disabled(provider: &DeviceProvider) -> JitError {
+    let res = async {
+        AmfiClient::connect(provider)
+            .await?
+            .reveal_developer_mode_option_in_ui()
+            .await
+    }
+    .await;
+    if let Err(e) = res {
+        debug!("Failed to reveal the Developer Mode switch: {e:?}");
+    }
+    JitError::new(
+        ErrorCode::DeveloperModeDisabled,
+        "Developer Mode is off. Turn it on in Settings > Privacy & Security > Developer Mode, restart your device and try again.",
+    )
+}
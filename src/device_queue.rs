@@ -0,0 +1,126 @@
+// Jackson Coxson
+// One request per device at a time, so racing requests don't fight over its heartbeat and tunnel
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use axum::{
+    extract::{Request, State},
+    http::HeaderValue,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_client_ip::SecureClientIp;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use tracing::debug;
+
+use crate::{
+    common::{self, DeviceSelector},
+    error::{ErrorCode, JitError},
+    JitStreamerState,
+};
+
+/// How many of the device's requests were ahead of this one
+pub const QUEUE_POSITION_HEADER: &str = "x-jitstreamer-queue-position";
+/// Requests beyond this many waiting on a device are turned away instead of queued
+const MAX_AHEAD: usize = 5;
+
+#[derive(Default)]
+struct Queue {
+    turn: Arc<Mutex<()>>,
+    /// Requests holding or waiting for the turn
+    queued: AtomicUsize,
+}
+
+/// A request's place in its device's queue, given up when dropped even if it never got a turn
+struct Place(Arc<Queue>);
+
+impl Drop for Place {
+    fn drop(&mut self) {
+        self.0.queued.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The device is the request's until this is dropped
+pub struct Turn {
+    _guard: OwnedMutexGuard<()>,
+    _place: Place,
+}
+
+#[derive(Clone, Default)]
+pub struct DeviceQueues(Arc<Mutex<HashMap<String, Arc<Queue>>>>);
+
+impl DeviceQueues {
+    /// Waits for the device's earlier requests to finish, in the order they came in.
+    /// Returns how many were ahead.
+    pub async fn wait(&self, udid: &str) -> Result<(Turn, usize), JitError> {
+        let queue = {
+            let mut queues = self.0.lock().await;
+            // Queues nobody holds a place in are only referenced by the map
+            queues.retain(|_, q| Arc::strong_count(q) > 1);
+            queues.entry(udid.to_string()).or_default().clone()
+        };
+        let ahead = queue.queued.fetch_add(1, Ordering::SeqCst);
+        let place = Place(queue);
+        if ahead >= MAX_AHEAD {
+            return Err(JitError::new(
+                ErrorCode::Busy,
+                "This device has too many requests waiting, retry when they finish",
+            ));
+        }
+        let guard = place.0.turn.clone().lock_owned().await;
+        Ok((
+            Turn {
+                _guard: guard,
+                _place: place,
+            },
+            ahead,
+        ))
+    }
+
+    /// How many of the device's requests are running or waiting
+    pub async fn queued(&self, udid: &str) -> usize {
+        self.0
+            .lock()
+            .await
+            .get(udid)
+            .map(|q| q.queued.load(Ordering::SeqCst))
+            .unwrap_or(0)
+    }
+}
+
+/// Holds the caller's device for the length of the request, answering with how many of its
+/// requests were ahead in X-JitStreamer-Queue-Position
+pub async fn serialize(
+    State(state): State<JitStreamerState>,
+    ip: SecureClientIp,
+    selector: DeviceSelector,
+    request: Request,
+    next: Next,
+) -> Response {
+    let udid = match common::get_device(&state, ip.0, &selector, state.config().allow_udid_override)
+        .await
+    {
+        Ok((udid, _)) => udid,
+        // The handler answers with the error in its own shape
+        Err(_) => return next.run(request).await,
+    };
+    let (turn, ahead) = match state.device_queues.wait(&udid).await {
+        Ok(t) => t,
+        Err(e) => return e.into_response(),
+    };
+    if ahead > 0 {
+        debug!("{udid}'s request waited behind {ahead} others");
+    }
+    let mut response = next.run(request).await;
+    drop(turn);
+    response
+        .headers_mut()
+        .insert(QUEUE_POSITION_HEADER, HeaderValue::from(ahead));
+    response
+}
@@ -0,0 +1,231 @@
+// Jackson Coxson
+// `jitstreamer doctor`, which checks the environment for the problems most deployments hit
+
+use std::{
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::Path,
+};
+
+use crate::{
+    config::{Config, WireguardConfig},
+    db, netmuxd,
+    pairing_store::PairingStore,
+};
+
+/// Written to the pairing store and removed again, no UDID looks like it
+const PAIRING_STORE_PROBE: &str = "jitstreamer-doctor";
+
+enum Outcome {
+    Pass(String),
+    Fail { problem: String, fix: String },
+    Skip(String),
+}
+
+fn fail(problem: impl Into<String>, fix: impl Into<String>) -> Outcome {
+    Outcome::Fail {
+        problem: problem.into(),
+        fix: fix.into(),
+    }
+}
+
+/// Runs every check, printing each as it finishes. Fails if any check did.
+pub async fn run(config: &Config) -> Result<(), String> {
+    let mut failed = 0;
+    let mut report = |name: &str, outcome: Outcome| match outcome {
+        Outcome::Pass(detail) => println!("[PASS] {name}: {detail}"),
+        Outcome::Skip(reason) => println!("[SKIP] {name}: {reason}"),
+        Outcome::Fail { problem, fix } => {
+            failed += 1;
+            println!("[FAIL] {name}: {problem}");
+            println!("       fix: {fix}");
+        }
+    };
+
+    if config.wireguard_registration() {
+        report("ip command", ip_command());
+        for wireguard in &config.wireguard {
+            let name = &wireguard.config_name;
+            report(&format!("Wireguard {name}"), wireguard_mode(wireguard));
+            report(
+                &format!("Wireguard {name} config"),
+                wireguard_conf(wireguard),
+            );
+            if wireguard.embedded {
+                report(
+                    &format!("Wireguard {name} port"),
+                    udp_port(wireguard.port, "WIREGUARD_PORT"),
+                );
+            }
+        }
+    } else {
+        report(
+            "Wireguard",
+            Outcome::Skip("registration doesn't use Wireguard".to_string()),
+        );
+    }
+
+    report(
+        "usbmuxd",
+        match config.usb_devices {
+            true => usbmuxd().await,
+            false => Outcome::Skip("USB_DEVICES is off".to_string()),
+        },
+    );
+    report("database", database(&config.database_path).await);
+    report("pairing file storage", pairing_store(config).await);
+
+    report(
+        "HTTP port",
+        match config.listen_tcp {
+            true => tcp_port(config.port, "JITSTREAMER_PORT"),
+            false => Outcome::Skip("JITSTREAMER_TCP is off".to_string()),
+        },
+    );
+    report(
+        "gRPC port",
+        match config.grpc_port {
+            Some(port) => tcp_port(port, "GRPC_PORT"),
+            None => Outcome::Skip("GRPC_PORT is unset".to_string()),
+        },
+    );
+
+    match failed {
+        0 => {
+            println!("Everything looks good");
+            Ok(())
+        }
+        1 => Err("1 check failed".to_string()),
+        n => Err(format!("{n} checks failed")),
+    }
+}
+
+fn ip_command() -> Outcome {
+    match std::process::Command::new("ip").arg("-V").output() {
+        Ok(output) if output.status.success() => {
+            Outcome::Pass(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        }
+        Ok(output) => fail(
+            format!("ip -V exited with {}", output.status),
+            "Reinstall iproute2",
+        ),
+        Err(e) => fail(
+            format!("Couldn't run ip: {e}"),
+            "Install iproute2, it sets the interfaces' addresses and routes",
+        ),
+    }
+}
+
+/// The kernel module, or a TUN device for embedded mode
+fn wireguard_mode(wireguard: &WireguardConfig) -> Outcome {
+    if wireguard.embedded {
+        return match Path::new("/dev/net/tun").exists() {
+            true => Outcome::Pass("embedded, /dev/net/tun is available".to_string()),
+            false => fail(
+                "/dev/net/tun doesn't exist, embedded mode needs it",
+                "Run modprobe tun, or pass --device /dev/net/tun to Docker",
+            ),
+        };
+    }
+    match Path::new("/sys/module/wireguard").exists() {
+        true => Outcome::Pass("the kernel module is loaded".to_string()),
+        false => fail(
+            "the wireguard kernel module isn't loaded",
+            "Install wireguard-tools and run modprobe wireguard, or set WIREGUARD_EMBEDDED=true to run Wireguard in-process",
+        ),
+    }
+}
+
+/// The config file is created on first start and rewritten for every registration
+fn wireguard_conf(wireguard: &WireguardConfig) -> Outcome {
+    let path = wireguard.conf_path();
+    let writable = match Path::new(&path).exists() {
+        true => std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .map(|_| ()),
+        false => {
+            let probe = format!("{path}.doctor");
+            std::fs::write(&probe, b"").and_then(|_| std::fs::remove_file(&probe))
+        }
+    };
+    match writable {
+        Ok(()) => Outcome::Pass(format!("{path} is writable")),
+        Err(e) => fail(
+            format!("can't write {path}: {e}"),
+            "Run as root, or give the server's user write access to /etc/wireguard",
+        ),
+    }
+}
+
+async fn usbmuxd() -> Outcome {
+    match netmuxd::list_devices().await {
+        Ok(devices) => Outcome::Pass(format!("answered with {} devices", devices.len())),
+        Err(e) => fail(
+            e.to_string(),
+            "Start usbmuxd, or point USBMUXD_SOCKET_ADDRESS at it (tcp://host:port or a socket path)",
+        ),
+    }
+}
+
+/// Opening the database migrates it, then a write lock proves it's writable
+async fn database(path: &str) -> Outcome {
+    let fix = "Check DATABASE_PATH and that the server's user can write it and its folder";
+    let pool = match db::connect(path).await {
+        Ok(p) => p,
+        Err(e) => return fail(format!("couldn't open {path}: {e}"), fix),
+    };
+    let res = async {
+        let mut conn = pool.acquire().await?;
+        sqlx::query("BEGIN IMMEDIATE").execute(&mut *conn).await?;
+        sqlx::query("ROLLBACK").execute(&mut *conn).await?;
+        Ok::<_, sqlx::Error>(())
+    }
+    .await;
+    match res {
+        Ok(()) => Outcome::Pass(format!("{path} is writable")),
+        Err(e) => fail(format!("{path} isn't writable: {e}"), fix),
+    }
+}
+
+async fn pairing_store(config: &Config) -> Outcome {
+    let store = &config.pairing_store;
+    let res = async {
+        store.put(PAIRING_STORE_PROBE, b"").await?;
+        store.remove(PAIRING_STORE_PROBE).await
+    }
+    .await;
+    match res {
+        Ok(()) => Outcome::Pass("pairing files can be saved".to_string()),
+        Err(e) => fail(
+            format!("couldn't save a pairing file: {e}"),
+            "Check that PLIST_STORAGE is writable by the server's user, or the S3 credentials and bucket",
+        ),
+    }
+}
+
+fn tcp_port(port: u16, var: &str) -> Outcome {
+    match std::net::TcpListener::bind(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port)) {
+        Ok(_) => Outcome::Pass(format!("{port} is free")),
+        Err(e) => port_taken(port, var, e),
+    }
+}
+
+fn udp_port(port: u16, var: &str) -> Outcome {
+    match std::net::UdpSocket::bind(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port)) {
+        Ok(_) => Outcome::Pass(format!("{port} is free")),
+        Err(e) => port_taken(port, var, e),
+    }
+}
+
+fn port_taken(port: u16, var: &str, e: std::io::Error) -> Outcome {
+    let fix = match e.kind() {
+        std::io::ErrorKind::AddrInUse => {
+            format!("Stop whatever is using it, such as a running server, or change {var}")
+        }
+        std::io::ErrorKind::PermissionDenied => {
+            format!("Ports under 1024 need root or CAP_NET_BIND_SERVICE, or change {var}")
+        }
+        _ => format!("Change {var}"),
+    };
+    fail(format!("can't bind {port}: {e}"), fix)
+}
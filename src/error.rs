@@ -0,0 +1,198 @@
+// Jackson Coxson
+// Error codes sent with every error message, so clients can branch on them
+
+use std::{fmt::Display, net::IpAddr};
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use idevice::IdeviceError;
+use serde::{Deserialize, Serialize};
+
+use crate::{config::Config, register};
+
+/// A stable code for each kind of failure. Never rename or reuse one, clients match on them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    /// Something went wrong on the server, such as the database
+    Internal,
+    /// No device is registered for the client's IP, token or UDID
+    NotRegistered,
+    /// Several devices share the client's IP and it didn't pick one
+    DeviceAmbiguous,
+    /// The client isn't allowed to do this
+    Forbidden,
+    /// The client or its device is banned
+    Banned,
+    RateLimited,
+    /// Too many launches are running, retry shortly
+    Busy,
+    /// The server has no pairing file for the device
+    PairingMissing,
+    /// The device rejected the pairing file, it needs to be paired again
+    PairingInvalid,
+    /// The device didn't answer
+    DeviceUnreachable,
+    /// The device's Wireguard tunnel hasn't completed a handshake recently
+    VpnNoHandshake,
+    /// The developer disk image isn't mounted, so developer services are missing
+    DdiNotMounted,
+    DdiMountFailed,
+    /// The device class or OS version can't do this
+    UnsupportedDevice,
+    /// A lockdown service on the device failed
+    ServiceFailed,
+    /// The CoreDevice tunnel or RemoteXPC handshake failed
+    TunnelFailed,
+    LaunchFailed,
+    AttachFailed,
+    /// No running process matched the requested name
+    ProcessNotFound,
+    /// No debuggable app has the requested name
+    AppNotFound,
+    /// The device has no apps with get-task-allow
+    NoDebuggableApps,
+    /// The server has as many devices registered as it takes
+    ServerFull,
+    /// The request couldn't be understood, such as a malformed /ws call
+    BadRequest,
+    /// The device used up its launches for the hour or day, see `resets_at`
+    QuotaExceeded,
+    /// The device stopped answering partway through, such as over a hung connection
+    DeviceTimeout,
+    /// The device is locked with its passcode, it has to be unlocked to launch
+    DeviceLocked,
+    /// Developer Mode is off on the device, so developer services are missing
+    DeveloperModeDisabled,
+    /// The server can't reach usbmuxd or netmuxd, such as while it restarts
+    MuxerUnavailable,
+    /// The server is running without something this needs, such as tunneld. /healthz says what.
+    Degraded,
+}
+
+impl ErrorCode {
+    /// The HTTP status for routes that answer errors with one
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::NotRegistered
+            | ErrorCode::ProcessNotFound
+            | ErrorCode::AppNotFound
+            | ErrorCode::NoDebuggableApps => StatusCode::NOT_FOUND,
+            ErrorCode::Forbidden | ErrorCode::Banned => StatusCode::FORBIDDEN,
+            ErrorCode::RateLimited | ErrorCode::QuotaExceeded => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::Busy
+            | ErrorCode::ServerFull
+            | ErrorCode::MuxerUnavailable
+            | ErrorCode::Degraded => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::DeviceUnreachable | ErrorCode::VpnNoHandshake => StatusCode::BAD_GATEWAY,
+            ErrorCode::DeviceAmbiguous
+            | ErrorCode::PairingMissing
+            | ErrorCode::PairingInvalid
+            | ErrorCode::DdiNotMounted
+            | ErrorCode::UnsupportedDevice
+            | ErrorCode::DeviceLocked
+            | ErrorCode::DeveloperModeDisabled => StatusCode::CONFLICT,
+            ErrorCode::BadRequest => StatusCode::BAD_REQUEST,
+            ErrorCode::DeviceTimeout => StatusCode::GATEWAY_TIMEOUT,
+            ErrorCode::DdiMountFailed
+            | ErrorCode::ServiceFailed
+            | ErrorCode::TunnelFailed
+            | ErrorCode::LaunchFailed
+            | ErrorCode::AttachFailed => StatusCode::BAD_GATEWAY,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// An error message with its code. Flattened into responses as `error` and `code`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JitError {
+    pub code: ErrorCode,
+    #[serde(rename = "error")]
+    pub message: String,
+    /// The untranslated message, when `message` was translated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// When the quota that rejected the request frees up, in UTC
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resets_at: Option<String>,
+}
+
+impl JitError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            detail: None,
+            resets_at: None,
+        }
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Internal, message)
+    }
+
+    /// Classifies a failure to start the device's heartbeat
+    pub fn heartbeat(e: IdeviceError, config: &Config, ip: IpAddr) -> Self {
+        if let IdeviceError::InvalidHostID = e {
+            return Self::new(
+                ErrorCode::PairingInvalid,
+                "Your pairing file is invalid. Regenerate it with jitterbug pair.",
+            );
+        }
+        if config.wireguard_registration() && !register::recent_handshake(&config.wireguard, ip) {
+            return Self::new(
+                ErrorCode::VpnNoHandshake,
+                format!(
+                    "Failed to heartbeat device: {e}. The VPN isn't connected, turn on the JitStreamer tunnel in the WireGuard app."
+                ),
+            );
+        }
+        if config.tailnet.is_some() {
+            return Self::new(
+                ErrorCode::DeviceUnreachable,
+                format!(
+                    "Failed to heartbeat device: {e}. Make sure Tailscale is connected on your device."
+                ),
+            );
+        }
+        Self::new(
+            ErrorCode::DeviceUnreachable,
+            format!("Failed to heartbeat device: {e}"),
+        )
+    }
+}
+
+impl Display for JitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorReturn<'a> {
+    ok: bool,
+    #[serde(flatten)]
+    error: &'a JitError,
+}
+
+/// `{"ok": false, "code": ..., "error": ...}` with the code's HTTP status, for routes
+/// that return a bare error instead of their usual response
+impl IntoResponse for JitError {
+    fn into_response(self) -> Response {
+        let body = Json(ErrorReturn {
+            ok: false,
+            error: &self,
+        });
+        (self.code.status(), body).into_response()
+    }
+}
+
+impl From<JitError> for String {
+    fn from(e: JitError) -> Self {
+        e.message
+    }
+}
@@ -0,0 +1,164 @@
+// Jackson Coxson
+// Notable things that happen to devices, streamed to clients from /events
+
+use std::{convert::Infallible, net::IpAddr};
+
+use axum::{
+    extract::State,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    Json,
+};
+use axum_client_ip::SecureClientIp;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::{
+    common::{self, DeviceSelector},
+    error::{ErrorCode, JitError},
+    i18n::Language,
+    JitStreamerState,
+};
+
+/// Events kept for slow subscribers before they miss some
+const BACKLOG: usize = 256;
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum DeviceEvent {
+    /// The device registered, or registered again
+    Registered,
+    /// The developer disk image finished mounting
+    Mounted,
+    MountFailed {
+        error: String,
+    },
+    Launched {
+        bundle_id: String,
+        pid: Option<u64>,
+    },
+    LaunchFailed {
+        bundle_id: String,
+        #[serde(flatten)]
+        error: JitError,
+    },
+}
+
+impl DeviceEvent {
+    /// The `event` tag the event is serialized with
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeviceEvent::Registered => "registered",
+            DeviceEvent::Mounted => "mounted",
+            DeviceEvent::MountFailed { .. } => "mount_failed",
+            DeviceEvent::Launched { .. } => "launched",
+            DeviceEvent::LaunchFailed { .. } => "launch_failed",
+        }
+    }
+}
+
+/// An event with the device it happened to, and the client that caused it
+#[derive(Clone, Debug)]
+pub struct Notice {
+    pub udid: String,
+    pub ip: IpAddr,
+    pub event: DeviceEvent,
+}
+
+/// Where device events are published, anything can subscribe
+#[derive(Clone)]
+pub struct EventBus(broadcast::Sender<Notice>);
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self(broadcast::channel(BACKLOG).0)
+    }
+}
+
+impl EventBus {
+    pub fn send(&self, udid: &str, ip: IpAddr, event: DeviceEvent) {
+        // Nobody listening is fine
+        self.0
+            .send(Notice {
+                udid: udid.to_string(),
+                ip: ip.to_canonical(),
+                event,
+            })
+            .ok();
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Notice> {
+        self.0.subscribe()
+    }
+}
+
+/// Which events a subscriber gets
+#[derive(Clone)]
+enum Filter {
+    Device(String),
+    /// The caller isn't registered yet, so it gets events it causes, like registering
+    Client(IpAddr),
+}
+
+impl Filter {
+    fn matches(&self, notice: &Notice) -> bool {
+        match self {
+            Filter::Device(udid) => notice.udid == *udid,
+            Filter::Client(ip) => notice.ip == *ip,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct EventsReturn {
+    ok: bool,
+    #[serde(flatten)]
+    error: JitError,
+}
+
+/// Streams the caller's device's events as server-sent events, named after the event
+pub async fn handler(
+    ip: SecureClientIp,
+    selector: DeviceSelector,
+    language: Language,
+    State(state): State<JitStreamerState>,
+) -> Response {
+    let filter =
+        match common::get_device(&state, ip.0, &selector, state.config().allow_udid_override).await
+        {
+            Ok((udid, _)) => Filter::Device(udid),
+            Err(e) if e.code == ErrorCode::NotRegistered => Filter::Client(ip.0.to_canonical()),
+            Err(e) => {
+                return Json(EventsReturn {
+                    ok: false,
+                    error: language.localize(e),
+                })
+                .into_response()
+            }
+        };
+
+    let receiver = state.events.subscribe();
+    let stream = futures_util::stream::unfold(receiver, move |mut receiver| {
+        let filter = filter.clone();
+        async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(notice) if filter.matches(&notice) => {
+                        let event = Event::default()
+                            .event(notice.event.name())
+                            .json_data(&notice.event)
+                            .unwrap();
+                        return Some((Ok::<_, Infallible>(event), receiver));
+                    }
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        }
+    });
+    Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
@@ -0,0 +1,192 @@
+// Jackson Coxson
+// Favorite apps per device, which the shortcut shows before the full app list
+
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use axum_client_ip::SecureClientIp;
+use serde::Serialize;
+use tracing::info;
+
+use crate::{
+    apps,
+    common::DeviceSelector,
+    error::{ErrorCode, JitError},
+    jit, JitStreamerState,
+};
+
+/// The most favorites a device keeps, the rest are for the app list
+const MAX_FAVORITES: i64 = 50;
+
+#[derive(Serialize, Default)]
+pub struct FavoritesReturn {
+    ok: bool,
+    /// App names in the order they were added, as /get_apps shows them
+    apps: Vec<String>,
+    /// App names to bundle IDs
+    bundle_ids: HashMap<String, String>,
+    #[serde(flatten)]
+    error: Option<JitError>,
+}
+
+impl FavoritesReturn {
+    fn fail(error: JitError) -> Self {
+        Self {
+            error: Some(error),
+            ..Default::default()
+        }
+    }
+}
+
+fn db_error(e: sqlx::Error) -> JitError {
+    tracing::error!("Failed to read or write favorites: {e:?}");
+    JitError::internal("Failed to read or write favorites")
+}
+
+/// The device's favorites, named from its cached app list. Apps that aren't in the cache
+/// are named by their bundle ID.
+async fn listed(state: &JitStreamerState, udid: &str) -> Result<FavoritesReturn, JitError> {
+    let bundle_ids = sqlx::query_scalar::<_, String>(
+        "SELECT bundle_id FROM favorites WHERE udid = ? ORDER BY added_at, rowid",
+    )
+    .bind(udid)
+    .fetch_all(&state.db)
+    .await
+    .map_err(db_error)?;
+
+    let cached = state.apps_cache.get(udid, false).await;
+    let mut res = FavoritesReturn {
+        ok: true,
+        ..Default::default()
+    };
+    for bundle_id in bundle_ids {
+        let name = cached
+            .as_ref()
+            .and_then(|c| c.details.get(&bundle_id))
+            .map(|d| d.name.clone())
+            // Two apps with the same name can't share it
+            .filter(|n| !res.bundle_ids.contains_key(n))
+            .unwrap_or_else(|| bundle_id.clone());
+        res.apps.push(name.clone());
+        res.bundle_ids.insert(name, bundle_id);
+    }
+    Ok(res)
+}
+
+async fn udid(
+    state: &JitStreamerState,
+    ip: SecureClientIp,
+    selector: &DeviceSelector,
+) -> Result<String, JitError> {
+    jit::JitSession::new(state, ip.0, selector)
+        .device()
+        .await
+        .map(|(udid, _)| udid)
+}
+
+/// Lists the caller's favorite apps
+pub async fn list(
+    ip: SecureClientIp,
+    selector: DeviceSelector,
+    State(state): State<JitStreamerState>,
+) -> Json<FavoritesReturn> {
+    let res = match udid(&state, ip, &selector).await {
+        Ok(udid) => listed(&state, &udid).await,
+        Err(e) => Err(e),
+    };
+    Json(res.unwrap_or_else(FavoritesReturn::fail))
+}
+
+/// Marks an app as a favorite, returning the favorites
+pub async fn add(
+    ip: SecureClientIp,
+    selector: DeviceSelector,
+    Path(bundle_id): Path<String>,
+    State(state): State<JitStreamerState>,
+) -> Json<FavoritesReturn> {
+    info!(
+        "Got request to add {bundle_id} to the favorites of {:?}",
+        ip.0
+    );
+    let res = match udid(&state, ip, &selector).await {
+        Ok(udid) => match insert(&state, &udid, &bundle_id).await {
+            Ok(()) => listed(&state, &udid).await,
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    };
+    Json(res.unwrap_or_else(FavoritesReturn::fail))
+}
+
+async fn insert(state: &JitStreamerState, udid: &str, bundle_id: &str) -> Result<(), JitError> {
+    if !apps::valid_bundle_id(bundle_id) {
+        return Err(JitError::new(
+            ErrorCode::BadRequest,
+            format!("{bundle_id} isn't a bundle ID"),
+        ));
+    }
+    // The writer is only ours until it's dropped, so the count can't change under us
+    let mut conn = state.db_writer.acquire().await.map_err(db_error)?;
+    let (count, exists) = sqlx::query_as::<_, (i64, bool)>(
+        "SELECT COUNT(*), COALESCE(SUM(bundle_id = ?), 0) > 0 FROM favorites WHERE udid = ?",
+    )
+    .bind(bundle_id)
+    .bind(udid)
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(db_error)?;
+    if exists {
+        return Ok(());
+    }
+    if count >= MAX_FAVORITES {
+        return Err(JitError::new(
+            ErrorCode::BadRequest,
+            format!("A device can have up to {MAX_FAVORITES} favorites, remove one first"),
+        ));
+    }
+    sqlx::query(
+        "INSERT INTO favorites (udid, bundle_id, added_at) VALUES (?, ?, CURRENT_TIMESTAMP)",
+    )
+    .bind(udid)
+    .bind(bundle_id)
+    .execute(&mut *conn)
+    .await
+    .map_err(db_error)?;
+    Ok(())
+}
+
+/// Removes an app from the favorites, returning what's left. Apps that weren't favorites
+/// are ignored.
+pub async fn remove(
+    ip: SecureClientIp,
+    selector: DeviceSelector,
+    Path(bundle_id): Path<String>,
+    State(state): State<JitStreamerState>,
+) -> Json<FavoritesReturn> {
+    info!(
+        "Got request to remove {bundle_id} from the favorites of {:?}",
+        ip.0
+    );
+    let res = match udid(&state, ip, &selector).await {
+        Ok(udid) => {
+            let deleted = state
+                .db_writer
+                .execute(
+                    sqlx::query("DELETE FROM favorites WHERE udid = ? AND bundle_id = ?")
+                        .bind(&udid)
+                        .bind(&bundle_id),
+                )
+                .await
+                .map_err(db_error);
+            match deleted {
+                Ok(_) => listed(&state, &udid).await,
+                Err(e) => Err(e),
+            }
+        }
+        Err(e) => Err(e),
+    };
+    Json(res.unwrap_or_else(FavoritesReturn::fail))
+}
@@ -0,0 +1,250 @@
+// Jackson Coxson
+// Blocks clients for a while when their requests keep failing, like a broken client retrying in a loop
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{Body, HttpBody},
+    extract::{Request, State},
+    http::{
+        header::{CONTENT_TYPE, RETRY_AFTER},
+        HeaderValue,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_client_ip::SecureClientIp;
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::{
+    bans::BanKind,
+    common::{self, DeviceSelector},
+    error::{ErrorCode, JitError},
+    JitStreamerState,
+};
+
+/// Errors are counted per minute
+const WINDOW: Duration = Duration::from_secs(60);
+/// Past this many tracked clients, the ones that aren't blocked and went quiet are forgotten
+const PRUNE_AT: usize = 1024;
+/// Error responses are small, anything bigger isn't read to look for one
+const MAX_ERROR_BODY: usize = 64 * 1024;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Ip(IpAddr),
+    Udid(String),
+}
+
+impl Key {
+    fn kind(&self) -> BanKind {
+        match self {
+            Key::Ip(_) => BanKind::Ip,
+            Key::Udid(_) => BanKind::Udid,
+        }
+    }
+
+    fn value(&self) -> String {
+        match self {
+            Key::Ip(ip) => ip.to_string(),
+            Key::Udid(udid) => udid.clone(),
+        }
+    }
+}
+
+struct Record {
+    window_start: Instant,
+    errors: u32,
+    blocked_until: Option<Instant>,
+}
+
+impl Record {
+    fn blocked_for(&self, now: Instant) -> Option<Duration> {
+        self.blocked_until
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+}
+
+#[derive(Serialize)]
+pub struct Block {
+    pub kind: BanKind,
+    pub value: String,
+    /// Errors in the minute that got it blocked
+    pub errors: u32,
+    pub expires_in_secs: u64,
+}
+
+/// Error counts per client IP and device, and the ones blocked for going over
+#[derive(Clone, Default)]
+pub struct FloodGuard(Arc<Mutex<HashMap<Key, Record>>>);
+
+impl FloodGuard {
+    /// How much longer the longest block on any of the keys lasts
+    async fn blocked(&self, keys: &[Key]) -> Option<Duration> {
+        let records = self.0.lock().await;
+        let now = Instant::now();
+        keys.iter()
+            .filter_map(|k| records.get(k).and_then(|r| r.blocked_for(now)))
+            .max()
+    }
+
+    /// Counts an error against each key, blocking the ones past `limit` this minute
+    async fn failed(&self, keys: &[Key], limit: u32, block: Duration) {
+        let mut records = self.0.lock().await;
+        let now = Instant::now();
+        if records.len() >= PRUNE_AT {
+            records.retain(|_, r| {
+                r.blocked_for(now).is_some() || now.duration_since(r.window_start) < WINDOW
+            });
+        }
+        for key in keys {
+            let record = records.entry(key.clone()).or_insert(Record {
+                window_start: now,
+                errors: 0,
+                blocked_until: None,
+            });
+            if now.duration_since(record.window_start) >= WINDOW {
+                record.window_start = now;
+                record.errors = 0;
+            }
+            record.errors += 1;
+            if record.errors > limit && record.blocked_for(now).is_none() {
+                warn!(
+                    "Blocking {} {} for {}s after {} errors in a minute",
+                    key.kind().as_str(),
+                    key.value(),
+                    block.as_secs(),
+                    record.errors
+                );
+                record.blocked_until = Some(now + block);
+            }
+        }
+    }
+
+    /// Every block that hasn't expired, longest lasting first
+    pub async fn list(&self) -> Vec<Block> {
+        let records = self.0.lock().await;
+        let now = Instant::now();
+        let mut blocks = records
+            .iter()
+            .filter_map(|(key, record)| {
+                record.blocked_for(now).map(|left| Block {
+                    kind: key.kind(),
+                    value: key.value(),
+                    errors: record.errors,
+                    expires_in_secs: left.as_secs() + 1,
+                })
+            })
+            .collect::<Vec<_>>();
+        blocks.sort_by(|a, b| b.expires_in_secs.cmp(&a.expires_in_secs));
+        blocks
+    }
+
+    /// Lifts a block early, forgetting the client's errors. Returns false if it wasn't blocked.
+    pub async fn lift(&self, kind: BanKind, value: &str) -> bool {
+        let key = match kind {
+            BanKind::Ip => match value.parse::<IpAddr>() {
+                Ok(ip) => Key::Ip(ip),
+                Err(_) => return false,
+            },
+            BanKind::Udid => Key::Udid(value.to_string()),
+        };
+        let mut records = self.0.lock().await;
+        match records.remove(&key) {
+            Some(record) if record.blocked_for(Instant::now()).is_some() => {
+                info!("Lifted the block on {} {value}", kind.as_str());
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+fn blocked(left: Duration) -> Response {
+    let retry_after = left.as_secs() + 1;
+    let mut response = JitError::new(
+        ErrorCode::RateLimited,
+        format!("Too many failed requests, try again in {retry_after} seconds"),
+    )
+    .into_response();
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+    response
+}
+
+/// Whether the response is an error, either by status or by a JSON body with `"ok": false`
+async fn is_error(response: Response) -> (Response, bool) {
+    let status = response.status();
+    if status.is_client_error() || status.is_server_error() {
+        return (response, true);
+    }
+    let is_json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|c| c.as_bytes().starts_with(b"application/json"));
+    // Streams and big bodies are left alone, they aren't errors
+    let small = response
+        .body()
+        .size_hint()
+        .upper()
+        .is_some_and(|u| u <= MAX_ERROR_BODY as u64);
+    if !is_json || !small {
+        return (response, false);
+    }
+
+    let (parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, MAX_ERROR_BODY).await {
+        Ok(b) => b,
+        Err(e) => {
+            warn!("Failed to read response body: {e:?}");
+            return (Response::from_parts(parts, Body::empty()), false);
+        }
+    };
+    let failed = serde_json::from_slice::<serde_json::Value>(&bytes)
+        .is_ok_and(|v| v.get("ok") == Some(&serde_json::Value::Bool(false)));
+    (Response::from_parts(parts, Body::from(bytes)), failed)
+}
+
+/// Middleware turning away clients blocked for too many errors, and counting the errors of
+/// the rest against their IP and the device they resolve to. The device headers alone
+/// aren't trusted, or anyone could get another device blocked by naming it.
+pub async fn guard(
+    State(state): State<JitStreamerState>,
+    ip: SecureClientIp,
+    selector: DeviceSelector,
+    request: Request,
+    next: Next,
+) -> Response {
+    let config = state.config();
+    if config.flood_errors_per_minute == 0 {
+        return next.run(request).await;
+    }
+
+    let mut keys = vec![Key::Ip(ip.0)];
+    if let Ok((udid, _)) =
+        common::get_device(&state, ip.0, &selector, config.allow_udid_override).await
+    {
+        keys.push(Key::Udid(udid));
+    }
+
+    if let Some(left) = state.flood_guard.blocked(&keys).await {
+        return blocked(left);
+    }
+    let (response, failed) = is_error(next.run(request).await).await;
+    if failed {
+        state
+            .flood_guard
+            .failed(&keys, config.flood_errors_per_minute, config.flood_block)
+            .await;
+    }
+    response
+}
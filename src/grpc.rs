@@ -0,0 +1,423 @@
+// Jackson Coxson
+// The core operations over gRPC on their own port, reusing the REST handlers
+
+use std::{future::Future, net::IpAddr, net::SocketAddr, pin::Pin, time::Instant};
+
+use axum::{
+    body::Body,
+    extract::{Json, Query, State},
+    http::{HeaderMap, StatusCode},
+};
+use axum_client_ip::SecureClientIp;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use futures_util::Stream;
+use tonic::{transport::Server, Code, Request, Response, Status};
+use tracing::info;
+
+use crate::{
+    api_keys::{self, Scope},
+    bans,
+    client::{self, Client},
+    common::{self, DeviceSelector, DEVICE_TOKEN_HEADER},
+    error::{ErrorCode, JitError},
+    jit, launcher, mount, progress,
+    rate_limit::{self, Budget},
+    register, JitStreamerState,
+};
+
+mod pb {
+    tonic::include_proto!("jitstreamer");
+}
+
+use pb::jit_streamer_server::{JitStreamer, JitStreamerServer};
+
+/// Where the error's code is sent, since gRPC status codes are too coarse to branch on
+const ERROR_CODE_KEY: &str = "jitstreamer-error-code";
+/// Where a QUOTA_EXCEEDED error's reset time is sent
+const RESETS_AT_KEY: &str = "jitstreamer-resets-at";
+
+/// Serves the gRPC service until shutdown resolves
+pub async fn serve(
+    addr: SocketAddr,
+    state: JitStreamerState,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> std::io::Result<()> {
+    Server::builder()
+        .add_service(JitStreamerServer::new(Service { state }))
+        .serve_with_shutdown(addr, shutdown)
+        .await
+        .map_err(std::io::Error::other)
+}
+
+struct Service {
+    state: JitStreamerState,
+}
+
+/// The status for a failed call, with the error's code in the metadata
+fn status(error: JitError) -> Status {
+    let code = match error.code {
+        ErrorCode::NotRegistered
+        | ErrorCode::ProcessNotFound
+        | ErrorCode::AppNotFound
+        | ErrorCode::NoDebuggableApps => Code::NotFound,
+        ErrorCode::Forbidden | ErrorCode::Banned => Code::PermissionDenied,
+        ErrorCode::RateLimited
+        | ErrorCode::Busy
+        | ErrorCode::ServerFull
+        | ErrorCode::QuotaExceeded => Code::ResourceExhausted,
+        ErrorCode::DeviceUnreachable
+        | ErrorCode::VpnNoHandshake
+        | ErrorCode::MuxerUnavailable
+        | ErrorCode::Degraded => Code::Unavailable,
+        ErrorCode::DeviceAmbiguous
+        | ErrorCode::PairingMissing
+        | ErrorCode::PairingInvalid
+        | ErrorCode::DdiNotMounted
+        | ErrorCode::UnsupportedDevice
+        | ErrorCode::DeviceLocked
+        | ErrorCode::DeveloperModeDisabled => Code::FailedPrecondition,
+        ErrorCode::BadRequest => Code::InvalidArgument,
+        ErrorCode::DeviceTimeout => Code::DeadlineExceeded,
+        ErrorCode::DdiMountFailed
+        | ErrorCode::ServiceFailed
+        | ErrorCode::TunnelFailed
+        | ErrorCode::LaunchFailed
+        | ErrorCode::AttachFailed => Code::Aborted,
+        ErrorCode::Internal => Code::Internal,
+    };
+    let mut status = Status::new(code, error.message);
+    if let Some(value) = serde_json::to_value(error.code)
+        .ok()
+        .and_then(|v| v.as_str().and_then(|v| v.parse().ok()))
+    {
+        status.metadata_mut().insert(ERROR_CODE_KEY, value);
+    }
+    if let Some(value) = error.resets_at.and_then(|r| r.parse().ok()) {
+        status.metadata_mut().insert(RESETS_AT_KEY, value);
+    }
+    status
+}
+
+fn selector(device: Option<pb::Device>) -> DeviceSelector {
+    let device = device.unwrap_or_default();
+    DeviceSelector {
+        token: device.token,
+        device: device.device,
+        udid: device.udid,
+    }
+}
+
+/// The caller's address. There's no proxy in front of gRPC, so it's the peer's.
+fn peer<T>(request: &Request<T>) -> Result<IpAddr, Status> {
+    request
+        .remote_addr()
+        .map(|a| a.ip().to_canonical())
+        .ok_or_else(|| Status::internal("no peer address"))
+}
+
+/// The API key sent in the `authorization` metadata as a bearer token
+fn api_key<T>(request: &Request<T>) -> Option<String> {
+    request
+        .metadata()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+impl Service {
+    /// Applies the same allowlist, API keys, bans and rate limits as the REST routes
+    async fn admit(
+        &self,
+        ip: IpAddr,
+        key: Option<&str>,
+        selector: &DeviceSelector,
+        register: bool,
+        budget: Option<Budget>,
+        what: &str,
+    ) -> Result<(), Status> {
+        let config = self.state.config();
+        let served = match register {
+            true => config.role.registers(),
+            false => config.role.serves_devices(),
+        };
+        if !served {
+            return Err(status(JitError::new(
+                ErrorCode::Forbidden,
+                format!(
+                    "This server's role is {}, it doesn't serve {what}",
+                    config.role
+                ),
+            )));
+        }
+        let allowlist = match register {
+            true => &config.register_allowlist,
+            false => &config.device_allowlist,
+        };
+        if !allowlist.allows(ip) {
+            info!("Rejecting {ip} for {what}");
+            return Err(status(JitError::new(ErrorCode::Forbidden, "forbidden")));
+        }
+        if config.require_api_key {
+            api_keys::check(&self.state, key, Scope::Launch, what)
+                .await
+                .map_err(|r| status(r.error()))?;
+        }
+        bans::check(&self.state, ip, selector, what)
+            .await
+            .map_err(status)?;
+        if let Some(budget) = budget {
+            rate_limit::check(&self.state, budget, ip, what)
+                .await
+                .map_err(|(e, _)| status(e))?;
+        }
+        Ok(())
+    }
+}
+
+fn launch_event(event: progress::LaunchEvent, started: Instant) -> pb::LaunchEvent {
+    let mut reply = pb::LaunchEvent {
+        phase: event.phase().to_string(),
+        elapsed_ms: started.elapsed().as_millis() as u64,
+        ..Default::default()
+    };
+    match event {
+        progress::LaunchEvent::Nudge { woke } => reply.woke = Some(woke),
+        progress::LaunchEvent::Heartbeat { reused, woke } => {
+            reply.reused = Some(reused);
+            reply.woke = Some(woke);
+        }
+        progress::LaunchEvent::Xpc { cached } => reply.cached = Some(cached),
+        progress::LaunchEvent::Launched { pid }
+        | progress::LaunchEvent::Attached { pid }
+        | progress::LaunchEvent::Detached { pid }
+        | progress::LaunchEvent::Done { pid } => reply.pid = Some(pid),
+        progress::LaunchEvent::Retrying { attempt, error } => {
+            reply.attempt = Some(attempt);
+            reply.error = Some(error);
+        }
+        progress::LaunchEvent::Tunnel | progress::LaunchEvent::Error { .. } => {}
+    }
+    reply
+}
+
+#[tonic::async_trait]
+impl JitStreamer for Service {
+    async fn get_apps(
+        &self,
+        request: Request<pb::GetAppsRequest>,
+    ) -> Result<Response<pb::GetAppsReply>, Status> {
+        let ip = peer(&request)?;
+        let key = api_key(&request);
+        let request = request.into_inner();
+        let selector = selector(request.device);
+        self.admit(
+            ip,
+            key.as_deref(),
+            &selector,
+            false,
+            Some(Budget::GetApps),
+            "GetApps",
+        )
+        .await?;
+        let options = crate::GetAppsOptions {
+            icons: request.icons,
+            refresh: request.refresh,
+            system: request.system,
+            all: request.all,
+        };
+
+        let Json(res) = crate::get_apps(
+            SecureClientIp(ip),
+            selector,
+            Query(options),
+            State(self.state.clone()),
+        )
+        .await;
+        if let Some(error) = res.error {
+            return Err(status(error));
+        }
+        let mut icons = res.icons.unwrap_or_default();
+        let apps = res
+            .details
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(bundle_id, details)| pb::App {
+                icon: icons
+                    .remove(&bundle_id)
+                    .and_then(|i| BASE64_STANDARD.decode(i).ok()),
+                bundle_id,
+                name: details.name,
+                version: details.version,
+                executable: details.executable,
+                is_debuggable: details.is_debuggable,
+            })
+            .collect();
+        Ok(Response::new(pb::GetAppsReply { apps }))
+    }
+
+    type LaunchStream = Pin<Box<dyn Stream<Item = Result<pb::LaunchEvent, Status>> + Send>>;
+
+    async fn launch(
+        &self,
+        request: Request<pb::LaunchRequest>,
+    ) -> Result<Response<Self::LaunchStream>, Status> {
+        let ip = peer(&request)?;
+        let key = api_key(&request);
+        let client = Client::new(
+            request
+                .metadata()
+                .get(client::CLIENT_HEADER)
+                .and_then(|v| v.to_str().ok()),
+        );
+        let request = request.into_inner();
+        let selector = selector(request.device.clone());
+        self.admit(
+            ip,
+            key.as_deref(),
+            &selector,
+            false,
+            Some(Budget::Launch),
+            "Launch",
+        )
+        .await?;
+        let options = launcher::LaunchOptions {
+            provider: launcher::LaunchProvider::Instruments,
+            mode: match request.mode() {
+                pb::LaunchMode::Jit => launcher::LaunchMode::Jit,
+                pb::LaunchMode::Open => launcher::LaunchMode::Open,
+                pb::LaunchMode::Continue => launcher::LaunchMode::Continue,
+            },
+            process: launcher::ProcessOptions {
+                args: request.args,
+                env: request.env,
+                start_suspended: request.start_suspended,
+                disable_memory_limit: request.disable_memory_limit,
+            },
+        };
+
+        let state = self.state.clone();
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        // Keep launching if the client goes away, the app would be left suspended otherwise
+        tokio::spawn(async move {
+            let started = Instant::now();
+            let (progress, mut events) = progress::Progress::channel();
+            let session = jit::JitSession::new(&state, ip, &selector)
+                .client(client.name())
+                .progress(&progress);
+            let launch = session.launch(request.bundle_id, options);
+            tokio::pin!(launch);
+
+            let res = loop {
+                tokio::select! {
+                    res = &mut launch => break res,
+                    Some(event) = events.recv() => {
+                        sender.send(Ok(launch_event(event, started))).ok();
+                    }
+                }
+            };
+            while let Ok(event) = events.try_recv() {
+                sender.send(Ok(launch_event(event, started))).ok();
+            }
+            if let Err(error) = res {
+                sender.send(Err(status(error))).ok();
+            }
+        });
+
+        let stream = futures_util::stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|item| (item, receiver))
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn attach(
+        &self,
+        request: Request<pb::AttachRequest>,
+    ) -> Result<Response<pb::AttachReply>, Status> {
+        let ip = peer(&request)?;
+        let key = api_key(&request);
+        let request = request.into_inner();
+        let selector = selector(request.device);
+        self.admit(ip, key.as_deref(), &selector, false, None, "Attach")
+            .await?;
+        let pid = u16::try_from(request.pid)
+            .map_err(|_| Status::invalid_argument("pid is out of range"))?;
+
+        jit::JitSession::new(&self.state, ip, &selector)
+            .attach(pid as u64)
+            .await
+            .map_err(status)?;
+        Ok(Response::new(pb::AttachReply {
+            message: String::new(),
+        }))
+    }
+
+    async fn mount_status(
+        &self,
+        request: Request<pb::MountStatusRequest>,
+    ) -> Result<Response<pb::MountStatusReply>, Status> {
+        let ip = peer(&request)?;
+        let key = api_key(&request);
+        let selector = selector(request.into_inner().device);
+        self.admit(ip, key.as_deref(), &selector, false, None, "MountStatus")
+            .await?;
+
+        let udid = common::get_udid(&self.state, ip.to_string(), &selector)
+            .await
+            .map_err(status)?;
+        let mounting = mount::start_mount(&self.state, &udid, ip)
+            .await
+            .map_err(status)?;
+        Ok(Response::new(pb::MountStatusReply { mounting }))
+    }
+
+    async fn register(
+        &self,
+        request: Request<pb::RegisterRequest>,
+    ) -> Result<Response<pb::RegisterReply>, Status> {
+        let ip = peer(&request)?;
+        let key = api_key(&request);
+        let request = request.into_inner();
+        self.admit(
+            ip,
+            key.as_deref(),
+            &DeviceSelector::default(),
+            true,
+            Some(Budget::Register),
+            "Register",
+        )
+        .await?;
+        let options = register::RegisterOptions {
+            qr: false,
+            mobileconfig: false,
+            invite: request.invite,
+        };
+
+        let (headers, body) = register::register(
+            SecureClientIp(ip),
+            Query(options),
+            State(self.state.clone()),
+            HeaderMap::new(),
+            Body::from(request.pairing_file),
+        )
+        .await
+        .map_err(|e| {
+            let (code, message) = e.into_text();
+            let code = match code {
+                StatusCode::BAD_REQUEST | StatusCode::PAYLOAD_TOO_LARGE => Code::InvalidArgument,
+                StatusCode::FORBIDDEN => Code::PermissionDenied,
+                StatusCode::SERVICE_UNAVAILABLE => Code::ResourceExhausted,
+                _ => Code::Internal,
+            };
+            Status::new(code, message)
+        })?;
+        Ok(Response::new(pb::RegisterReply {
+            config: String::from_utf8_lossy(&body).into_owned(),
+            token: headers
+                .get(DEVICE_TOKEN_HEADER)
+                .and_then(|t| t.to_str().ok())
+                .map(|t| t.to_string()),
+        }))
+    }
+}
@@ -0,0 +1,136 @@
+// Jackson Coxson
+// Deep health check for load balancers and monitoring
+
+use axum::{extract::State, http::StatusCode, Json};
+use serde::Serialize;
+use sqlx::Connection;
+use tracing::warn;
+
+use crate::{
+    config::Config,
+    dependencies::{self, Dependency},
+    JitStreamerState,
+};
+
+#[derive(Serialize)]
+pub struct ComponentHealth {
+    name: &'static str,
+    ok: bool,
+    /// The component isn't used with the current configuration
+    skipped: bool,
+    error: Option<String>,
+    /// What's turned off until the component is back, when the server is running without it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disabled: Option<&'static str>,
+}
+
+impl ComponentHealth {
+    fn check(name: &'static str, res: Result<(), String>) -> Self {
+        if let Err(e) = &res {
+            warn!("Health check for {name} failed: {e}");
+        }
+        Self {
+            name,
+            ok: res.is_ok(),
+            skipped: false,
+            error: res.err(),
+            disabled: None,
+        }
+    }
+
+    fn skipped(name: &'static str) -> Self {
+        Self {
+            name,
+            ok: true,
+            skipped: true,
+            error: None,
+            disabled: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct HealthReturn {
+    ok: bool,
+    /// Some features are off because a component is missing, see `disabled`
+    degraded: bool,
+    components: Vec<ComponentHealth>,
+}
+
+/// Checks a dependency the configuration uses, turning its features back on if it's there
+/// again, or off if it's gone
+async fn dependency(state: &JitStreamerState, dependency: Dependency) -> ComponentHealth {
+    let config = state.config();
+    if !dependency.configured(&config) {
+        return ComponentHealth::skipped(dependency.as_str());
+    }
+    let res = dependency.check(&config).await;
+    dependencies::record(state, dependency, &res).await;
+    ComponentHealth {
+        disabled: res.is_err().then_some(dependency.disables()),
+        ..ComponentHealth::check(dependency.as_str(), res)
+    }
+}
+
+/// Checks every component the server depends on. Responds with 503 if any failed.
+///
+/// Devices are reached directly over TCP, so netmuxd and tunneld are only checked when
+/// USB_DEVICES is on or TUNNEL_PROVIDER uses tunneld. The server runs without them and
+/// WireGuard, with the features that need them off.
+pub async fn healthz(State(state): State<JitStreamerState>) -> (StatusCode, Json<HealthReturn>) {
+    let mut components = vec![
+        ComponentHealth::check("database", database_writable(&state).await),
+        ComponentHealth::check(
+            "heartbeat_manager",
+            match state.heartbeats.is_closed() {
+                true => Err("heartbeat manager has stopped".to_string()),
+                false => Ok(()),
+            },
+        ),
+    ];
+    components.push(dependency(&state, Dependency::Wireguard).await);
+    components.push(match state.cluster.ping().await {
+        Some(res) => ComponentHealth::check("redis", res),
+        None => ComponentHealth::skipped("redis"),
+    });
+    components.push(dependency(&state, Dependency::Usbmuxd).await);
+    components.push(dependency(&state, Dependency::Tunneld).await);
+
+    let ok = components.iter().all(|c| c.ok);
+    let degraded = components.iter().any(|c| c.disabled.is_some());
+    let status = match ok {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (
+        status,
+        Json(HealthReturn {
+            ok,
+            degraded,
+            components,
+        }),
+    )
+}
+
+/// Takes the writer's connection and the write lock without changing anything
+pub async fn database_writable(state: &JitStreamerState) -> Result<(), String> {
+    let mut conn = state.db_writer.acquire().await.map_err(|e| e.to_string())?;
+    let mut tx = conn.begin().await.map_err(|e| e.to_string())?;
+    sqlx::query("UPDATE schema_version SET version = version")
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+    tx.rollback().await.map_err(|e| e.to_string())
+}
+
+/// Whether every Wireguard interface is up
+pub async fn wireguard_up(config: &Config) -> Result<(), String> {
+    for name in config.wireguard.iter().map(|w| &w.config_name) {
+        match tokio::fs::try_exists(format!("/sys/class/net/{name}")).await {
+            Ok(true) => {}
+            Ok(false) => return Err(format!("interface {name} is down")),
+            Err(e) => return Err(format!("failed to check interface {name}: {e}")),
+        }
+    }
+    Ok(())
+}
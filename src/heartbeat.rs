@@ -1,48 +1,510 @@
 // Jackson Coxson
 // Orchestrator for heartbeat threads
 
-use std::{collections::HashMap, net::IpAddr};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use idevice::{
     heartbeat::HeartbeatClient, pairing_file::PairingFile, provider::TcpProvider, IdeviceError,
     IdeviceService,
 };
-use log::debug;
-use tokio::sync::oneshot::error::TryRecvError;
+use serde::Serialize;
+use tokio::sync::{mpsc, oneshot, oneshot::error::TryRecvError};
+use tracing::{debug, info, warn};
+
+use crate::{
+    cluster::{Cluster, HeartbeatOp},
+    error::{ErrorCode, JitError},
+};
+
+const MAX_RECONNECT_ATTEMPTS: u32 = 5;
+/// How much longer than the interval the device asks for a marco is waited for, at first
+const BASE_SLACK: Duration = Duration::from_secs(5);
+/// The most the slack grows to for a device that keeps dropping
+const MAX_SLACK: Duration = Duration::from_secs(60);
+/// Heartbeats in a row without a drop before the slack is halved again
+const STEADY_BEATS: u32 = 20;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "state", content = "detail", rename_all = "snake_case")]
+pub enum HeartbeatStatus {
+    Alive,
+    /// The connection dropped and is being re-established, with the attempt number
+    Reconnecting(u32),
+    /// The device could not be reached again
+    Failed(String),
+}
+
+pub struct HeartbeatHandle {
+    pub kill: tokio::sync::oneshot::Sender<()>,
+    pub status: tokio::sync::watch::Receiver<HeartbeatStatus>,
+}
+
+/// Why the manager couldn't do what it was asked
+#[derive(Debug)]
+pub enum HeartbeatError {
+    /// The device couldn't be connected to
+    Connect(IdeviceError),
+    /// The manager's task has stopped, so no heartbeat can be kept
+    Stopped,
+    /// The heartbeat ended before the manager took it
+    Dead,
+}
+
+impl Display for HeartbeatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HeartbeatError::Connect(e) => write!(f, "Failed to heartbeat device: {e}"),
+            HeartbeatError::Stopped => f.write_str("The heartbeat manager has stopped"),
+            HeartbeatError::Dead => f.write_str("The device dropped the heartbeat right away"),
+        }
+    }
+}
+
+impl From<HeartbeatError> for JitError {
+    fn from(e: HeartbeatError) -> Self {
+        match e {
+            HeartbeatError::Stopped => JitError::internal(format!("{e}, restart the server")),
+            HeartbeatError::Connect(_) | HeartbeatError::Dead => {
+                JitError::new(ErrorCode::DeviceUnreachable, e.to_string())
+            }
+        }
+    }
+}
+
+type Reply<T> = oneshot::Sender<T>;
+
+/// Every request is answered, so the caller knows it was applied
+enum Request {
+    Store(String, HeartbeatHandle, Reply<Result<(), HeartbeatError>>),
+    /// Replies whether there was a heartbeat
+    Kill(String, Reply<bool>),
+    /// Kills the heartbeat because another node in the cluster took the device, without
+    /// telling the cluster again
+    Evict(String, Reply<bool>),
+    /// Keep the heartbeat alive for the grace period, then kill it
+    Release(String, Reply<bool>),
+    /// Claims a live heartbeat if one exists, cancelling its expiry
+    Reuse(String, Reply<bool>),
+    /// Gets the status of the device's heartbeat, if it has one
+    Status(String, Reply<Option<HeartbeatStatus>>),
+    /// Lists every heartbeat the manager holds
+    List(Reply<Vec<HeartbeatSummary>>),
+    /// Kills every heartbeat, replying with how many there were
+    KillAll(Reply<usize>),
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct HeartbeatSummary {
+    pub udid: String,
+    pub status: HeartbeatStatus,
+    pub age_secs: u64,
+    /// Seconds until a released heartbeat is killed, none while a request is using it
+    pub expires_in_secs: Option<u64>,
+}
+
+/// Handle to the manager's task, which owns every heartbeat
+#[derive(Clone)]
+pub struct HeartbeatManager {
+    sender: mpsc::Sender<Request>,
+    config: HeartbeatConfig,
+    tuning: Tuning,
+}
+
+#[derive(Clone, Copy)]
+struct DeviceTuning {
+    slack: Duration,
+    /// Heartbeats since the last drop
+    beats: u32,
+}
+
+impl Default for DeviceTuning {
+    fn default() -> Self {
+        Self {
+            slack: BASE_SLACK,
+            beats: 0,
+        }
+    }
+}
+
+/// How long past its interval each device's marco is waited for. It doubles whenever the
+/// device drops, so devices on flaky Wi-Fi get more leeway, and halves once it's steady.
+/// Kept across heartbeats, so the next one to the device starts with what was learnt.
+/// Only devices past BASE_SLACK have an entry, so the map doesn't grow with every device.
+#[derive(Clone, Default)]
+struct Tuning(Arc<Mutex<HashMap<String, DeviceTuning>>>);
+
+impl Tuning {
+    fn slack(&self, udid: &str) -> Duration {
+        let tuning = self.0.lock().unwrap();
+        tuning.get(udid).copied().unwrap_or_default().slack
+    }
+
+    fn beat(&self, udid: &str) {
+        let mut tuning = self.0.lock().unwrap();
+        let Some(device) = tuning.get_mut(udid) else {
+            return;
+        };
+        device.beats += 1;
+        if device.beats >= STEADY_BEATS {
+            device.slack = (device.slack / 2).max(BASE_SLACK);
+            device.beats = 0;
+            debug!(
+                "Heartbeat for {udid} is steady, waiting {:?} past its interval",
+                device.slack
+            );
+            if device.slack == BASE_SLACK {
+                tuning.remove(udid);
+            }
+        }
+    }
+
+    fn dropped(&self, udid: &str) {
+        let mut tuning = self.0.lock().unwrap();
+        let device = tuning.entry(udid.to_string()).or_default();
+        device.slack = (device.slack * 2).min(MAX_SLACK);
+        device.beats = 0;
+        debug!(
+            "Heartbeat for {udid} dropped, waiting {:?} past its interval",
+            device.slack
+        );
+    }
+
+    fn forget(&self, udid: &str) {
+        self.0.lock().unwrap().remove(udid);
+    }
+}
 
-pub enum SendRequest {
-    Store((String, tokio::sync::oneshot::Sender<()>)),
-    Kill(String),
+impl HeartbeatManager {
+    async fn request<T>(
+        &self,
+        request: impl FnOnce(Reply<T>) -> Request,
+    ) -> Result<T, HeartbeatError> {
+        let (reply, res) = oneshot::channel();
+        self.sender
+            .send(request(reply))
+            .await
+            .map_err(|_| HeartbeatError::Stopped)?;
+        res.await.map_err(|_| HeartbeatError::Stopped)
+    }
+
+    /// Hands a new heartbeat to the manager, replacing the device's old one
+    pub async fn store(&self, udid: &str, handle: HeartbeatHandle) -> Result<(), HeartbeatError> {
+        self.request(|r| Request::Store(udid.to_string(), handle, r))
+            .await?
+    }
+
+    /// Kills the device's heartbeat, returning whether it had one
+    pub async fn kill(&self, udid: &str) -> Result<bool, HeartbeatError> {
+        self.request(|r| Request::Kill(udid.to_string(), r)).await
+    }
+
+    /// Kills the device's heartbeat because another node in the cluster took the device
+    pub async fn evict(&self, udid: &str) -> Result<bool, HeartbeatError> {
+        self.request(|r| Request::Evict(udid.to_string(), r)).await
+    }
+
+    /// Keeps the device's heartbeat alive for the grace period, then kills it
+    pub async fn release(&self, udid: &str) -> Result<bool, HeartbeatError> {
+        self.request(|r| Request::Release(udid.to_string(), r))
+            .await
+    }
+
+    /// Claims the device's heartbeat if it's alive, cancelling its expiry
+    pub async fn reuse(&self, udid: &str) -> Result<bool, HeartbeatError> {
+        self.request(|r| Request::Reuse(udid.to_string(), r)).await
+    }
+
+    /// Gets the status of the device's heartbeat, if it has one
+    pub async fn status(&self, udid: &str) -> Result<Option<HeartbeatStatus>, HeartbeatError> {
+        self.request(|r| Request::Status(udid.to_string(), r)).await
+    }
+
+    /// Lists every heartbeat the manager holds
+    pub async fn list(&self) -> Result<Vec<HeartbeatSummary>, HeartbeatError> {
+        self.request(Request::List).await
+    }
+
+    /// Kills every heartbeat, returning how many were alive
+    pub async fn kill_all(&self) -> Result<usize, HeartbeatError> {
+        self.request(Request::KillAll).await
+    }
+
+    /// Forgets how much slack the device's heartbeats needed, once it's unregistered
+    pub fn forget(&self, udid: &str) {
+        self.tuning.forget(udid);
+    }
+
+    /// The manager's task has stopped
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
 }
-pub type NewHeartbeatSender = tokio::sync::mpsc::Sender<SendRequest>;
 
-pub fn heartbeat() -> NewHeartbeatSender {
-    let (sender, mut receiver) = tokio::sync::mpsc::channel::<SendRequest>(100);
+#[derive(Clone, Debug, PartialEq)]
+pub struct HeartbeatConfig {
+    /// How long a released heartbeat stays alive for reuse
+    pub grace_period: Duration,
+    /// Maximum number of heartbeats alive at once, the least recently used is evicted
+    pub max_heartbeats: usize,
+    /// Hard limit on how long a heartbeat can live, in case a handler never kills it
+    pub max_lifetime: Duration,
+    /// How long the device's first marco is waited for. After that, it's the interval the
+    /// device asks for, with some slack that grows for devices that drop.
+    pub interval: Duration,
+    /// How long answering a marco with a polo may take before the connection counts as dropped
+    pub polo_timeout: Duration,
+}
+
+struct Heartbeat {
+    handle: tokio::sync::oneshot::Sender<()>,
+    status: tokio::sync::watch::Receiver<HeartbeatStatus>,
+    expires: Option<Instant>,
+    last_used: Instant,
+    started: Instant,
+}
+
+/// Starts the manager. Stores and kills are published to the rest of the cluster, so no
+/// other node keeps a heartbeat to the same device.
+pub fn heartbeat(config: HeartbeatConfig, cluster: Cluster) -> HeartbeatManager {
+    let (sender, mut receiver) = mpsc::channel::<Request>(100);
+    let manager = HeartbeatManager {
+        sender,
+        config: config.clone(),
+        tuning: Tuning::default(),
+    };
     tokio::task::spawn(async move {
-        let mut cache: HashMap<String, tokio::sync::oneshot::Sender<()>> = HashMap::new();
-        while let Some(msg) = receiver.recv().await {
-            match msg {
-                SendRequest::Store((udid, handle)) => {
-                    if let Some(old_sender) = cache.insert(udid, handle) {
-                        old_sender.send(()).ok();
+        let mut cache: HashMap<String, Heartbeat> = HashMap::new();
+        let mut evictions: u64 = 0;
+        let mut expiry_interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                msg = receiver.recv() => {
+                    let msg = match msg {
+                        Some(m) => m,
+                        None => break,
+                    };
+                    match msg {
+                        Request::Store(udid, handle, res) => {
+                            // The receiver is dropped once the heartbeat task exits
+                            if handle.kill.is_closed() {
+                                res.send(Err(HeartbeatError::Dead)).ok();
+                                continue;
+                            }
+                            cluster.publish(HeartbeatOp::Store, &udid);
+                            if !cache.contains_key(&udid) && cache.len() >= config.max_heartbeats {
+                                let lru = cache
+                                    .iter()
+                                    .min_by_key(|(_, h)| h.last_used)
+                                    .map(|(u, _)| u.clone());
+                                if let Some(lru) = lru {
+                                    evictions += 1;
+                                    warn!(
+                                        "Heartbeat limit of {} reached, evicting {lru} ({evictions} evictions total)",
+                                        config.max_heartbeats
+                                    );
+                                    if let Some(old) = cache.remove(&lru) {
+                                        old.handle.send(()).ok();
+                                    }
+                                }
+                            }
+                            if let Some(old) = cache.insert(
+                                udid,
+                                Heartbeat {
+                                    handle: handle.kill,
+                                    status: handle.status,
+                                    expires: None,
+                                    last_used: Instant::now(),
+                                    started: Instant::now(),
+                                },
+                            ) {
+                                old.handle.send(()).ok();
+                            }
+                            res.send(Ok(())).ok();
+                        }
+                        Request::Kill(udid, res) => {
+                            cluster.publish(HeartbeatOp::Kill, &udid);
+                            let old = cache.remove(&udid);
+                            res.send(old.is_some()).ok();
+                            if let Some(old) = old {
+                                old.handle.send(()).ok();
+                            }
+                        }
+                        Request::Evict(udid, res) => {
+                            let old = cache.remove(&udid);
+                            res.send(old.is_some()).ok();
+                            if let Some(old) = old {
+                                debug!("Another node took {udid}, killing its heartbeat");
+                                old.handle.send(()).ok();
+                            }
+                        }
+                        Request::Release(udid, res) => {
+                            let held = match cache.get_mut(&udid) {
+                                Some(h) => {
+                                    h.expires = Some(Instant::now() + config.grace_period);
+                                    h.last_used = Instant::now();
+                                    true
+                                }
+                                None => false,
+                            };
+                            res.send(held).ok();
+                        }
+                        Request::Reuse(udid, res) => {
+                            let alive = match cache.get_mut(&udid) {
+                                // The receiver is dropped once the heartbeat task exits
+                                Some(h)
+                                    if !h.handle.is_closed()
+                                        && *h.status.borrow() == HeartbeatStatus::Alive =>
+                                {
+                                    h.expires = None;
+                                    h.last_used = Instant::now();
+                                    true
+                                }
+                                Some(_) => {
+                                    cache.remove(&udid);
+                                    false
+                                }
+                                None => false,
+                            };
+                            res.send(alive).ok();
+                        }
+                        Request::Status(udid, res) => {
+                            res.send(cache.get(&udid).map(|h| h.status.borrow().clone()))
+                                .ok();
+                        }
+                        Request::KillAll(res) => {
+                            let count = cache.len();
+                            for (_, old) in cache.drain() {
+                                old.handle.send(()).ok();
+                            }
+                            res.send(count).ok();
+                        }
+                        Request::List(res) => {
+                            let now = Instant::now();
+                            res.send(
+                                cache
+                                    .iter()
+                                    .map(|(udid, h)| HeartbeatSummary {
+                                        udid: udid.clone(),
+                                        status: h.status.borrow().clone(),
+                                        age_secs: now.duration_since(h.started).as_secs(),
+                                        expires_in_secs: h
+                                            .expires
+                                            .map(|e| e.saturating_duration_since(now).as_secs()),
+                                    })
+                                    .collect(),
+                            )
+                            .ok();
+                        }
                     }
                 }
-                SendRequest::Kill(udid) => {
-                    if let Some(old_sender) = cache.remove(&udid) {
-                        old_sender.send(()).ok();
+                _ = expiry_interval.tick() => {
+                    let now = Instant::now();
+                    // Failed heartbeats are kept until they're released and expire, so
+                    // handlers can still tell why the device was lost
+                    let expired = cache
+                        .iter()
+                        .filter(|(_, h)| {
+                            (h.handle.is_closed()
+                                && !matches!(*h.status.borrow(), HeartbeatStatus::Failed(_)))
+                                || h.expires.map(|e| e <= now).unwrap_or(false)
+                                || now.duration_since(h.started) >= config.max_lifetime
+                        })
+                        .map(|(udid, _)| udid.clone())
+                        .collect::<Vec<String>>();
+                    for udid in expired {
+                        debug!("Heartbeat for {udid} expired");
+                        if let Some(old) = cache.remove(&udid) {
+                            old.handle.send(()).ok();
+                        }
                     }
                 }
             }
         }
     });
-    sender
+    manager
+}
+
+/// How long to wait for the first heartbeat connection before trying to wake the device
+const INITIAL_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HeartbeatStart {
+    /// An existing heartbeat was reused
+    pub reused: bool,
+    /// The device didn't answer at first and had to be woken up
+    pub woke: bool,
 }
 
+/// Reuses the device's live heartbeat if there is one, or starts a new one
+#[tracing::instrument(name = "heartbeat", skip(manager, pairing_file))]
+pub async fn ensure_heartbeat(
+    manager: &HeartbeatManager,
+    udid: &str,
+    ip: IpAddr,
+    pairing_file: &PairingFile,
+) -> Result<HeartbeatStart, HeartbeatError> {
+    if manager.reuse(udid).await? {
+        debug!("Reusing heartbeat for {udid}");
+        return Ok(HeartbeatStart {
+            reused: true,
+            woke: false,
+        });
+    }
+
+    let mut woke = false;
+    let s = match tokio::time::timeout(
+        INITIAL_CONNECT_TIMEOUT,
+        heartbeat_thread(manager, udid.to_string(), ip, pairing_file),
+    )
+    .await
+    {
+        Ok(s) => s.map_err(HeartbeatError::Connect)?,
+        Err(_) => {
+            // Sleeping devices frequently drop their VPN until they're nudged
+            info!("Device {udid} didn't answer, attempting to wake it");
+            woke = crate::wake::wake(ip).await;
+            heartbeat_thread(manager, udid.to_string(), ip, pairing_file)
+                .await
+                .map_err(HeartbeatError::Connect)?
+        }
+    };
+    manager.store(udid, s).await?;
+    Ok(HeartbeatStart {
+        reused: false,
+        woke,
+    })
+}
+
+/// Appends the heartbeat's state to an error message if the heartbeat isn't healthy,
+/// so a connection blip shows up as the cause instead of a confusing service error
+pub async fn describe_failure(manager: &HeartbeatManager, udid: &str, error: JitError) -> JitError {
+    match manager.status(udid).await.ok().flatten() {
+        Some(HeartbeatStatus::Reconnecting(attempt)) => JitError::new(
+            ErrorCode::DeviceUnreachable,
+            format!("{error} (device connection dropped, reconnect attempt {attempt})"),
+        ),
+        Some(HeartbeatStatus::Failed(e)) => JitError::new(
+            ErrorCode::DeviceUnreachable,
+            format!("{error} (lost connection to the device: {e})"),
+        ),
+        _ => error,
+    }
+}
+
+/// Connects the heartbeat and keeps answering the device's marcos in the background,
+/// reconnecting when it drops
 pub async fn heartbeat_thread(
+    manager: &HeartbeatManager,
     udid: String,
     ip: IpAddr,
     pairing_file: &PairingFile,
-) -> Result<tokio::sync::oneshot::Sender<()>, IdeviceError> {
+) -> Result<HeartbeatHandle, IdeviceError> {
     debug!("Connecting to device {udid} to get apps");
     let provider = TcpProvider {
         addr: ip,
@@ -53,21 +515,79 @@ pub async fn heartbeat_thread(
     let mut heartbeat_client = HeartbeatClient::connect(&provider).await?;
 
     let (sender, mut receiver) = tokio::sync::oneshot::channel::<()>();
+    let (status_sender, status_receiver) = tokio::sync::watch::channel(HeartbeatStatus::Alive);
 
+    let config = manager.config.clone();
+    let tuning = manager.tuning.clone();
     tokio::task::spawn(async move {
-        let interval = 30;
+        // Until the device says how often it sends a marco
+        let mut interval = config.interval;
+        let mut failures = 0;
         loop {
-            let _ = match heartbeat_client.get_marco(interval).await {
-                Ok(interval) => interval,
+            let wait = interval + tuning.slack(&udid);
+            let res = async {
+                let next = heartbeat_client.get_marco(wait.as_secs()).await?;
+                tokio::time::timeout(config.polo_timeout, heartbeat_client.send_polo())
+                    .await
+                    .map_err(|_| {
+                        IdeviceError::Socket(std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            "the device didn't take the polo in time",
+                        ))
+                    })??;
+                Ok::<_, IdeviceError>(next)
+            }
+            .await;
+
+            match res {
+                Ok(next) => {
+                    if next > 0 {
+                        interval = Duration::from_secs(next);
+                    }
+                    tuning.beat(&udid);
+                    if failures > 0 {
+                        debug!("Heartbeat for {udid} recovered");
+                        failures = 0;
+                        status_sender.send(HeartbeatStatus::Alive).ok();
+                    }
+                }
                 Err(e) => {
-                    debug!("Failed to get marco for {udid}: {e:?}");
-                    break;
+                    debug!("Heartbeat failed for {udid}: {e:?}");
+                    tuning.dropped(&udid);
+                    interval = config.interval;
+                    // Reconnect with exponential backoff
+                    loop {
+                        failures += 1;
+                        if failures > MAX_RECONNECT_ATTEMPTS {
+                            warn!("Giving up on heartbeat for {udid}: {e:?}");
+                            status_sender
+                                .send(HeartbeatStatus::Failed(e.to_string()))
+                                .ok();
+                            return;
+                        }
+                        status_sender
+                            .send(HeartbeatStatus::Reconnecting(failures))
+                            .ok();
+
+                        let backoff = Duration::from_millis(500 * 2u64.pow(failures - 1));
+                        tokio::select! {
+                            _ = tokio::time::sleep(backoff) => {}
+                            _ = &mut receiver => return,
+                        }
+
+                        match HeartbeatClient::connect(&provider).await {
+                            Ok(c) => {
+                                heartbeat_client = c;
+                                break;
+                            }
+                            Err(e) => {
+                                debug!("Failed to reconnect heartbeat for {udid}: {e:?}");
+                            }
+                        }
+                    }
                 }
-            };
-            if heartbeat_client.send_polo().await.is_err() {
-                debug!("Failed to send polo for {udid}");
-                break;
             }
+
             match receiver.try_recv() {
                 Ok(_) => break,
                 Err(TryRecvError::Closed) => break,
@@ -75,5 +595,8 @@ pub async fn heartbeat_thread(
             }
         }
     });
-    Ok(sender)
+    Ok(HeartbeatHandle {
+        kill: sender,
+        status: status_receiver,
+    })
 }
@@ -0,0 +1,60 @@
+// Jackson Coxson
+// Recent launches across all devices, for the admin dashboard
+
+use std::{
+    collections::VecDeque,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+/// How many launches are kept
+const CAPACITY: usize = 100;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct LaunchRecord {
+    /// Unix timestamp of when the launch finished
+    pub at: u64,
+    pub ip: String,
+    pub bundle_id: String,
+    pub ok: bool,
+    pub error: Option<String>,
+    pub duration_ms: u64,
+}
+
+#[derive(Clone, Default)]
+pub struct LaunchHistory(Arc<Mutex<VecDeque<LaunchRecord>>>);
+
+impl LaunchHistory {
+    pub async fn record(
+        &self,
+        ip: IpAddr,
+        bundle_id: String,
+        error: Option<String>,
+        duration: Duration,
+    ) {
+        let mut lock = self.0.lock().await;
+        if lock.len() >= CAPACITY {
+            lock.pop_front();
+        }
+        lock.push_back(LaunchRecord {
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            ip: ip.to_string(),
+            bundle_id,
+            ok: error.is_none(),
+            error,
+            duration_ms: duration.as_millis() as u64,
+        });
+    }
+
+    /// The recorded launches, newest first
+    pub async fn recent(&self) -> Vec<LaunchRecord> {
+        self.0.lock().await.iter().rev().cloned().collect()
+    }
+}
@@ -0,0 +1,314 @@
+// Jackson Coxson
+// Translated error messages, picked from the client's Accept-Language
+
+use std::convert::Infallible;
+
+use axum::{
+    body::Body,
+    extract::{FromRequestParts, Request},
+    http::{
+        header::{ACCEPT_LANGUAGE, CONTENT_LENGTH, CONTENT_TYPE, VARY},
+        request::Parts,
+        HeaderMap, HeaderValue,
+    },
+    middleware::Next,
+    response::Response,
+};
+use tracing::warn;
+
+use crate::error::{ErrorCode, JitError};
+
+/// JSON bodies larger than this are passed through untranslated
+const MAX_JSON_BODY: usize = 1024 * 1024;
+
+/// Languages with translations. English is the untranslated message.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Language {
+    #[default]
+    English,
+    Spanish,
+    Portuguese,
+    French,
+    German,
+    Chinese,
+}
+
+impl Language {
+    fn from_tag(tag: &str) -> Option<Self> {
+        let primary = tag.split(['-', '_']).next()?.trim().to_ascii_lowercase();
+        match primary.as_str() {
+            "en" => Some(Self::English),
+            "es" => Some(Self::Spanish),
+            "pt" => Some(Self::Portuguese),
+            "fr" => Some(Self::French),
+            "de" => Some(Self::German),
+            "zh" => Some(Self::Chinese),
+            _ => None,
+        }
+    }
+
+    /// Picks the supported language the client prefers most, such as `pt-BR,pt;q=0.9,en;q=0.8`
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let header = match headers.get(ACCEPT_LANGUAGE).and_then(|h| h.to_str().ok()) {
+            Some(h) => h,
+            None => return Self::English,
+        };
+        let mut best: Option<(f32, Self)> = None;
+        for entry in header.split(',') {
+            let mut params = entry.split(';');
+            let language = match params.next().and_then(Self::from_tag) {
+                Some(l) => l,
+                None => continue,
+            };
+            let quality = params
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .and_then(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            // Ties go to the earlier entry
+            if quality > 0.0 && best.is_none_or(|(q, _)| quality > q) {
+                best = Some((quality, language));
+            }
+        }
+        best.map(|(_, l)| l).unwrap_or_default()
+    }
+
+    /// The translated explanation of an error code, None for English
+    pub fn message(self, code: ErrorCode) -> Option<&'static str> {
+        use ErrorCode::*;
+        use Language::*;
+        Some(match (self, code) {
+            (English, _) => return None,
+
+            (Spanish, Internal) => "Error interno del servidor. Inténtalo de nuevo más tarde.",
+            (Spanish, NotRegistered) => "Tu dispositivo no está registrado. Regístralo de nuevo.",
+            (Spanish, DeviceAmbiguous) => "Hay varios dispositivos registrados desde tu IP. Elige uno con la cabecera X-JitStreamer-Device.",
+            (Spanish, Forbidden) => "No tienes permiso para hacer esto.",
+            (Spanish, Banned) => "Este dispositivo ha sido bloqueado en este servidor.",
+            (Spanish, RateLimited) => "Demasiadas solicitudes. Espera un momento e inténtalo de nuevo.",
+            (Spanish, Busy) => "El servidor está ocupado. Inténtalo de nuevo en unos segundos.",
+            (Spanish, PairingMissing) => "No se encontró el archivo de emparejamiento. Regístrate de nuevo para subirlo.",
+            (Spanish, PairingInvalid) => "Tu archivo de emparejamiento no es válido. Genéralo de nuevo con jitterbug pair.",
+            (Spanish, DeviceUnreachable) => "No se pudo conectar con el dispositivo. Asegúrate de que esté desbloqueado y conectado a internet.",
+            (Spanish, VpnNoHandshake) => "La VPN no está conectada. Activa el túnel de JitStreamer en la app de WireGuard.",
+            (Spanish, DdiNotMounted) => "La imagen de disco de desarrollador no está montada. Móntala e inténtalo de nuevo.",
+            (Spanish, DdiMountFailed) => "No se pudo montar la imagen de disco de desarrollador.",
+            (Spanish, UnsupportedDevice) => "Este dispositivo no es compatible.",
+            (Spanish, ServiceFailed) => "Un servicio del dispositivo falló. Inténtalo de nuevo.",
+            (Spanish, TunnelFailed) => "No se pudo conectar con los servicios de desarrollador del dispositivo. Inténtalo de nuevo.",
+            (Spanish, LaunchFailed) => "No se pudo abrir la app.",
+            (Spanish, AttachFailed) => "No se pudo conectar el depurador a la app.",
+            (Spanish, ProcessNotFound) => "No hay ningún proceso en ejecución con ese nombre. Abre la app primero.",
+            (Spanish, AppNotFound) => "Ninguna app depurable tiene ese nombre. Revisa la lista de apps.",
+            (Spanish, NoDebuggableApps) => "No hay apps con get-task-allow instaladas.",
+            (Spanish, ServerFull) => "El servidor está lleno y no acepta más dispositivos por ahora.",
+            (Spanish, QuotaExceeded) => "Has alcanzado el límite de lanzamientos de este dispositivo. Inténtalo de nuevo más tarde.",
+            (Spanish, DeviceTimeout) => "El dispositivo dejó de responder. Asegúrate de que esté desbloqueado y conectado, e inténtalo de nuevo.",
+            (Spanish, DeviceLocked) => "Tu dispositivo está bloqueado. Desbloquéalo e inténtalo de nuevo.",
+            (Spanish, DeveloperModeDisabled) => "El modo de desarrollador está desactivado. Actívalo en Ajustes > Privacidad y seguridad > Modo de desarrollador, reinicia el dispositivo e inténtalo de nuevo.",
+            (Spanish, BadRequest) => "La solicitud no es válida. Actualiza la app e inténtalo de nuevo.",
+            (Spanish, MuxerUnavailable) => "El servidor no puede comunicarse con los dispositivos USB en este momento. Inténtalo de nuevo en unos segundos.",
+            (Spanish, Degraded) => "Esta función está desactivada en el servidor por ahora porque falta un componente. Inténtalo de nuevo más tarde.",
+
+            (Portuguese, Internal) => "Erro interno do servidor. Tente novamente mais tarde.",
+            (Portuguese, NotRegistered) => "Seu dispositivo não está registrado. Registre-o novamente.",
+            (Portuguese, DeviceAmbiguous) => "Há vários dispositivos registrados no seu IP. Escolha um com o cabeçalho X-JitStreamer-Device.",
+            (Portuguese, Forbidden) => "Você não tem permissão para fazer isso.",
+            (Portuguese, Banned) => "Este dispositivo foi banido deste servidor.",
+            (Portuguese, RateLimited) => "Muitas solicitações. Aguarde um pouco e tente novamente.",
+            (Portuguese, Busy) => "O servidor está ocupado. Tente novamente em alguns segundos.",
+            (Portuguese, PairingMissing) => "Arquivo de pareamento não encontrado. Registre-se novamente para enviá-lo.",
+            (Portuguese, PairingInvalid) => "Seu arquivo de pareamento é inválido. Gere-o novamente com jitterbug pair.",
+            (Portuguese, DeviceUnreachable) => "Não foi possível conectar ao dispositivo. Verifique se ele está desbloqueado e conectado à internet.",
+            (Portuguese, VpnNoHandshake) => "A VPN não está conectada. Ative o túnel do JitStreamer no app WireGuard.",
+            (Portuguese, DdiNotMounted) => "A imagem de disco de desenvolvedor não está montada. Monte-a e tente novamente.",
+            (Portuguese, DdiMountFailed) => "Não foi possível montar a imagem de disco de desenvolvedor.",
+            (Portuguese, UnsupportedDevice) => "Este dispositivo não é compatível.",
+            (Portuguese, ServiceFailed) => "Um serviço do dispositivo falhou. Tente novamente.",
+            (Portuguese, TunnelFailed) => "Não foi possível conectar aos serviços de desenvolvedor do dispositivo. Tente novamente.",
+            (Portuguese, LaunchFailed) => "Não foi possível abrir o app.",
+            (Portuguese, AttachFailed) => "Não foi possível anexar o depurador ao app.",
+            (Portuguese, ProcessNotFound) => "Nenhum processo em execução tem esse nome. Abra o app primeiro.",
+            (Portuguese, AppNotFound) => "Nenhum app depurável tem esse nome. Confira a lista de apps.",
+            (Portuguese, NoDebuggableApps) => "Nenhum app com get-task-allow está instalado.",
+            (Portuguese, ServerFull) => "O servidor está cheio e não aceita mais dispositivos no momento.",
+            (Portuguese, QuotaExceeded) => "Você atingiu o limite de inicializações deste dispositivo. Tente novamente mais tarde.",
+            (Portuguese, DeviceTimeout) => "O dispositivo parou de responder. Verifique se ele está desbloqueado e conectado e tente novamente.",
+            (Portuguese, DeviceLocked) => "Seu dispositivo está bloqueado. Desbloqueie-o e tente novamente.",
+            (Portuguese, DeveloperModeDisabled) => "O Modo de Desenvolvedor está desativado. Ative-o em Ajustes > Privacidade e Segurança > Modo de Desenvolvedor, reinicie o dispositivo e tente novamente.",
+            (Portuguese, BadRequest) => "A solicitação é inválida. Atualize o app e tente novamente.",
+            (Portuguese, MuxerUnavailable) => "O servidor não consegue se comunicar com dispositivos USB no momento. Tente novamente em alguns segundos.",
+            (Portuguese, Degraded) => "Este recurso está desativado no servidor no momento porque falta um componente. Tente novamente mais tarde.",
+
+            (French, Internal) => "Erreur interne du serveur. Réessayez plus tard.",
+            (French, NotRegistered) => "Votre appareil n'est pas enregistré. Enregistrez-le à nouveau.",
+            (French, DeviceAmbiguous) => "Plusieurs appareils sont enregistrés depuis votre IP. Choisissez-en un avec l'en-tête X-JitStreamer-Device.",
+            (French, Forbidden) => "Vous n'êtes pas autorisé à faire ceci.",
+            (French, Banned) => "Cet appareil a été banni de ce serveur.",
+            (French, RateLimited) => "Trop de requêtes. Patientez un instant puis réessayez.",
+            (French, Busy) => "Le serveur est occupé. Réessayez dans quelques secondes.",
+            (French, PairingMissing) => "Fichier d'appairage introuvable. Enregistrez-vous à nouveau pour l'envoyer.",
+            (French, PairingInvalid) => "Votre fichier d'appairage est invalide. Régénérez-le avec jitterbug pair.",
+            (French, DeviceUnreachable) => "Impossible de joindre l'appareil. Vérifiez qu'il est déverrouillé et connecté à internet.",
+            (French, VpnNoHandshake) => "Le VPN n'est pas connecté. Activez le tunnel JitStreamer dans l'app WireGuard.",
+            (French, DdiNotMounted) => "L'image disque développeur n'est pas montée. Montez-la puis réessayez.",
+            (French, DdiMountFailed) => "Impossible de monter l'image disque développeur.",
+            (French, UnsupportedDevice) => "Cet appareil n'est pas pris en charge.",
+            (French, ServiceFailed) => "Un service de l'appareil a échoué. Réessayez.",
+            (French, TunnelFailed) => "Impossible de se connecter aux services développeur de l'appareil. Réessayez.",
+            (French, LaunchFailed) => "Impossible de lancer l'app.",
+            (French, AttachFailed) => "Impossible d'attacher le débogueur à l'app.",
+            (French, ProcessNotFound) => "Aucun processus en cours ne porte ce nom. Ouvrez d'abord l'app.",
+            (French, AppNotFound) => "Aucune app débogable ne porte ce nom. Vérifiez la liste des apps.",
+            (French, NoDebuggableApps) => "Aucune app avec get-task-allow n'est installée.",
+            (French, ServerFull) => "Le serveur est plein et n'accepte plus d'appareils pour le moment.",
+            (French, QuotaExceeded) => "Vous avez atteint la limite de lancements pour cet appareil. Réessayez plus tard.",
+            (French, DeviceTimeout) => "L'appareil a cessé de répondre. Vérifiez qu'il est déverrouillé et connecté, puis réessayez.",
+            (French, DeviceLocked) => "Votre appareil est verrouillé. Déverrouillez-le et réessayez.",
+            (French, DeveloperModeDisabled) => "Le mode développeur est désactivé. Activez-le dans Réglages > Confidentialité et sécurité > Mode développeur, redémarrez l'appareil et réessayez.",
+            (French, BadRequest) => "La requête est invalide. Mettez à jour l'app puis réessayez.",
+            (French, MuxerUnavailable) => "Le serveur ne peut pas joindre les appareils USB pour le moment. Réessayez dans quelques secondes.",
+            (French, Degraded) => "Cette fonction est désactivée sur le serveur pour le moment, car un composant manque. Réessayez plus tard.",
+
+            (German, Internal) => "Interner Serverfehler. Versuche es später erneut.",
+            (German, NotRegistered) => "Dein Gerät ist nicht registriert. Registriere es erneut.",
+            (German, DeviceAmbiguous) => "Von deiner IP sind mehrere Geräte registriert. Wähle eines mit dem X-JitStreamer-Device-Header.",
+            (German, Forbidden) => "Das ist dir nicht erlaubt.",
+            (German, Banned) => "Dieses Gerät wurde auf diesem Server gesperrt.",
+            (German, RateLimited) => "Zu viele Anfragen. Warte kurz und versuche es erneut.",
+            (German, Busy) => "Der Server ist ausgelastet. Versuche es in ein paar Sekunden erneut.",
+            (German, PairingMissing) => "Keine Pairing-Datei gefunden. Registriere dich erneut, um sie hochzuladen.",
+            (German, PairingInvalid) => "Deine Pairing-Datei ist ungültig. Erstelle sie mit jitterbug pair neu.",
+            (German, DeviceUnreachable) => "Das Gerät ist nicht erreichbar. Stelle sicher, dass es entsperrt und mit dem Internet verbunden ist.",
+            (German, VpnNoHandshake) => "Das VPN ist nicht verbunden. Aktiviere den JitStreamer-Tunnel in der WireGuard-App.",
+            (German, DdiNotMounted) => "Das Developer Disk Image ist nicht eingebunden. Binde es ein und versuche es erneut.",
+            (German, DdiMountFailed) => "Das Developer Disk Image konnte nicht eingebunden werden.",
+            (German, UnsupportedDevice) => "Dieses Gerät wird nicht unterstützt.",
+            (German, ServiceFailed) => "Ein Dienst auf dem Gerät ist fehlgeschlagen. Versuche es erneut.",
+            (German, TunnelFailed) => "Verbindung zu den Entwicklerdiensten des Geräts fehlgeschlagen. Versuche es erneut.",
+            (German, LaunchFailed) => "Die App konnte nicht gestartet werden.",
+            (German, AttachFailed) => "Der Debugger konnte sich nicht mit der App verbinden.",
+            (German, ProcessNotFound) => "Kein laufender Prozess hat diesen Namen. Öffne zuerst die App.",
+            (German, AppNotFound) => "Keine debugfähige App hat diesen Namen. Prüfe die App-Liste.",
+            (German, NoDebuggableApps) => "Keine App mit get-task-allow installiert.",
+            (German, ServerFull) => "Der Server ist voll und nimmt gerade keine weiteren Geräte an.",
+            (German, QuotaExceeded) => "Du hast das Startlimit für dieses Gerät erreicht. Versuche es später erneut.",
+            (German, DeviceTimeout) => "Das Gerät antwortet nicht mehr. Stelle sicher, dass es entsperrt und verbunden ist, und versuche es erneut.",
+            (German, DeviceLocked) => "Dein Gerät ist gesperrt. Entsperre es und versuche es erneut.",
+            (German, DeveloperModeDisabled) => "Der Entwicklermodus ist deaktiviert. Aktiviere ihn unter Einstellungen > Datenschutz & Sicherheit > Entwicklermodus, starte das Gerät neu und versuche es erneut.",
+            (German, BadRequest) => "Die Anfrage ist ungültig. Aktualisiere die App und versuche es erneut.",
+            (German, MuxerUnavailable) => "Der Server kann USB-Geräte gerade nicht erreichen. Versuche es in ein paar Sekunden erneut.",
+            (German, Degraded) => "Diese Funktion ist auf dem Server gerade deaktiviert, weil eine Komponente fehlt. Versuche es später erneut.",
+
+            (Chinese, Internal) => "服务器内部错误，请稍后再试。",
+            (Chinese, NotRegistered) => "你的设备尚未注册，请重新注册。",
+            (Chinese, DeviceAmbiguous) => "你的 IP 下注册了多台设备，请用 X-JitStreamer-Device 请求头选择一台。",
+            (Chinese, Forbidden) => "你没有执行此操作的权限。",
+            (Chinese, Banned) => "此设备已被本服务器封禁。",
+            (Chinese, RateLimited) => "请求过于频繁，请稍后再试。",
+            (Chinese, Busy) => "服务器繁忙，请几秒后再试。",
+            (Chinese, PairingMissing) => "找不到配对文件，请重新注册以上传。",
+            (Chinese, PairingInvalid) => "你的配对文件无效，请用 jitterbug pair 重新生成。",
+            (Chinese, DeviceUnreachable) => "无法连接到设备，请确认设备已解锁并已联网。",
+            (Chinese, VpnNoHandshake) => "VPN 未连接，请在 WireGuard 中打开 JitStreamer 隧道。",
+            (Chinese, DdiNotMounted) => "开发者磁盘映像未挂载，请挂载后再试。",
+            (Chinese, DdiMountFailed) => "无法挂载开发者磁盘映像。",
+            (Chinese, UnsupportedDevice) => "不支持此设备。",
+            (Chinese, ServiceFailed) => "设备上的服务出错，请重试。",
+            (Chinese, TunnelFailed) => "无法连接到设备的开发者服务，请重试。",
+            (Chinese, LaunchFailed) => "无法启动该应用。",
+            (Chinese, AttachFailed) => "调试器无法附加到该应用。",
+            (Chinese, ProcessNotFound) => "没有找到该名称的运行中进程，请先打开应用。",
+            (Chinese, AppNotFound) => "没有找到该名称的可调试应用，请检查应用列表。",
+            (Chinese, NoDebuggableApps) => "没有安装带有 get-task-allow 的应用。",
+            (Chinese, ServerFull) => "服务器已满，暂时不接受新设备。",
+            (Chinese, QuotaExceeded) => "此设备的启动次数已达上限，请稍后再试。",
+            (Chinese, DeviceTimeout) => "设备停止响应，请确认设备已解锁并已连接后重试。",
+            (Chinese, DeviceLocked) => "设备已锁定，请解锁后重试。",
+            (Chinese, DeveloperModeDisabled) => "开发者模式已关闭。请在“设置 > 隐私与安全性 > 开发者模式”中开启，重启设备后重试。",
+            (Chinese, BadRequest) => "请求无效，请更新应用后再试。",
+            (Chinese, MuxerUnavailable) => "服务器暂时无法连接 USB 设备，请几秒后再试。",
+            (Chinese, Degraded) => "服务器缺少所需组件，此功能暂时不可用，请稍后再试。",
+        })
+    }
+
+    /// Swaps the message for its translation, keeping the original as the detail
+    pub fn localize(self, error: JitError) -> JitError {
+        match self.message(error.code) {
+            Some(message) => JitError {
+                detail: Some(error.message),
+                message: message.to_string(),
+                ..error
+            },
+            None => error,
+        }
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Language {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self::from_headers(&parts.headers))
+    }
+}
+
+/// Translates the message of JSON error responses carrying a `code`.
+/// The untranslated message is kept in `detail` for bug reports.
+pub async fn middleware(request: Request, next: Next) -> Response {
+    let language = Language::from_headers(request.headers());
+    let mut response = next.run(request).await;
+    response
+        .headers_mut()
+        .append(VARY, HeaderValue::from_static("accept-language"));
+    if language == Language::English {
+        return response;
+    }
+    translate_body(response, language).await
+}
+
+async fn translate_body(response: Response, language: Language) -> Response {
+    let is_json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|c| c.as_bytes().starts_with(b"application/json"));
+    if !is_json {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, MAX_JSON_BODY).await {
+        Ok(b) => b,
+        Err(e) => {
+            warn!("Failed to read response body: {e:?}");
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+
+    let mut value = match serde_json::from_slice::<serde_json::Value>(&bytes) {
+        Ok(serde_json::Value::Object(v)) => v,
+        _ => return Response::from_parts(parts, Body::from(bytes)),
+    };
+    let message = match value
+        .get("code")
+        .and_then(|c| serde_json::from_value::<ErrorCode>(c.clone()).ok())
+        .and_then(|c| language.message(c))
+    {
+        Some(m) => m,
+        None => return Response::from_parts(parts, Body::from(bytes)),
+    };
+    // `/attach` keeps the original JitStreamer shape, with `message` instead of `error`
+    let field = match value.contains_key("error") {
+        true => "error",
+        false => "message",
+    };
+    if let Some(original) = value.insert(
+        field.to_string(),
+        serde_json::Value::String(message.to_string()),
+    ) {
+        value.insert("detail".to_string(), original);
+    }
+
+    let body = serde_json::to_vec(&value).unwrap_or_else(|_| bytes.to_vec());
+    parts.headers.remove(CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(body))
+}
@@ -0,0 +1,146 @@
+// Jackson Coxson
+// Device tokens signed with the server's secret, so a device is known by its token instead of
+// an IP that carrier NAT shares or a mobile network changes
+
+use std::{io::Write, path::Path};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::config::Config;
+
+/// What the token signs, so it's tied to the one device
+const CONTEXT: &str = "JitStreamer device token";
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn mac(secret: &str, udid: &str) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(format!("{CONTEXT}:{udid}").as_bytes());
+    mac
+}
+
+/// Signs the UDID with DEVICE_TOKEN_SECRET. The same device always gets the same token
+/// until the secret is rotated, so registering again keeps it.
+pub fn issue(config: &Config, udid: &str) -> String {
+    sign(&config.device_token_secret, udid)
+}
+
+fn sign(secret: &str, udid: &str) -> String {
+    let signature = mac(secret, udid)
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+    format!("{udid}.{signature}")
+}
+
+/// The UDID the token was issued to, if it's signed with DEVICE_TOKEN_SECRET or
+/// DEVICE_TOKEN_PREVIOUS_SECRET. The signature is checked in constant time.
+pub fn verify<'a>(config: &Config, token: &'a str) -> Option<&'a str> {
+    verify_with(
+        &config.device_token_secret,
+        config.device_token_previous_secret.as_deref(),
+        token,
+    )
+}
+
+fn verify_with<'a>(secret: &str, previous: Option<&str>, token: &'a str) -> Option<&'a str> {
+    let (udid, signature) = token.rsplit_once('.')?;
+    let signature = decode_hex(signature)?;
+    std::iter::once(secret)
+        .chain(previous)
+        .any(|secret| mac(secret, udid).verify_slice(&signature).is_ok())
+        .then_some(udid)
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    let digit = |c: &u8| char::from(*c).to_digit(16);
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [high, low] => Some((digit(high)? << 4 | digit(low)?) as u8),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Reads the secret kept in the file, generating it the first time. Used when
+/// DEVICE_TOKEN_SECRET isn't set, so tokens survive restarts without any setup.
+pub fn stored_secret(path: &Path) -> std::io::Result<String> {
+    match std::fs::read_to_string(path) {
+        Ok(secret) if !secret.trim().is_empty() => return Ok(secret.trim().to_string()),
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    let secret = rand::random::<[u8; 32]>()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+    // Config is read before anything else creates DATA_DIR
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(secret.as_bytes())?;
+    Ok(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UDID: &str = "00008030-001A2B3C4D5E6F70";
+    const SECRET: &str = "current secret";
+
+    #[test]
+    fn verifies_issued_tokens() {
+        let token = sign(SECRET, UDID);
+        assert_eq!(verify_with(SECRET, None, &token), Some(UDID));
+        // The same device keeps its token
+        assert_eq!(token, sign(SECRET, UDID));
+    }
+
+    #[test]
+    fn rejects_wrong_signatures() {
+        let token = sign(SECRET, UDID);
+        let (rest, last) = token.split_at(token.len() - 1);
+        let flipped = format!("{rest}{}", if last == "0" { "1" } else { "0" });
+        assert_eq!(verify_with(SECRET, None, &flipped), None);
+        assert_eq!(verify_with("other secret", None, &token), None);
+    }
+
+    #[test]
+    fn accepts_the_previous_secret() {
+        let token = sign("old secret", UDID);
+        assert_eq!(verify_with(SECRET, Some("old secret"), &token), Some(UDID));
+        // Once the previous secret is dropped, its tokens stop working
+        assert_eq!(verify_with(SECRET, None, &token), None);
+    }
+
+    #[test]
+    fn rejects_malformed_signatures() {
+        let token = sign(SECRET, UDID);
+        let (_, signature) = token.rsplit_once('.').unwrap();
+        let odd = format!("{UDID}.{}", &signature[1..]);
+        let not_hex = format!("{UDID}.{}", "zz".repeat(32));
+        for token in [odd.as_str(), &not_hex, UDID, "", "."] {
+            assert_eq!(verify_with(SECRET, None, token), None, "{token}");
+        }
+    }
+
+    #[test]
+    fn rejects_swapped_udids() {
+        let token = sign(SECRET, UDID);
+        let (_, signature) = token.rsplit_once('.').unwrap();
+        let swapped = format!("00008030-000000000000000.{signature}");
+        assert_eq!(verify_with(SECRET, None, &swapped), None);
+    }
+}
@@ -0,0 +1,146 @@
+// Jackson Coxson
+// Launches with a pairing file sent in the request, for servers run ad hoc without registering devices
+
+use std::net::IpAddr;
+
+use axum::{extract::State, Json};
+use axum_client_ip::SecureClientIp;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use idevice::pairing_file::PairingFile;
+use serde::Deserialize;
+use tracing::info;
+
+use crate::{
+    backend,
+    error::{ErrorCode, JitError},
+    heartbeat, launcher, progress, provider, stats, JitStreamerState, LaunchAppReturn,
+};
+
+#[derive(Deserialize)]
+pub struct InlineLaunchRequest {
+    /// The device's pairing file, as plist text or base64
+    pairing_file: String,
+    /// Where the device is reached, the caller's address by default
+    ip: Option<IpAddr>,
+    #[serde(flatten)]
+    launch: launcher::LaunchRequest,
+}
+
+/// Reads the pairing file and the UDID in it
+fn parse_pairing_file(pairing_file: &str) -> Result<(String, PairingFile), JitError> {
+    let invalid = |e: String| {
+        JitError::new(
+            ErrorCode::PairingInvalid,
+            format!("The pairing file is invalid: {e}"),
+        )
+    };
+    let text = pairing_file.trim();
+    let bytes = match text.starts_with('<') {
+        true => text.as_bytes().to_vec(),
+        false => BASE64_STANDARD
+            .decode(text)
+            .map_err(|e| invalid(e.to_string()))?,
+    };
+    let plist =
+        plist::from_bytes::<plist::Dictionary>(&bytes).map_err(|e| invalid(e.to_string()))?;
+    let udid = match plist.get("UDID") {
+        Some(plist::Value::String(udid)) => udid.to_owned(),
+        _ => return Err(invalid("it has no UDID".to_string())),
+    };
+    let pairing_file = PairingFile::from_bytes(&bytes).map_err(|e| invalid(e.to_string()))?;
+    Ok((udid, pairing_file))
+}
+
+/// Launches on the device in the request without it being registered. Nothing about the
+/// device is kept besides the anonymous stats.
+pub async fn handler(
+    ip: SecureClientIp,
+    State(state): State<JitStreamerState>,
+    Json(request): Json<InlineLaunchRequest>,
+) -> Json<LaunchAppReturn> {
+    let started = std::time::Instant::now();
+    if !state.config().allow_inline_launch {
+        return Json(LaunchAppReturn::fail(JitError::new(
+            ErrorCode::Forbidden,
+            "Launching with an inline pairing file is disabled on this server",
+        )));
+    }
+    let (udid, pairing_file) = match parse_pairing_file(&request.pairing_file) {
+        Ok(p) => p,
+        Err(e) => return Json(LaunchAppReturn::fail(e)),
+    };
+    let device_ip = request.ip.unwrap_or(ip.0);
+    let (bundle_id, options) = request.launch.into_parts();
+    info!(
+        "Got request to launch {bundle_id} on {udid} at {device_ip} with an inline pairing file from {:?}",
+        ip.0
+    );
+
+    let res = launch(
+        &state,
+        &udid,
+        device_ip,
+        pairing_file,
+        bundle_id.clone(),
+        options,
+    )
+    .await;
+    stats::record(
+        &state,
+        stats::Kind::Launch,
+        Some(&udid),
+        Some(&bundle_id),
+        res.as_ref().err(),
+        started.elapsed(),
+        None,
+    )
+    .await;
+    match res {
+        Ok(pid) => Json(LaunchAppReturn {
+            ok: true,
+            pid: Some(pid),
+            error: None,
+            busy: false,
+            legacy: None,
+        }),
+        Err(e) => Json(LaunchAppReturn {
+            busy: e.code == ErrorCode::Busy,
+            ..LaunchAppReturn::fail(e)
+        }),
+    }
+}
+
+async fn launch(
+    state: &JitStreamerState,
+    udid: &str,
+    ip: IpAddr,
+    pairing_file: PairingFile,
+    bundle_id: String,
+    options: launcher::LaunchOptions,
+) -> Result<u64, JitError> {
+    if state.bans.udid_banned(udid).await {
+        return Err(JitError::new(
+            ErrorCode::Banned,
+            "This device has been banned",
+        ));
+    }
+    // Released when the launch returns
+    let permit = state
+        .launch_limiter
+        .try_acquire(udid, &bundle_id)
+        .await
+        .map_err(|busy| JitError::new(ErrorCode::Busy, busy.message()))?;
+
+    let (provider, _) = provider::start(state, udid, ip, pairing_file).await?;
+    let progress = progress::Progress::default();
+    let res = permit
+        .run(backend::launch_on(
+            state, udid, &provider, bundle_id, options, &progress,
+        ))
+        .await;
+    state.heartbeats.release(udid).await.ok();
+    match res {
+        Ok(pid) => Ok(pid),
+        Err(e) => Err(heartbeat::describe_failure(&state.heartbeats, udid, e).await),
+    }
+}
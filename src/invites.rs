@@ -0,0 +1,143 @@
+// Jackson Coxson
+// One-time invite codes for semi-public instances, minted by the admin
+
+use serde::Serialize;
+use tracing::info;
+
+use crate::db::{DbPool, Writer};
+
+/// The most codes minted in one request
+pub const MAX_MINT: u32 = 100;
+
+#[derive(Serialize)]
+pub struct Invite {
+    pub code: String,
+    pub note: Option<String>,
+    pub created_at: String,
+    pub expires_at: Option<String>,
+    pub used_by: Option<String>,
+    pub used_at: Option<String>,
+}
+
+/// Generates a code that's easy to read out, like `7KQ4-MZ2T-X9PB`
+fn generate_code() -> String {
+    // No 0/O or 1/I, they're easy to confuse
+    const ALPHABET: &[u8] = b"23456789ABCDEFGHJKLMNPQRSTUVWXYZ";
+    (0..3)
+        .map(|_| {
+            rand::random::<[u8; 4]>()
+                .iter()
+                .map(|b| ALPHABET[*b as usize % ALPHABET.len()] as char)
+                .collect::<String>()
+        })
+        .collect::<Vec<String>>()
+        .join("-")
+}
+
+/// Mints unused codes, expiring after the number of hours if given
+pub async fn mint(
+    db: &Writer,
+    count: u32,
+    note: Option<&str>,
+    expires_in_hours: Option<u64>,
+) -> Result<Vec<String>, String> {
+    let expires = expires_in_hours.map(|h| format!("+{} seconds", h * 60 * 60));
+    let mut codes = Vec::new();
+    for _ in 0..count {
+        let code = generate_code();
+        // datetime() is NULL without a modifier, so the code never expires
+        db.execute(
+            sqlx::query(
+                "INSERT INTO invites (code, note, created_at, expires_at) VALUES (?, ?, CURRENT_TIMESTAMP, datetime('now', ?))",
+            )
+            .bind(&code)
+            .bind(note)
+            .bind(&expires),
+        )
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to save invite: {e:?}");
+            "Failed to save invite".to_string()
+        })?;
+        codes.push(code);
+    }
+    info!("Minted {count} invite codes");
+    Ok(codes)
+}
+
+pub async fn list(db: &DbPool) -> Result<Vec<Invite>, sqlx::Error> {
+    let rows = sqlx::query_as::<
+        _,
+        (
+            String,
+            Option<String>,
+            String,
+            Option<String>,
+            Option<String>,
+            Option<String>,
+        ),
+    >(
+        "SELECT code, note, CAST(created_at AS TEXT), CAST(expires_at AS TEXT), used_by, CAST(used_at AS TEXT) FROM invites ORDER BY created_at DESC",
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(
+            |(code, note, created_at, expires_at, used_by, used_at)| Invite {
+                code,
+                note,
+                created_at,
+                expires_at,
+                used_by,
+                used_at,
+            },
+        )
+        .collect())
+}
+
+/// Deletes a code, used or not, returning whether there was one
+pub async fn revoke(db: &Writer, code: &str) -> Result<bool, sqlx::Error> {
+    let res = db
+        .execute(sqlx::query("DELETE FROM invites WHERE code = ?").bind(code))
+        .await?;
+    Ok(res.rows_affected() > 0)
+}
+
+/// Deletes the unused codes that have expired, returning how many
+pub async fn prune(db: &Writer) -> Result<u64, sqlx::Error> {
+    let res = db
+        .execute(sqlx::query(
+            "DELETE FROM invites WHERE used_by IS NULL AND expires_at < CURRENT_TIMESTAMP",
+        ))
+        .await?;
+    Ok(res.rows_affected())
+}
+
+/// Marks the code used by the device, returning false if it doesn't exist, has expired
+/// or was already used. Codes are matched ignoring case.
+pub async fn redeem(db: &Writer, code: &str, udid: &str) -> Result<bool, sqlx::Error> {
+    let res = db
+        .execute(
+            sqlx::query(
+                "UPDATE invites SET used_by = ?, used_at = CURRENT_TIMESTAMP WHERE code = ? AND used_by IS NULL AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)",
+            )
+            .bind(udid)
+            .bind(code.trim().to_ascii_uppercase()),
+        )
+        .await?;
+    Ok(res.rows_affected() == 1)
+}
+
+/// Makes a redeemed code usable again, when the registration it was redeemed for failed
+pub async fn release(db: &Writer, code: &str) {
+    if let Err(e) = db
+        .execute(
+            sqlx::query("UPDATE invites SET used_by = NULL, used_at = NULL WHERE code = ?")
+                .bind(code.trim().to_ascii_uppercase()),
+        )
+        .await
+    {
+        tracing::error!("Failed to release invite: {e:?}");
+    }
+}
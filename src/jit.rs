@@ -0,0 +1,316 @@
+// Jackson Coxson
+// Talking to the caller's device: its heartbeat, developer services, launching and attaching,
+// shared by the routes, the control channel and the gRPC service
+
+use std::{net::IpAddr, time::Instant};
+
+use idevice::debug_proxy::DebugProxyClient;
+use tracing::{debug, info, warn};
+
+use crate::{
+    common::{self, DeviceSelector},
+    device,
+    error::{ErrorCode, JitError},
+    events, heartbeat,
+    launcher::{LaunchMode, LaunchOptions},
+    processes::RunningProcess,
+    progress::{LaunchEvent, Progress},
+    provider::DeviceProvider,
+    quota, rsd, standby, stats,
+    tunnel::{self, Tunnel},
+    JitStreamerState,
+};
+
+/// A request for the caller's device. Launches and attaches through it are counted in the
+/// stats and the launch history, with the device's events sent to its listeners.
+pub struct JitSession<'a> {
+    state: &'a JitStreamerState,
+    ip: IpAddr,
+    selector: &'a DeviceSelector,
+    client: Option<&'a str>,
+    progress: Option<&'a Progress>,
+}
+
+impl<'a> JitSession<'a> {
+    pub fn new(state: &'a JitStreamerState, ip: IpAddr, selector: &'a DeviceSelector) -> Self {
+        Self {
+            state,
+            ip,
+            selector,
+            client: None,
+            progress: None,
+        }
+    }
+
+    /// The client named in the stats
+    pub fn client(mut self, client: Option<&'a str>) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Where each phase of a launch is reported as it completes
+    pub fn progress(mut self, progress: &'a Progress) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// The caller's UDID and the address its device is reached at
+    pub async fn device(&self) -> Result<(String, IpAddr), JitError> {
+        common::get_device(
+            self.state,
+            self.ip,
+            self.selector,
+            self.state.config().allow_udid_override,
+        )
+        .await
+    }
+
+    /// Resolves the caller's device and starts its heartbeat, for talking to the device
+    /// outside of a launch. Release the heartbeat when done.
+    pub async fn connect(&self) -> Result<(String, DeviceProvider), JitError> {
+        let (udid, ip) = self.device().await?;
+        let provider = self.state.backend.provider(self.state, &udid, ip).await?;
+        Ok((udid, provider))
+    }
+
+    /// The processes running on the caller's device
+    pub async fn processes(&self) -> Result<Vec<RunningProcess>, JitError> {
+        let (udid, ip) = self.device().await?;
+        let res = self.state.backend.processes(self.state, &udid, ip).await;
+        self.state.heartbeats.release(&udid).await.ok();
+        res
+    }
+
+    /// Launches the app on the caller's device, returning its PID
+    pub async fn launch(&self, bundle_id: String, options: LaunchOptions) -> Result<u64, JitError> {
+        let state = self.state;
+        let started = Instant::now();
+        let default_progress = Progress::default();
+        let progress = self.progress.unwrap_or(&default_progress);
+
+        let (udid, res) = match self.device().await {
+            Ok((udid, device_ip)) => {
+                let res = launch(
+                    state,
+                    &udid,
+                    device_ip,
+                    bundle_id.clone(),
+                    options,
+                    progress,
+                )
+                .await;
+                if res.is_ok() {
+                    standby::record(state, &udid).await;
+                }
+                (Some(udid), res)
+            }
+            Err(e) => (None, Err(e)),
+        };
+        stats::record(
+            state,
+            stats::Kind::Launch,
+            udid.as_deref(),
+            Some(&bundle_id),
+            res.as_ref().err(),
+            started.elapsed(),
+            self.client,
+        )
+        .await;
+        if let Some(udid) = &udid {
+            let event = match &res {
+                Ok(pid) => events::DeviceEvent::Launched {
+                    bundle_id: bundle_id.clone(),
+                    pid: Some(*pid),
+                },
+                Err(e) => events::DeviceEvent::LaunchFailed {
+                    bundle_id: bundle_id.clone(),
+                    error: e.clone(),
+                },
+            };
+            // After it's recorded, so /launch_status reads this launch once it hears of it
+            state.events.send(udid, self.ip, event);
+        }
+        state
+            .launch_history
+            .record(
+                self.ip,
+                bundle_id,
+                res.as_ref().err().map(|e| e.to_string()),
+                started.elapsed(),
+            )
+            .await;
+        res
+    }
+
+    /// Attaches debugserver to a running process on the caller's device, and detaches
+    pub async fn attach(&self, pid: u64) -> Result<(), JitError> {
+        info!("Got request to attach {pid} from {:?}", self.ip);
+        let started = Instant::now();
+
+        let (udid, res) = match self.device().await {
+            Ok((udid, device_ip)) => {
+                let res = self
+                    .state
+                    .backend
+                    .attach(self.state, &udid, device_ip, pid)
+                    .await;
+                self.state.heartbeats.release(&udid).await.ok();
+                (Some(udid), res)
+            }
+            Err(e) => (None, Err(e)),
+        };
+        self.record_attach(udid.as_deref(), res.as_ref().err(), started)
+            .await;
+        res
+    }
+
+    /// Attaches to a running process by name, since PIDs change on every launch
+    pub async fn attach_name(&self, name: &str) -> Result<(), JitError> {
+        info!("Got request to attach {name} from {:?}", self.ip);
+        let started = Instant::now();
+
+        let (udid, res) = match self.device().await {
+            Ok((udid, device_ip)) => {
+                let res = self
+                    .state
+                    .backend
+                    .attach_name(self.state, &udid, device_ip, name)
+                    .await;
+                self.state.heartbeats.release(&udid).await.ok();
+                (Some(udid), res)
+            }
+            Err(e) => (None, Err(e)),
+        };
+        self.record_attach(udid.as_deref(), res.as_ref().err(), started)
+            .await;
+        res
+    }
+
+    async fn record_attach(&self, udid: Option<&str>, error: Option<&JitError>, started: Instant) {
+        stats::record(
+            self.state,
+            stats::Kind::Attach,
+            udid,
+            None,
+            error,
+            started.elapsed(),
+            self.client,
+        )
+        .await;
+    }
+}
+
+/// Launches the app on the device, which is reached at `ip`
+async fn launch(
+    state: &JitStreamerState,
+    udid: &str,
+    ip: IpAddr,
+    bundle_id: String,
+    options: LaunchOptions,
+    progress: &Progress,
+) -> Result<u64, JitError> {
+    let started = Instant::now();
+
+    info!("Got request to launch {bundle_id} on {udid}");
+    common::touch_device(&state.db_writer, udid).await;
+    quota::check(state, udid).await?;
+
+    // Released when the launch returns
+    let permit = match state.launch_limiter.try_acquire(udid, &bundle_id).await {
+        Ok(p) => p,
+        Err(busy) => {
+            info!("Not launching {bundle_id} for {udid}: {busy:?}");
+            return Err(JitError::new(ErrorCode::Busy, busy.message()));
+        }
+    };
+
+    let mode = options.mode;
+    let res = permit
+        .run(
+            state
+                .backend
+                .launch(state, udid, ip, bundle_id, options, progress),
+        )
+        .await;
+    let (pid, heartbeat_start) = match res {
+        Ok(p) => p,
+        Err(e) => {
+            if e.code == ErrorCode::LaunchFailed {
+                // The app may have been uninstalled since it was listed
+                state.apps_cache.invalidate(udid).await;
+            }
+            return Err(heartbeat::describe_failure(&state.heartbeats, udid, e).await);
+        }
+    };
+
+    progress.send(LaunchEvent::Done { pid });
+
+    if mode == LaunchMode::Open {
+        debug!("Opened app without JIT, releasing heartbeat");
+        state.heartbeats.release(udid).await.ok();
+        return Ok(pid);
+    }
+
+    state.latency.record(udid, started.elapsed()).await;
+    info!(
+        "Launched {pid} for {udid} in {:?} (heartbeat reused: {}, woke device: {})",
+        started.elapsed(),
+        heartbeat_start.reused,
+        heartbeat_start.woke
+    );
+
+    debug!("JIT finished, killing heartbeat");
+    state.heartbeats.release(udid).await.ok();
+    Ok(pid)
+}
+
+/// Connects to a developer service over the device's tunnel
+pub async fn developer_service(
+    state: &JitStreamerState,
+    udid: &str,
+    provider: &DeviceProvider,
+    service_name: &str,
+    missing_message: &str,
+) -> Result<(Tunnel, rsd::RsdServices), JitError> {
+    let tunnels = tunnel::for_device(state, udid, provider).await?;
+    let target = rsd::TunnelTarget {
+        provider,
+        udid,
+        tunnels: tunnels.as_ref(),
+        cache: &state.rsd_cache,
+    };
+    match rsd::connect_service(target, service_name, missing_message, &Progress::default()).await {
+        Ok(a) => Ok(a),
+        Err(e) => {
+            let error = match e == missing_message {
+                true => {
+                    let error = JitError::new(ErrorCode::DdiNotMounted, e);
+                    device::explain_missing_services(provider, error).await
+                }
+                false => JitError::new(ErrorCode::TunnelFailed, e),
+            };
+            Err(heartbeat::describe_failure(&state.heartbeats, udid, error).await)
+        }
+    }
+}
+
+/// Attaches debugserver over a tunnel connected to the debug proxy, and detaches
+pub async fn attach_pid(adapter: Tunnel, pid: u64) -> Result<(), JitError> {
+    let mut dp = DebugProxyClient::new(adapter);
+    let commands = [format!("vAttach;{pid:02X}"), "D".to_string()];
+    for command in commands {
+        match dp.send_command(command.into()).await {
+            Ok(res) => {
+                debug!("command res: {res:?}");
+            }
+            Err(e) => {
+                warn!("Failed to send command to debug server: {e:?}");
+                return Err(JitError::new(
+                    ErrorCode::AttachFailed,
+                    format!("Failed to send command to debug server: {e:?}"),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,137 @@
+// Jackson Coxson
+// LAN mode, for a server on the same home network as its devices with no Wireguard
+
+use std::net::IpAddr;
+
+use axum::{extract::State, Json};
+use axum_client_ip::SecureClientIp;
+use idevice::{lockdownd::LockdowndClient, provider::TcpProvider, IdeviceService};
+use serde::Serialize;
+use tracing::info;
+
+use crate::{
+    common,
+    error::{ErrorCode, JitError},
+    JitStreamerState,
+};
+
+#[derive(Serialize)]
+pub struct AnnounceReturn {
+    ok: bool,
+    /// The device's address now
+    ip: Option<String>,
+    #[serde(flatten)]
+    error: Option<JitError>,
+}
+
+/// Moves a registered device to the caller's address, such as after its router gave it a
+/// new one. The device sends its UDID in X-JitStreamer-UDID, which is only believed if the
+/// device at the caller's address accepts the stored pairing file and has that UDID.
+pub async fn announce(
+    ip: SecureClientIp,
+    selector: common::DeviceSelector,
+    State(state): State<JitStreamerState>,
+) -> Json<AnnounceReturn> {
+    match move_device(&state, ip.0, selector.udid).await {
+        Ok(ip) => Json(AnnounceReturn {
+            ok: true,
+            ip: Some(ip),
+            error: None,
+        }),
+        Err(e) => Json(AnnounceReturn {
+            ok: false,
+            ip: None,
+            error: Some(e),
+        }),
+    }
+}
+
+async fn move_device(
+    state: &JitStreamerState,
+    client_ip: IpAddr,
+    udid: Option<String>,
+) -> Result<String, JitError> {
+    if !state.config().lan_mode() {
+        return Err(JitError::new(
+            ErrorCode::Forbidden,
+            "Announcing devices is only possible in LAN mode",
+        ));
+    }
+    let udid = udid.ok_or_else(|| {
+        JitError::new(
+            ErrorCode::BadRequest,
+            "Send the device's UDID in the X-JitStreamer-UDID header",
+        )
+    })?;
+    let old_ip = sqlx::query_scalar::<_, String>("SELECT ip FROM devices WHERE udid = ?")
+        .bind(&udid)
+        .fetch_optional(&state.db)
+        .await
+        .map_err(|e| JitError::internal(format!("Failed to read the device: {e}")))?
+        .ok_or_else(|| {
+            JitError::new(
+                ErrorCode::NotRegistered,
+                "This device isn't registered. Register it first.",
+            )
+        })?;
+    // Stored the way registering by address stores it
+    let new_ip = match client_ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    }
+    .to_string();
+    if old_ip == new_ip {
+        return Ok(new_ip);
+    }
+
+    let pairing_file = common::get_pairing_file(&udid, &state.config().pairing_store).await?;
+    let provider = TcpProvider {
+        addr: client_ip,
+        pairing_file,
+        label: "JitStreamer-EB".to_string(),
+    };
+    let timeout = state.config().device_timeouts.heartbeat;
+    common::timeout(timeout, "answering", async {
+        let unreachable = |e: idevice::IdeviceError| {
+            JitError::new(
+                ErrorCode::DeviceUnreachable,
+                format!("Couldn't reach the device at {client_ip}: {e}"),
+            )
+        };
+        let mut lockdown_client = LockdowndClient::connect(&provider)
+            .await
+            .map_err(unreachable)?;
+        lockdown_client
+            .start_session(&provider.pairing_file)
+            .await
+            .map_err(|e| JitError::heartbeat(e, &state.config(), client_ip))?;
+        match lockdown_client.get_value("UniqueDeviceID").await {
+            Ok(plist::Value::String(u)) if u == udid => Ok(()),
+            Ok(_) => Err(JitError::new(
+                ErrorCode::Forbidden,
+                "The device at this address isn't the one announced",
+            )),
+            Err(e) => Err(unreachable(e)),
+        }
+    })
+    .await?;
+
+    state
+        .db_writer
+        .execute(
+            sqlx::query("UPDATE devices SET ip = ?, last_used = CURRENT_TIMESTAMP WHERE udid = ?")
+                .bind(&new_ip)
+                .bind(&udid),
+        )
+        .await
+        .map_err(|e| JitError::internal(format!("Failed to save the device: {e}")))?;
+    info!("{udid} moved from {old_ip} to {new_ip}");
+
+    // The heartbeat is still pointed at the old address
+    state.heartbeats.kill(&udid).await.ok();
+    state.udid_cache.invalidate(&udid, &old_ip).await;
+    state.udid_cache.invalidate(&udid, &new_ip).await;
+    state.rsd_cache.invalidate(&udid).await;
+    state.muxer.add(&udid, client_ip.to_canonical()).await;
+    Ok(new_ip)
+}
@@ -0,0 +1,64 @@
+// Jackson Coxson
+// Rolling launch latency stats per device
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
+
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+/// How many launches are kept per device
+const WINDOW: usize = 50;
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct LatencyStats {
+    pub samples: usize,
+    pub p50_ms: Option<u64>,
+    pub p95_ms: Option<u64>,
+}
+
+#[derive(Clone, Default)]
+pub struct LatencyTracker(Arc<Mutex<HashMap<String, VecDeque<u64>>>>);
+
+impl LatencyTracker {
+    pub async fn record(&self, udid: &str, duration: Duration) {
+        let mut lock = self.0.lock().await;
+        let samples = lock.entry(udid.to_string()).or_default();
+        if samples.len() >= WINDOW {
+            samples.pop_front();
+        }
+        samples.push_back(duration.as_millis() as u64);
+    }
+
+    pub async fn stats(&self, udid: &str) -> LatencyStats {
+        match self.0.lock().await.get(udid) {
+            Some(s) => compute(s.iter().copied().collect()),
+            None => LatencyStats::default(),
+        }
+    }
+
+    pub async fn remove(&self, udid: &str) {
+        self.0.lock().await.remove(udid);
+    }
+}
+
+fn compute(mut samples: Vec<u64>) -> LatencyStats {
+    if samples.is_empty() {
+        return LatencyStats::default();
+    }
+    samples.sort_unstable();
+    LatencyStats {
+        samples: samples.len(),
+        p50_ms: Some(percentile(&samples, 50)),
+        p95_ms: Some(percentile(&samples, 95)),
+    }
+}
+
+/// Nearest-rank percentile of sorted samples
+fn percentile(sorted: &[u64], p: usize) -> u64 {
+    let rank = (p * sorted.len()).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
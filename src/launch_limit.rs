@@ -0,0 +1,180 @@
+// Jackson Coxson
+// Caps how many launches run at once, so a flood of requests can't exhaust tunnels and heartbeats
+
+use std::{collections::HashMap, future::Future, sync::Arc, time::Instant};
+
+use serde::Serialize;
+use tokio::sync::{Mutex, Notify, OwnedMutexGuard, OwnedSemaphorePermit, Semaphore};
+
+use crate::error::{ErrorCode, JitError};
+
+/// Why a launch couldn't start right away
+#[derive(Debug)]
+pub enum Busy {
+    /// Every launch permit is taken
+    Server,
+    /// The device already has a launch running
+    Device,
+}
+
+impl Busy {
+    pub fn message(&self) -> &'static str {
+        match self {
+            Busy::Server => "The server is busy with other launches, retry in a few seconds",
+            Busy::Device => "This device is already launching an app, retry when it finishes",
+        }
+    }
+}
+
+struct Running {
+    bundle_id: String,
+    started: Instant,
+    stop: Arc<Notify>,
+}
+
+/// A launch in progress, for the admin API
+#[derive(Serialize)]
+pub struct RunningLaunch {
+    pub udid: String,
+    pub bundle_id: String,
+    pub age_secs: u64,
+}
+
+/// Held for the length of a launch
+pub struct LaunchPermit {
+    udid: String,
+    stop: Arc<Notify>,
+    running: Arc<std::sync::Mutex<HashMap<String, Running>>>,
+    _server: OwnedSemaphorePermit,
+    _device: OwnedMutexGuard<()>,
+}
+
+impl LaunchPermit {
+    /// Runs the launch until it finishes or an admin stops it
+    pub async fn run<T>(
+        &self,
+        launch: impl Future<Output = Result<T, JitError>>,
+    ) -> Result<T, JitError> {
+        tokio::select! {
+            res = launch => res,
+            _ = self.stop.notified() => Err(JitError::new(
+                ErrorCode::LaunchFailed,
+                "The server's admin stopped this launch",
+            )),
+        }
+    }
+}
+
+impl Drop for LaunchPermit {
+    fn drop(&mut self) {
+        // Still holding the device's lock, so no newer launch of it is listed yet
+        self.running.lock().unwrap().remove(&self.udid);
+    }
+}
+
+/// Launches aren't queued. Each device runs one at a time and everything over the limit is
+/// turned away busy, so a device launching over and over only ever holds one permit and
+/// can't starve the others.
+#[derive(Clone)]
+pub struct LaunchLimiter {
+    permits: Arc<Semaphore>,
+    capacity: usize,
+    devices: Arc<Mutex<HashMap<String, Arc<Mutex<()>>>>>,
+    running: Arc<std::sync::Mutex<HashMap<String, Running>>>,
+}
+
+impl LaunchLimiter {
+    pub fn new(permits: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(permits)),
+            capacity: permits,
+            devices: Arc::new(Mutex::new(HashMap::new())),
+            running: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Takes a global permit and the device's lock without waiting for either
+    pub async fn try_acquire(&self, udid: &str, bundle_id: &str) -> Result<LaunchPermit, Busy> {
+        let device = {
+            let mut devices = self.devices.lock().await;
+            // Locks nobody holds or waits on are only referenced by the map
+            devices.retain(|_, l| Arc::strong_count(l) > 1);
+            devices.entry(udid.to_string()).or_default().clone()
+        };
+        let device = device.try_lock_owned().map_err(|_| Busy::Device)?;
+        let server = self
+            .permits
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| Busy::Server)?;
+        let stop = Arc::new(Notify::new());
+        self.running.lock().unwrap().insert(
+            udid.to_string(),
+            Running {
+                bundle_id: bundle_id.to_string(),
+                started: Instant::now(),
+                stop: stop.clone(),
+            },
+        );
+        Ok(LaunchPermit {
+            udid: udid.to_string(),
+            stop,
+            running: self.running.clone(),
+            _server: server,
+            _device: device,
+        })
+    }
+
+    /// How many launches are running, and how many can at once
+    pub fn load(&self) -> (usize, usize) {
+        (
+            self.capacity - self.permits.available_permits(),
+            self.capacity,
+        )
+    }
+
+    /// The launches in progress, oldest first
+    pub fn running(&self) -> Vec<RunningLaunch> {
+        let running = self.running.lock().unwrap();
+        let mut launches = running
+            .iter()
+            .map(|(udid, r)| {
+                (
+                    r.started,
+                    RunningLaunch {
+                        udid: udid.clone(),
+                        bundle_id: r.bundle_id.clone(),
+                        age_secs: r.started.elapsed().as_secs(),
+                    },
+                )
+            })
+            .collect::<Vec<_>>();
+        launches.sort_by_key(|(started, _)| *started);
+        launches.into_iter().map(|(_, l)| l).collect()
+    }
+
+    /// The device's launch in progress, if it has one
+    pub fn running_for(&self, udid: &str) -> Option<RunningLaunch> {
+        self.running
+            .lock()
+            .unwrap()
+            .get(udid)
+            .map(|r| RunningLaunch {
+                udid: udid.to_string(),
+                bundle_id: r.bundle_id.clone(),
+                age_secs: r.started.elapsed().as_secs(),
+            })
+    }
+
+    /// Stops the device's launch in progress, returning whether it had one
+    pub fn stop(&self, udid: &str) -> bool {
+        match self.running.lock().unwrap().get(udid) {
+            Some(r) => {
+                // Stored if the launch isn't waiting on it yet
+                r.stop.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,237 @@
+// Jackson Coxson
+// The state of the caller's latest launch, for clients that poll instead of holding the launch open
+
+use std::time::Duration;
+
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use axum_client_ip::SecureClientIp;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    common::{self, DeviceSelector},
+    error::{ErrorCode, JitError},
+    events::DeviceEvent,
+    stats, JitStreamerState,
+};
+
+/// The longest a request waits for a running launch to finish
+const MAX_WAIT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LaunchState {
+    /// The device has never launched an app here
+    None,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+struct Latest {
+    state: LaunchState,
+    bundle_id: Option<String>,
+    running_secs: Option<u64>,
+    finished_at: Option<String>,
+    duration_ms: Option<i64>,
+    error: Option<JitError>,
+    /// The device's requests waiting behind the one it's running
+    position: usize,
+}
+
+/// The launch running on the device, or else the last one recorded in the stats
+async fn latest(state: &JitStreamerState, udid: &str) -> Result<Latest, JitError> {
+    let position = state.device_queues.queued(udid).await.saturating_sub(1);
+    if let Some(running) = state.launch_limiter.running_for(udid) {
+        return Ok(Latest {
+            state: LaunchState::Running,
+            bundle_id: Some(running.bundle_id),
+            running_secs: Some(running.age_secs),
+            finished_at: None,
+            duration_ms: None,
+            error: None,
+            position,
+        });
+    }
+    let row = sqlx::query_as::<_, (Option<String>, bool, Option<String>, Option<String>, String, i64)>(
+        "SELECT bundle_id, ok, code, error, CAST(at AS TEXT), duration_ms FROM launch_stats WHERE kind = 'launch' AND udid_hash = ? ORDER BY at DESC LIMIT 1",
+    )
+    .bind(stats::hash_udid(udid))
+    .fetch_optional(&state.db)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to query database: {e:?}");
+        JitError::internal("Failed to query database")
+    })?;
+    Ok(match row {
+        Some((bundle_id, ok, code, error, at, duration_ms)) => Latest {
+            state: match ok {
+                true => LaunchState::Succeeded,
+                false => LaunchState::Failed,
+            },
+            bundle_id,
+            running_secs: None,
+            finished_at: Some(at),
+            duration_ms: Some(duration_ms),
+            error: (!ok).then(|| {
+                let code = code
+                    .and_then(|c| serde_json::from_value(serde_json::Value::String(c)).ok())
+                    .unwrap_or(ErrorCode::Internal);
+                JitError::new(
+                    code,
+                    error.unwrap_or_else(|| "The launch failed".to_string()),
+                )
+            }),
+            position,
+        },
+        None => Latest {
+            state: LaunchState::None,
+            bundle_id: None,
+            running_secs: None,
+            finished_at: None,
+            duration_ms: None,
+            error: None,
+            position,
+        },
+    })
+}
+
+/// Waits up to `wait` for the device's running launch to finish, then reads its state
+async fn wait_for(
+    state: &JitStreamerState,
+    udid: &str,
+    wait: Duration,
+) -> Result<Latest, JitError> {
+    // Subscribed first, so a launch finishing in between isn't missed
+    let mut receiver = state.events.subscribe();
+    let current = latest(state, udid).await?;
+    if current.state != LaunchState::Running || wait.is_zero() {
+        return Ok(current);
+    }
+    let deadline = tokio::time::Instant::now() + wait;
+    loop {
+        match tokio::time::timeout_at(deadline, receiver.recv()).await {
+            Ok(Ok(notice))
+                if notice.udid == udid
+                    && matches!(
+                        notice.event,
+                        DeviceEvent::Launched { .. } | DeviceEvent::LaunchFailed { .. }
+                    ) =>
+            {
+                break
+            }
+            Ok(Ok(_)) | Ok(Err(RecvError::Lagged(_))) => continue,
+            Ok(Err(RecvError::Closed)) | Err(_) => break,
+        }
+    }
+    latest(state, udid).await
+}
+
+#[derive(Deserialize)]
+pub struct LaunchStatusOptions {
+    /// Seconds to wait for a running launch to finish before answering
+    #[serde(default)]
+    wait: u64,
+}
+
+#[derive(Serialize)]
+pub struct LaunchStatusReturn {
+    ok: bool,
+    state: Option<LaunchState>,
+    bundle_id: Option<String>,
+    /// Seconds since it started, while it's running
+    running_secs: Option<u64>,
+    /// When it finished, in UTC
+    finished_at: Option<String>,
+    duration_ms: Option<i64>,
+    /// Why it failed
+    launch_error: Option<JitError>,
+    /// The device's requests waiting behind the one it's running
+    position: usize,
+    /// Why the state couldn't be read
+    #[serde(flatten)]
+    error: Option<JitError>,
+}
+
+/// The state of the caller's device's latest launch. With `?wait=`, a running launch is
+/// waited on for up to that many seconds, 30 at most, so polling clients hear when it ends.
+pub async fn handler(
+    ip: SecureClientIp,
+    selector: DeviceSelector,
+    Query(options): Query<LaunchStatusOptions>,
+    State(state): State<JitStreamerState>,
+) -> Json<LaunchStatusReturn> {
+    let wait = Duration::from_secs(options.wait).min(MAX_WAIT);
+    let res = async {
+        let udid = common::get_udid(&state, ip.0.to_string(), &selector).await?;
+        wait_for(&state, &udid, wait).await
+    }
+    .await;
+    Json(match res {
+        Ok(l) => LaunchStatusReturn {
+            ok: true,
+            state: Some(l.state),
+            bundle_id: l.bundle_id,
+            running_secs: l.running_secs,
+            finished_at: l.finished_at,
+            duration_ms: l.duration_ms,
+            launch_error: l.error,
+            position: l.position,
+            error: None,
+        },
+        Err(e) => LaunchStatusReturn {
+            ok: false,
+            state: None,
+            bundle_id: None,
+            running_secs: None,
+            finished_at: None,
+            duration_ms: None,
+            launch_error: None,
+            position: 0,
+            error: Some(e),
+        },
+    })
+}
+
+// compat with OG JitStreamer
+#[derive(Debug, Serialize)]
+pub struct StatusReturn {
+    done: bool,
+    ok: bool,
+    position: usize,
+    #[serde(flatten)]
+    error: Option<JitError>,
+    in_progress: bool, // NOTICE: this field is deprecated and will be removed in future versions
+}
+
+/// The original JitStreamer's /status, which its clients poll after launching until it's
+/// done. Answers right away, and callers whose device can't be found are always done.
+pub async fn legacy(
+    ip: SecureClientIp,
+    selector: DeviceSelector,
+    State(state): State<JitStreamerState>,
+) -> Json<StatusReturn> {
+    let current = match common::get_udid(&state, ip.0.to_string(), &selector).await {
+        Ok(udid) => latest(&state, &udid).await.ok(),
+        Err(_) => None,
+    };
+    Json(match current {
+        Some(l) => StatusReturn {
+            done: l.state != LaunchState::Running,
+            ok: l.state != LaunchState::Failed,
+            position: l.position,
+            in_progress: l.state == LaunchState::Running,
+            error: l.error,
+        },
+        None => StatusReturn {
+            done: true,
+            ok: true,
+            position: 0,
+            error: None,
+            in_progress: false,
+        },
+    })
+}
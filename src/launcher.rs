@@ -0,0 +1,232 @@
+// Jackson Coxson
+// Providers for starting an app on the device
+
+use std::collections::HashMap;
+
+use idevice::{dvt::remote_server::RemoteServerClient, ReadWrite};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::tunnel::Tunnel;
+
+/// The technique used to start the app
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LaunchProvider {
+    /// DVT process control through the instruments remote server.
+    /// This is the only provider that can hand the process to debugserver.
+    #[default]
+    Instruments,
+}
+
+/// How JIT is enabled on the device, picked from its iOS version so clients don't have to
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JitMethod {
+    /// debugserver started through lockdown, for iOS 16 and earlier
+    Lockdown,
+    /// The developer services over a CoreDeviceProxy tunnel and RemoteXPC, for iOS 17 and later
+    RemoteXpc,
+}
+
+impl JitMethod {
+    /// Picks the method for a ProductVersion such as `17.4.1`. An unknown version gets
+    /// the newest method, since that's what most devices run.
+    pub fn for_version(version: Option<&str>) -> Self {
+        let major = version
+            .and_then(|v| v.split('.').next())
+            .and_then(|m| m.parse::<u32>().ok());
+        match major {
+            Some(m) if m < 17 => JitMethod::Lockdown,
+            _ => JitMethod::RemoteXpc,
+        }
+    }
+}
+
+/// What to do once the app is started
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LaunchMode {
+    /// Launch suspended, attach debugserver and detach to enable JIT
+    #[default]
+    Jit,
+    /// Just open the app, skipping the debugserver entirely
+    Open,
+    /// Launch suspended, attach debugserver and continue the process while attached,
+    /// so an app that crashes right away reports it instead of dying before the attach
+    Continue,
+}
+
+#[derive(Deserialize, Default, Debug)]
+pub struct LaunchOptions {
+    #[serde(default)]
+    pub provider: LaunchProvider,
+    #[serde(default)]
+    pub mode: LaunchMode,
+    /// Only settable through the JSON body of `/v2/launch_app`
+    #[serde(skip)]
+    pub process: ProcessOptions,
+}
+
+/// How the process itself is started
+#[derive(Deserialize, Default, Debug)]
+pub struct ProcessOptions {
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Defaults to suspending unless the app is only opened
+    pub start_suspended: Option<bool>,
+    /// Defaults to disabling the memory limit unless the app is only opened
+    pub disable_memory_limit: Option<bool>,
+}
+
+/// The body of `/v2/launch_app`
+#[derive(Deserialize, Debug)]
+pub struct LaunchRequest {
+    pub bundle_id: String,
+    #[serde(default)]
+    pub provider: LaunchProvider,
+    #[serde(default)]
+    pub mode: LaunchMode,
+    #[serde(flatten)]
+    pub process: ProcessOptions,
+}
+
+impl LaunchRequest {
+    pub fn into_parts(self) -> (String, LaunchOptions) {
+        (
+            self.bundle_id,
+            LaunchOptions {
+                provider: self.provider,
+                mode: self.mode,
+                process: self.process,
+            },
+        )
+    }
+}
+
+impl LaunchProvider {
+    /// The RSD service the provider needs a connection to
+    pub fn service_name(&self) -> &'static str {
+        match self {
+            LaunchProvider::Instruments => idevice::dvt::SERVICE_NAME,
+        }
+    }
+
+    /// Starts the app using the tunnel connected to `service_name`.
+    /// Returns the PID and the tunnel with the service connection closed.
+    pub async fn launch(
+        &self,
+        adapter: Tunnel,
+        bundle_id: String,
+        mode: LaunchMode,
+        process: &ProcessOptions,
+    ) -> Result<(u64, Tunnel), String> {
+        match self {
+            LaunchProvider::Instruments => {
+                launch_instruments(adapter, bundle_id, mode, process).await
+            }
+        }
+    }
+}
+
+#[tracing::instrument(name = "dvt", skip_all)]
+async fn launch_instruments(
+    adapter: Tunnel,
+    bundle_id: String,
+    mode: LaunchMode,
+    process: &ProcessOptions,
+) -> Result<(u64, Tunnel), String> {
+    let mut rs_client = remote_server(adapter)?;
+    let pid = process_control(&mut rs_client, bundle_id, mode, process).await?;
+
+    let mut adapter = rs_client.into_inner();
+    if let Err(e) = adapter.close().await {
+        warn!("Failed to close DVT port: {e:?}");
+        return Err("Failed to close RemoteXPC port".to_string());
+    }
+    Ok((pid, adapter))
+}
+
+/// Starts the app over an instruments connection made through lockdown, for the lockdown
+/// method. Returns the PID.
+#[tracing::instrument(name = "dvt", skip_all)]
+pub async fn launch_direct<R: ReadWrite>(
+    socket: R,
+    bundle_id: String,
+    mode: LaunchMode,
+    process: &ProcessOptions,
+) -> Result<u64, String> {
+    let mut rs_client = remote_server(socket)?;
+    process_control(&mut rs_client, bundle_id, mode, process).await
+}
+
+fn remote_server<R: ReadWrite>(socket: R) -> Result<RemoteServerClient<R>, String> {
+    RemoteServerClient::new(socket).map_err(|e| {
+        warn!("Failed to create remote server client: {e:?}");
+        format!("Failed to create remote server client: {e:?}")
+    })
+}
+
+/// Launches the app through DVT process control, returning its PID
+async fn process_control<R: ReadWrite>(
+    rs_client: &mut RemoteServerClient<R>,
+    bundle_id: String,
+    mode: LaunchMode,
+    process: &ProcessOptions,
+) -> Result<u64, String> {
+    if let Err(e) = rs_client.read_message(0).await {
+        warn!("Failed to read first message from remote server client: {e:?}");
+        return Err(format!(
+            "Failed to read first message from remote server client: {e:?}"
+        ));
+    }
+
+    let mut pc_client =
+        match idevice::dvt::process_control::ProcessControlClient::new(rs_client).await {
+            Ok(p) => p,
+            Err(e) => {
+                warn!("Failed to create process control client: {e:?}");
+                return Err(format!("Failed to create process control client: {e:?}"));
+            }
+        };
+
+    let env = (!process.env.is_empty()).then(|| {
+        process
+            .env
+            .iter()
+            .map(|(k, v)| (k.clone(), plist::Value::String(v.clone())))
+            .collect::<plist::Dictionary>()
+    });
+    // Process control takes the arguments as a dictionary, in order
+    let args = (!process.args.is_empty()).then(|| {
+        process
+            .args
+            .iter()
+            .enumerate()
+            .map(|(i, a)| (i.to_string(), plist::Value::String(a.clone())))
+            .collect::<plist::Dictionary>()
+    });
+    let start_suspended = process.start_suspended.unwrap_or(mode != LaunchMode::Open);
+    let pid = match pc_client
+        .launch_app(bundle_id, env, args, start_suspended, false)
+        .await
+    {
+        Ok(p) => p,
+        Err(e) => {
+            warn!("Failed to launch app: {e:?}");
+            return Err(format!("Failed to launch app: {e:?}"));
+        }
+    };
+    debug!("Launched app with PID {pid}");
+    if process
+        .disable_memory_limit
+        .unwrap_or(mode != LaunchMode::Open)
+    {
+        if let Err(e) = pc_client.disable_memory_limit(pid).await {
+            warn!("Failed to disable memory limit: {e:?}")
+        }
+    }
+    Ok(pid)
+}
@@ -0,0 +1,151 @@
+// Jackson Coxson
+// Imports registrations from the original JitStreamer, so its users don't register again
+
+use std::{collections::HashSet, path::Path};
+
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
+
+use crate::{config::Config, db::Writer, pairing_store::PairingStore};
+
+/// Copies the devices in the original JitStreamer's database, their pairing files from
+/// its plist folder, and their peers from its Wireguard config.
+/// Devices that are already registered here are left alone.
+pub async fn import(
+    config: &Config,
+    db: &Writer,
+    database: &Path,
+    pairing_dir: Option<&Path>,
+    wireguard_conf: Option<&Path>,
+) -> Result<(), String> {
+    let legacy = SqlitePool::connect_with(
+        SqliteConnectOptions::new()
+            .filename(database)
+            .read_only(true),
+    )
+    .await
+    .map_err(|e| format!("Failed to open {}: {e}", database.display()))?;
+
+    let columns = sqlx::query_scalar::<_, String>("SELECT name FROM pragma_table_info('devices')")
+        .fetch_all(&legacy)
+        .await
+        .map_err(|e| format!("Failed to read the devices table: {e}"))?;
+    if !columns.iter().any(|c| c == "udid") || !columns.iter().any(|c| c == "ip") {
+        return Err(format!(
+            "{} has no devices table with udid and ip columns",
+            database.display()
+        ));
+    }
+    let devices = sqlx::query_as::<_, (String, String)>("SELECT udid, ip FROM devices")
+        .fetch_all(&legacy)
+        .await
+        .map_err(|e| format!("Failed to read devices: {e}"))?;
+
+    let mut imported = Vec::new();
+    for (udid, ip) in devices {
+        if let Some(dir) = pairing_dir {
+            let file = dir.join(format!("{udid}.plist"));
+            match tokio::fs::read(&file).await {
+                Ok(bytes) => config.pairing_store.put(&udid, &bytes).await?,
+                Err(e) => {
+                    eprintln!("Skipping {udid}, failed to read {}: {e}", file.display());
+                    continue;
+                }
+            }
+        }
+        let res = db
+            .execute(
+                sqlx::query(
+                    "INSERT OR IGNORE INTO devices (udid, ip, last_used) VALUES (?, ?, CURRENT_TIMESTAMP)",
+                )
+                .bind(&udid)
+                .bind(&ip),
+            )
+            .await
+        .map_err(|e| format!("Failed to import {udid}: {e}"))?;
+        match res.rows_affected() {
+            0 => eprintln!("Skipping {udid}, it's already registered"),
+            _ => imported.push(ip),
+        }
+    }
+    println!(
+        "Imported {} devices from {}",
+        imported.len(),
+        database.display()
+    );
+
+    if let Some(conf) = wireguard_conf {
+        if !config.wireguard_registration() {
+            return Err(
+                "Wireguard peers can only be imported with ALLOW_REGISTRATION=1 or 3".to_string(),
+            );
+        }
+        let copied = import_peers(config, conf, &imported)?;
+        println!(
+            "Copied {copied} Wireguard peers into {}, they're applied when the server starts",
+            config.wireguard[0].conf_path()
+        );
+    }
+    Ok(())
+}
+
+/// Whether the peer's AllowedIPs include one of the addresses
+fn peer_matches(peer: &[&str], ips: &HashSet<&str>) -> bool {
+    peer.iter().any(|l| {
+        l.trim_start().starts_with("AllowedIPs")
+            && l.split(['=', ','])
+                .skip(1)
+                .filter_map(|a| a.trim().split('/').next())
+                .any(|a| ips.contains(a))
+    })
+}
+
+/// Appends the old config's [Peer] blocks for the imported devices to the first interface's
+/// config, keeping their keys so the devices' VPN profiles keep working
+fn import_peers(config: &Config, conf: &Path, ips: &[String]) -> Result<usize, String> {
+    let old = std::fs::read_to_string(conf)
+        .map_err(|e| format!("Failed to read {}: {e}", conf.display()))?;
+    let path = config.wireguard[0].conf_path();
+    let mut current =
+        std::fs::read_to_string(&path).map_err(|e| format!("Failed to read {path}: {e}"))?;
+
+    // Peers already in the current config aren't copied twice
+    let existing = current.lines().collect::<Vec<&str>>();
+    let ips = ips
+        .iter()
+        .map(|i| i.as_str())
+        .filter(|i| !peer_matches(&existing, &HashSet::from([*i])))
+        .collect::<HashSet<&str>>();
+
+    let mut peers: Vec<Vec<&str>> = Vec::new();
+    let mut in_peer = false;
+    for line in old.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            in_peer = trimmed.eq_ignore_ascii_case("[Peer]");
+            if in_peer {
+                peers.push(Vec::new());
+            }
+            continue;
+        }
+        if let Some(peer) = peers.last_mut().filter(|_| in_peer) {
+            if !trimmed.is_empty() {
+                peer.push(line);
+            }
+        }
+    }
+
+    let mut copied = 0;
+    for peer in peers.iter().filter(|p| peer_matches(p, &ips)) {
+        if !current.ends_with('\n') {
+            current.push('\n');
+        }
+        current.push_str("\n[Peer]\n");
+        for line in peer {
+            current.push_str(line);
+            current.push('\n');
+        }
+        copied += 1;
+    }
+    std::fs::write(&path, current).map_err(|e| format!("Failed to write {path}: {e}"))?;
+    Ok(copied)
+}
@@ -0,0 +1,74 @@
+// Jackson Coxson
+// Cheap check for whether a device is reachable, shared by everything that needs one
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::net::TcpStream;
+use tracing::debug;
+
+use crate::heartbeat::{HeartbeatManager, HeartbeatStatus};
+
+const LOCKDOWN_PORT: u16 = 62078;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProbeMethod {
+    /// The device's heartbeat is alive, so nothing was sent
+    Heartbeat,
+    /// A single TCP connect to lockdown
+    Lockdown,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Liveness {
+    pub alive: bool,
+    pub method: ProbeMethod,
+    pub elapsed_ms: u128,
+}
+
+/// Checks if the device is reachable, trusting a live heartbeat if there is one
+/// and otherwise opening one TCP connection to lockdown. No ICMP needed.
+pub async fn probe(heartbeats: &HeartbeatManager, udid: &str, ip: IpAddr) -> Liveness {
+    let started = Instant::now();
+    if matches!(
+        heartbeats.status(udid).await,
+        Ok(Some(HeartbeatStatus::Alive))
+    ) {
+        return Liveness {
+            alive: true,
+            method: ProbeMethod::Heartbeat,
+            elapsed_ms: started.elapsed().as_millis(),
+        };
+    }
+    let alive = lockdown_reachable(ip).await;
+    Liveness {
+        alive,
+        method: ProbeMethod::Lockdown,
+        elapsed_ms: started.elapsed().as_millis(),
+    }
+}
+
+/// Returns true if lockdown accepts a TCP connection within a second
+pub async fn lockdown_reachable(ip: IpAddr) -> bool {
+    match tokio::time::timeout(
+        CONNECT_TIMEOUT,
+        TcpStream::connect(SocketAddr::new(ip, LOCKDOWN_PORT)),
+    )
+    .await
+    {
+        Ok(Ok(_)) => true,
+        Ok(Err(e)) => {
+            debug!("Lockdown connect to {ip} failed: {e:?}");
+            false
+        }
+        Err(_) => {
+            debug!("Lockdown connect to {ip} timed out");
+            false
+        }
+    }
+}
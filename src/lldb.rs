@@ -0,0 +1,139 @@
+// Jackson Coxson
+// Hands a port to lldb that's proxied to the device's debugserver, for debugging over the VPN
+
+use std::net::{IpAddr, Ipv6Addr};
+
+use axum::{extract::State, Json};
+use axum_client_ip::SecureClientIp;
+use serde::Serialize;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+use crate::{
+    common::DeviceSelector,
+    error::{ErrorCode, JitError},
+    pipeline,
+    tunnel::Tunnel,
+    JitStreamerState,
+};
+
+#[derive(Serialize)]
+pub struct LldbReturn {
+    ok: bool,
+    /// The port on this server to point lldb at
+    port: Option<u16>,
+    #[serde(flatten)]
+    error: Option<JitError>,
+}
+
+impl LldbReturn {
+    fn fail(error: JitError) -> Json<Self> {
+        Json(Self {
+            ok: false,
+            port: None,
+            error: Some(error),
+        })
+    }
+}
+
+/// Opens a port proxied to debugserver on the caller's device. Only the caller's address
+/// may connect to it, once, within LLDB_PROXY_TIMEOUT. The device stays connected until
+/// lldb disconnects.
+pub async fn handler(
+    ip: SecureClientIp,
+    selector: DeviceSelector,
+    State(state): State<JitStreamerState>,
+) -> Json<LldbReturn> {
+    info!("Got request to proxy debugserver for lldb from {:?}", ip.0);
+    if !state.config().allow_lldb_proxy {
+        return LldbReturn::fail(JitError::new(
+            ErrorCode::Forbidden,
+            "The lldb proxy is disabled on this server",
+        ));
+    }
+
+    let (udid, provider) = match crate::jit::JitSession::new(&state, ip.0, &selector)
+        .connect()
+        .await
+    {
+        Ok(d) => d,
+        Err(e) => return LldbReturn::fail(e),
+    };
+
+    let res = async {
+        let (tunnel, _) = crate::jit::developer_service(
+            &state,
+            &udid,
+            &provider,
+            idevice::debug_proxy::SERVICE_NAME,
+            pipeline::DEBUG_PROXY_MISSING,
+        )
+        .await?;
+        let listener = TcpListener::bind((Ipv6Addr::UNSPECIFIED, 0))
+            .await
+            .map_err(|e| {
+                warn!("Failed to bind a port for lldb: {e:?}");
+                JitError::internal("Failed to open a port for lldb")
+            })?;
+        let port = listener
+            .local_addr()
+            .map_err(|_| JitError::internal("Failed to open a port for lldb"))?
+            .port();
+        Ok::<_, JitError>((tunnel, listener, port))
+    }
+    .await;
+
+    match res {
+        Ok((tunnel, listener, port)) => {
+            info!("Proxying debugserver on {udid} to port {port}");
+            tokio::spawn(bridge(state, udid, ip.0, listener, tunnel));
+            Json(LldbReturn {
+                ok: true,
+                port: Some(port),
+                error: None,
+            })
+        }
+        Err(e) => {
+            state.heartbeats.release(&udid).await.ok();
+            LldbReturn::fail(e)
+        }
+    }
+}
+
+/// Waits for the caller to connect, then passes bytes both ways until either side closes
+async fn bridge(
+    state: JitStreamerState,
+    udid: String,
+    client: IpAddr,
+    listener: TcpListener,
+    mut tunnel: Tunnel,
+) {
+    let timeout = state.config().lldb_proxy_timeout;
+    match tokio::time::timeout(timeout, accept(&listener, client, &udid)).await {
+        Ok(Ok(mut socket)) => {
+            drop(listener);
+            info!("lldb connected to {udid}");
+            match tokio::io::copy_bidirectional(&mut socket, &mut tunnel).await {
+                Ok((sent, received)) => info!(
+                    "lldb disconnected from {udid} after sending {sent} bytes and receiving {received}"
+                ),
+                Err(e) => debug!("lldb session with {udid} ended: {e:?}"),
+            }
+        }
+        Ok(Err(e)) => warn!("Failed to accept lldb's connection for {udid}: {e:?}"),
+        Err(_) => info!("lldb didn't connect to {udid} within {timeout:?}, closing the port"),
+    }
+
+    state.heartbeats.release(&udid).await.ok();
+}
+
+/// Accepts the first connection from the client, refusing anyone else who finds the port
+async fn accept(listener: &TcpListener, client: IpAddr, udid: &str) -> std::io::Result<TcpStream> {
+    loop {
+        let (socket, addr) = listener.accept().await?;
+        if addr.ip().to_canonical() == client.to_canonical() {
+            return Ok(socket);
+        }
+        warn!("Refusing lldb connection for {udid} from {addr}");
+    }
+}
@@ -0,0 +1,91 @@
+// Jackson Coxson
+// JIT for iOS 16 and earlier, which start developer services through lockdownd instead of a tunnel
+
+use idevice::{
+    lockdownd::LockdowndClient, provider::IdeviceProvider, IdeviceError, IdeviceService, ReadWrite,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    error::{ErrorCode, JitError},
+    launcher::{self, LaunchMode, LaunchOptions},
+    pipeline,
+    progress::{LaunchEvent, Progress},
+    provider::DeviceProvider,
+};
+
+const INSTRUMENTS_SERVICE: &str = "com.apple.instruments.remoteserver.DVTSecureSocketProxy";
+const DEBUGSERVER_SERVICE: &str = "com.apple.debugserver.DVTSecureSocketProxy";
+const SERVICE_MISSING: &str =
+    "Developer services aren't running. Mount the developer disk image with Xcode and try again.";
+
+/// Asks lockdownd to start the service and connects to it
+async fn connect_service(
+    provider: &DeviceProvider,
+    service: &str,
+) -> Result<Box<dyn ReadWrite>, JitError> {
+    let unreachable = |e: IdeviceError| {
+        warn!("Failed to start {service}: {e:?}");
+        JitError::new(
+            ErrorCode::DeviceUnreachable,
+            format!("Failed to start {service}"),
+        )
+    };
+    let mut lockdown_client = LockdowndClient::connect(provider)
+        .await
+        .map_err(unreachable)?;
+    lockdown_client
+        .start_session(provider.pairing_file())
+        .await
+        .map_err(unreachable)?;
+
+    // Without the developer disk image, lockdownd doesn't know the service
+    let (port, ssl) = match lockdown_client.start_service(service).await {
+        Ok(p) => p,
+        Err(e) => {
+            warn!("Failed to start {service}: {e:?}");
+            return Err(JitError::new(ErrorCode::DdiNotMounted, SERVICE_MISSING));
+        }
+    };
+    debug!("Started {service} on port {port}");
+
+    let mut idevice = provider.connect(port).await.map_err(unreachable)?;
+    if ssl {
+        idevice
+            .start_session(provider.pairing_file())
+            .await
+            .map_err(unreachable)?;
+    }
+    idevice
+        .get_socket()
+        .ok_or_else(|| JitError::internal(format!("Lost the socket to {service}")))
+}
+
+/// Whether lockdownd can start debugserver, which needs the developer disk image
+pub async fn debugserver_available(provider: &DeviceProvider) -> Result<(), JitError> {
+    connect_service(provider, DEBUGSERVER_SERVICE)
+        .await
+        .map(|_| ())
+}
+
+/// Launches the app and, unless it's only being opened, attaches and detaches debugserver
+/// to enable JIT. Returns the PID.
+pub async fn launch(
+    provider: &DeviceProvider,
+    bundle_id: String,
+    options: &LaunchOptions,
+    progress: &Progress,
+) -> Result<u64, JitError> {
+    let socket = connect_service(provider, INSTRUMENTS_SERVICE).await?;
+    let pid = launcher::launch_direct(socket, bundle_id, options.mode, &options.process)
+        .await
+        .map_err(|e| JitError::new(ErrorCode::LaunchFailed, e))?;
+    progress.send(LaunchEvent::Launched { pid });
+    if options.mode == LaunchMode::Open {
+        return Ok(pid);
+    }
+
+    let socket = connect_service(provider, DEBUGSERVER_SERVICE).await?;
+    pipeline::attach_direct(socket, pid, options.mode, progress).await?;
+    Ok(pid)
+}
@@ -1,43 +1,168 @@
 // Jackson Coxson
 // JitStreamer for the year of our Lord, 2025
 
-const VERSION: [u8; 3] = [0, 2, 0];
-
 use std::{
     collections::HashMap,
-    net::{IpAddr, SocketAddr},
-    str::FromStr,
+    future::IntoFuture,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::Arc,
 };
 
 use axum::{
-    extract::{Json, Path, State},
-    http::{header::CONTENT_TYPE, Method},
-    response::Html,
-    routing::{any, get, post},
+    extract::{Json, Path, Query, State, WebSocketUpgrade},
+    http::header::{HeaderName, AUTHORIZATION, CONTENT_TYPE},
+    response::{Html, IntoResponse},
+    routing::{any, delete, get, post, put},
 };
 use axum_client_ip::SecureClientIp;
-use common::get_pairing_file;
-use heartbeat::NewHeartbeatSender;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use error::{ErrorCode, JitError};
+use heartbeat::HeartbeatManager;
 use idevice::{
-    core_device_proxy::CoreDeviceProxy, debug_proxy::DebugProxyClient,
-    installation_proxy::InstallationProxyClient, provider::TcpProvider, IdeviceService,
+    installation_proxy::InstallationProxyClient, springboardservices::SpringBoardServicesClient,
+    IdeviceService,
 };
-use log::{debug, info};
+use provider::DeviceProvider;
 use serde::{Deserialize, Serialize};
-use tower_http::cors::CorsLayer;
-
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::{debug, info, warn};
+
+mod acl;
+mod admin;
+mod api_keys;
+mod apps;
+mod backend;
+mod backup;
+mod bans;
+mod breaker;
+mod captcha;
+mod check;
+mod cli;
+mod client;
+mod client_ip;
+mod cluster;
 mod common;
+mod config;
+mod console;
+mod control;
 mod db;
+mod debug_sessions;
+mod dependencies;
+mod device;
+mod device_queue;
+mod doctor;
+mod error;
+mod events;
+mod favorites;
+mod flood;
+mod grpc;
+mod health;
 mod heartbeat;
+mod history;
+mod i18n;
+mod identity;
+mod inline_launch;
+mod invites;
+mod jit;
+mod lan;
+mod latency;
+mod launch_limit;
+mod launch_status;
+mod launcher;
+mod legacy;
+mod liveness;
+mod lldb;
+mod lockdown_jit;
+mod mdns;
+mod mobileconfig;
+#[cfg(feature = "mock")]
+mod mock;
 mod mount;
+mod muxer;
+mod netmuxd;
+mod notify;
+mod ops;
+mod pair;
+mod pairing_store;
+mod performance;
+mod pipeline;
+mod privacy;
+mod processes;
+mod progress;
+mod provider;
+mod quota;
+mod rate_limit;
 mod raw_packet;
 mod register;
+mod request_id;
+mod retention;
+mod retry;
+mod rsd;
+mod scheduler;
+mod screenshot;
+mod shortcut;
+mod sidejit;
+mod standby;
+mod stats;
+mod status;
+mod subscriptions;
+mod syslog;
+mod systemd;
+mod telemetry;
+mod tls;
+mod tunnel;
+#[cfg(unix)]
+mod unix_socket;
+mod vpn_status;
+mod wake;
+mod wireguard;
 
 #[derive(Clone)]
 struct JitStreamerState {
-    pub new_heartbeat_sender: NewHeartbeatSender,
+    pub db: db::DbPool,
+    /// Every write goes through here, reads use the pool
+    pub db_writer: db::Writer,
+    pub heartbeats: HeartbeatManager,
     pub mount_cache: mount::MountCache,
-    pub pairing_file_storage: String,
+    pub config: config::SharedConfig,
+    pub rsd_cache: rsd::RsdCache,
+    pub apps_cache: apps::AppsCache,
+    pub device_info_cache: device::DeviceInfoCache,
+    pub latency: latency::LatencyTracker,
+    pub udid_cache: common::UdidCache,
+    pub launch_checkpoints: pipeline::CheckpointStore,
+    pub scheduler: scheduler::Scheduler,
+    pub launch_history: history::LaunchHistory,
+    pub rate_limiter: rate_limit::RateLimiter,
+    pub register_cooldowns: rate_limit::Cooldowns,
+    /// Buckets for API keys with a rate limit, by key ID
+    pub key_rate_limiter: rate_limit::RateLimiter<i64>,
+    pub api_key_last_used: api_keys::LastUsed,
+    pub bans: bans::BanList,
+    /// Clients blocked for a while for making too many failed requests
+    pub flood_guard: flood::FloodGuard,
+    pub launch_limiter: launch_limit::LaunchLimiter,
+    /// Runs the requests for each device one at a time
+    pub device_queues: device_queue::DeviceQueues,
+    pub circuit_breaker: breaker::CircuitBreaker,
+    pub debug_sessions: debug_sessions::DebugSessions,
+    pub muxer: muxer::Muxer,
+    pub events: events::EventBus,
+    pub notifier: notify::Notifier,
+    /// WireGuard, usbmuxd or tunneld when they're missing, with their features off
+    pub dependencies: dependencies::Dependencies,
+    pub cluster: cluster::Cluster,
+    /// Real devices, or a mock for tests
+    pub backend: Arc<dyn backend::DeviceBackend>,
+    /// When the server started, for the status page's uptime
+    pub started: std::time::Instant,
+}
+
+impl JitStreamerState {
+    /// The current config, which stays the same for the caller even if it's reloaded
+    pub fn config(&self) -> Arc<config::Config> {
+        self.config.load_full()
+    }
 }
 
 #[tokio::main]
@@ -45,287 +170,1103 @@ async fn main() {
     println!("Starting JitStreamer-EB, enabling logger");
     dotenvy::dotenv().ok();
 
-    // Read the environment variable constants
-    let allow_registration = std::env::var("ALLOW_REGISTRATION")
-        .unwrap_or("1".to_string())
-        .parse::<u8>()
-        .unwrap();
-    let port = std::env::var("JITSTREAMER_PORT")
-        .unwrap_or("9172".to_string())
-        .parse::<u16>()
-        .unwrap();
-    let pairing_file_storage =
-        std::env::var("PLIST_STORAGE").unwrap_or("/var/lib/lockdown".to_string());
-
-    env_logger::init();
+    // Read and validate the settings
+    let config = match config::Config::load() {
+        Ok(c) => c,
+        Err(errors) => {
+            eprintln!("Invalid configuration:");
+            for e in errors {
+                eprintln!("  {e}");
+            }
+            std::process::exit(1);
+        }
+    };
+
+    telemetry::init(
+        config.otlp_endpoint.as_deref(),
+        &config.node_id,
+        config.privacy_salt.as_deref(),
+    );
     info!("Logger initialized");
 
+    if let Some(command) = config::command() {
+        if let Err(e) = cli::run(command, &config).await {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
     // Run the environment checks
-    if allow_registration == 1 {
-        register::check_wireguard();
+    if config.wireguard_registration() && config.role.registers() {
+        for wireguard in &config.wireguard {
+            register::check_wireguard(wireguard);
+        }
+    }
+    let (state, alerts) = build_state(config).await;
+
+    // Missing dependencies only turn off what needs them, /healthz lists what's off
+    dependencies::probe(&state).await;
+
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(state.config.clone()));
+    tokio::spawn(scheduler::run(state.clone()));
+    tokio::spawn(notify::run(state.clone(), alerts));
+    tokio::spawn(dependencies::run(state.clone()));
+    tokio::spawn(cluster::renew_leases(state.clone()));
+    tokio::spawn(cluster::listen(state.clone()));
+    tokio::spawn(standby::run(state.clone()));
+    if let Some(address) = state.config().muxer_socket.clone() {
+        if state.config().role.serves_devices() {
+            tokio::spawn(muxer::serve(address, state.clone()));
+        }
+    }
+
+    let config = state.config();
+    let app = router(&state);
+
+    let (shutdown_sender, shutdown_receiver) = tokio::sync::watch::channel(false);
+    let stopped = |mut receiver: tokio::sync::watch::Receiver<bool>| async move {
+        receiver.wait_for(|s| *s).await.ok();
+    };
+    let mut servers = tokio::task::JoinSet::new();
+
+    #[cfg(unix)]
+    if let Some(path) = config.unix_socket.clone() {
+        let listener =
+            unix_socket::bind(&path, config.unix_socket_mode).expect("Failed to bind UNIX_SOCKET");
+        info!("Starting server on {}", path.display());
+        servers.spawn(
+            axum::serve(listener, unix_socket::app(app.clone()).into_make_service())
+                .with_graceful_shutdown(stopped(shutdown_receiver.clone()))
+                .into_future(),
+        );
+    }
+
+    if config.listen_tcp {
+        let listener = match systemd::listener() {
+            Some(l) => l,
+            None => {
+                let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), config.port);
+                let listener = std::net::TcpListener::bind(addr).expect("Failed to bind the port");
+                listener
+                    .set_nonblocking(true)
+                    .expect("Failed to make the listener non-blocking");
+                listener
+            }
+        };
+        if let Ok(addr) = listener.local_addr() {
+            info!("Starting server on {:?}", addr);
+        }
+        let app = app.into_make_service_with_connect_info::<SocketAddr>();
+        let shutdown = stopped(shutdown_receiver.clone());
+        match config.tls.clone() {
+            Some(tls) => {
+                servers.spawn(tls::serve(
+                    listener,
+                    app,
+                    tls,
+                    shutdown,
+                    SHUTDOWN_DRAIN_TIMEOUT,
+                ));
+            }
+            None => {
+                let listener = tokio::net::TcpListener::from_std(listener)
+                    .expect("Failed to register the listener with tokio");
+                servers.spawn(
+                    axum::serve(listener, app)
+                        .with_graceful_shutdown(shutdown)
+                        .into_future(),
+                );
+            }
+        }
     }
-    if !std::fs::exists("jitstreamer.db").unwrap() {
-        info!("Creating database");
-        let db = sqlite::open("jitstreamer.db").unwrap();
-        db.execute(include_str!("sql/up.sql")).unwrap();
+
+    if let Some(port) = config.grpc_port {
+        let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port);
+        info!("Starting gRPC server on {:?}", addr);
+        servers.spawn(grpc::serve(
+            addr,
+            state.clone(),
+            stopped(shutdown_receiver.clone()),
+        ));
     }
 
+    let advertiser = match config.mdns && config.listen_tcp {
+        true => mdns::Advertiser::start(state.config.clone())
+            .inspect_err(|e| warn!("{e}"))
+            .ok(),
+        false => None,
+    };
+
+    systemd::ready();
+    tokio::task::spawn(async move {
+        shutdown_signal().await;
+        systemd::stopping();
+        shutdown_sender.send(true).ok();
+    });
+
+    // Stop waiting on requests that never finish, such as open mount websockets
+    tokio::select! {
+        _ = async {
+            while let Some(res) = servers.join_next().await {
+                if let Ok(Err(e)) = res {
+                    tracing::error!("Server failed: {e:?}");
+                }
+            }
+        } => {},
+        _ = async {
+            stopped(shutdown_receiver).await;
+            tokio::time::sleep(SHUTDOWN_DRAIN_TIMEOUT).await;
+        } => warn!("Requests didn't finish within {SHUTDOWN_DRAIN_TIMEOUT:?}, shutting down anyway"),
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = &config.unix_socket {
+        std::fs::remove_file(path).ok();
+    }
+    if let Some(advertiser) = advertiser {
+        advertiser.stop();
+    }
+
+    match state.heartbeats.kill_all().await {
+        Ok(killed) => info!("Killed {killed} heartbeats"),
+        Err(e) => warn!("Failed to kill heartbeats: {e}"),
+    }
+    state.db.close().await;
+    telemetry::shutdown();
+    info!("Shut down");
+}
+
+/// Opens the database and builds the state, without starting its background tasks.
+/// Returns the receiver of the alerts for `notify::run`.
+async fn build_state(
+    config: config::Config,
+) -> (
+    JitStreamerState,
+    tokio::sync::mpsc::UnboundedReceiver<notify::Alert>,
+) {
+    let db = db::connect(&config.database_path)
+        .await
+        .expect("Failed to open database");
+    let db_writer = db::Writer::spawn(&db)
+        .await
+        .expect("Failed to open the database writer");
+
+    let bans = bans::BanList::load(&db).await.expect("Failed to load bans");
+    let muxer = muxer::Muxer::load(&db)
+        .await
+        .expect("Failed to load devices for the muxer");
+
+    let (notifier, alerts) = notify::Notifier::channel();
+    let cluster = cluster::Cluster::connect(config.node_id.clone(), config.redis_url.as_deref())
+        .await
+        .expect("Failed to connect to REDIS_URL");
+
     // Create a heartbeat manager
     let state = JitStreamerState {
-        new_heartbeat_sender: heartbeat::heartbeat(),
+        db,
+        db_writer,
+        heartbeats: heartbeat::heartbeat(config.heartbeat.clone(), cluster.clone()),
         mount_cache: mount::MountCache::default(),
-        pairing_file_storage,
+        rsd_cache: rsd::RsdCache::new(config.rsd_cache_ttl),
+        apps_cache: apps::AppsCache::new(config.apps_cache_ttl),
+        device_info_cache: device::DeviceInfoCache::default(),
+        latency: latency::LatencyTracker::default(),
+        udid_cache: common::UdidCache::new(config.udid_cache_ttl),
+        launch_checkpoints: pipeline::CheckpointStore::default(),
+        scheduler: scheduler::Scheduler::default(),
+        launch_history: history::LaunchHistory::default(),
+        rate_limiter: rate_limit::RateLimiter::default(),
+        register_cooldowns: rate_limit::Cooldowns::default(),
+        key_rate_limiter: rate_limit::RateLimiter::default(),
+        api_key_last_used: api_keys::LastUsed::default(),
+        bans,
+        flood_guard: flood::FloodGuard::default(),
+        launch_limiter: launch_limit::LaunchLimiter::new(config.launch_concurrency),
+        device_queues: device_queue::DeviceQueues::default(),
+        circuit_breaker: breaker::CircuitBreaker::default(),
+        debug_sessions: debug_sessions::DebugSessions::default(),
+        muxer,
+        events: events::EventBus::default(),
+        notifier,
+        dependencies: dependencies::Dependencies::default(),
+        cluster,
+        backend: backend::from_config(&config),
+        started: std::time::Instant::now(),
+        config: Arc::new(arc_swap::ArcSwap::from_pointee(config)),
     };
+    (state, alerts)
+}
 
+/// Every route this node serves, with the layers that find the client and its language
+fn router(state: &JitStreamerState) -> axum::Router {
+    let config = state.config();
     let cors = CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
-        .allow_origin(tower_http::cors::Any)
-        .allow_headers([CONTENT_TYPE]);
+        .allow_methods(config.cors_methods.clone())
+        .allow_origin(match config.cors_origins.is_empty() {
+            true => AllowOrigin::any(),
+            false => AllowOrigin::list(config.cors_origins.clone()),
+        })
+        .allow_headers(
+            [
+                CONTENT_TYPE,
+                AUTHORIZATION,
+                HeaderName::from_static(common::DEVICE_TOKEN_HEADER),
+                HeaderName::from_static(common::DEVICE_HEADER),
+                HeaderName::from_static(common::UDID_OVERRIDE_HEADER),
+                HeaderName::from_static(captcha::CAPTCHA_HEADER),
+            ]
+            .into_iter()
+            .chain(config.cors_headers.clone())
+            .collect::<Vec<HeaderName>>(),
+        )
+        .expose_headers([
+            HeaderName::from_static(common::DEVICE_TOKEN_HEADER),
+            HeaderName::from_static(request_id::REQUEST_ID_HEADER),
+            HeaderName::from_static(device_queue::QUEUE_POSITION_HEADER),
+        ]);
+
+    // Routes that talk to the caller's device, run one at a time for each device
+    let queued_routes = axum::Router::new()
+        .route("/mount", get(mount::check_mount))
+        .route("/device_info", get(device_info))
+        .route("/ping_device", get(ping_device))
+        .route("/check", get(check::handler))
+        .route(
+            "/get_apps",
+            get(get_apps).layer(axum::middleware::from_fn_with_state(
+                (state.clone(), rate_limit::Budget::GetApps),
+                rate_limit::enforce,
+            )),
+        )
+        .route(
+            "/launch_app/{bundle_id}",
+            get(launch_app).layer(axum::middleware::from_fn_with_state(
+                (state.clone(), rate_limit::Budget::Launch),
+                rate_limit::enforce,
+            )),
+        )
+        .route(
+            "/launch_name/{name}",
+            get(launch_name).layer(axum::middleware::from_fn_with_state(
+                (state.clone(), rate_limit::Budget::Launch),
+                rate_limit::enforce,
+            )),
+        )
+        .route(
+            "/v2/launch_app",
+            post(launch_app_v2).layer(axum::middleware::from_fn_with_state(
+                (state.clone(), rate_limit::Budget::Launch),
+                rate_limit::enforce,
+            )),
+        )
+        .route("/processes", get(list_processes))
+        .route("/screenshot", get(take_screenshot))
+        .route("/performance/{pid}", get(performance::handler))
+        .route("/attach/{pid}", post(attach_app))
+        .route("/disable_memory_limit/{pid}", post(disable_memory_limit))
+        .route("/attach_name/{name}", post(attach_name))
+        .route("/uninstall/{bundle_id}", post(uninstall_app))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            device_queue::serialize,
+        ));
+
+    // Routes that operate on the caller's device. Websockets and long lived sessions
+    // outlast their request, so they aren't queued.
+    let device_routes = axum::Router::new()
+        .route("/mount_ws", any(mount::handler))
+        .route(
+            "/mount_status",
+            get(|| async { Html(include_str!("mount.html")) }),
+        )
+        .route("/whoami", get(whoami))
+        .route("/devices", get(devices))
+        .route(
+            "/v2/launch_inline",
+            post(inline_launch::handler).layer(axum::middleware::from_fn_with_state(
+                (state.clone(), rate_limit::Budget::Launch),
+                rate_limit::enforce,
+            )),
+        )
+        .route(
+            "/launch_ws/{bundle_id}",
+            any(launch_ws).layer(axum::middleware::from_fn_with_state(
+                (state.clone(), rate_limit::Budget::Launch),
+                rate_limit::enforce,
+            )),
+        )
+        .route("/console_ws/{pid}", any(console::handler))
+        .route("/syslog_ws", any(syslog::handler))
+        .route("/ws", any(control::handler))
+        .route("/events", get(events::handler))
+        .route("/history", get(stats::history))
+        .route("/lldb", post(lldb::handler))
+        .route(
+            "/debug_sessions",
+            get(debug_sessions::list).post(debug_sessions::create),
+        )
+        .route("/debug_sessions/{id}", delete(debug_sessions::release))
+        .route("/launch_status", get(launch_status::handler))
+        .route("/vpn_status", get(vpn_status::handler))
+        .route("/favorites", get(favorites::list))
+        .route(
+            "/subscriptions",
+            get(subscriptions::list).post(subscriptions::create),
+        )
+        .route("/subscriptions/{id}", delete(subscriptions::remove))
+        .route(
+            "/favorites/{bundle_id}",
+            put(favorites::add).delete(favorites::remove),
+        )
+        .route("/status", get(launch_status::legacy)) // will be removed soon
+        .merge(queued_routes);
+    let device_routes = match state.config().sidejit_compat {
+        true => device_routes.merge(sidejit::router(state)),
+        false => device_routes,
+    };
+    let device_routes = device_routes
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            flood::guard,
+        ))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            bans::enforce,
+        ))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            api_keys::enforce,
+        ))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.config.clone(),
+            acl::enforce_device,
+        ))
+        .with_state(state.clone());
 
     // Start with Axum
     let app = axum::Router::new()
         .layer(cors.clone())
         .route("/hello", get(|| async { "Hello, world!" }))
+        .route("/healthz", get(health::healthz).with_state(state.clone()))
+        .route("/stats", get(stats::handler).with_state(state.clone()))
+        .route(
+            "/capacity",
+            get(stats::capacity)
+                .layer(axum::middleware::from_fn_with_state(
+                    (state.clone(), rate_limit::Budget::Capacity),
+                    rate_limit::enforce,
+                ))
+                .with_state(state.clone()),
+        )
+        .route(
+            "/server_status",
+            get(status::handler).with_state(state.clone()),
+        )
+        .route(
+            "/status_page",
+            get(|| async { Html(include_str!("status.html")) }),
+        )
         .route("/version", post(version))
-        .route("/mount", get(mount::check_mount))
-        .route("/mount_ws", any(mount::handler))
         .route(
-            "/mount_status",
-            get(|| async { Html(include_str!("mount.html")) }),
+            "/shortcut",
+            get(shortcut::handler).with_state(state.clone()),
         )
-        .route("/get_apps", get(get_apps))
-        .route("/launch_app/{bundle_id}", get(launch_app))
-        .route("/attach/{pid}", post(attach_app))
-        .route("/status", get(status)) // will be removed soon
-        .with_state(state);
-
-    let app = if allow_registration == 1 {
-        app.route("/register", post(register::register))
-    } else if allow_registration == 2 {
-        app.route("/register", post(register::register))
-            .route("/upload", get(register::upload))
-    } else {
-        app
+        .route(
+            "/openapi.json",
+            get(|| async {
+                (
+                    [(CONTENT_TYPE, "application/json")],
+                    include_str!("openapi.json"),
+                )
+            }),
+        )
+        .route("/docs", get(|| async { Html(include_str!("docs.html")) }));
+    let app = match config.role.serves_devices() {
+        true => app.merge(device_routes),
+        false => app,
     };
 
-    let app = app
+    // Routed for every registration mode, the handlers check it so it can be reloaded
+    let register_routes = axum::Router::new()
+        .route(
+            "/register",
+            post(register::register)
+                .layer(axum::middleware::from_fn_with_state(
+                    (state.clone(), rate_limit::Budget::Register),
+                    rate_limit::enforce,
+                ))
+                .delete(register::unregister),
+        )
+        .route(
+            "/pair",
+            post(pair::pair).layer(axum::middleware::from_fn_with_state(
+                (state.clone(), rate_limit::Budget::Register),
+                rate_limit::enforce,
+            )),
+        )
+        .route("/upload", get(register::upload))
+        .route(
+            "/announce",
+            post(lan::announce).layer(axum::middleware::from_fn_with_state(
+                (state.clone(), rate_limit::Budget::Register),
+                rate_limit::enforce,
+            )),
+        )
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            bans::enforce,
+        ))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            api_keys::enforce,
+        ))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.config.clone(),
+            acl::enforce_register,
+        ))
+        .with_state(state.clone());
+    let app = match config.role.registers() {
+        true => app.merge(register_routes),
+        false => app,
+    };
+    if config.role != config::Role::All {
+        info!("Serving as {}", config.role);
+    }
+
+    // Fleet operations, only enabled with an admin token
+    let app = match state.config().admin_token.clone() {
+        Some(token) => app.merge(
+            axum::Router::new()
+                .route("/admin/batch", post(admin::batch))
+                .route("/admin/devices", get(admin::list_devices))
+                .route("/admin/devices/{udid}", delete(admin::delete_device))
+                .route("/admin/devices/{udid}/kill", post(admin::kill_sessions))
+                .route("/admin/purge", post(admin::purge))
+                .route("/admin/sessions", get(admin::sessions))
+                .route("/admin/sessions/{kind}/{udid}", delete(admin::end_session))
+                .route("/admin/launches", get(admin::launches))
+                .route("/admin/stats", get(admin::stats))
+                .route("/admin/stale", get(admin::stale_devices))
+                .route("/admin/reload", post(admin::reload))
+                .route("/admin/jobs", get(admin::jobs))
+                .route("/admin/jobs/{name}/run", post(admin::run_job))
+                .route(
+                    "/admin/bans",
+                    get(admin::list_bans).post(admin::ban).delete(admin::unban),
+                )
+                .route(
+                    "/admin/blocks",
+                    get(admin::list_blocks).delete(admin::lift_block),
+                )
+                .route(
+                    "/admin/invites",
+                    get(admin::list_invites).post(admin::mint_invites),
+                )
+                .route("/admin/invites/{code}", delete(admin::revoke_invite))
+                .route(
+                    "/admin/api_keys",
+                    get(admin::list_api_keys).post(admin::mint_api_key),
+                )
+                .route("/admin/api_keys/{id}", delete(admin::revoke_api_key))
+                .route("/admin/muxer_devices", get(admin::muxer_devices))
+                .route("/admin/waitlist", get(admin::waitlist))
+                .route(
+                    "/admin/waitlist/{udid}",
+                    delete(admin::remove_from_waitlist),
+                )
+                .route(
+                    "/admin/incidents",
+                    get(admin::list_incidents).post(admin::post_incident),
+                )
+                .route("/admin/incidents/{id}", delete(admin::remove_incident))
+                .route(
+                    "/admin/incidents/{id}/resolve",
+                    post(admin::resolve_incident),
+                )
+                .route_layer(axum::middleware::from_fn_with_state(
+                    state.clone(),
+                    privacy::admin,
+                ))
+                .route_layer(axum::middleware::from_fn_with_state(
+                    (state.clone(), Arc::new(token)),
+                    admin::authorize,
+                ))
+                .with_state(state.clone())
+                // The page asks for the token and sends it with its API calls
+                .route(
+                    "/admin/ui",
+                    get(|| async { Html(include_str!("admin.html")) }),
+                ),
+        ),
+        None => app,
+    };
+
+    app.layer(axum::middleware::from_fn(i18n::middleware))
+        .layer(axum::middleware::from_fn_with_state(
+            state.cluster.clone(),
+            request_id::middleware,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.config.clone(),
+            client_ip::middleware,
+        ))
         .layer(axum_client_ip::SecureClientIpSource::ConnectInfo.into_extension())
-        .layer(cors);
+        .layer(cors)
+}
+
+/// Reloads the config whenever the process gets SIGHUP
+#[cfg(unix)]
+async fn reload_on_sighup(config: config::SharedConfig) {
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("Failed to listen for SIGHUP, config reloading is disabled: {e:?}");
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        info!("SIGHUP received, reloading config");
+        if let Err(errors) = config::Config::reload(&config) {
+            for e in errors {
+                warn!("Not reloading, invalid configuration: {e}");
+            }
+        }
+    }
+}
+
+/// How long in-flight requests get to finish after a shutdown signal
+const SHUTDOWN_DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Resolves on SIGINT or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for ctrl-c");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("Shutdown signal received, no longer accepting requests");
+}
 
-    let addr = SocketAddr::new(IpAddr::from_str("::0").unwrap(), port);
-    info!("Starting server on {:?}", addr);
-    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .await
-    .unwrap();
+/// The apps that talk to the server
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ClientType {
+    /// Shortcuts from before the client was sent are all the official one
+    #[default]
+    Shortcut,
+    SideStore,
+    StikJit,
+}
+
+impl ClientType {
+    /// The oldest version of the client the server works with
+    fn minimum_version(&self) -> [u32; 3] {
+        match self {
+            Self::Shortcut => [0, 2, 0],
+            // Every release so far speaks the current API
+            Self::SideStore | Self::StikJit => [0, 0, 0],
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Shortcut => "the JitStreamer shortcut",
+            Self::SideStore => "SideStore",
+            Self::StikJit => "StikJIT",
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 struct VersionRequest {
     version: String,
+    #[serde(default)]
+    client: ClientType,
 }
-#[derive(Serialize, Deserialize)]
+
+#[derive(Serialize)]
+struct ServerBuild {
+    version: &'static str,
+    /// The commit the server was built from, if it was built from a git checkout
+    git_hash: Option<&'static str>,
+}
+
+#[derive(Serialize)]
 struct VersionResponse {
+    /// The client is new enough
     ok: bool,
+    server: ServerBuild,
+    client: ClientType,
+    minimum_version: String,
+    /// What the user has to update, when the client is too old
+    deprecation: Option<String>,
 }
 
-async fn version(Json(version): Json<VersionRequest>) -> Json<VersionResponse> {
-    info!("Checking version {}", version.version);
+/// Tells the client whether it's new enough for the server, and what the server is
+async fn version(Json(request): Json<VersionRequest>) -> Json<VersionResponse> {
+    info!("Checking {:?} version {}", request.client, request.version);
 
-    // Parse the version as 3 numbers
-    let version = version
+    // Missing or unparsable parts count as 0
+    let version = request
         .version
+        .trim()
         .split('.')
-        .map(|v| v.parse::<u8>().unwrap_or(0))
-        .collect::<Vec<u8>>();
+        .map(|v| v.parse::<u32>().unwrap_or(0))
+        .chain(std::iter::repeat(0))
+        .take(3)
+        .collect::<Vec<u32>>();
+    let minimum = request.client.minimum_version();
+    let ok = version.as_slice() >= minimum.as_slice();
+    let minimum_version = minimum.map(|v| v.to_string()).join(".");
+
+    Json(VersionResponse {
+        ok,
+        server: ServerBuild {
+            version: env!("CARGO_PKG_VERSION"),
+            git_hash: option_env!("JITSTREAMER_GIT_HASH"),
+        },
+        client: request.client,
+        deprecation: match ok {
+            true => None,
+            false => Some(format!(
+                "Version {} of {} is no longer supported, update to {minimum_version} or newer",
+                request.version.trim(),
+                request.client.name()
+            )),
+        },
+        minimum_version,
+    })
+}
+
+#[derive(Serialize)]
+struct DeviceInfoReturn {
+    ok: bool,
+    udid: Option<String>,
+    info: Option<device::DeviceInfo>,
+    #[serde(flatten)]
+    error: Option<JitError>,
+}
 
-    // Compare the version, compare each number
-    for (i, v) in VERSION.iter().enumerate() {
-        if version.get(i).unwrap_or(&0) < v {
-            return Json(VersionResponse { ok: false });
+impl DeviceInfoReturn {
+    fn fail(error: JitError) -> Self {
+        Self {
+            ok: false,
+            udid: None,
+            info: None,
+            error: Some(error),
         }
     }
+}
+
+/// Reports the class and OS version of the caller's device
+async fn device_info(
+    ip: SecureClientIp,
+    selector: common::DeviceSelector,
+    State(state): State<JitStreamerState>,
+) -> Json<DeviceInfoReturn> {
+    let ip = ip.0;
 
-    Json(VersionResponse { ok: true })
+    let udid = match common::get_udid(&state, ip.to_string(), &selector).await {
+        Ok(u) => u,
+        Err(e) => return Json(DeviceInfoReturn::fail(e)),
+    };
+
+    let provider = match state.backend.provider(&state, &udid, ip).await {
+        Ok(provider) => provider,
+        Err(e) => return Json(DeviceInfoReturn::fail(e)),
+    };
+
+    let res = device::get_device_info(&state.device_info_cache, &udid, &provider).await;
+    state.heartbeats.release(&udid).await.ok();
+
+    match res {
+        Ok(info) => Json(DeviceInfoReturn {
+            ok: true,
+            udid: Some(udid),
+            info: Some(info),
+            error: None,
+        }),
+        Err(e) => Json(DeviceInfoReturn::fail(JitError::new(
+            ErrorCode::ServiceFailed,
+            format!("Failed to get device info: {e:?}"),
+        ))),
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-struct GetAppsReturn {
+#[derive(Serialize)]
+struct WhoamiReturn {
     ok: bool,
-    apps: Vec<String>,
-    bundle_ids: Option<HashMap<String, String>>,
-    error: Option<String>,
+    ip: String,
+    udid: Option<String>,
+    latency: Option<latency::LatencyStats>,
+    #[serde(flatten)]
+    error: Option<JitError>,
 }
 
-/// Gets the list of apps with get-task-allow on the device
-///  - Get the IP from the request and UDID from the database
-///  - Send the udid/IP to netmuxd for heartbeat-ing
-///  - Connect to the device and get the list of bundle IDs
-#[axum::debug_handler]
-async fn get_apps(
+/// Reports which device the caller resolves to, and its recent launch latency
+async fn whoami(
     ip: SecureClientIp,
+    selector: common::DeviceSelector,
     State(state): State<JitStreamerState>,
-) -> Json<GetAppsReturn> {
+) -> Json<WhoamiReturn> {
     let ip = ip.0;
+    match common::get_udid(&state, ip.to_string(), &selector).await {
+        Ok(udid) => Json(WhoamiReturn {
+            ok: true,
+            ip: ip.to_string(),
+            latency: Some(state.latency.stats(&udid).await),
+            udid: Some(udid),
+            error: None,
+        }),
+        Err(e) => Json(WhoamiReturn {
+            ok: false,
+            ip: ip.to_string(),
+            udid: None,
+            latency: None,
+            error: Some(e),
+        }),
+    }
+}
 
-    info!("Got request to get apps from {:?}", ip);
+#[derive(Serialize)]
+struct PingDeviceReturn {
+    ok: bool,
+    udid: Option<String>,
+    liveness: Option<liveness::Liveness>,
+    #[serde(flatten)]
+    error: Option<JitError>,
+}
 
-    let udid = match common::get_udid_from_ip(ip.to_string()).await {
+/// Checks if the caller's device is reachable without starting a heartbeat
+async fn ping_device(
+    ip: SecureClientIp,
+    selector: common::DeviceSelector,
+    State(state): State<JitStreamerState>,
+) -> Json<PingDeviceReturn> {
+    let ip = ip.0;
+    let udid = match common::get_udid(&state, ip.to_string(), &selector).await {
         Ok(u) => u,
         Err(e) => {
-            return Json(GetAppsReturn {
+            return Json(PingDeviceReturn {
                 ok: false,
-                apps: Vec::new(),
-                bundle_ids: None,
+                udid: None,
+                liveness: None,
                 error: Some(e),
             })
         }
     };
 
-    // Get the pairing file
-    debug!("Getting pairing file for {udid}");
-    let pairing_file = match get_pairing_file(&udid, &state.pairing_file_storage).await {
-        Ok(pairing_file) => pairing_file,
+    let liveness = liveness::probe(&state.heartbeats, &udid, ip).await;
+    Json(PingDeviceReturn {
+        ok: liveness.alive,
+        udid: Some(udid),
+        liveness: Some(liveness),
+        error: (!liveness.alive)
+            .then(|| JitError::new(ErrorCode::DeviceUnreachable, "Device is not reachable")),
+    })
+}
+
+#[derive(Serialize)]
+struct RegisteredDevice {
+    udid: String,
+    last_used: String,
+    info: Option<device::DeviceInfo>,
+}
+
+#[derive(Serialize)]
+struct DevicesReturn {
+    ok: bool,
+    devices: Vec<RegisteredDevice>,
+    #[serde(flatten)]
+    error: Option<JitError>,
+}
+
+/// Lists the devices registered from the caller's IP, so it can pick one
+/// with the X-JitStreamer-Device header
+async fn devices(ip: SecureClientIp, State(state): State<JitStreamerState>) -> Json<DevicesReturn> {
+    let devices = match common::get_devices_for_ip(&state.db, &ip.0.to_string()).await {
+        Ok(d) => d,
         Err(e) => {
-            info!("Failed to get pairing file: {:?}", e);
-            return Json(GetAppsReturn {
+            return Json(DevicesReturn {
                 ok: false,
-                apps: Vec::new(),
-                bundle_ids: None,
-                error: Some(format!("Failed to get pairing file: {:?}", e)),
-            });
+                devices: Vec::new(),
+                error: Some(e),
+            })
         }
     };
 
-    // Heartbeat the device
-    match heartbeat::heartbeat_thread(udid.clone(), ip, &pairing_file).await {
-        Ok(s) => {
-            state
-                .new_heartbeat_sender
-                .send(heartbeat::SendRequest::Store((udid.clone(), s)))
-                .await
-                .unwrap();
+    let info_cache = state.device_info_cache.lock().await;
+    Json(DevicesReturn {
+        ok: true,
+        devices: devices
+            .into_iter()
+            .map(|(udid, last_used)| RegisteredDevice {
+                info: info_cache.get(&udid).cloned(),
+                udid,
+                last_used,
+            })
+            .collect(),
+        error: None,
+    })
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct GetAppsReturn {
+    ok: bool,
+    apps: Vec<String>,
+    bundle_ids: Option<HashMap<String, String>>,
+    /// Version, executable and debuggability by bundle ID
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    details: Option<HashMap<String, apps::AppDetails>>,
+    /// Base64 PNGs by bundle ID, when requested with `icons=true`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    icons: Option<HashMap<String, String>>,
+    #[serde(flatten)]
+    error: Option<JitError>,
+}
+
+#[derive(Deserialize, Default)]
+struct GetAppsOptions {
+    #[serde(default)]
+    icons: bool,
+    /// Skip the cache, such as after installing an app
+    #[serde(default)]
+    refresh: bool,
+    /// Include system apps
+    #[serde(default)]
+    system: bool,
+    /// Include apps without get-task-allow, such as TrollStore-signed ones
+    #[serde(default)]
+    all: bool,
+}
+
+impl GetAppsReturn {
+    fn listed(list: apps::AppList, client: &client::Client) -> Self {
+        let mut apps = list.bundle_ids;
+        // Shortcuts from before /shortcut existed learn about updates from this entry.
+        // Newer ones send X-Client and check /shortcut themselves.
+        if client.name().is_none() {
+            apps.insert("Other...".to_string(), "UPDATE YOUR SHORTCUT".to_string());
         }
-        Err(e) => {
-            let e = match e {
-                idevice::IdeviceError::InvalidHostID => {
-                    "your pairing file is invalid. Regenerate it with jitterbug pair.".to_string()
-                }
-                _ => e.to_string(),
-            };
-            info!("Failed to heartbeat device: {:?}", e);
-            return Json(GetAppsReturn {
-                ok: false,
-                apps: Vec::new(),
-                bundle_ids: None,
-                error: Some(format!("Failed to heartbeat device: {e}")),
-            });
+        GetAppsReturn {
+            ok: true,
+            apps: apps.keys().map(|x| x.to_string()).collect(),
+            bundle_ids: Some(apps),
+            details: Some(list.details),
+            icons: list.icons,
+            error: None,
         }
     }
+}
 
-    // Connect to the device and get the list of bundle IDs
-    debug!("Connecting to device {udid} to get apps");
+/// Gets the list of apps with get-task-allow on the device
+///  - Get the IP from the request and UDID from the database
+///  - Heartbeat the device
+///  - Connect to the device and get the list of bundle IDs
+#[axum::debug_handler]
+async fn get_apps(
+    ip: SecureClientIp,
+    selector: common::DeviceSelector,
+    client: client::Client,
+    Query(options): Query<GetAppsOptions>,
+    State(state): State<JitStreamerState>,
+) -> Json<GetAppsReturn> {
+    let ip = ip.0;
 
-    let provider = TcpProvider {
-        addr: ip,
-        pairing_file,
-        label: "JitStreamer-EB".to_string(),
-    };
+    info!("Got request to get apps from {:?}", ip);
 
-    let mut instproxy_client = match InstallationProxyClient::connect(&provider).await {
-        Ok(i) => i,
-        Err(e) => {
-            return Json(GetAppsReturn {
-                ok: false,
-                apps: Vec::new(),
-                bundle_ids: None,
-                error: Some(format!("Failed to start instproxy: {e:?}")),
-            })
+    let (udid, ip) =
+        match common::get_device(&state, ip, &selector, state.config().allow_udid_override).await {
+            Ok(d) => d,
+            Err(e) => {
+                return Json(GetAppsReturn {
+                    ok: false,
+                    apps: Vec::new(),
+                    bundle_ids: None,
+                    details: None,
+                    icons: None,
+                    error: Some(e),
+                })
+            }
+        };
+
+    // Only the default list is cached
+    let cacheable = !options.system && !options.all;
+    if cacheable && !options.refresh {
+        if let Some(list) = state.apps_cache.get(&udid, options.icons).await {
+            debug!("Using cached apps for {udid}");
+            prewarm_tunnel(&state, &udid, ip);
+            return Json(GetAppsReturn::listed(list, &client));
         }
-    };
+    }
 
-    let apps = match instproxy_client
-        .get_apps(Some("User".to_string()), None)
-        .await
-    {
-        Ok(apps) => apps,
+    let get_apps_timeout = state.config().device_timeouts.get_apps;
+    let details = match state.backend.apps(&state, &udid, ip, options.system).await {
+        Ok(d) => d,
         Err(e) => {
-            info!("Failed to get apps: {:?}", e);
+            state.heartbeats.release(&udid).await.ok();
             return Json(GetAppsReturn {
                 ok: false,
                 apps: Vec::new(),
                 bundle_ids: None,
-                error: Some(format!("Failed to get apps: {:?}", e)),
+                details: None,
+                icons: None,
+                error: Some(e),
             });
         }
     };
-    let mut apps: HashMap<String, String> = apps
+    let details: HashMap<String, apps::AppDetails> = details
         .into_iter()
-        .filter(|(_, app)| {
-            // Filter out apps that don't have get-task-allow
-            let app = match app {
-                plist::Value::Dictionary(app) => app,
-                _ => return false,
-            };
-
-            match app.get("Entitlements") {
-                Some(plist::Value::Dictionary(entitlements)) => {
-                    matches!(
-                        entitlements.get("get-task-allow"),
-                        Some(plist::Value::Boolean(true))
-                    )
-                }
-                _ => false,
-            }
-        })
-        .map(|(bundle_id, app)| {
-            let name = match app {
-                plist::Value::Dictionary(mut d) => match d.remove("CFBundleName") {
-                    Some(plist::Value::String(bundle_name)) => bundle_name,
-                    _ => bundle_id.clone(),
-                },
-                _ => bundle_id.clone(),
-            };
-            (name.clone(), bundle_id)
-        })
+        // Filter out apps that don't have get-task-allow
+        .filter(|(_, app)| options.all || app.is_debuggable)
         .collect();
+    let apps = apps::names(&details);
 
     if apps.is_empty() {
+        state.heartbeats.release(&udid).await.ok();
         return Json(GetAppsReturn {
             ok: false,
             apps: Vec::new(),
             bundle_ids: None,
-            error: Some("No apps with get-task-allow found".to_string()),
+            details: None,
+            icons: None,
+            error: Some(JitError::new(
+                ErrorCode::NoDebuggableApps,
+                "No apps with get-task-allow found",
+            )),
         });
     }
 
-    apps.insert("Other...".to_string(), "UPDATE YOUR SHORTCUT".to_string());
-
-    state
-        .new_heartbeat_sender
-        .send(heartbeat::SendRequest::Kill(udid.clone()))
+    // Icons are extras, the list is still returned if they time out
+    let icons = match options.icons {
+        true => tokio::time::timeout(
+            get_apps_timeout,
+            state
+                .backend
+                .icons(&state, &udid, ip, apps.values().cloned().collect()),
+        )
         .await
-        .unwrap();
+        .inspect_err(|_| warn!("Timed out getting app icons for {udid}"))
+        .ok()
+        .and_then(|icons| icons.ok()),
+        false => None,
+    };
 
-    Json(GetAppsReturn {
-        ok: true,
-        apps: apps.keys().map(|x| x.to_string()).collect(),
-        bundle_ids: Some(apps),
-        error: None,
-    })
+    state.heartbeats.release(&udid).await.ok();
+
+    let list = apps::AppList {
+        bundle_ids: apps,
+        details,
+        icons,
+    };
+    if cacheable {
+        state.apps_cache.insert(&udid, list.clone()).await;
+    }
+    prewarm_tunnel(&state, &udid, ip);
+    Json(GetAppsReturn::listed(list, &client))
+}
+
+/// Opens the device's tunnel in the background, since the shortcut launches right after
+/// listing apps. It takes its turn in the device's queue, so that launch waits for it.
+fn prewarm_tunnel(state: &JitStreamerState, udid: &str, ip: IpAddr) {
+    if !state.config().prewarm_tunnels {
+        return;
+    }
+    let state = state.clone();
+    let udid = udid.to_string();
+    tokio::spawn(async move {
+        let Ok((_turn, _)) = state.device_queues.wait(&udid).await else {
+            return;
+        };
+        if let Err(e) = state.backend.prewarm(&state, &udid, ip).await {
+            debug!("Failed to prewarm a tunnel for {udid}: {e}");
+        }
+        state.heartbeats.release(&udid).await.ok();
+    });
+}
+
+/// Fetches the home screen icons of apps, skipping any that fail
+async fn app_icons(
+    provider: &DeviceProvider,
+    bundle_ids: impl Iterator<Item = &String>,
+) -> HashMap<String, String> {
+    let mut client = match SpringBoardServicesClient::connect(provider).await {
+        Ok(c) => c,
+        Err(e) => {
+            warn!("Failed to start springboard services: {e:?}");
+            return HashMap::new();
+        }
+    };
+    let mut icons = HashMap::new();
+    for bundle_id in bundle_ids {
+        match client.get_icon_pngdata(bundle_id.clone()).await {
+            Ok(png) => {
+                icons.insert(bundle_id.clone(), BASE64_STANDARD.encode(png));
+            }
+            Err(e) => debug!("Failed to get icon for {bundle_id}: {e:?}"),
+        }
+    }
+    icons
 }
 
 #[derive(Serialize, Deserialize)]
 struct LaunchAppReturn {
     ok: bool,
-    launching: bool,
-    position: Option<usize>,
-    error: Option<String>,
-    mounting: bool, // NOTICE: this field does literally nothing and will be removed in future
-                    // versions
+    pid: Option<u64>,
+    #[serde(flatten)]
+    error: Option<JitError>,
+    /// Too many launches are running, retry shortly
+    busy: bool,
+    /// Only for clients that expect the original JitStreamer's response
+    #[serde(flatten)]
+    legacy: Option<client::LegacyLaunchFields>,
+}
+
+impl LaunchAppReturn {
+    fn fail(error: JitError) -> Self {
+        Self {
+            ok: false,
+            pid: None,
+            error: Some(error),
+            busy: false,
+            legacy: None,
+        }
+    }
+}
+
+impl From<Result<u64, JitError>> for LaunchAppReturn {
+    fn from(res: Result<u64, JitError>) -> Self {
+        match res {
+            Ok(pid) => Self {
+                ok: true,
+                pid: Some(pid),
+                error: None,
+                busy: false,
+                legacy: None,
+            },
+            Err(e) => Self {
+                busy: e.code == ErrorCode::Busy,
+                ..Self::fail(e)
+            },
+        }
+    }
 }
 
 ///  - Get the IP from the request and UDID from the database
@@ -333,309 +1274,179 @@ struct LaunchAppReturn {
 ///  - Connect to tunneld and get the interface and port for the developer service
 ///  - Send the commands to launch the app and detach
 ///  - Set last_used to now in the database
+///
+/// Pass `?mode=open` to only open the app without attaching debugserver
 async fn launch_app(
     ip: SecureClientIp,
+    selector: common::DeviceSelector,
+    client: client::Client,
     Path(bundle_id): Path<String>,
+    Query(options): Query<launcher::LaunchOptions>,
     State(state): State<JitStreamerState>,
 ) -> Json<LaunchAppReturn> {
-    let ip = ip.0;
-
-    info!("Got request to launch {bundle_id} from {:?}", ip);
-
-    let udid = match common::get_udid_from_ip(ip.to_string()).await {
-        Ok(u) => u,
-        Err(e) => {
-            return Json(LaunchAppReturn {
-                ok: false,
-                error: Some(e),
-                launching: false,
-                position: None,
-                mounting: false,
-            })
-        }
-    };
+    let res = jit::JitSession::new(&state, ip.0, &selector)
+        .client(client.name())
+        .launch(bundle_id, options)
+        .await;
+    let mut res = LaunchAppReturn::from(res);
+    res.legacy = client.profile().launch_fields(res.ok);
+    Json(res)
+}
 
-    // Get the pairing file
-    debug!("Getting pairing file for {udid}");
-    let pairing_file = match get_pairing_file(&udid, &state.pairing_file_storage).await {
-        Ok(pairing_file) => pairing_file,
-        Err(e) => {
-            info!("Failed to get pairing file: {:?}", e);
-            return Json(LaunchAppReturn {
-                ok: false,
-                launching: false,
-                position: None,
-                mounting: false,
-                error: Some(format!("Failed to get pairing file: {:?}", e)),
-            });
-        }
+/// Like `/launch_app`, but takes the app's name as shown by `/get_apps`
+async fn launch_name(
+    ip: SecureClientIp,
+    selector: common::DeviceSelector,
+    client: client::Client,
+    Path(name): Path<String>,
+    Query(options): Query<launcher::LaunchOptions>,
+    State(state): State<JitStreamerState>,
+) -> Json<LaunchAppReturn> {
+    info!("Got request to launch {name} by name from {:?}", ip.0);
+    let mut res = match resolve_app_name(ip.0, &selector, &name, &state).await {
+        Ok(bundle_id) => jit::JitSession::new(&state, ip.0, &selector)
+            .client(client.name())
+            .launch(bundle_id, options)
+            .await
+            .into(),
+        Err(e) => LaunchAppReturn::fail(e),
     };
+    res.legacy = client.profile().launch_fields(res.ok);
+    Json(res)
+}
 
-    // Heartbeat the device
-    match heartbeat::heartbeat_thread(udid.clone(), ip, &pairing_file).await {
-        Ok(s) => {
-            state
-                .new_heartbeat_sender
-                .send(heartbeat::SendRequest::Store((udid.clone(), s)))
-                .await
-                .unwrap();
-        }
-        Err(e) => {
-            let e = match e {
-                idevice::IdeviceError::InvalidHostID => {
-                    "your pairing file is invalid. Regenerate it with jitterbug pair.".to_string()
-                }
-                _ => e.to_string(),
-            };
-            info!("Failed to heartbeat device: {:?}", e);
-            return Json(LaunchAppReturn {
-                ok: false,
-                launching: false,
-                position: None,
-                mounting: false,
-                error: Some(format!("Failed to heartbeat device: {e}")),
-            });
+/// Finds the bundle ID of a debuggable app by name, using the cached app list when it
+/// has the app. A miss refreshes the list, in case the app was installed since.
+async fn resolve_app_name(
+    ip: IpAddr,
+    selector: &common::DeviceSelector,
+    name: &str,
+    state: &JitStreamerState,
+) -> Result<String, JitError> {
+    let (udid, device_ip) =
+        common::get_device(state, ip, selector, state.config().allow_udid_override).await?;
+    if let Some(list) = state.apps_cache.get(&udid, false).await {
+        if let Some(bundle_id) = list.bundle_id(name) {
+            return Ok(bundle_id.clone());
         }
     }
 
-    let provider = TcpProvider {
-        addr: ip,
-        pairing_file,
-        label: "JitStreamer-EB".to_string(),
-    };
-
-    let proxy = match CoreDeviceProxy::connect(&provider).await {
-        Ok(p) => p,
-        Err(e) => {
-            info!("Failed to proxy device: {:?}", e);
-            return Json(LaunchAppReturn {
-                ok: false,
-                launching: false,
-                position: None,
-                mounting: false,
-                error: Some(format!("Failed to start core device proxy: {e}")),
-            });
-        }
-    };
-    let rsd_port = proxy.handshake.server_rsd_port;
-    let mut adapter = match proxy.create_software_tunnel() {
-        Ok(a) => a,
-        Err(e) => {
-            info!("Failed to create software tunnel: {:?}", e);
-            return Json(LaunchAppReturn {
-                ok: false,
-                launching: false,
-                position: None,
-                mounting: false,
-                error: Some(format!("Failed to create software tunnel: {e}")),
-            });
-        }
+    let details = state.backend.apps(state, &udid, device_ip, false).await;
+    state.heartbeats.release(&udid).await.ok();
+    let details: HashMap<String, apps::AppDetails> = details?
+        .into_iter()
+        .filter(|(_, app)| app.is_debuggable)
+        .collect();
+    let list = apps::AppList {
+        bundle_ids: apps::names(&details),
+        details,
+        icons: None,
     };
+    state.apps_cache.insert(&udid, list.clone()).await;
 
-    if let Err(e) = adapter.connect(rsd_port).await {
-        info!("Failed to connect to RemoteXPC port: {:?}", e);
-        return Json(LaunchAppReturn {
-            ok: false,
-            launching: false,
-            position: None,
-            mounting: false,
-            error: Some(format!("Failed to connect to RemoteXPC port: {e}")),
-        });
-    }
-
-    let xpc_client = match idevice::xpc::XPCDevice::new(adapter).await {
-        Ok(x) => x,
-        Err(e) => {
-            log::warn!("Failed to connect to RemoteXPC: {e:?}");
-            return Json(LaunchAppReturn {
-                ok: false,
-                error: Some("Failed to connect to RemoteXPC".to_string()),
-                launching: false,
-                position: None,
-                mounting: false,
-            });
-        }
-    };
+    list.bundle_id(name).cloned().ok_or_else(|| {
+        JitError::new(
+            ErrorCode::AppNotFound,
+            format!("No debuggable app is named {name}"),
+        )
+    })
+}
 
-    let dvt_port = match xpc_client.services.get(idevice::dvt::SERVICE_NAME) {
-        Some(s) => s.port,
-        None => {
-            return Json(LaunchAppReturn {
-                ok: false,
-                error: Some(
-                    "Device did not contain DVT service. Is the image mounted?".to_string(),
-                ),
-                launching: false,
-                position: None,
-                mounting: false,
-            });
+/// Like `/launch_app`, but streams each phase of the launch as it completes
+async fn launch_ws(
+    ws: WebSocketUpgrade,
+    ip: SecureClientIp,
+    selector: common::DeviceSelector,
+    Path(bundle_id): Path<String>,
+    Query(options): Query<launcher::LaunchOptions>,
+    language: i18n::Language,
+    client: client::Client,
+    State(state): State<JitStreamerState>,
+) -> axum::response::Response {
+    ws.on_upgrade(move |mut socket| async move {
+        let (progress, mut events) = progress::Progress::channel();
+        let session = jit::JitSession::new(&state, ip.0, &selector)
+            .client(client.name())
+            .progress(&progress);
+        let launch = session.launch(bundle_id, options);
+        tokio::pin!(launch);
+
+        // Keep launching if the client goes away, the app would be left suspended otherwise
+        let res = loop {
+            tokio::select! {
+                res = &mut launch => break res,
+                Some(event) = events.recv() => {
+                    socket.send(event.to_ws_message()).await.ok();
+                }
+            }
+        };
+        while let Ok(event) = events.try_recv() {
+            socket.send(event.to_ws_message()).await.ok();
         }
-    };
-    let debug_proxy_port = match xpc_client.services.get(idevice::debug_proxy::SERVICE_NAME) {
-        Some(s) => s.port,
-        None => {
-            return Json(LaunchAppReturn {
-                ok: false,
-                error: Some(
-                    "Device did not contain debug server service. Is the image mounted?"
-                        .to_string(),
-                ),
-                launching: false,
-                position: None,
-                mounting: false,
-            });
+        if let Err(error) = res {
+            let event = progress::LaunchEvent::Error {
+                busy: error.code == ErrorCode::Busy,
+                error: language.localize(error),
+            };
+            socket.send(event.to_ws_message()).await.ok();
         }
-    };
-
-    let mut adapter = xpc_client.into_inner();
-    if let Err(e) = adapter.close().await {
-        log::warn!("Failed to close RemoteXPC port: {e:?}");
-        return Json(LaunchAppReturn {
-            ok: false,
-            error: Some("Failed to close RemoteXPC port".to_string()),
-            launching: false,
-            position: None,
-            mounting: false,
-        });
-    }
+        socket.close().await.ok();
+    })
+}
 
-    info!("Connecting to DVT port");
-    if let Err(e) = adapter.connect(dvt_port).await {
-        log::warn!("Failed to connect to DVT port: {e:?}");
-        return Json(LaunchAppReturn {
-            ok: false,
-            error: Some("Failed to connect to DVT port".to_string()),
-            launching: false,
-            position: None,
-            mounting: false,
-        });
-    }
+#[derive(Serialize)]
+struct LaunchV2Return {
+    ok: bool,
+    pid: Option<u64>,
+    timings: Vec<progress::PhaseTiming>,
+    phases: progress::PhaseDurations,
+    busy: bool,
+    #[serde(flatten)]
+    error: Option<JitError>,
+}
 
-    let mut rs_client = match idevice::dvt::remote_server::RemoteServerClient::new(adapter) {
-        Ok(r) => r,
-        Err(e) => {
-            log::warn!("Failed to create remote server client: {e:?}");
-            return Json(LaunchAppReturn {
-                ok: false,
-                error: Some(format!("Failed to create remote server client: {e:?}")),
-                launching: false,
-                position: None,
-                mounting: false,
-            });
-        }
+/// Launches with the options in the JSON body, such as arguments and environment variables,
+/// and reports when each phase of the launch completed
+async fn launch_app_v2(
+    ip: SecureClientIp,
+    selector: common::DeviceSelector,
+    client: client::Client,
+    State(state): State<JitStreamerState>,
+    Json(request): Json<launcher::LaunchRequest>,
+) -> Json<LaunchV2Return> {
+    let started = std::time::Instant::now();
+    let (bundle_id, options) = request.into_parts();
+    let (progress, mut events) = progress::Progress::channel();
+    let session = jit::JitSession::new(&state, ip.0, &selector)
+        .client(client.name())
+        .progress(&progress);
+    let launch = session.launch(bundle_id, options);
+    tokio::pin!(launch);
+
+    let mut timings = Vec::new();
+    let mut record = |event: progress::LaunchEvent| {
+        timings.push(progress::PhaseTiming {
+            phase: event.phase(),
+            elapsed_ms: started.elapsed().as_millis() as u64,
+        })
     };
-    if let Err(e) = rs_client.read_message(0).await {
-        log::warn!("Failed to read first message from remote server client: {e:?}");
-        return Json(LaunchAppReturn {
-            ok: false,
-            error: Some(format!(
-                "Failed to read first message from remote server client: {e:?}"
-            )),
-            launching: false,
-            position: None,
-            mounting: false,
-        });
-    }
-
-    let mut pc_client =
-        match idevice::dvt::process_control::ProcessControlClient::new(&mut rs_client).await {
-            Ok(p) => p,
-            Err(e) => {
-                log::warn!("Failed to create process control client: {e:?}");
-                return Json(LaunchAppReturn {
-                    ok: false,
-                    error: Some(format!("Failed to create process control client: {e:?}")),
-                    launching: false,
-                    position: None,
-                    mounting: false,
-                });
-            }
-        };
-
-    let pid = match pc_client
-        .launch_app(bundle_id, None, None, true, false)
-        .await
-    {
-        Ok(p) => p,
-        Err(e) => {
-            log::warn!("Failed to launch app: {e:?}");
-            return Json(LaunchAppReturn {
-                ok: false,
-                error: Some(format!("Failed to launch app: {e:?}")),
-                launching: false,
-                position: None,
-                mounting: false,
-            });
+    let res = loop {
+        tokio::select! {
+            res = &mut launch => break res,
+            Some(event) = events.recv() => record(event),
         }
     };
-    debug!("Launched app with PID {pid}");
-    if let Err(e) = pc_client.disable_memory_limit(pid).await {
-        log::warn!("Failed to disable memory limit: {e:?}")
-    }
-
-    let mut adapter = rs_client.into_inner();
-    if let Err(e) = adapter.close().await {
-        log::warn!("Failed to close DVT port: {e:?}");
-        return Json(LaunchAppReturn {
-            ok: false,
-            error: Some("Failed to close RemoteXPC port".to_string()),
-            launching: false,
-            position: None,
-            mounting: false,
-        });
-    }
-
-    info!("Connecting to debug proxy port: {debug_proxy_port}");
-    if let Err(e) = adapter.connect(debug_proxy_port).await {
-        log::warn!("Failed to connect to debug proxy port: {e:?}");
-        return Json(LaunchAppReturn {
-            ok: false,
-            error: Some("Failed to connect to debug proxy port".to_string()),
-            launching: false,
-            position: None,
-            mounting: false,
-        });
-    }
-
-    let mut dp = DebugProxyClient::new(adapter);
-    let commands = [
-        format!("vAttach;{pid:02X}"),
-        "D".to_string(),
-        "D".to_string(),
-        "D".to_string(),
-        "D".to_string(),
-    ];
-    for command in commands {
-        match dp.send_command(command.into()).await {
-            Ok(res) => {
-                debug!("command res: {res:?}");
-            }
-            Err(e) => {
-                log::warn!("Failed to send command to debug server: {e:?}");
-                return Json(LaunchAppReturn {
-                    ok: false,
-                    error: Some(format!("Failed to send command to debug server: {e:?}")),
-                    launching: false,
-                    position: None,
-                    mounting: false,
-                });
-            }
-        }
+    while let Ok(event) = events.try_recv() {
+        record(event);
     }
 
-    debug!("JIT finished, killing heartbeat");
-    state
-        .new_heartbeat_sender
-        .send(heartbeat::SendRequest::Kill(udid.clone()))
-        .await
-        .unwrap();
-
-    Json(LaunchAppReturn {
-        ok: true,
-        error: None,
-        launching: true,   // true for compatibility reasons, will be removed
-        position: Some(0), // compat field
-        mounting: false,
+    Json(LaunchV2Return {
+        ok: res.is_ok(),
+        pid: res.as_ref().ok().copied(),
+        phases: progress::PhaseDurations::from_timings(&timings),
+        timings,
+        busy: res.as_ref().is_err_and(|e| e.code == ErrorCode::Busy),
+        error: res.err(),
     })
 }
 
@@ -644,177 +1455,278 @@ async fn launch_app(
 struct AttachReturn {
     success: bool,
     message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<ErrorCode>,
 }
 
 impl AttachReturn {
-    fn fail(message: String) -> Self {
+    fn fail(error: JitError) -> Self {
         Self {
             success: false,
-            message,
+            message: error.message,
+            code: Some(error.code),
+        }
+    }
+}
+
+impl From<Result<(), JitError>> for AttachReturn {
+    fn from(res: Result<(), JitError>) -> Self {
+        match res {
+            Ok(()) => AttachReturn {
+                success: true,
+                message: "".to_string(),
+                code: None,
+            },
+            Err(e) => AttachReturn::fail(e),
         }
     }
 }
 
 async fn attach_app(
     ip: SecureClientIp,
+    selector: common::DeviceSelector,
     Path(pid): Path<u16>,
     State(state): State<JitStreamerState>,
 ) -> Json<AttachReturn> {
-    let ip = ip.0;
+    let res = jit::JitSession::new(&state, ip.0, &selector)
+        .attach(pid as u64)
+        .await;
+    Json(res.into())
+}
 
-    info!("Got request to attach {pid} from {:?}", ip);
+/// Attaches to a running process by name, since PIDs change on every launch
+async fn attach_name(
+    ip: SecureClientIp,
+    selector: common::DeviceSelector,
+    Path(name): Path<String>,
+    State(state): State<JitStreamerState>,
+) -> Json<AttachReturn> {
+    let res = jit::JitSession::new(&state, ip.0, &selector)
+        .attach_name(&name)
+        .await;
+    Json(res.into())
+}
 
-    let udid = match common::get_udid_from_ip(ip.to_string()).await {
-        Ok(u) => u,
-        Err(e) => return Json(AttachReturn::fail(e)),
-    };
+#[derive(Serialize)]
+struct ProcessesReturn {
+    ok: bool,
+    processes: Vec<processes::RunningProcess>,
+    #[serde(flatten)]
+    error: Option<JitError>,
+}
 
-    // Get the pairing file
-    debug!("Getting pairing file for {udid}");
-    let pairing_file = match get_pairing_file(&udid, &state.pairing_file_storage).await {
-        Ok(pairing_file) => pairing_file,
-        Err(e) => {
-            info!("Failed to get pairing file: {:?}", e);
-            return Json(AttachReturn::fail(format!(
-                "Failed to get pairing file: {:?}",
-                e
-            )));
-        }
-    };
+/// Lists the processes running on the caller's device, to pick one to attach to
+async fn list_processes(
+    ip: SecureClientIp,
+    selector: common::DeviceSelector,
+    State(state): State<JitStreamerState>,
+) -> Json<ProcessesReturn> {
+    let res = jit::JitSession::new(&state, ip.0, &selector)
+        .processes()
+        .await;
+    Json(match res {
+        Ok(processes) => ProcessesReturn {
+            ok: true,
+            processes,
+            error: None,
+        },
+        Err(e) => ProcessesReturn {
+            ok: false,
+            processes: Vec::new(),
+            error: Some(e),
+        },
+    })
+}
 
-    // Heartbeat the device
-    match heartbeat::heartbeat_thread(udid.clone(), ip, &pairing_file).await {
-        Ok(s) => {
-            state
-                .new_heartbeat_sender
-                .send(heartbeat::SendRequest::Store((udid.clone(), s)))
-                .await
-                .unwrap();
-        }
-        Err(e) => {
-            let e = match e {
-                idevice::IdeviceError::InvalidHostID => {
-                    "your pairing file is invalid. Regenerate it with jitterbug pair.".to_string()
-                }
-                _ => e.to_string(),
-            };
-            info!("Failed to heartbeat device: {:?}", e);
-            return Json(AttachReturn::fail(format!(
-                "Failed to heartbeat device: {e}"
-            )));
-        }
-    }
+#[derive(Deserialize)]
+struct UninstallOptions {
+    /// Must repeat the bundle ID, guarding against stray requests
+    confirm: Option<String>,
+}
 
-    let provider = TcpProvider {
-        addr: ip,
-        pairing_file,
-        label: "JitStreamer-EB".to_string(),
-    };
+#[derive(Serialize)]
+struct UninstallReturn {
+    ok: bool,
+    #[serde(flatten)]
+    error: Option<JitError>,
+}
 
-    let proxy = match CoreDeviceProxy::connect(&provider).await {
-        Ok(p) => p,
-        Err(e) => {
-            info!("Failed to proxy device: {:?}", e);
-            return Json(AttachReturn::fail(format!(
-                "Failed to start core device proxy: {e}"
-            )));
-        }
-    };
-    let rsd_port = proxy.handshake.server_rsd_port;
-    let mut adapter = match proxy.create_software_tunnel() {
-        Ok(a) => a,
-        Err(e) => {
-            info!("Failed to create software tunnel: {:?}", e);
-            return Json(AttachReturn::fail(format!(
-                "Failed to create software tunnel: {e}"
-            )));
-        }
-    };
-    if let Err(e) = adapter.connect(rsd_port).await {
-        info!("Failed to connect to RemoteXPC port: {:?}", e);
-        return Json(AttachReturn::fail(format!(
-            "Failed to connect to RemoteXPC port: {e}"
-        )));
+/// Deletes an app and its data from the caller's device
+async fn uninstall_app(
+    ip: SecureClientIp,
+    selector: common::DeviceSelector,
+    Path(bundle_id): Path<String>,
+    Query(options): Query<UninstallOptions>,
+    State(state): State<JitStreamerState>,
+) -> Json<UninstallReturn> {
+    info!("Got request to uninstall {bundle_id} from {:?}", ip.0);
+
+    if !state.config().allow_uninstall {
+        return Json(UninstallReturn {
+            ok: false,
+            error: Some(JitError::new(
+                ErrorCode::Forbidden,
+                "Uninstalling apps is disabled on this server",
+            )),
+        });
+    }
+    if options.confirm.as_deref() != Some(bundle_id.as_str()) {
+        return Json(UninstallReturn {
+            ok: false,
+            error: Some(JitError::new(
+                ErrorCode::Forbidden,
+                format!("Pass confirm={bundle_id} to uninstall {bundle_id}"),
+            )),
+        });
     }
 
-    let xpc_client = match idevice::xpc::XPCDevice::new(adapter).await {
-        Ok(x) => x,
+    let (udid, provider) = match jit::JitSession::new(&state, ip.0, &selector)
+        .connect()
+        .await
+    {
+        Ok(d) => d,
         Err(e) => {
-            log::warn!("Failed to connect to RemoteXPC: {e:?}");
-            return Json(AttachReturn::fail(
-                "Failed to connect to RemoteXPC".to_string(),
-            ));
+            return Json(UninstallReturn {
+                ok: false,
+                error: Some(e),
+            })
         }
     };
 
-    let service_port = match xpc_client.services.get(idevice::debug_proxy::SERVICE_NAME) {
-        Some(s) => s.port,
-        None => {
-            return Json(AttachReturn::fail(
-                "Device did not contain debug server service. Is the image mounted?".to_string(),
-            ));
+    let res = async {
+        let mut client = InstallationProxyClient::connect(&provider)
+            .await
+            .map_err(|e| {
+                JitError::new(
+                    ErrorCode::ServiceFailed,
+                    format!("Failed to start instproxy: {e:?}"),
+                )
+            })?;
+        client.uninstall(&bundle_id, None).await.map_err(|e| {
+            info!("Failed to uninstall {bundle_id}: {e:?}");
+            JitError::new(
+                ErrorCode::ServiceFailed,
+                format!("Failed to uninstall {bundle_id}: {e:?}"),
+            )
+        })
+    }
+    .await;
+
+    state.apps_cache.invalidate(&udid).await;
+    state.heartbeats.release(&udid).await.ok();
+
+    Json(UninstallReturn {
+        ok: res.is_ok(),
+        error: res.err(),
+    })
+}
+
+#[derive(Serialize)]
+struct ScreenshotReturn {
+    ok: bool,
+    #[serde(flatten)]
+    error: Option<JitError>,
+}
+
+/// Captures the device's screen, returning a PNG or a JSON error
+async fn take_screenshot(
+    ip: SecureClientIp,
+    selector: common::DeviceSelector,
+    State(state): State<JitStreamerState>,
+) -> axum::response::Response {
+    let (udid, provider) = match jit::JitSession::new(&state, ip.0, &selector)
+        .connect()
+        .await
+    {
+        Ok(d) => d,
+        Err(e) => {
+            return Json(ScreenshotReturn {
+                ok: false,
+                error: Some(e),
+            })
+            .into_response()
         }
     };
 
-    let mut adapter = xpc_client.into_inner();
-    if let Err(e) = adapter.close().await {
-        log::warn!("Failed to close RemoteXPC port: {e:?}");
-        return Json(AttachReturn::fail(format!(
-            "Failed to close RemoteXPC port: {e:?}"
-        )));
-    }
-    if let Err(e) = adapter.connect(service_port).await {
-        log::warn!("Failed to connect to debug proxy port: {e:?}");
-        return Json(AttachReturn::fail(format!(
-            "Failed to connect to debug proxy port: {e:?}"
-        )));
-    }
-
-    let mut dp = DebugProxyClient::new(adapter);
-    let commands = [format!("vAttach;{pid:02X}"), "D".to_string()];
-    for command in commands {
-        match dp.send_command(command.into()).await {
-            Ok(res) => {
-                debug!("command res: {res:?}");
-            }
-            Err(e) => {
-                log::warn!("Failed to send command to debug server: {e:?}");
-                return Json(AttachReturn::fail(format!(
-                    "Failed to send command to debug server: {e:?}"
-                )));
-            }
-        }
+    let res = async {
+        let (adapter, _) = jit::developer_service(
+            &state,
+            &udid,
+            &provider,
+            idevice::dvt::SERVICE_NAME,
+            pipeline::DVT_MISSING,
+        )
+        .await?;
+        screenshot::capture(adapter)
+            .await
+            .map_err(|e| JitError::new(ErrorCode::ServiceFailed, e))
     }
+    .await;
 
-    state
-        .new_heartbeat_sender
-        .send(heartbeat::SendRequest::Kill(udid.clone()))
-        .await
-        .unwrap();
+    state.heartbeats.release(&udid).await.ok();
 
-    Json(AttachReturn {
-        success: true,
-        message: "".to_string(),
-    })
+    match res {
+        Ok(png) => ([(CONTENT_TYPE, "image/png")], png).into_response(),
+        Err(e) => Json(ScreenshotReturn {
+            ok: false,
+            error: Some(e),
+        })
+        .into_response(),
+    }
 }
 
-#[derive(Debug, Serialize)]
-struct StatusReturn {
-    done: bool,
+#[derive(Serialize)]
+struct DisableMemoryLimitReturn {
     ok: bool,
-    position: usize,
-    error: Option<String>,
-    in_progress: bool, // NOTICE: this field is deprecated and will be removed in future versions
+    #[serde(flatten)]
+    error: Option<JitError>,
 }
 
-/// Stub function to remain compatible with dependant apps
-/// Will be removed in future updates
-async fn status() -> Json<StatusReturn> {
-    Json(StatusReturn {
-        ok: true,
-        done: true,
-        position: 0,
-        error: None,
-        in_progress: false,
+/// Lifts the memory limit of a process that's already running, such as an emulator
+/// attached to earlier, without relaunching it
+async fn disable_memory_limit(
+    ip: SecureClientIp,
+    selector: common::DeviceSelector,
+    Path(pid): Path<u64>,
+    State(state): State<JitStreamerState>,
+) -> Json<DisableMemoryLimitReturn> {
+    info!(
+        "Got request to disable the memory limit of {pid} from {:?}",
+        ip.0
+    );
+
+    let (udid, provider) = match jit::JitSession::new(&state, ip.0, &selector)
+        .connect()
+        .await
+    {
+        Ok(d) => d,
+        Err(e) => {
+            return Json(DisableMemoryLimitReturn {
+                ok: false,
+                error: Some(e),
+            })
+        }
+    };
+
+    let res = async {
+        let (adapter, _) = jit::developer_service(
+            &state,
+            &udid,
+            &provider,
+            idevice::dvt::SERVICE_NAME,
+            pipeline::DVT_MISSING,
+        )
+        .await?;
+        processes::disable_memory_limit(adapter, pid)
+            .await
+            .map_err(|e| JitError::new(ErrorCode::ServiceFailed, e))
+    }
+    .await;
+
+    state.heartbeats.release(&udid).await.ok();
+
+    Json(DisableMemoryLimitReturn {
+        ok: res.is_ok(),
+        error: res.err(),
     })
 }
@@ -0,0 +1,92 @@
+// Jackson Coxson
+// Advertises the server over mDNS, so apps on the same network find it without typing its IP
+
+use std::time::Duration;
+
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use tracing::{debug, info, warn};
+
+use crate::config::{Config, SharedConfig};
+
+pub const SERVICE_TYPE: &str = "_jitstreamer._tcp.local.";
+/// How often the TXT records are compared with the config, which can be reloaded
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Answers mDNS queries for the server until stopped
+pub struct Advertiser {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl Advertiser {
+    /// Starts advertising on every interface, keeping the TXT records in step with the config
+    pub fn start(config: SharedConfig) -> Result<Self, String> {
+        let daemon = ServiceDaemon::new().map_err(|e| format!("Failed to start mDNS: {e}"))?;
+        let service = service(&config.load())?;
+        let fullname = service.get_fullname().to_string();
+        daemon
+            .register(service)
+            .map_err(|e| format!("Failed to advertise over mDNS: {e}"))?;
+        info!("Advertising {fullname} over mDNS");
+
+        let refresher = daemon.clone();
+        tokio::spawn(async move {
+            let mut registered = txt(&config.load());
+            loop {
+                tokio::time::sleep(REFRESH_INTERVAL).await;
+                let current = txt(&config.load());
+                if current == registered {
+                    continue;
+                }
+                debug!("Config changed, updating the mDNS TXT records");
+                let res = service(&config.load()).and_then(|s| {
+                    refresher
+                        .register(s)
+                        .map_err(|e| format!("Failed to update the mDNS advertisement: {e}"))
+                });
+                match res {
+                    Ok(()) => registered = current,
+                    // The daemon is gone once stopped
+                    Err(e) => {
+                        warn!("{e}");
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(Self { daemon, fullname })
+    }
+
+    /// Says goodbye so clients drop the server right away instead of when the record expires
+    pub fn stop(self) {
+        if let Err(e) = self.daemon.unregister(&self.fullname) {
+            warn!("Failed to stop advertising over mDNS: {e}");
+        }
+        self.daemon.shutdown().ok();
+    }
+}
+
+/// The TXT records, what a client needs to know before talking to the server
+fn txt(config: &Config) -> Vec<(&'static str, String)> {
+    vec![
+        ("version", env!("CARGO_PKG_VERSION").to_string()),
+        ("port", config.port.to_string()),
+        ("registration", config.allow_registration.to_string()),
+        ("tls", config.tls.is_some().to_string()),
+    ]
+}
+
+fn service(config: &Config) -> Result<ServiceInfo, String> {
+    let name = &config.mdns_name;
+    ServiceInfo::new(
+        SERVICE_TYPE,
+        name,
+        &format!("{name}.local."),
+        (),
+        config.port,
+        txt(config).as_slice(),
+    )
+    .map(|s| s.enable_addr_auto())
+    .map_err(|e| format!("Invalid mDNS service: {e}"))
+}
@@ -0,0 +1,101 @@
+// Jackson Coxson
+// Apple configuration profiles that install the Wireguard tunnel in one tap
+
+use std::{path::PathBuf, process::Stdio};
+
+use plist::{Dictionary, Value};
+use tokio::io::AsyncWriteExt;
+use tracing::warn;
+
+use crate::common::random_uuid;
+
+/// The WireGuard app's VPN plugin
+const WIREGUARD_SUBTYPE: &str = "com.wireguard.ios";
+
+/// Certificate and key profiles are signed with, so iOS shows them as verified
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProfileSigning {
+    /// PEM certificate chain, the signer first
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Builds a profile with a VPN payload for the WireGuard app holding the client config.
+/// Identifiers are derived from the UDID, so installing a new profile replaces the old one.
+pub fn profile(wg_config: &str, udid: &str, endpoint: &str) -> Result<Vec<u8>, String> {
+    let identifier = format!("com.jitstreamer.vpn.{udid}");
+
+    let mut vendor_config = Dictionary::new();
+    vendor_config.insert("WgQuickConfig".into(), wg_config.into());
+
+    let mut vpn = Dictionary::new();
+    vpn.insert("RemoteAddress".into(), endpoint.into());
+    vpn.insert("AuthenticationMethod".into(), "Password".into());
+
+    let mut payload = Dictionary::new();
+    payload.insert("PayloadType".into(), "com.apple.vpn.managed".into());
+    payload.insert("PayloadVersion".into(), 1.into());
+    payload.insert(
+        "PayloadIdentifier".into(),
+        format!("{identifier}.wireguard").into(),
+    );
+    payload.insert("PayloadUUID".into(), random_uuid().into());
+    payload.insert("PayloadDisplayName".into(), "JitStreamer".into());
+    payload.insert("UserDefinedName".into(), "JitStreamer".into());
+    payload.insert("VPNType".into(), "VPN".into());
+    payload.insert("VPNSubType".into(), WIREGUARD_SUBTYPE.into());
+    payload.insert("VendorConfig".into(), vendor_config.into());
+    payload.insert("VPN".into(), vpn.into());
+
+    let mut profile = Dictionary::new();
+    profile.insert("PayloadType".into(), "Configuration".into());
+    profile.insert("PayloadVersion".into(), 1.into());
+    profile.insert("PayloadIdentifier".into(), identifier.into());
+    profile.insert("PayloadUUID".into(), random_uuid().into());
+    profile.insert("PayloadDisplayName".into(), "JitStreamer VPN".into());
+    profile.insert(
+        "PayloadDescription".into(),
+        "Connects this device to JitStreamer through the WireGuard app".into(),
+    );
+    profile.insert("PayloadContent".into(), Value::Array(vec![payload.into()]));
+
+    let mut bytes = Vec::new();
+    plist::to_writer_xml(&mut bytes, &profile).map_err(|e| e.to_string())?;
+    Ok(bytes)
+}
+
+/// Wraps the profile in a CMS signature using the openssl binary
+pub async fn sign(profile: &[u8], signing: &ProfileSigning) -> Result<Vec<u8>, String> {
+    let mut child = tokio::process::Command::new("openssl")
+        .args(["smime", "-sign", "-nodetach", "-binary", "-outform", "der"])
+        .arg("-signer")
+        .arg(&signing.cert)
+        .arg("-inkey")
+        .arg(&signing.key)
+        // Include the rest of the chain so iOS can verify the signer
+        .arg("-certfile")
+        .arg(&signing.cert)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run openssl: {e}"))?;
+
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    stdin
+        .write_all(profile)
+        .await
+        .map_err(|e| format!("Failed to write to openssl: {e}"))?;
+    drop(stdin);
+
+    let output = child
+        .wait_with_output()
+        .await
+        .map_err(|e| format!("Failed to run openssl: {e}"))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        warn!("Failed to sign profile: {stderr}");
+        return Err(format!("Failed to sign profile: {stderr}"));
+    }
+    Ok(output.stdout)
+}
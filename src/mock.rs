@@ -0,0 +1,538 @@
+// Jackson Coxson
+// Pretend devices for testing the handlers without an iPhone, built with the mock feature
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use tracing::debug;
+
+use crate::{
+    apps::AppDetails,
+    backend::{BackendFuture, DeviceBackend},
+    error::{ErrorCode, JitError},
+    heartbeat::{HeartbeatHandle, HeartbeatStart, HeartbeatStatus},
+    launcher::{LaunchMode, LaunchOptions},
+    pairing_store::PairingStore,
+    pipeline,
+    processes::{self, RunningProcess},
+    progress::{LaunchEvent, Progress},
+    provider::DeviceProvider,
+    JitStreamerState,
+};
+
+/// Every registered UDID with a stored pairing file is a device that answers, has the
+/// developer disk image mounted and has the same two apps installed, one debuggable
+pub struct MockBackend {
+    apps: HashMap<String, AppDetails>,
+    /// The RemoteXPC services the device offers, the ones the developer disk image adds
+    services: HashSet<&'static str>,
+    /// Launched apps by UDID and PID
+    running: Mutex<HashMap<(String, u64), String>>,
+    next_pid: AtomicU64,
+}
+
+impl Default for MockBackend {
+    fn default() -> Self {
+        let apps = [
+            ("com.example.debuggable", "Debuggable", true),
+            ("com.example.release", "Release", false),
+        ]
+        .into_iter()
+        .map(|(bundle_id, name, is_debuggable)| {
+            let app = AppDetails {
+                name: name.to_string(),
+                version: Some("1.0".to_string()),
+                executable: Some(name.to_string()),
+                is_debuggable,
+            };
+            (bundle_id.to_string(), app)
+        })
+        .collect();
+        Self {
+            apps,
+            services: HashSet::from([
+                idevice::dvt::SERVICE_NAME,
+                idevice::debug_proxy::SERVICE_NAME,
+            ]),
+            running: Mutex::default(),
+            next_pid: AtomicU64::new(1000),
+        }
+    }
+}
+
+impl MockBackend {
+    /// Stands in for reading the pairing file and heartbeating the device. The heartbeat is
+    /// kept by the server's manager like a real one, so it's reused, released and killed
+    /// the same way.
+    async fn connect(
+        &self,
+        state: &JitStreamerState,
+        udid: &str,
+    ) -> Result<HeartbeatStart, JitError> {
+        match state.config().pairing_store.get(udid).await {
+            Ok(Some(_)) => {}
+            _ => {
+                return Err(JitError::new(
+                    ErrorCode::PairingMissing,
+                    "No pairing file is stored for this device. Register again to upload it.",
+                ))
+            }
+        }
+        if state.heartbeats.reuse(udid).await? {
+            return Ok(HeartbeatStart {
+                reused: true,
+                woke: false,
+            });
+        }
+
+        // Answers marcos until the manager kills it
+        let (kill, killed) = tokio::sync::oneshot::channel();
+        let (alive, status) = tokio::sync::watch::channel(HeartbeatStatus::Alive);
+        tokio::spawn(async move {
+            killed.await.ok();
+            drop(alive);
+        });
+        state
+            .heartbeats
+            .store(udid, HeartbeatHandle { kill, status })
+            .await?;
+        Ok(HeartbeatStart::default())
+    }
+
+    /// Stands in for finding a service in the RemoteXPC service list
+    fn service(&self, name: &str, missing_message: &str) -> Result<(), JitError> {
+        match self.services.contains(name) {
+            true => Ok(()),
+            false => Err(JitError::new(ErrorCode::DdiNotMounted, missing_message)),
+        }
+    }
+
+    /// Answers a debugserver packet the way it does for attaching and detaching
+    fn debugserver(&self, udid: &str, command: &str) -> Result<String, JitError> {
+        let running = self.running.lock().unwrap();
+        let attached = |pid: u64| running.contains_key(&(udid.to_string(), pid));
+        match command.split_once(';') {
+            Some(("vAttach", pid)) => match u64::from_str_radix(pid, 16) {
+                Ok(pid) if attached(pid) => Ok("T11thread:01;".to_string()),
+                _ => Err(JitError::new(
+                    ErrorCode::AttachFailed,
+                    format!("No process {pid} is running on the mock device"),
+                )),
+            },
+            None if command == "D" => Ok("OK".to_string()),
+            _ => Ok(String::new()),
+        }
+    }
+
+    /// Attaches debugserver and detaches, as `jit::attach_pid` does
+    fn attach_pid(&self, udid: &str, pid: u64) -> Result<(), JitError> {
+        self.service(
+            idevice::debug_proxy::SERVICE_NAME,
+            pipeline::DEBUG_PROXY_MISSING,
+        )?;
+        for command in [format!("vAttach;{pid:02X}"), "D".to_string()] {
+            let res = self.debugserver(udid, &command)?;
+            debug!("Mock debugserver answered {command} with {res}");
+        }
+        Ok(())
+    }
+
+    /// The apps launched on the device, as the DVT service lists them
+    fn running(&self, udid: &str) -> Vec<RunningProcess> {
+        self.running
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((u, _), _)| u == udid)
+            .map(|((_, pid), bundle_id)| {
+                let name = self.apps[bundle_id].executable.clone().unwrap_or_default();
+                RunningProcess::app(*pid, name, bundle_id.clone())
+            })
+            .collect()
+    }
+}
+
+impl DeviceBackend for MockBackend {
+    fn launch<'a>(
+        &'a self,
+        state: &'a JitStreamerState,
+        udid: &'a str,
+        _ip: IpAddr,
+        bundle_id: String,
+        options: LaunchOptions,
+        progress: &'a Progress,
+    ) -> BackendFuture<'a, (u64, HeartbeatStart)> {
+        Box::pin(async move {
+            let heartbeat_start = self.connect(state, udid).await?;
+            progress.send(LaunchEvent::Heartbeat {
+                reused: heartbeat_start.reused,
+                woke: heartbeat_start.woke,
+            });
+            progress.send(LaunchEvent::Tunnel);
+            self.service(idevice::dvt::SERVICE_NAME, pipeline::DVT_MISSING)?;
+            progress.send(LaunchEvent::Xpc { cached: false });
+
+            if !self.apps.contains_key(&bundle_id) {
+                return Err(JitError::new(
+                    ErrorCode::LaunchFailed,
+                    format!("{bundle_id} isn't installed on the mock device"),
+                ));
+            }
+            let pid = self.next_pid.fetch_add(1, Ordering::Relaxed);
+            debug!("Mock device {udid} launched {bundle_id} as {pid}");
+            self.running
+                .lock()
+                .unwrap()
+                .insert((udid.to_string(), pid), bundle_id);
+            progress.send(LaunchEvent::Launched { pid });
+
+            if options.mode != LaunchMode::Open {
+                self.attach_pid(udid, pid)?;
+                progress.send(LaunchEvent::Attached { pid });
+                progress.send(LaunchEvent::Detached { pid });
+            }
+            Ok((pid, heartbeat_start))
+        })
+    }
+
+    fn attach<'a>(
+        &'a self,
+        state: &'a JitStreamerState,
+        udid: &'a str,
+        _ip: IpAddr,
+        pid: u64,
+    ) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            self.connect(state, udid).await?;
+            self.attach_pid(udid, pid)
+        })
+    }
+
+    fn apps<'a>(
+        &'a self,
+        state: &'a JitStreamerState,
+        udid: &'a str,
+        _ip: IpAddr,
+        _system: bool,
+    ) -> BackendFuture<'a, HashMap<String, AppDetails>> {
+        Box::pin(async move {
+            self.connect(state, udid).await?;
+            Ok(self.apps.clone())
+        })
+    }
+
+    fn icons<'a>(
+        &'a self,
+        state: &'a JitStreamerState,
+        udid: &'a str,
+        _ip: IpAddr,
+        _bundle_ids: Vec<String>,
+    ) -> BackendFuture<'a, HashMap<String, String>> {
+        Box::pin(async move {
+            self.connect(state, udid).await?;
+            Ok(HashMap::new())
+        })
+    }
+
+    fn prewarm<'a>(
+        &'a self,
+        state: &'a JitStreamerState,
+        udid: &'a str,
+        _ip: IpAddr,
+    ) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            self.connect(state, udid).await?;
+            Ok(())
+        })
+    }
+
+    fn processes<'a>(
+        &'a self,
+        state: &'a JitStreamerState,
+        udid: &'a str,
+        _ip: IpAddr,
+    ) -> BackendFuture<'a, Vec<RunningProcess>> {
+        Box::pin(async move {
+            self.connect(state, udid).await?;
+            self.service(idevice::dvt::SERVICE_NAME, pipeline::DVT_MISSING)?;
+            Ok(self.running(udid))
+        })
+    }
+
+    fn attach_name<'a>(
+        &'a self,
+        state: &'a JitStreamerState,
+        udid: &'a str,
+        _ip: IpAddr,
+        name: &'a str,
+    ) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            self.connect(state, udid).await?;
+            self.service(idevice::dvt::SERVICE_NAME, pipeline::DVT_MISSING)?;
+            let running = self.running(udid);
+            let pid = match processes::find(&running, name) {
+                Some(p) => p.pid,
+                None => {
+                    return Err(JitError::new(
+                        ErrorCode::ProcessNotFound,
+                        format!("No running process is named {name}"),
+                    ))
+                }
+            };
+            self.attach_pid(udid, pid)
+        })
+    }
+
+    fn provider<'a>(
+        &'a self,
+        _state: &'a JitStreamerState,
+        _udid: &'a str,
+        _ip: IpAddr,
+    ) -> BackendFuture<'a, DeviceProvider> {
+        // Anything past this talks to the device's services directly
+        Box::pin(async move {
+            Err(JitError::new(
+                ErrorCode::ServiceFailed,
+                "The mock device doesn't offer this service",
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::{
+        body::Body,
+        extract::ConnectInfo,
+        http::{
+            header::{AUTHORIZATION, CONTENT_TYPE},
+            Request, StatusCode,
+        },
+        Router,
+    };
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    use crate::{
+        build_state,
+        common::{DEVICE_HEADER, UDID_OVERRIDE_HEADER},
+        config::Config,
+        pairing_store::PairingStore,
+        router,
+    };
+
+    const UDID: &str = "00008030-001A2B3C4D5E6F70";
+    const DEVICE_IP: &str = "fd00::2";
+
+    /// A server on a fresh data folder, with one registered device calling from DEVICE_IP
+    async fn server() -> Router {
+        server_with(&[]).await
+    }
+
+    /// Like `server`, with extra `VAR=VALUE` settings
+    async fn server_with(settings: &[&str]) -> Router {
+        let data_dir = std::env::temp_dir()
+            .join(format!("jitstreamer-mock-{}", rand::random::<u64>()))
+            .to_string_lossy()
+            .into_owned();
+        let mut args: Vec<&str> = vec![
+            "--data-dir",
+            &data_dir,
+            "--set",
+            "MOCK_DEVICES=true",
+            "--set",
+            "PREWARM_TUNNELS=false",
+            "--set",
+            "CLIENT_IP_SOURCE=connect_info",
+            "--set",
+            "REQUIRE_API_KEY=false",
+            "--set",
+            "REDIS_URL=",
+        ];
+        for setting in settings {
+            args.extend(["--set", *setting]);
+        }
+        let config = Config::load_args(&args).expect("Invalid test config");
+        let (state, _alerts) = build_state(config).await;
+
+        sqlx::query("INSERT INTO devices (udid, ip, last_used) VALUES (?, ?, CURRENT_TIMESTAMP)")
+            .bind(UDID)
+            .bind(DEVICE_IP)
+            .execute(&state.db)
+            .await
+            .unwrap();
+        state
+            .config()
+            .pairing_store
+            .put(UDID, b"mock pairing file")
+            .await
+            .unwrap();
+        router(&state)
+    }
+
+    /// Calls the route from the device and returns its JSON
+    async fn get(app: &Router, uri: &str) -> Value {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        let (status, res) = send(app, request, DEVICE_IP).await;
+        assert_eq!(status, StatusCode::OK);
+        res
+    }
+
+    /// Posts to the route from the device and returns its JSON
+    async fn post(app: &Router, uri: &str) -> Value {
+        let request = Request::post(uri).body(Body::empty()).unwrap();
+        let (status, res) = send(app, request, DEVICE_IP).await;
+        assert_eq!(status, StatusCode::OK);
+        res
+    }
+
+    /// Sends the request from the address and returns its status and JSON
+    async fn send(app: &Router, mut request: Request<Body>, from: &str) -> (StatusCode, Value) {
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::new(from.parse().unwrap(), 50000)));
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn lists_debuggable_apps() {
+        let app = server().await;
+        let res = get(&app, "/get_apps").await;
+        assert_eq!(res["ok"], true, "{res}");
+        assert_eq!(res["bundle_ids"]["Debuggable"], "com.example.debuggable");
+        assert!(res["bundle_ids"].get("Release").is_none());
+
+        let res = get(&app, "/get_apps?all=true").await;
+        assert_eq!(res["bundle_ids"]["Release"], "com.example.release");
+    }
+
+    #[tokio::test]
+    async fn launches_installed_apps() {
+        let app = server().await;
+        let res = get(&app, "/launch_app/com.example.debuggable").await;
+        assert_eq!(res["ok"], true, "{res}");
+        assert!(res["pid"].as_u64().is_some());
+
+        let res = get(&app, "/launch_app/com.example.missing").await;
+        assert_eq!(res["ok"], false);
+        assert_eq!(res["code"], "LAUNCH_FAILED");
+    }
+
+    #[tokio::test]
+    async fn launches_apps_by_name() {
+        let app = server().await;
+        // Nothing is cached yet, so the name is looked up on the device
+        let res = get(&app, "/launch_name/Debuggable").await;
+        assert_eq!(res["ok"], true, "{res}");
+        assert!(res["pid"].as_u64().is_some());
+
+        let res = get(&app, "/launch_name/Release").await;
+        assert_eq!(res["ok"], false);
+        assert_eq!(res["code"], "APP_NOT_FOUND");
+    }
+
+    #[tokio::test]
+    async fn attaches_to_running_apps() {
+        let app = server().await;
+        let res = get(&app, "/launch_app/com.example.debuggable?mode=open").await;
+        assert_eq!(res["ok"], true, "{res}");
+        let pid = res["pid"].as_u64().unwrap();
+
+        let res = get(&app, "/processes").await;
+        assert_eq!(res["processes"][0]["pid"], pid, "{res}");
+        assert_eq!(res["processes"][0]["bundle_id"], "com.example.debuggable");
+
+        let res = post(&app, &format!("/attach/{pid}")).await;
+        assert_eq!(res["success"], true, "{res}");
+        let res = post(&app, "/attach_name/Debuggable").await;
+        assert_eq!(res["success"], true, "{res}");
+
+        let res = post(&app, "/attach/1").await;
+        assert_eq!(res["success"], false);
+        assert_eq!(res["code"], "ATTACH_FAILED");
+        let res = post(&app, "/attach_name/Missing").await;
+        assert_eq!(res["success"], false);
+        assert_eq!(res["code"], "PROCESS_NOT_FOUND");
+    }
+
+    #[tokio::test]
+    async fn rejects_unregistered_callers() {
+        let app = server().await;
+        let request = Request::get("/get_apps").body(Body::empty()).unwrap();
+        let (_, res) = send(&app, request, "fd00::99").await;
+        assert_eq!(res["ok"], false);
+        assert_eq!(res["code"], "NOT_REGISTERED");
+    }
+
+    #[tokio::test]
+    async fn spoofed_device_headers_do_not_block_the_device() {
+        let app = server_with(&["FLOOD_ERRORS_PER_MINUTE=2"]).await;
+        for _ in 0..5 {
+            let request = Request::get("/get_apps")
+                .header(DEVICE_HEADER, UDID)
+                .header(UDID_OVERRIDE_HEADER, UDID)
+                .body(Body::empty())
+                .unwrap();
+            let (_, res) = send(&app, request, "fd00::99").await;
+            assert_eq!(res["ok"], false);
+        }
+
+        // The caller is blocked, the device it named isn't
+        let request = Request::get("/get_apps").body(Body::empty()).unwrap();
+        let (status, res) = send(&app, request, "fd00::99").await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res["code"], "RATE_LIMITED");
+        let res = get(&app, "/get_apps").await;
+        assert_eq!(res["ok"], true, "{res}");
+    }
+
+    /// Sends an admin request with the test's admin token
+    async fn admin(app: &Router, method: &str, uri: &str, body: Value) -> Value {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(AUTHORIZATION, "Bearer admin")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let (status, res) = send(app, request, "127.0.0.1").await;
+        assert_eq!(status, StatusCode::OK, "{res}");
+        res
+    }
+
+    #[tokio::test]
+    async fn admin_actions_take_hashed_udids() {
+        let app = server_with(&["ADMIN_TOKEN=admin", "PRIVACY_SALT=salt"]).await;
+        let res = admin(&app, "GET", "/admin/devices", Value::Null).await;
+        let hashed = res["devices"][0]["udid"].as_str().unwrap().to_string();
+        assert!(hashed.starts_with("anon-"), "{res}");
+
+        let ban = serde_json::json!({ "kind": "udid", "value": hashed });
+        let res = admin(&app, "POST", "/admin/bans", ban.clone()).await;
+        assert_eq!(res["ok"], true, "{res}");
+        let request = Request::get("/get_apps").body(Body::empty()).unwrap();
+        let (_, res) = send(&app, request, DEVICE_IP).await;
+        assert_eq!(res["code"], "BANNED");
+        let res = admin(&app, "DELETE", "/admin/bans", ban).await;
+        assert_eq!(res["ok"], true, "{res}");
+
+        let uri = format!("/admin/devices/{hashed}");
+        let res = admin(&app, "DELETE", &uri, Value::Null).await;
+        assert_eq!(res["ok"], true, "{res}");
+        let res = admin(&app, "GET", "/admin/devices", Value::Null).await;
+        assert_eq!(res["devices"], serde_json::json!([]));
+    }
+}
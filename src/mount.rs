@@ -1,6 +1,11 @@
 // Jackson Coxson
 
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
+};
 
 use axum::{
     extract::{
@@ -11,32 +16,65 @@ use axum::{
 };
 use axum_client_ip::SecureClientIp;
 use idevice::{
-    lockdownd::LockdowndClient,
-    mounter::ImageMounter,
-    provider::{IdeviceProvider, TcpProvider},
-    IdeviceError, IdeviceService,
+    lockdownd::LockdowndClient, mounter::ImageMounter, provider::IdeviceProvider, IdeviceError,
+    IdeviceService,
 };
-use log::{debug, info, warn};
 use serde::Serialize;
 use tokio::sync::{watch, Mutex};
+use tracing::{debug, info, warn, Instrument};
 
 use crate::{
     common,
-    heartbeat::{self, NewHeartbeatSender},
-    JitStreamerState,
+    error::{ErrorCode, JitError},
+    events::DeviceEvent,
+    heartbeat::HeartbeatManager,
+    i18n::Language,
+    provider::DeviceProvider,
+    stats, JitStreamerState,
 };
 
 const BUILD_MANIFEST: &[u8] = include_bytes!("../DDI/BuildManifest.plist");
 const DDI_IMAGE: &[u8] = include_bytes!("../DDI/Image.dmg");
 const DDI_TRUSTCACHE: &[u8] = include_bytes!("../DDI/Image.dmg.trustcache");
 
-pub type MountCache =
-    Arc<Mutex<HashMap<String, watch::Receiver<Result<(usize, usize, bool), String>>>>>;
+/// Finished and failed mounts are kept this long for their result to be read, then forgotten
+const MOUNT_KEEP: Duration = Duration::from_secs(10 * 60);
+/// A mount still going after this long is stuck, and forgotten so it can start over
+const MOUNT_STUCK: Duration = Duration::from_secs(60 * 60);
+
+/// A mount started on this node, kept after it ends so its result can be read
+pub struct Mount {
+    pub progress: watch::Receiver<Result<(usize, usize, bool), String>>,
+    pub started: Instant,
+}
+
+impl Mount {
+    /// Whether it finished or failed, or its thread went away without saying
+    pub fn ended(&self) -> bool {
+        self.progress.has_changed().is_err()
+            || !matches!(*self.progress.borrow(), Ok((_, _, false)))
+    }
+}
+
+/// The mounts on this node, one per device
+pub type MountCache = Arc<Mutex<HashMap<String, Mount>>>;
+
+/// Forgets mounts that ended a while ago and ones that are stuck, returning how many
+pub async fn prune(cache: &MountCache) -> usize {
+    let mut mounts = cache.lock().await;
+    let before = mounts.len();
+    mounts.retain(|_, m| {
+        let age = m.started.elapsed();
+        age < MOUNT_STUCK && (age < MOUNT_KEEP || !m.ended())
+    });
+    before - mounts.len()
+}
 
 #[derive(Serialize)]
 pub struct CheckMountResponse {
     ok: bool,
-    error: Option<String>,
+    #[serde(flatten)]
+    error: Option<JitError>,
     mounting: bool,
 }
 
@@ -44,15 +82,17 @@ pub struct CheckMountResponse {
 pub struct MountWebSocketMessage {
     ok: bool,
     percentage: f32,
-    error: Option<String>,
+    #[serde(flatten)]
+    error: Option<JitError>,
     done: bool,
 }
 
 pub async fn check_mount(
     ip: SecureClientIp,
+    selector: common::DeviceSelector,
     State(state): State<JitStreamerState>,
 ) -> Json<CheckMountResponse> {
-    let udid = match common::get_udid_from_ip(ip.0.to_string()).await {
+    let udid = match common::get_udid(&state, ip.0.to_string(), &selector).await {
         Ok(u) => u,
         Err(e) => {
             return Json(CheckMountResponse {
@@ -63,105 +103,119 @@ pub async fn check_mount(
         }
     };
 
+    match start_mount(&state, &udid, ip.0).await {
+        Ok(mounting) => Json(CheckMountResponse {
+            ok: true,
+            error: None,
+            mounting,
+        }),
+        Err(e) => Json(CheckMountResponse {
+            ok: false,
+            error: Some(e),
+            mounting: false,
+        }),
+    }
+}
+
+/// Starts mounting the developer disk image if it isn't already.
+/// Returns true while a mount is in progress, false once the image is mounted.
+pub async fn start_mount(
+    state: &JitStreamerState,
+    udid: &str,
+    ip: IpAddr,
+) -> Result<bool, JitError> {
     let mut lock = state.mount_cache.lock().await;
-    if let Some(i) = lock.get(&udid) {
-        let i = i.borrow().clone();
-        match i {
-            Ok((_, _, complete)) => {
-                if complete {
-                    lock.remove(&udid);
-                    return Json(CheckMountResponse {
-                        ok: true,
-                        error: None,
-                        mounting: false,
-                    });
-                }
+    if let Some(mount) = lock.get(udid) {
+        let progress = mount.progress.borrow().clone();
+        let abandoned = mount.progress.has_changed().is_err();
+        match progress {
+            Ok((_, _, true)) => {
+                lock.remove(udid);
+                return Ok(false);
             }
             Err(e) => {
-                lock.remove(&udid);
-                return Json(CheckMountResponse {
-                    ok: false,
-                    error: Some(format!("Failed to mount image: {e}")),
-                    mounting: false,
-                });
+                lock.remove(udid);
+                return Err(JitError::new(
+                    ErrorCode::DdiMountFailed,
+                    format!("Failed to mount image: {e}"),
+                ));
+            }
+            Ok(_) if abandoned => {
+                warn!("Mounting on {udid} stopped without finishing, starting over");
+                lock.remove(udid);
+            }
+            Ok(_) => {
+                debug!("Device {udid} is already mounting");
+                return Ok(true);
             }
         }
-        debug!("Device {udid} is already mounting");
-        return Json(CheckMountResponse {
-            ok: true,
-            error: None,
-            mounting: true,
-        });
     }
     std::mem::drop(lock);
 
-    let pairing_file = match common::get_pairing_file(&udid, &state.pairing_file_storage).await {
-        Ok(p) => p,
-        Err(e) => {
-            return Json(CheckMountResponse {
-                ok: false,
-                mounting: false,
-                error: Some(format!("Unable to get pairing file: {e}")),
-            })
-        }
-    };
-
     // Start a heartbeat, get the list of images
-    match heartbeat::heartbeat_thread(udid.clone(), ip.0, &pairing_file).await {
-        Ok(s) => {
-            state
-                .new_heartbeat_sender
-                .send(heartbeat::SendRequest::Store((udid.clone(), s)))
-                .await
-                .unwrap();
+    let provider = state.backend.provider(state, udid, ip).await?;
+
+    if ddi_mounted(&provider).await? {
+        state.heartbeats.release(udid).await.ok();
+        return Ok(false);
+    }
+
+    if let Ok(info) =
+        crate::device::get_device_info(&state.device_info_cache, udid, &provider).await
+    {
+        if !info.device_class.can_mount_ddi() {
+            return Err(JitError::new(
+                ErrorCode::UnsupportedDevice,
+                format!(
+                    "Mounting the developer disk image is not supported on {}. Mount it with Xcode first.",
+                    info.device_class
+                ),
+            ));
         }
-        Err(e) => {
-            let e = match e {
-                idevice::IdeviceError::InvalidHostID => {
-                    "your pairing file is invalid. Regenerate it with jitterbug pair.".to_string()
-                }
-                _ => e.to_string(),
-            };
-            info!("Failed to heartbeat device: {:?}", e);
-            return Json(CheckMountResponse {
-                ok: false,
-                mounting: false,
-                error: Some(format!("Failed to heartbeat device: {e}")),
-            });
+    }
+
+    let (sw, rw) = watch::channel(Ok((0, 100, false)));
+    {
+        let mut lock = state.mount_cache.lock().await;
+        // Another request started mounting while this one was connecting
+        if lock.get(udid).is_some_and(|m| !m.ended()) {
+            debug!("Device {udid} is already mounting");
+            state.heartbeats.release(udid).await.ok();
+            return Ok(true);
         }
+        lock.insert(
+            udid.to_string(),
+            Mount {
+                progress: rw,
+                started: Instant::now(),
+            },
+        );
     }
+    mount_thread(state.clone(), provider, sw, udid.to_string(), ip);
 
-    // Get the list of mounted images
-    let provider = TcpProvider {
-        addr: ip.0,
-        pairing_file,
-        label: "JitStreamer-EB".to_string(),
-    };
+    Ok(true)
+}
 
-    let mut mounter_client = match ImageMounter::connect(&provider).await {
-        Ok(m) => m,
-        Err(e) => {
-            return Json(CheckMountResponse {
-                ok: false,
-                mounting: false,
-                error: Some(format!("Failed to start image mounter: {e:?}")),
-            })
-        }
-    };
+/// Whether the developer disk image is among the device's mounted images
+pub async fn ddi_mounted(provider: &DeviceProvider) -> Result<bool, JitError> {
+    let mut mounter_client = ImageMounter::connect(provider).await.map_err(|e| {
+        JitError::new(
+            ErrorCode::ServiceFailed,
+            format!("Failed to start image mounter: {e:?}"),
+        )
+    })?;
 
     let images = match mounter_client.copy_devices().await {
         Ok(images) => images,
         Err(e) => {
             info!("Failed to get images: {:?}", e);
-            return Json(CheckMountResponse {
-                ok: false,
-                mounting: false,
-                error: Some(format!("Failed to get images: {:?}", e)),
-            });
+            return Err(JitError::new(
+                ErrorCode::ServiceFailed,
+                format!("Failed to get images: {:?}", e),
+            ));
         }
     };
 
-    let mut mounted = false;
     for image in images {
         let mut buf = Vec::new();
         let mut writer = std::io::Cursor::new(&mut buf);
@@ -169,107 +223,134 @@ pub async fn check_mount(
 
         let image = String::from_utf8_lossy(&buf);
         if image.contains("Developer") {
-            mounted = true;
-            break;
+            return Ok(true);
         }
     }
+    Ok(false)
+}
 
-    if mounted {
-        Json(CheckMountResponse {
-            ok: true,
-            error: None,
-            mounting: false,
-        })
-    } else {
-        let (sw, rw) = watch::channel(Ok((0, 100, false)));
-        mount_thread(
-            provider,
-            sw,
-            state.new_heartbeat_sender.clone(),
-            udid.clone(),
-        );
-        state.mount_cache.lock().await.insert(udid, rw);
-
-        Json(CheckMountResponse {
-            ok: true,
-            error: None,
-            mounting: true,
+/// The build of the image in DDI/, recorded with each mount's stats
+fn ddi_build() -> Option<&'static str> {
+    static BUILD: OnceLock<Option<String>> = OnceLock::new();
+    BUILD
+        .get_or_init(|| {
+            plist::Value::from_reader(std::io::Cursor::new(BUILD_MANIFEST))
+                .ok()?
+                .as_dictionary()?
+                .get("ProductBuildVersion")?
+                .as_string()
+                .map(|b| b.to_string())
         })
-    }
+        .as_deref()
 }
 
 fn mount_thread(
-    provider: TcpProvider,
+    state: JitStreamerState,
+    provider: DeviceProvider,
     sender: watch::Sender<Result<(usize, usize, bool), String>>,
-    hb: NewHeartbeatSender,
     udid: String,
+    ip: IpAddr,
 ) {
     debug!("Starting mount thread for {udid}");
-    tokio::task::spawn(async move {
-        // Start work in a new fuction so we can use ?
-        async fn work(
-            provider: TcpProvider,
-            sender: watch::Sender<Result<(usize, usize, bool), String>>,
-            hb: NewHeartbeatSender,
-            udid: String,
-        ) -> Result<(), IdeviceError> {
-            debug!("Getting chip ID for {udid}");
-            let mut lockdown_client = LockdowndClient::connect(&provider).await?;
-            lockdown_client
-                .start_session(&provider.get_pairing_file().await?)
-                .await?;
-
-            let unique_chip_id = match lockdown_client
-                .get_value("UniqueChipID")
-                .await?
-                .as_unsigned_integer()
-            {
-                Some(u) => u,
-                None => {
-                    return Err(IdeviceError::UnexpectedResponse);
-                }
-            };
-
-            let mut mounter_client = ImageMounter::connect(&provider).await?;
-            mounter_client
-                .mount_personalized_with_callback(
-                    &provider,
-                    DDI_IMAGE.to_vec(),
-                    DDI_TRUSTCACHE.to_vec(),
-                    BUILD_MANIFEST,
-                    None,
-                    unique_chip_id,
-                    |(progress, state)| async move {
-                        state.clone().send(Ok((progress.0, progress.1, false))).ok();
+    tokio::task::spawn(
+        async move {
+            let started = Instant::now();
+            // Start work in a new fuction so we can use ?
+            async fn work(
+                provider: DeviceProvider,
+                sender: watch::Sender<Result<(usize, usize, bool), String>>,
+                hb: HeartbeatManager,
+                udid: String,
+            ) -> Result<(), IdeviceError> {
+                debug!("Getting chip ID for {udid}");
+                let mut lockdown_client = LockdowndClient::connect(&provider).await?;
+                lockdown_client
+                    .start_session(&provider.get_pairing_file().await?)
+                    .await?;
+
+                let unique_chip_id = match lockdown_client
+                    .get_value("UniqueChipID")
+                    .await?
+                    .as_unsigned_integer()
+                {
+                    Some(u) => u,
+                    None => {
+                        return Err(IdeviceError::UnexpectedResponse);
+                    }
+                };
+
+                let mut mounter_client = ImageMounter::connect(&provider).await?;
+                mounter_client
+                    .mount_personalized_with_callback(
+                        &provider,
+                        DDI_IMAGE.to_vec(),
+                        DDI_TRUSTCACHE.to_vec(),
+                        BUILD_MANIFEST,
+                        None,
+                        unique_chip_id,
+                        |(progress, state)| async move {
+                            state.clone().send(Ok((progress.0, progress.1, false))).ok();
+                        },
+                        sender,
+                    )
+                    .await?;
+                hb.release(&udid).await.ok();
+                Ok(())
+            }
+            let res = work(
+                provider,
+                sender.clone(),
+                state.heartbeats.clone(),
+                udid.clone(),
+            )
+            .await;
+            let error = res.as_ref().err().map(|e| e.to_string());
+            stats::record_mount(
+                &state,
+                &udid,
+                ddi_build(),
+                error.as_deref(),
+                started.elapsed(),
+            )
+            .await;
+            if let Err(e) = res {
+                warn!("Failed to mount for {udid}: {e:?}");
+                sender.send(Err(e.to_string())).ok();
+                state.events.send(
+                    &udid,
+                    ip,
+                    DeviceEvent::MountFailed {
+                        error: e.to_string(),
                     },
-                    sender,
-                )
-                .await?;
-            hb.send(crate::heartbeat::SendRequest::Kill(udid))
-                .await
-                .ok();
-            Ok(())
-        }
-        if let Err(e) = work(provider, sender.clone(), hb, udid.clone()).await {
-            warn!("Failed to mount for {udid}: {e:?}");
-            sender.send(Err(e.to_string())).ok();
-        } else {
-            sender.send(Ok((1, 1, true))).ok();
+                );
+            } else {
+                sender.send(Ok((1, 1, true))).ok();
+                state.events.send(&udid, ip, DeviceEvent::Mounted);
+            }
         }
-    });
+        .instrument(tracing::Span::current()),
+    );
 }
 
 pub async fn handler(
     ws: WebSocketUpgrade,
     ip: SecureClientIp,
+    selector: common::DeviceSelector,
+    language: Language,
     State(state): State<JitStreamerState>,
 ) -> axum::response::Response {
     let ip = ip.0.to_string();
-    ws.on_upgrade(|s| async move { handle_socket(s, ip.clone(), state).await })
+    ws.on_upgrade(move |s| async move { handle_socket(s, ip, selector, language, state).await })
 }
 
-async fn handle_socket(mut socket: WebSocket, ip: String, state: JitStreamerState) {
-    let udid = match common::get_udid_from_ip(ip).await {
+async fn handle_socket(
+    mut socket: WebSocket,
+    ip: String,
+    selector: common::DeviceSelector,
+    language: Language,
+    state: JitStreamerState,
+) {
+    let udid = match common::get_udid(&state, ip, &selector).await {
         Ok(u) => u,
         Err(e) => {
             socket
@@ -277,7 +358,7 @@ async fn handle_socket(mut socket: WebSocket, ip: String, state: JitStreamerStat
                     MountWebSocketMessage {
                         ok: false,
                         percentage: 0.0,
-                        error: Some(e),
+                        error: Some(language.localize(e)),
                         done: false,
                     }
                     .to_ws_message(),
@@ -290,7 +371,7 @@ async fn handle_socket(mut socket: WebSocket, ip: String, state: JitStreamerStat
 
     let lock = state.mount_cache.lock().await;
     let mut receiver = match lock.get(&udid) {
-        Some(r) => r.clone(),
+        Some(m) => m.progress.clone(),
         None => {
             socket
                 .send(
@@ -324,7 +405,7 @@ async fn handle_socket(mut socket: WebSocket, ip: String, state: JitStreamerStat
             Err(e) => socket.send(
                 MountWebSocketMessage {
                     ok: false,
-                    error: Some(e),
+                    error: Some(language.localize(JitError::new(ErrorCode::DdiMountFailed, e))),
                     percentage: 0.0,
                     done: false,
                 }
@@ -0,0 +1,394 @@
+// Jackson Coxson
+// An in-process netmuxd, serving registered devices over the usbmuxd protocol
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, RwLock},
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    common,
+    db::DbPool,
+    pairing_store::PairingStore,
+    raw_packet::{Message, RawPacket, Tag, Version},
+    JitStreamerState,
+};
+
+/// usbmuxd's result codes
+const RESULT_OK: u64 = 0;
+const RESULT_BAD_COMMAND: u64 = 1;
+const RESULT_BAD_DEVICE: u64 = 2;
+const RESULT_CONNECTION_REFUSED: u64 = 3;
+
+/// Attach and detach events kept for slow listeners before they miss some
+const EVENT_BACKLOG: usize = 64;
+
+#[derive(Clone, Debug)]
+struct MuxedDevice {
+    device_id: u64,
+    ip: IpAddr,
+}
+
+#[derive(Clone, Debug)]
+enum MuxerEvent {
+    Attached(String, MuxedDevice),
+    Detached(u64),
+}
+
+/// The network devices clients of the socket can see, which are the registered ones
+#[derive(Clone)]
+pub struct Muxer {
+    devices: Arc<RwLock<HashMap<String, MuxedDevice>>>,
+    next_id: Arc<AtomicU64>,
+    events: broadcast::Sender<MuxerEvent>,
+    /// Sent to clients that ask for the host's BUID, like usbmuxd's SystemBUID
+    buid: String,
+}
+
+impl Default for Muxer {
+    fn default() -> Self {
+        Self {
+            devices: Arc::default(),
+            next_id: Arc::new(AtomicU64::new(1)),
+            events: broadcast::channel(EVENT_BACKLOG).0,
+            buid: common::random_uuid(),
+        }
+    }
+}
+
+impl Muxer {
+    /// Starts with every registered device
+    pub async fn load(db: &DbPool) -> Result<Self, sqlx::Error> {
+        let muxer = Self::default();
+        let devices = sqlx::query_as::<_, (String, String)>("SELECT udid, ip FROM devices")
+            .fetch_all(db)
+            .await?;
+        for (udid, ip) in devices {
+            match IpAddr::from_str(&ip) {
+                Ok(ip) => muxer.add(&udid, ip.to_canonical()).await,
+                Err(_) => warn!("Device {udid} has an invalid registered IP"),
+            }
+        }
+        Ok(muxer)
+    }
+
+    /// Adds the device, or moves it to its new address keeping its device ID
+    pub async fn add(&self, udid: &str, ip: IpAddr) {
+        let mut devices = self.devices.write().await;
+        let device_id = match devices.get(udid) {
+            Some(d) if d.ip == ip => return,
+            Some(d) => d.device_id,
+            None => self.next_id.fetch_add(1, Ordering::Relaxed),
+        };
+        let device = MuxedDevice { device_id, ip };
+        devices.insert(udid.to_string(), device.clone());
+        self.events
+            .send(MuxerEvent::Attached(udid.to_string(), device))
+            .ok();
+    }
+
+    pub async fn remove(&self, udid: &str) {
+        if let Some(d) = self.devices.write().await.remove(udid) {
+            self.events.send(MuxerEvent::Detached(d.device_id)).ok();
+        }
+    }
+
+    async fn by_id(&self, device_id: u64) -> Option<(String, MuxedDevice)> {
+        self.devices
+            .read()
+            .await
+            .iter()
+            .find(|(_, d)| d.device_id == device_id)
+            .map(|(u, d)| (u.clone(), d.clone()))
+    }
+}
+
+/// Serves the muxer at `tcp://host:port` or a Unix socket path (optionally `unix://`)
+pub async fn serve(address: String, state: JitStreamerState) {
+    if let Some(host) = address.strip_prefix("tcp://") {
+        let listener = match TcpListener::bind(host).await {
+            Ok(l) => l,
+            Err(e) => {
+                tracing::error!("Failed to bind the muxer to {host}: {e}");
+                return;
+            }
+        };
+        info!("Serving the muxer on {host}");
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(client(stream, state.clone()));
+                }
+                Err(e) => warn!("Failed to accept a muxer client: {e}"),
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    {
+        let path = address.strip_prefix("unix://").unwrap_or(&address);
+        // Anything on the host may use the muxer, like usbmuxd's own socket
+        let listener = match crate::unix_socket::bind(std::path::Path::new(path), 0o666) {
+            Ok(l) => l,
+            Err(e) => {
+                tracing::error!("Failed to bind the muxer to {path}: {e}");
+                return;
+            }
+        };
+        info!("Serving the muxer on {path}");
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(client(stream, state.clone()));
+                }
+                Err(e) => warn!("Failed to accept a muxer client: {e}"),
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    tracing::error!("The muxer can only listen on TCP on this OS, not {address}");
+}
+
+/// Answers the client's requests until it disconnects, or hands the connection over
+/// to a device or event stream
+async fn client<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, state: JitStreamerState) {
+    loop {
+        let request = match RawPacket::read(&mut stream).await {
+            Ok(r) => r,
+            Err(e) => {
+                debug!("Muxer client left: {e}");
+                return;
+            }
+        };
+        let message_type = request
+            .plist
+            .get("MessageType")
+            .and_then(|m| m.as_string())
+            .unwrap_or_default()
+            .to_string();
+        debug!("Muxer request {message_type}");
+
+        let response = match message_type.as_str() {
+            "ListDevices" => {
+                let devices = state.muxer.devices.read().await;
+                let list = devices
+                    .iter()
+                    .map(|(udid, d)| attached(udid, d).into())
+                    .collect::<Vec<plist::Value>>();
+                let mut response = plist::Dictionary::new();
+                response.insert("DeviceList".into(), plist::Value::Array(list));
+                response
+            }
+            "ReadBUID" => {
+                let mut response = plist::Dictionary::new();
+                response.insert("BUID".into(), state.muxer.buid.clone().into());
+                response
+            }
+            "ReadPairRecord" => {
+                let udid = request
+                    .plist
+                    .get("PairRecordID")
+                    .and_then(|p| p.as_string())
+                    .unwrap_or_default();
+                match state.config().pairing_store.get(udid).await {
+                    Ok(Some(bytes)) => {
+                        let mut response = plist::Dictionary::new();
+                        response.insert("PairRecordData".into(), plist::Value::Data(bytes));
+                        response
+                    }
+                    Ok(None) => result(RESULT_BAD_DEVICE),
+                    Err(e) => {
+                        warn!("Failed to read pairing file for {udid}: {e}");
+                        result(RESULT_BAD_DEVICE)
+                    }
+                }
+            }
+            "Listen" => {
+                if request
+                    .reply(result(RESULT_OK))
+                    .write(&mut stream)
+                    .await
+                    .is_ok()
+                {
+                    listen(stream, &state).await;
+                }
+                return;
+            }
+            "Connect" => {
+                connect(stream, &request, &state).await;
+                return;
+            }
+            // Pairing files only come in through registration
+            _ => result(RESULT_BAD_COMMAND),
+        };
+        if let Err(e) = request.reply(response).write(&mut stream).await {
+            debug!("Muxer client left: {e}");
+            return;
+        }
+    }
+}
+
+/// Connects to the port on the device, then forwards the client's connection to it
+async fn connect<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    request: &RawPacket,
+    state: &JitStreamerState,
+) {
+    let device_id = request
+        .plist
+        .get("DeviceID")
+        .and_then(|d| d.as_unsigned_integer());
+    // The port is sent in network byte order
+    let port = request
+        .plist
+        .get("PortNumber")
+        .and_then(|p| p.as_unsigned_integer())
+        .map(|p| u16::from_be(p as u16));
+    let (udid, device) = match (device_id, port) {
+        (Some(device_id), Some(_)) => match state.muxer.by_id(device_id).await {
+            Some(d) => d,
+            None => {
+                request
+                    .reply(result(RESULT_BAD_DEVICE))
+                    .write(&mut stream)
+                    .await
+                    .ok();
+                return;
+            }
+        },
+        _ => {
+            request
+                .reply(result(RESULT_BAD_COMMAND))
+                .write(&mut stream)
+                .await
+                .ok();
+            return;
+        }
+    };
+    let port = port.unwrap_or_default();
+
+    let mut device_stream = match TcpStream::connect((device.ip, port)).await {
+        Ok(s) => s,
+        Err(e) => {
+            info!("Muxer client failed to reach {udid} on port {port}: {e}");
+            request
+                .reply(result(RESULT_CONNECTION_REFUSED))
+                .write(&mut stream)
+                .await
+                .ok();
+            return;
+        }
+    };
+    if request
+        .reply(result(RESULT_OK))
+        .write(&mut stream)
+        .await
+        .is_err()
+    {
+        return;
+    }
+    debug!("Muxer client connected to {udid} on port {port}");
+    tokio::io::copy_bidirectional(&mut stream, &mut device_stream)
+        .await
+        .ok();
+}
+
+/// Sends every device as attached, then each change until the client leaves
+async fn listen<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, state: &JitStreamerState) {
+    // Subscribe first, so nothing added in between is missed
+    let mut events = state.muxer.events.subscribe();
+    let devices = state.muxer.devices.read().await.clone();
+    for (udid, device) in devices {
+        if event(attached(&udid, &device))
+            .write(&mut stream)
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+
+    loop {
+        let packet = match events.recv().await {
+            Ok(MuxerEvent::Attached(udid, device)) => event(attached(&udid, &device)),
+            Ok(MuxerEvent::Detached(device_id)) => {
+                let mut detached = plist::Dictionary::new();
+                detached.insert("MessageType".into(), "Detached".into());
+                detached.insert("DeviceID".into(), device_id.into());
+                event(detached)
+            }
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                warn!("A muxer listener missed {n} device events");
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        if packet.write(&mut stream).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn result(number: u64) -> plist::Dictionary {
+    let mut response = plist::Dictionary::new();
+    response.insert("MessageType".into(), "Result".into());
+    response.insert("Number".into(), number.into());
+    response
+}
+
+/// Events aren't replies to a request, so they carry no tag
+fn event(plist: plist::Dictionary) -> RawPacket {
+    RawPacket::new(plist, Version::Plist, Message::Plist, Tag(0))
+}
+
+/// The device as usbmuxd and netmuxd describe a network device
+fn attached(udid: &str, device: &MuxedDevice) -> plist::Dictionary {
+    let mut properties = plist::Dictionary::new();
+    properties.insert("ConnectionType".into(), "Network".into());
+    properties.insert("DeviceID".into(), device.device_id.into());
+    properties.insert("SerialNumber".into(), udid.into());
+    properties.insert(
+        "NetworkAddress".into(),
+        plist::Value::Data(sockaddr(device.ip)),
+    );
+    properties.insert(
+        "EscapedFullServiceName".into(),
+        format!("{udid}._apple-mobdev2._tcp.local").into(),
+    );
+
+    let mut attached = plist::Dictionary::new();
+    attached.insert("MessageType".into(), "Attached".into());
+    attached.insert("DeviceID".into(), device.device_id.into());
+    attached.insert("Properties".into(), properties.into());
+    attached
+}
+
+/// A raw sockaddr laid out like on Darwin, which is what clients parse NetworkAddress as
+fn sockaddr(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mut addr = vec![16, 2, 0, 0];
+            addr.extend_from_slice(&ip.octets());
+            addr.resize(16, 0);
+            addr
+        }
+        IpAddr::V6(ip) => {
+            let mut addr = vec![28, 30, 0, 0, 0, 0, 0, 0];
+            addr.extend_from_slice(&ip.octets());
+            addr.resize(28, 0);
+            addr
+        }
+    }
+}
@@ -1,80 +1,271 @@
 // Jackson Coxson
+// Talks to usbmuxd, or netmuxd, over its socket
 
-use std::net::IpAddr;
+use std::{
+    fmt,
+    future::Future,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    pin::Pin,
+    time::Duration,
+};
 
-use log::error;
+use idevice::{pairing_file::PairingFile, provider::IdeviceProvider, Idevice, IdeviceError};
+use serde::Serialize;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::UnixStream,
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpStream, UnixStream},
 };
 
-use crate::raw_packet;
+use tracing::{debug, warn};
+
+use crate::{
+    raw_packet::{PacketError, RawPacket},
+    retry::RetryPolicy,
+};
 
 const NETMUXD_SOCKET: &str = "/var/run/usbmuxd";
-const SERVICE_NAME: &str = "apple-mobdev2";
-const SERVICE_PROTOCOL: &str = "tcp";
+/// Where netmuxd listens, `tcp://host:port` or a Unix socket path (optionally `unix://`)
+const SOCKET_ADDRESS_VAR: &str = "USBMUXD_SOCKET_ADDRESS";
+/// How often a request is tried again while the muxer can't be reached. A restarting
+/// netmuxd or usbmuxd is back within a second or two.
+const RECONNECT: RetryPolicy = RetryPolicy {
+    attempts: 4,
+    backoff: Duration::from_millis(250),
+};
 
-/// Connects to the unix socket and adds the device
-pub async fn add_device(ip: IpAddr, udid: &str) -> bool {
-    let mut stream = UnixStream::connect(NETMUXD_SOCKET)
-        .await
-        .expect("Could not connect to netmuxd socket, is it running?");
-
-    let mut request = plist::Dictionary::new();
-    request.insert("MessageType".into(), "AddDevice".into());
-    request.insert("ConnectionType".into(), "Network".into());
-    request.insert(
-        "ServiceName".into(),
-        format!("_{}._{}.local", SERVICE_NAME, SERVICE_PROTOCOL).into(),
-    );
-    request.insert("IPAddress".into(), ip.to_string().into());
-    request.insert("DeviceID".into(), udid.into());
-
-    let request = raw_packet::RawPacket::new(request, 69, 69, 69);
-    let request: Vec<u8> = request.into();
-
-    stream.write_all(&request).await.unwrap();
-
-    let mut buf = Vec::new();
-    let size = stream.read_to_end(&mut buf).await.unwrap();
-
-    let buffer = &mut buf[0..size].to_vec();
-    if size == 16 {
-        let packet_size = &buffer[0..4];
-        let packet_size = u32::from_le_bytes(packet_size.try_into().unwrap());
-        // Pull the rest of the packet
-        let mut packet = vec![0; packet_size as usize];
-        let _ = stream.read(&mut packet).await.unwrap();
-        // Append the packet to the buffer
-        buffer.append(&mut packet);
+/// Why a request to the muxer failed
+#[derive(Debug)]
+pub enum MuxerError {
+    /// The socket couldn't be reached or dropped partway through, such as while the muxer
+    /// isn't running or is restarting
+    Unavailable(std::io::Error),
+    /// The muxer answered with something that isn't the usbmuxd protocol
+    Protocol(String),
+    /// The muxer understood the request but turned it down
+    Refused(String),
+}
+
+impl fmt::Display for MuxerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unavailable(e) => write!(f, "Could not reach the muxer, is it running? {e}"),
+            Self::Protocol(e) => write!(f, "Bad response from the muxer: {e}"),
+            Self::Refused(e) => write!(f, "The muxer refused: {e}"),
+        }
     }
+}
 
-    let parsed: raw_packet::RawPacket = match buffer.try_into() {
-        Ok(p) => p,
-        Err(_) => {
-            log::error!("Failed to parse response as usbmuxd packet!!");
-            return false;
+impl std::error::Error for MuxerError {}
+
+impl From<PacketError> for MuxerError {
+    fn from(e: PacketError) -> Self {
+        match e {
+            PacketError::Io(e) => Self::Unavailable(e),
+            e => Self::Protocol(e.to_string()),
         }
+    }
+}
+
+/// A connection to netmuxd, over whichever transport it listens on
+pub trait MuxerStream: AsyncRead + AsyncWrite + Unpin + Send + Sync + std::fmt::Debug {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync + std::fmt::Debug> MuxerStream for T {}
+
+/// Connects to netmuxd at USBMUXD_SOCKET_ADDRESS, or the default Unix socket
+async fn connect() -> std::io::Result<Box<dyn MuxerStream>> {
+    let address = std::env::var(SOCKET_ADDRESS_VAR).unwrap_or_default();
+    let address = address.trim();
+    if let Some(host) = address.strip_prefix("tcp://") {
+        return Ok(Box::new(TcpStream::connect(host).await?));
+    }
+    let path = match address.strip_prefix("unix://").unwrap_or(address) {
+        "" => NETMUXD_SOCKET,
+        p => p,
     };
-    match parsed.plist.get("Result") {
-        Some(plist::Value::Integer(r)) => r.as_unsigned().unwrap() == 1,
-        _ => false,
+    Ok(Box::new(UnixStream::connect(path).await?))
+}
+
+/// Runs a request on a new connection, connecting again while the muxer can't be reached.
+/// Other failures are returned at once, the muxer would answer the same again.
+async fn with_reconnect<T, F, Fut>(mut run: F) -> Result<T, MuxerError>
+where
+    F: FnMut(Box<dyn MuxerStream>) -> Fut,
+    Fut: Future<Output = Result<T, MuxerError>>,
+{
+    let mut failures = 0;
+    loop {
+        let res = match connect().await {
+            Ok(stream) => run(stream).await,
+            Err(e) => Err(MuxerError::Unavailable(e)),
+        };
+        match res {
+            Err(MuxerError::Unavailable(e)) if RECONNECT.retries_left(failures + 1) => {
+                failures += 1;
+                let delay = RECONNECT.delay(failures);
+                debug!("The muxer is unavailable, reconnecting in {delay:?}: {e}");
+                tokio::time::sleep(delay).await;
+            }
+            res => return res,
+        }
     }
 }
 
-pub async fn remove_device(udid: &str) {
-    let mut stream = UnixStream::connect(NETMUXD_SOCKET)
+/// Sends a plist request and reads the response
+async fn request(
+    stream: &mut Box<dyn MuxerStream>,
+    request: plist::Dictionary,
+) -> Result<RawPacket, MuxerError> {
+    let request = RawPacket::request(request);
+    let tag = request.tag;
+    request
+        .write(stream)
         .await
-        .expect("Could not connect to netmuxd socket, is it running?");
+        .map_err(MuxerError::Unavailable)?;
+    let response = RawPacket::read(stream).await?;
+    if response.tag != tag {
+        warn!("netmuxd answered tag {:?} to {tag:?}", response.tag);
+    }
+    Ok(response)
+}
 
-    let mut request = plist::Dictionary::new();
-    request.insert("MessageType".into(), "RemoveDevice".into());
-    request.insert("DeviceID".into(), udid.into());
+#[derive(Serialize, Debug)]
+pub struct MuxerDevice {
+    pub device_id: u64,
+    pub udid: String,
+    /// USB or Network
+    pub connection_type: String,
+    /// The address of a network device
+    pub ip: Option<IpAddr>,
+}
+
+/// Asks the muxer which devices it knows about
+pub async fn list_devices() -> Result<Vec<MuxerDevice>, MuxerError> {
+    let response = with_reconnect(|mut stream| async move {
+        let mut request = plist::Dictionary::new();
+        request.insert("MessageType".into(), "ListDevices".into());
+        request.insert("ClientVersionString".into(), "JitStreamer-EB".into());
+        request.insert("ProgName".into(), "JitStreamer-EB".into());
+        self::request(&mut stream, request).await
+    })
+    .await?;
+    let devices = match response.plist.get("DeviceList") {
+        Some(plist::Value::Array(d)) => d,
+        _ => {
+            warn!("Unexpected ListDevices response: {:?}", response.plist);
+            return Err(MuxerError::Protocol("no device list".to_string()));
+        }
+    };
+    Ok(devices
+        .iter()
+        .filter_map(|d| {
+            let properties = d.as_dictionary()?.get("Properties")?.as_dictionary()?;
+            Some(MuxerDevice {
+                device_id: properties.get("DeviceID")?.as_unsigned_integer()?,
+                udid: properties.get("SerialNumber")?.as_string()?.to_string(),
+                connection_type: properties
+                    .get("ConnectionType")
+                    .and_then(|c| c.as_string())
+                    .unwrap_or("Unknown")
+                    .to_string(),
+                ip: properties
+                    .get("NetworkAddress")
+                    .and_then(|a| a.as_data())
+                    .and_then(parse_sockaddr),
+            })
+        })
+        .collect())
+}
+
+/// Network devices come with a raw sockaddr, laid out like on Darwin: length, family, port
+fn parse_sockaddr(addr: &[u8]) -> Option<IpAddr> {
+    match addr.get(1)? {
+        // AF_INET
+        2 => {
+            let octets: [u8; 4] = addr.get(4..8)?.try_into().ok()?;
+            Some(IpAddr::V4(Ipv4Addr::from(octets)))
+        }
+        // AF_INET6 on Darwin and Linux
+        30 | 10 => {
+            let octets: [u8; 16] = addr.get(8..24)?.try_into().ok()?;
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        _ => None,
+    }
+}
 
-    let request = raw_packet::RawPacket::new(request, 69, 69, 69);
-    let request: Vec<u8> = request.into();
+/// The muxer's ID for the device if it's plugged in over USB
+pub async fn usb_device_id(udid: &str) -> Result<Option<u64>, MuxerError> {
+    Ok(list_devices()
+        .await?
+        .into_iter()
+        .find(|d| d.udid == udid && d.connection_type == "USB")
+        .map(|d| d.device_id))
+}
+
+/// Asks the muxer to connect to a port on the device. Once it agrees, the socket is
+/// a plain connection to the device, which is all usbmuxd offers without netmuxd.
+async fn connect_to_device(device_id: u64, port: u16) -> Result<Box<dyn MuxerStream>, MuxerError> {
+    with_reconnect(|mut stream| async move {
+        let mut request = plist::Dictionary::new();
+        request.insert("MessageType".into(), "Connect".into());
+        request.insert("ClientVersionString".into(), "JitStreamer-EB".into());
+        request.insert("ProgName".into(), "JitStreamer-EB".into());
+        request.insert("DeviceID".into(), device_id.into());
+        // The port is sent in network byte order
+        request.insert("PortNumber".into(), (port.to_be() as u64).into());
+        let response = self::request(&mut stream, request).await?;
+        match response
+            .plist
+            .get("Number")
+            .and_then(|n| n.as_unsigned_integer())
+        {
+            Some(0) => Ok(stream),
+            r => Err(MuxerError::Refused(format!(
+                "connecting to port {port} answered {r:?}"
+            ))),
+        }
+    })
+    .await
+}
+
+/// Reaches a USB device through the muxer, with the pairing file JitStreamer keeps for it
+#[derive(Debug)]
+pub struct UsbProvider {
+    pub device_id: u64,
+    pub pairing_file: PairingFile,
+    pub label: String,
+}
+
+impl IdeviceProvider for UsbProvider {
+    fn connect(
+        &self,
+        port: u16,
+    ) -> Pin<Box<dyn Future<Output = Result<Idevice, IdeviceError>> + Send>> {
+        let device_id = self.device_id;
+        let label = self.label.clone();
+        Box::pin(async move {
+            let stream = connect_to_device(device_id, port).await.map_err(|e| {
+                warn!("{e}");
+                match e {
+                    MuxerError::Unavailable(e) => IdeviceError::Socket(e),
+                    e => IdeviceError::Socket(std::io::Error::new(
+                        std::io::ErrorKind::ConnectionRefused,
+                        e,
+                    )),
+                }
+            })?;
+            Ok(Idevice::new(Box::new(stream), label))
+        })
+    }
+
+    fn label(&self) -> &str {
+        &self.label
+    }
 
-    if let Err(e) = stream.write_all(&request).await {
-        error!("Error writing to netmuxd socket: {}", e);
+    fn get_pairing_file(
+        &self,
+    ) -> Pin<Box<dyn Future<Output = Result<PairingFile, IdeviceError>> + Send>> {
+        let pairing_file = self.pairing_file.clone();
+        Box::pin(async move { Ok(pairing_file) })
     }
 }
@@ -0,0 +1,204 @@
+// Jackson Coxson
+// Posts notable events to the operator's webhooks, such as Discord or Slack channels
+
+use std::{collections::HashMap, time::Duration};
+
+use reqwest::Url;
+use serde_json::json;
+use tokio::sync::{
+    broadcast,
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+};
+use tracing::{info, warn};
+
+use crate::{events::DeviceEvent, health, JitStreamerState};
+
+/// How often the database is checked, so failures are noticed without a request
+const DATABASE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug)]
+pub enum Alert {
+    Registered {
+        udid: String,
+    },
+    /// The device's last launches all failed
+    LaunchFailures {
+        udid: String,
+        count: u32,
+        error: String,
+    },
+    /// A registration couldn't create or apply the device's Wireguard peer
+    WireguardPeer {
+        udid: String,
+        error: String,
+    },
+    DatabaseFailed {
+        error: String,
+    },
+    DatabaseRecovered,
+    /// usbmuxd or netmuxd stopped answering while USB_DEVICES is on
+    MuxerUnavailable {
+        error: String,
+    },
+    MuxerRecovered,
+}
+
+impl Alert {
+    /// Sent as `event` to generic webhooks
+    fn kind(&self) -> &'static str {
+        match self {
+            Alert::Registered { .. } => "registered",
+            Alert::LaunchFailures { .. } => "launch_failures",
+            Alert::WireguardPeer { .. } => "wireguard_peer",
+            Alert::DatabaseFailed { .. } => "database_failed",
+            Alert::DatabaseRecovered => "database_recovered",
+            Alert::MuxerUnavailable { .. } => "muxer_unavailable",
+            Alert::MuxerRecovered => "muxer_recovered",
+        }
+    }
+
+    fn udid(&self) -> Option<&str> {
+        match self {
+            Alert::Registered { udid }
+            | Alert::LaunchFailures { udid, .. }
+            | Alert::WireguardPeer { udid, .. } => Some(udid),
+            Alert::DatabaseFailed { .. }
+            | Alert::DatabaseRecovered
+            | Alert::MuxerUnavailable { .. }
+            | Alert::MuxerRecovered => None,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Alert::Registered { udid } => format!("Device {udid} registered"),
+            Alert::LaunchFailures { udid, count, error } => {
+                format!("The last {count} launches on {udid} failed, most recently with: {error}")
+            }
+            Alert::WireguardPeer { udid, error } => {
+                format!("Failed to set up the Wireguard peer for {udid}: {error}")
+            }
+            Alert::DatabaseFailed { error } => format!("The database is failing: {error}"),
+            Alert::DatabaseRecovered => "The database is working again".to_string(),
+            Alert::MuxerUnavailable { error } => {
+                format!("USB devices can't be reached, the muxer isn't answering: {error}")
+            }
+            Alert::MuxerRecovered => "The muxer is answering again".to_string(),
+        }
+    }
+
+    /// The body for the webhook, in the shape its service expects
+    fn payload(&self, url: &Url) -> serde_json::Value {
+        let message = format!("JitStreamer-EB: {}", self.message());
+        match url.host_str().unwrap_or_default() {
+            "discord.com" | "discordapp.com" => json!({ "content": message }),
+            "hooks.slack.com" => json!({ "text": message }),
+            _ => json!({
+                "event": self.kind(),
+                "udid": self.udid(),
+                "message": self.message(),
+            }),
+        }
+    }
+}
+
+/// Webhook URLs hold their secret, so only the host is logged
+fn host(url: &Url) -> &str {
+    url.host_str().unwrap_or_default()
+}
+
+/// Where modules raise alerts. They're sent in the background, so raising one never waits.
+#[derive(Clone)]
+pub struct Notifier(UnboundedSender<Alert>);
+
+impl Notifier {
+    pub fn channel() -> (Self, UnboundedReceiver<Alert>) {
+        let (sender, receiver) = unbounded_channel();
+        (Self(sender), receiver)
+    }
+
+    pub fn alert(&self, alert: Alert) {
+        self.0.send(alert).ok();
+    }
+}
+
+/// Raises alerts for device events and database failures, and posts every alert to
+/// WEBHOOK_URLS. The URLs are read for each alert, so they can be reloaded.
+pub async fn run(state: JitStreamerState, mut alerts: UnboundedReceiver<Alert>) {
+    let client = reqwest::Client::builder()
+        .timeout(WEBHOOK_TIMEOUT)
+        .build()
+        .expect("Failed to build webhook client");
+    let mut events = state.events.subscribe();
+    let mut database = tokio::time::interval(DATABASE_CHECK_INTERVAL);
+    let mut database_failing = false;
+    // Failed launches in a row by device
+    let mut failures: HashMap<String, u32> = HashMap::new();
+
+    loop {
+        let alert = tokio::select! {
+            Some(alert) = alerts.recv() => alert,
+            event = events.recv() => match event {
+                Ok(notice) => match notice.event {
+                    DeviceEvent::Registered => Alert::Registered { udid: notice.udid },
+                    DeviceEvent::Launched { .. } => {
+                        failures.remove(&notice.udid);
+                        continue;
+                    }
+                    DeviceEvent::LaunchFailed { error, .. } => {
+                        let count = failures.entry(notice.udid.clone()).or_default();
+                        *count += 1;
+                        // Once per streak
+                        if *count != state.config().webhook_launch_failures {
+                            continue;
+                        }
+                        Alert::LaunchFailures {
+                            udid: notice.udid,
+                            count: *count,
+                            error: error.message,
+                        }
+                    }
+                    DeviceEvent::Mounted | DeviceEvent::MountFailed { .. } => continue,
+                },
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("Notifier missed {n} device events");
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            _ = database.tick() => {
+                match (health::database_writable(&state).await, database_failing) {
+                    (Err(error), false) => {
+                        database_failing = true;
+                        Alert::DatabaseFailed { error }
+                    }
+                    (Ok(()), true) => {
+                        database_failing = false;
+                        Alert::DatabaseRecovered
+                    }
+                    _ => continue,
+                }
+            }
+        };
+
+        let urls = state.config().webhook_urls.clone();
+        if urls.is_empty() {
+            continue;
+        }
+        info!("Sending {} alert to webhooks", alert.kind());
+        for url in urls {
+            let client = client.clone();
+            let body = alert.payload(&url);
+            tokio::spawn(async move {
+                match client.post(url.clone()).json(&body).send().await {
+                    Ok(r) if !r.status().is_success() => {
+                        warn!("Webhook on {} responded with {}", host(&url), r.status());
+                    }
+                    Ok(_) => {}
+                    Err(e) => warn!("Failed to send webhook to {}: {e}", host(&url)),
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,130 @@
+// Jackson Coxson
+// CLI subcommands for looking after registered devices without sqlite3 or wg
+
+use std::path::PathBuf;
+
+use clap::Subcommand;
+use serde::Deserialize;
+
+use crate::{
+    config::Config,
+    db::{DbPool, Writer},
+    pairing_store::PairingStore,
+    register,
+};
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Lists every registered device
+    Devices,
+    /// Deletes a device's registration, pairing file and Wireguard peer
+    RemoveDevice { udid: String },
+    /// Issues a device a new Wireguard peer and prints its config. The old config can't
+    /// be printed again, the server never keeps the device's private key.
+    WireguardConfig {
+        udid: String,
+        /// Write the config to a file instead of printing it
+        #[arg(long, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+    /// Makes the running server forget its mounts in progress and unfinished launches,
+    /// through the admin API
+    Purge {
+        /// The running server, http://localhost:<JITSTREAMER_PORT> by default
+        #[arg(long, value_name = "URL")]
+        server: Option<String>,
+    },
+}
+
+/// Runs the subcommand instead of the server
+pub async fn run(
+    command: Command,
+    config: &Config,
+    db: &DbPool,
+    writer: &Writer,
+) -> Result<(), String> {
+    match command {
+        Command::Devices => devices(config, db).await,
+        Command::RemoveDevice { udid } => {
+            register::remove_registration(writer, config, &udid).await?;
+            println!("Removed {udid}");
+            Ok(())
+        }
+        Command::WireguardConfig { udid, output } => {
+            let client_config = register::regenerate_config(db, writer, config, &udid).await?;
+            match output {
+                Some(path) => {
+                    std::fs::write(&path, client_config)
+                        .map_err(|e| format!("Failed to write {}: {e}", path.display()))?;
+                    println!("Wrote {udid}'s new config to {}", path.display());
+                }
+                None => print!("{client_config}"),
+            }
+            Ok(())
+        }
+        Command::Purge { server } => purge(config, server).await,
+    }
+}
+
+async fn devices(config: &Config, db: &DbPool) -> Result<(), String> {
+    let rows = sqlx::query_as::<_, (String, String, Option<String>, Option<String>, String)>(
+        "SELECT udid, ip, ipv4, wireguard_interface, CAST(last_used AS TEXT) FROM devices ORDER BY last_used DESC",
+    )
+    .fetch_all(db)
+    .await
+    .map_err(|e| format!("Failed to read devices: {e}"))?;
+
+    println!(
+        "{:<40} {:<39} {:<15} {:<12} {:<19} PAIRING FILE",
+        "UDID", "IP", "IPV4", "INTERFACE", "LAST USED"
+    );
+    for (udid, ip, ipv4, interface, last_used) in &rows {
+        let pairing_file = match config.pairing_store.get(udid).await {
+            Ok(Some(_)) => "yes".to_string(),
+            Ok(None) => "missing".to_string(),
+            Err(e) => format!("unreadable: {e}"),
+        };
+        println!(
+            "{udid:<40} {ip:<39} {:<15} {:<12} {last_used:<19} {pairing_file}",
+            ipv4.as_deref().unwrap_or("-"),
+            interface.as_deref().unwrap_or("-"),
+        );
+    }
+    println!("{} devices", rows.len());
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct PurgeReturn {
+    mounts: usize,
+    launches: usize,
+}
+
+/// The mounts and launches only live in the running server, so it has to do this itself
+async fn purge(config: &Config, server: Option<String>) -> Result<(), String> {
+    let token = config
+        .admin_token
+        .as_deref()
+        .ok_or("Purging goes through the admin API, set ADMIN_TOKEN")?;
+    let server = server.unwrap_or_else(|| format!("http://localhost:{}", config.port));
+    let url = format!("{}/admin/purge", server.trim_end_matches('/'));
+
+    let response = reqwest::Client::new()
+        .post(&url)
+        .bearer_auth(token)
+        .send()
+        .await
+        .map_err(|e| format!("Failed to reach the server at {server}, is it running? {e}"))?;
+    if !response.status().is_success() {
+        return Err(format!("{url} answered {}", response.status()));
+    }
+    let purged = response
+        .json::<PurgeReturn>()
+        .await
+        .map_err(|e| format!("Bad response from {url}: {e}"))?;
+    println!(
+        "Purged {} mounts in progress and {} unfinished launches",
+        purged.mounts, purged.launches
+    );
+    Ok(())
+}
@@ -0,0 +1,157 @@
+// Jackson Coxson
+// Pairs with a device over the network, so it can register without a computer
+
+use std::{net::IpAddr, time::Duration};
+
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+};
+use axum_client_ip::SecureClientIp;
+use idevice::{lockdown::LockdownClient, pairing_file::PairingFile, Idevice, IdeviceError};
+use tokio::net::TcpStream;
+use tracing::{info, warn};
+
+use crate::{
+    common::{self, DeviceSelector},
+    error::ErrorCode,
+    register, JitStreamerState,
+};
+
+/// How long the user has to tap Trust on the device
+const TRUST_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often the device is asked whether the user answered
+const TRUST_POLL: Duration = Duration::from_secs(2);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pairs with the calling device, which shows a trust prompt, and saves the new pairing
+/// file. A registered device is re-paired over its VPN address. Otherwise the device is
+/// reached at the caller's IP and registered, which only works with direct registration.
+pub async fn pair(
+    client_ip: SecureClientIp,
+    selector: DeviceSelector,
+    State(state): State<JitStreamerState>,
+) -> Result<(HeaderMap, Bytes), (StatusCode, String)> {
+    let config = state.config();
+    if config.allow_registration == 0 {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Registration is disabled".to_string(),
+        ));
+    }
+
+    let (registered, addr) = match common::get_device(
+        &state,
+        client_ip.0,
+        &selector,
+        config.allow_udid_override,
+    )
+    .await
+    {
+        Ok((udid, addr)) => (Some(udid), addr),
+        Err(e) if e.code == ErrorCode::NotRegistered && config.address_registration() => {
+            (None, client_ip.0)
+        }
+        Err(e) if e.code == ErrorCode::NotRegistered => {
+            return Err((
+                StatusCode::NOT_FOUND,
+                "Register first to get a VPN, then pair over it".to_string(),
+            ))
+        }
+        Err(e) => return Err((StatusCode::BAD_REQUEST, e.into())),
+    };
+
+    info!("Pairing with the device at {addr:?}");
+    let (udid, pairing_file) = lockdown_pair(addr)
+        .await
+        .map_err(|e| (StatusCode::BAD_GATEWAY, e))?;
+    if state.bans.udid_banned(&udid).await {
+        info!("Refusing to pair banned device {udid}");
+        return Err((
+            StatusCode::FORBIDDEN,
+            "This device has been banned".to_string(),
+        ));
+    }
+    let plist_bytes = pairing_file.serialize().map_err(|e| {
+        warn!("Failed to serialize pairing file: {e:?}");
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "failed to serialize pairing file".to_string(),
+        )
+    })?;
+
+    match registered {
+        Some(registered) => {
+            if registered != udid {
+                return Err((
+                    StatusCode::CONFLICT,
+                    format!("The device at your address is {udid}, not {registered}"),
+                ));
+            }
+            register::save_pairing_file(&config.pairing_store, &udid, &plist_bytes)
+                .await
+                .map_err(|(s, e)| (s, e.to_string()))?;
+
+            // Connections made with the old pairing file would be rejected
+            state.heartbeats.kill(&udid).await.ok();
+            state.rsd_cache.invalidate(&udid).await;
+            info!("Re-paired {udid}");
+            Ok((HeaderMap::new(), "paired".into()))
+        }
+        None => {
+            info!("Paired {udid}, registering it");
+            register::store_device(&state, client_ip.0, &udid, &plist_bytes)
+                .await
+                .map_err(register::RegisterError::into_text)
+        }
+    }
+}
+
+/// Runs the lockdown pairing handshake, waiting for the user to trust this server
+async fn lockdown_pair(addr: IpAddr) -> Result<(String, PairingFile), String> {
+    let stream = tokio::time::timeout(
+        CONNECT_TIMEOUT,
+        TcpStream::connect((addr, LockdownClient::LOCKDOWND_PORT)),
+    )
+    .await
+    .map_err(|_| "Timed out connecting to the device".to_string())?
+    .map_err(|e| format!("Failed to connect to the device: {e}"))?;
+    let mut lockdown = LockdownClient::new(Idevice::new(Box::new(stream), "JitStreamer-EB"));
+
+    let udid = lockdown
+        .get_value("UniqueDeviceID")
+        .await
+        .map_err(|e| format!("Failed to get the device's UDID: {e:?}"))?;
+    let udid = match udid.as_string() {
+        Some(u) => u.to_string(),
+        None => return Err("The device returned an invalid UDID".to_string()),
+    };
+
+    let host_id = common::random_uuid();
+    let system_buid = common::random_uuid();
+    let started = std::time::Instant::now();
+    let mut pairing_file = loop {
+        match lockdown.pair(host_id.clone(), system_buid.clone()).await {
+            Ok(p) => break p,
+            // The trust prompt is showing, ask again until it's answered
+            Err(IdeviceError::PairingDialogResponsePending)
+                if started.elapsed() < TRUST_TIMEOUT =>
+            {
+                tokio::time::sleep(TRUST_POLL).await;
+            }
+            Err(IdeviceError::PairingDialogResponsePending) => {
+                return Err("Timed out waiting for Trust to be tapped on the device".to_string())
+            }
+            Err(IdeviceError::UserDeniedPairing) => {
+                return Err("Pairing was denied on the device".to_string())
+            }
+            Err(e) => {
+                warn!("Failed to pair with {udid}: {e:?}");
+                return Err(format!("Failed to pair with the device: {e:?}"));
+            }
+        }
+    };
+    pairing_file.udid = Some(udid.clone());
+    Ok((udid, pairing_file))
+}
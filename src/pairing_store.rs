@@ -0,0 +1,171 @@
+// Jackson Coxson
+// Where pairing files are kept, a local folder or an S3 bucket shared by a cluster
+
+use std::future::Future;
+
+use s3::{creds::Credentials, Bucket, Region};
+use tracing::info;
+
+/// Stores pairing files by UDID
+pub trait PairingStore {
+    /// The device's pairing file, None if there isn't one
+    fn get(&self, udid: &str) -> impl Future<Output = Result<Option<Vec<u8>>, String>> + Send;
+    fn put(&self, udid: &str, bytes: &[u8]) -> impl Future<Output = Result<(), String>> + Send;
+    /// Deletes the device's pairing file, succeeding if there wasn't one
+    fn remove(&self, udid: &str) -> impl Future<Output = Result<(), String>> + Send;
+}
+
+/// A folder of `<udid>.plist` files, like the OS's lockdown folder
+#[derive(Clone, Debug)]
+pub struct Filesystem {
+    path: String,
+}
+
+impl Filesystem {
+    pub fn new(path: String) -> Self {
+        Self { path }
+    }
+
+    fn file(&self, udid: &str) -> String {
+        format!("{}/{udid}.plist", self.path)
+    }
+}
+
+impl PairingStore for Filesystem {
+    async fn get(&self, udid: &str) -> Result<Option<Vec<u8>>, String> {
+        match tokio::fs::read(self.file(udid)).await {
+            Ok(b) => Ok(Some(b)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    async fn put(&self, udid: &str, bytes: &[u8]) -> Result<(), String> {
+        // Create the folder if it doesn't exist
+        if let Err(e) = tokio::fs::create_dir_all(&self.path).await {
+            tracing::error!("Failed to create plist storage path: {e:?}");
+        }
+        tokio::fs::write(self.file(udid), bytes)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn remove(&self, udid: &str) -> Result<(), String> {
+        match tokio::fs::remove_file(self.file(udid)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// `<prefix><udid>.plist` objects in a bucket, on AWS or any S3 compatible service
+#[derive(Clone, Debug)]
+pub struct S3 {
+    bucket: Box<Bucket>,
+    prefix: String,
+}
+
+impl S3 {
+    /// Path style addressing is used with a custom endpoint, which is what MinIO and
+    /// most self-hosted services expect
+    pub fn new(
+        bucket: &str,
+        region: &str,
+        endpoint: Option<String>,
+        access_key: &str,
+        secret_key: &str,
+        prefix: String,
+    ) -> Result<Self, String> {
+        let credentials = Credentials::new(Some(access_key), Some(secret_key), None, None, None)
+            .map_err(|e| e.to_string())?;
+        let (region, path_style) = match endpoint {
+            Some(endpoint) => (
+                Region::Custom {
+                    region: region.to_string(),
+                    endpoint,
+                },
+                true,
+            ),
+            None => (region.parse::<Region>().map_err(|e| e.to_string())?, false),
+        };
+        let mut bucket = Bucket::new(bucket, region, credentials).map_err(|e| e.to_string())?;
+        if path_style {
+            bucket = bucket.with_path_style();
+        }
+        Ok(Self { bucket, prefix })
+    }
+
+    fn key(&self, udid: &str) -> String {
+        format!("{}{udid}.plist", self.prefix)
+    }
+}
+
+impl PairingStore for S3 {
+    async fn get(&self, udid: &str) -> Result<Option<Vec<u8>>, String> {
+        let res = self
+            .bucket
+            .get_object(self.key(udid))
+            .await
+            .map_err(|e| e.to_string())?;
+        match res.status_code() {
+            200 => Ok(Some(res.to_vec())),
+            404 => Ok(None),
+            s => Err(format!("S3 responded {s}")),
+        }
+    }
+
+    async fn put(&self, udid: &str, bytes: &[u8]) -> Result<(), String> {
+        let res = self
+            .bucket
+            .put_object(self.key(udid), bytes)
+            .await
+            .map_err(|e| e.to_string())?;
+        match res.status_code() {
+            200 => Ok(()),
+            s => Err(format!("S3 responded {s}")),
+        }
+    }
+
+    async fn remove(&self, udid: &str) -> Result<(), String> {
+        let res = self
+            .bucket
+            .delete_object(self.key(udid))
+            .await
+            .map_err(|e| e.to_string())?;
+        match res.status_code() {
+            200 | 204 | 404 => Ok(()),
+            s => Err(format!("S3 responded {s}")),
+        }
+    }
+}
+
+/// The store picked by PAIRING_STORE
+#[derive(Clone, Debug)]
+pub enum Backend {
+    Filesystem(Filesystem),
+    S3(S3),
+}
+
+impl PairingStore for Backend {
+    async fn get(&self, udid: &str) -> Result<Option<Vec<u8>>, String> {
+        match self {
+            Self::Filesystem(f) => f.get(udid).await,
+            Self::S3(s) => s.get(udid).await,
+        }
+    }
+
+    async fn put(&self, udid: &str, bytes: &[u8]) -> Result<(), String> {
+        info!("Saving pairing file for {udid}");
+        match self {
+            Self::Filesystem(f) => f.put(udid, bytes).await,
+            Self::S3(s) => s.put(udid, bytes).await,
+        }
+    }
+
+    async fn remove(&self, udid: &str) -> Result<(), String> {
+        match self {
+            Self::Filesystem(f) => f.remove(udid).await,
+            Self::S3(s) => s.remove(udid).await,
+        }
+    }
+}
@@ -0,0 +1,330 @@
+// Jackson Coxson
+// Samples a process's CPU and memory and the device's GPU over DVT, to tell whether a slow
+// app is slow on its own or because of the device
+
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, Query, State},
+    Json,
+};
+use axum_client_ip::SecureClientIp;
+use idevice::dvt::message::AuxValue;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+use crate::{
+    common::{self, DeviceSelector},
+    error::{ErrorCode, JitError},
+    jit, pipeline, processes,
+    tunnel::Tunnel,
+    JitStreamerState,
+};
+
+const SYSMONTAP_CHANNEL: &str = "com.apple.instruments.server.services.sysmontap";
+const GRAPHICS_CHANNEL: &str = "com.apple.instruments.server.services.graphics.opengl";
+const DEFAULT_SECONDS: u64 = 10;
+/// The device is held for the whole sampling, so it can't be long
+const MAX_SECONDS: u64 = 60;
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+/// Asked for each process, sysmontap returns them in this order
+const PROCESS_ATTRIBUTES: [&str; 3] = ["pid", "cpuUsage", "physFootprint"];
+
+#[derive(Deserialize)]
+pub struct PerformanceOptions {
+    /// How long to sample for, capped at a minute
+    seconds: Option<u64>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ProcessSample {
+    /// Milliseconds since sampling started
+    at_ms: u64,
+    /// Of one core, so a process busy on two cores is at 200
+    cpu_percent: Option<f64>,
+    /// The memory the process is charged for, what the memory limit counts
+    memory_bytes: Option<u64>,
+    /// The whole device's CPU load, summed over its cores
+    system_cpu_percent: Option<f64>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct GpuSample {
+    /// Milliseconds since sampling started
+    at_ms: u64,
+    device_percent: Option<f64>,
+    renderer_percent: Option<f64>,
+    tiler_percent: Option<f64>,
+    /// Frames Core Animation drew in the last second, for everything on screen
+    fps: Option<f64>,
+}
+
+#[derive(Serialize, Default)]
+pub struct PerformanceReturn {
+    ok: bool,
+    /// The process's CPU and memory, about once a second
+    samples: Vec<ProcessSample>,
+    /// The device's GPU, empty if it doesn't report it
+    gpu: Vec<GpuSample>,
+    #[serde(flatten)]
+    error: Option<JitError>,
+}
+
+fn number(value: &plist::Value) -> Option<f64> {
+    value
+        .as_real()
+        .or_else(|| value.as_signed_integer().map(|n| n as f64))
+        .or_else(|| value.as_unsigned_integer().map(|n| n as f64))
+}
+
+/// The rows of a sampling message, which come one at a time or batched
+fn rows(data: Option<plist::Value>) -> Vec<plist::Dictionary> {
+    match data {
+        Some(plist::Value::Array(rows)) => rows
+            .into_iter()
+            .filter_map(|r| r.into_dictionary())
+            .collect(),
+        Some(plist::Value::Dictionary(row)) => vec![row],
+        _ => Vec::new(),
+    }
+}
+
+fn process_sample(row: &plist::Dictionary, pid: u64, at_ms: u64) -> Option<ProcessSample> {
+    // Keyed by PID, which the archive doesn't keep as a number, so it's matched by value
+    let values = row
+        .get("Processes")?
+        .as_dictionary()?
+        .values()
+        .filter_map(|v| v.as_array())
+        .find(|v| v.first().and_then(number) == Some(pid as f64))?;
+    let value = |attribute: &str| {
+        let i = PROCESS_ATTRIBUTES.iter().position(|a| *a == attribute)?;
+        values.get(i).and_then(number)
+    };
+    Some(ProcessSample {
+        at_ms,
+        cpu_percent: value("cpuUsage"),
+        memory_bytes: value("physFootprint").map(|m| m as u64),
+        system_cpu_percent: row
+            .get("SystemCPUUsage")
+            .and_then(|u| u.as_dictionary())
+            .and_then(|u| u.get("CPU_TotalLoad"))
+            .and_then(number),
+    })
+}
+
+fn gpu_sample(row: &plist::Dictionary, at_ms: u64) -> Option<GpuSample> {
+    let value = |key: &str| row.get(key).and_then(number);
+    let sample = GpuSample {
+        at_ms,
+        device_percent: value("Device Utilization %"),
+        renderer_percent: value("Renderer Utilization %"),
+        tiler_percent: value("Tiler Utilization %"),
+        fps: value("CoreAnimationFramesPerSecond"),
+    };
+    (sample.device_percent.is_some() || sample.fps.is_some()).then_some(sample)
+}
+
+/// Samples the process until `until`, using the tunnel connected to the DVT service.
+/// Processes that aren't running are missing from every sample.
+#[tracing::instrument(name = "dvt", skip(adapter))]
+pub async fn sample_process(
+    adapter: Tunnel,
+    pid: u64,
+    started: Instant,
+    until: Instant,
+) -> Result<Vec<ProcessSample>, String> {
+    let mut rs_client = processes::remote_server(adapter).await?;
+    let samples = {
+        let mut channel = rs_client
+            .make_channel(SYSMONTAP_CHANNEL)
+            .await
+            .map_err(|e| format!("Failed to open sysmontap channel: {e:?}"))?;
+
+        let interval_ms = SAMPLE_INTERVAL.as_millis() as u64;
+        let mut config = plist::Dictionary::new();
+        config.insert("ur".into(), interval_ms.into());
+        config.insert("bm".into(), 0u64.into());
+        config.insert("cpuUsage".into(), true.into());
+        config.insert("physFootprint".into(), true.into());
+        config.insert("sampleInterval".into(), (interval_ms * 1_000_000).into());
+        config.insert(
+            "procAttrs".into(),
+            plist::Value::Array(PROCESS_ATTRIBUTES.iter().map(|a| (*a).into()).collect()),
+        );
+        config.insert(
+            "sysAttrs".into(),
+            plist::Value::Array(vec!["physMemSize".into()]),
+        );
+        channel
+            .call_method(
+                Some("setConfig:"),
+                Some(vec![AuxValue::archived_value(config)]),
+                false,
+            )
+            .await
+            .map_err(|e| format!("Failed to configure sysmontap: {e:?}"))?;
+        channel
+            .call_method(Some("start"), None, false)
+            .await
+            .map_err(|e| format!("Failed to start sysmontap: {e:?}"))?;
+
+        let mut samples = Vec::new();
+        while let Ok(message) = tokio::time::timeout_at(until, channel.read_message()).await {
+            let message = message.map_err(|e| format!("Failed to read sysmontap sample: {e:?}"))?;
+            let at_ms = started.elapsed().as_millis() as u64;
+            samples.extend(
+                rows(message.data)
+                    .iter()
+                    .filter_map(|row| process_sample(row, pid, at_ms)),
+            );
+        }
+        if let Err(e) = channel.call_method(Some("stop"), None, false).await {
+            warn!("Failed to stop sysmontap: {e:?}");
+        }
+        samples
+    };
+    debug!("Took {} samples of {pid}", samples.len());
+
+    let mut adapter = rs_client.into_inner();
+    if let Err(e) = adapter.close().await {
+        warn!("Failed to close DVT port: {e:?}");
+    }
+    Ok(samples)
+}
+
+/// Samples the device's GPU until `until`, using the tunnel connected to the DVT service
+#[tracing::instrument(name = "dvt", skip(adapter))]
+pub async fn sample_gpu(
+    adapter: Tunnel,
+    started: Instant,
+    until: Instant,
+) -> Result<Vec<GpuSample>, String> {
+    let mut rs_client = processes::remote_server(adapter).await?;
+    let samples = {
+        let mut channel = rs_client
+            .make_channel(GRAPHICS_CHANNEL)
+            .await
+            .map_err(|e| format!("Failed to open graphics channel: {e:?}"))?;
+        channel
+            .call_method(
+                Some("startSamplingAtTimeInterval:"),
+                Some(vec![AuxValue::archived_value(0.0)]),
+                false,
+            )
+            .await
+            .map_err(|e| format!("Failed to start GPU sampling: {e:?}"))?;
+
+        let mut samples = Vec::new();
+        while let Ok(message) = tokio::time::timeout_at(until, channel.read_message()).await {
+            let message = message.map_err(|e| format!("Failed to read GPU sample: {e:?}"))?;
+            let at_ms = started.elapsed().as_millis() as u64;
+            samples.extend(
+                rows(message.data)
+                    .iter()
+                    .filter_map(|row| gpu_sample(row, at_ms)),
+            );
+        }
+        if let Err(e) = channel.call_method(Some("stopSampling"), None, false).await {
+            warn!("Failed to stop GPU sampling: {e:?}");
+        }
+        samples
+    };
+
+    let mut adapter = rs_client.into_inner();
+    if let Err(e) = adapter.close().await {
+        warn!("Failed to close DVT port: {e:?}");
+    }
+    Ok(samples)
+}
+
+/// Samples the process's CPU and memory and the device's GPU for a few seconds, to check
+/// whether an app running slowly after JIT is slow on its own or held back by the device
+pub async fn handler(
+    ip: SecureClientIp,
+    selector: DeviceSelector,
+    Path(pid): Path<u64>,
+    Query(options): Query<PerformanceOptions>,
+    State(state): State<JitStreamerState>,
+) -> Json<PerformanceReturn> {
+    let seconds = options
+        .seconds
+        .unwrap_or(DEFAULT_SECONDS)
+        .clamp(1, MAX_SECONDS);
+    info!("Got request to sample {pid} for {seconds}s from {:?}", ip.0);
+
+    let (udid, provider) = match jit::JitSession::new(&state, ip.0, &selector)
+        .connect()
+        .await
+    {
+        Ok(d) => d,
+        Err(e) => {
+            return Json(PerformanceReturn {
+                error: Some(e),
+                ..Default::default()
+            })
+        }
+    };
+
+    let sampling = Duration::from_secs(seconds);
+    let limit = sampling + state.config().device_timeouts.attach;
+    let res = common::timeout(limit, "sampling performance", async {
+        let connect = || {
+            jit::developer_service(
+                &state,
+                &udid,
+                &provider,
+                idevice::dvt::SERVICE_NAME,
+                pipeline::DVT_MISSING,
+            )
+        };
+        let (process_adapter, _) = connect().await?;
+        // The GPU is sampled over its own connection, a channel holds the one it's on
+        let gpu_adapter = match connect().await {
+            Ok((adapter, _)) => Some(adapter),
+            Err(e) => {
+                warn!("Failed to connect to DVT again for the GPU: {e}");
+                None
+            }
+        };
+
+        let started = Instant::now();
+        let until = started + sampling;
+        let gpu = async {
+            match gpu_adapter {
+                Some(adapter) => sample_gpu(adapter, started, until)
+                    .await
+                    .inspect_err(|e| warn!("Failed to sample the GPU: {e}"))
+                    .unwrap_or_default(),
+                None => Vec::new(),
+            }
+        };
+        let (samples, gpu) =
+            tokio::join!(sample_process(process_adapter, pid, started, until), gpu);
+        let samples = samples.map_err(|e| JitError::new(ErrorCode::ServiceFailed, e))?;
+        if samples.is_empty() {
+            return Err(JitError::new(
+                ErrorCode::ProcessNotFound,
+                format!("No process is running as {pid}"),
+            ));
+        }
+        Ok((samples, gpu))
+    })
+    .await;
+
+    state.heartbeats.release(&udid).await.ok();
+
+    Json(match res {
+        Ok((samples, gpu)) => PerformanceReturn {
+            ok: true,
+            samples,
+            gpu,
+            error: None,
+        },
+        Err(e) => PerformanceReturn {
+            error: Some(e),
+            ..Default::default()
+        },
+    })
+}
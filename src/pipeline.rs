@@ -0,0 +1,374 @@
+// Jackson Coxson
+// The launch flow as resumable stages, so a dropped tunnel doesn't relaunch the app
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use idevice::{debug_proxy::DebugProxyClient, ReadWrite};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{debug, info, info_span, warn, Instrument};
+
+use crate::{
+    error::{ErrorCode, JitError},
+    launcher::{LaunchMode, LaunchOptions},
+    progress::{LaunchEvent, Progress},
+    retry::RetryPolicy,
+    rsd::{self, TunnelTarget},
+    tunnel::Tunnel,
+};
+
+/// How long a failed launch can be resumed by the client's next request
+const CHECKPOINT_TTL: Duration = Duration::from_secs(60);
+/// How long a continued app is watched for an immediate crash
+const CONTINUE_WATCH: Duration = Duration::from_secs(2);
+
+pub const DVT_MISSING: &str = "Device did not contain DVT service. Is the image mounted?";
+pub const DEBUG_PROXY_MISSING: &str =
+    "Device did not contain debug server service. Is the image mounted?";
+
+/// The last completed stage of a launch
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "stage", rename_all = "snake_case")]
+pub enum LaunchStage {
+    /// Nothing has run yet
+    Start,
+    /// The app is running, suspended if JIT was requested
+    Launched { pid: u64 },
+    /// Debugserver attached and detached, or the app was only opened
+    Done { pid: u64 },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub bundle_id: String,
+    pub stage: LaunchStage,
+    #[serde(skip, default = "Instant::now")]
+    saved: Instant,
+}
+
+/// The last checkpoint of each device's unfinished launch
+pub type CheckpointStore = Arc<Mutex<HashMap<String, Checkpoint>>>;
+
+/// Forgets the checkpoints too old to resume, returning how many
+pub async fn prune(checkpoints: &CheckpointStore) -> usize {
+    let mut checkpoints = checkpoints.lock().await;
+    let before = checkpoints.len();
+    checkpoints.retain(|_, c| c.saved.elapsed() < CHECKPOINT_TTL);
+    before - checkpoints.len()
+}
+
+struct StepError {
+    error: JitError,
+    /// The tunnel dropped, retrying the stage may succeed
+    transient: bool,
+}
+
+impl StepError {
+    fn transient(code: ErrorCode, message: String) -> Self {
+        Self {
+            error: JitError::new(code, message),
+            transient: true,
+        }
+    }
+
+    fn fatal(code: ErrorCode, message: String) -> Self {
+        Self {
+            error: JitError::new(code, message),
+            transient: false,
+        }
+    }
+}
+
+pub struct LaunchPipeline<'a> {
+    target: TunnelTarget<'a>,
+    udid: &'a str,
+    checkpoints: &'a CheckpointStore,
+    progress: &'a Progress,
+    bundle_id: String,
+    options: LaunchOptions,
+    /// How often a stage is retried after a transient failure
+    retry: RetryPolicy,
+    stage: LaunchStage,
+    /// The stage was restored from a previous request's checkpoint
+    resumed: bool,
+    /// The tunnel left open by the launch stage, and the debugserver port on it
+    tunnel: Option<(Tunnel, u16)>,
+}
+
+impl<'a> LaunchPipeline<'a> {
+    /// Creates the pipeline, resuming the device's last unfinished launch of the same app
+    pub async fn new(
+        target: TunnelTarget<'a>,
+        checkpoints: &'a CheckpointStore,
+        progress: &'a Progress,
+        bundle_id: String,
+        options: LaunchOptions,
+        retry: RetryPolicy,
+    ) -> Self {
+        let udid = target.udid;
+        let mut stage = LaunchStage::Start;
+        if let Some(c) = checkpoints.lock().await.remove(udid) {
+            if c.bundle_id == bundle_id
+                && c.saved.elapsed() < CHECKPOINT_TTL
+                && options.mode != LaunchMode::Open
+            {
+                info!(
+                    "Resuming launch of {bundle_id} for {udid} from {:?}",
+                    c.stage
+                );
+                stage = c.stage;
+            }
+        }
+        Self {
+            target,
+            udid,
+            checkpoints,
+            progress,
+            bundle_id,
+            options,
+            retry,
+            resumed: stage != LaunchStage::Start,
+            stage,
+            tunnel: None,
+        }
+    }
+
+    /// Runs the remaining stages, returning the PID of the launched app
+    pub async fn run(mut self) -> Result<u64, JitError> {
+        let mut attempts = 0;
+        loop {
+            if let LaunchStage::Done { pid } = self.stage {
+                self.checkpoints.lock().await.remove(self.udid);
+                return Ok(pid);
+            }
+            let span = info_span!("stage", stage = ?self.stage, attempt = attempts + 1);
+            match self.step().instrument(span).await {
+                Ok(()) => self.checkpoint().await,
+                Err(e) if e.transient && self.retry.retries_left(attempts + 1) => {
+                    attempts += 1;
+                    let delay = self.retry.delay(attempts);
+                    warn!(
+                        "Launch for {} failed at {:?}, retrying in {delay:?}: {}",
+                        self.udid, self.stage, e.error
+                    );
+                    self.progress.send(LaunchEvent::Retrying {
+                        attempt: attempts,
+                        error: e.error.message,
+                    });
+                    self.tunnel = None;
+                    self.target.cache.invalidate(self.udid).await;
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) if self.resumed => {
+                    // The app from the old checkpoint is likely gone, start over
+                    debug!("Resumed launch failed, starting over: {}", e.error);
+                    self.resumed = false;
+                    self.stage = LaunchStage::Start;
+                }
+                Err(e) => {
+                    if e.transient {
+                        // Let the client's retry pick up where this left off
+                        self.checkpoint().await;
+                    } else {
+                        self.checkpoints.lock().await.remove(self.udid);
+                    }
+                    return Err(e.error);
+                }
+            }
+        }
+    }
+
+    async fn checkpoint(&self) {
+        let checkpoint = Checkpoint {
+            bundle_id: self.bundle_id.clone(),
+            stage: self.stage,
+            saved: Instant::now(),
+        };
+        debug!(
+            "Launch checkpoint for {}: {}",
+            self.udid,
+            serde_json::to_string(&checkpoint).unwrap_or_default()
+        );
+        self.checkpoints
+            .lock()
+            .await
+            .insert(self.udid.to_string(), checkpoint);
+    }
+
+    /// Runs the next stage
+    async fn step(&mut self) -> Result<(), StepError> {
+        self.stage = match self.stage {
+            LaunchStage::Start => {
+                let (adapter, services) = rsd::connect_service(
+                    self.target,
+                    self.options.provider.service_name(),
+                    DVT_MISSING,
+                    self.progress,
+                )
+                .await
+                .map_err(tunnel_error)?;
+
+                let debug_proxy_port = match services.port(idevice::debug_proxy::SERVICE_NAME) {
+                    Some(p) => Some(p),
+                    // Opening without JIT never touches debugserver
+                    None if self.options.mode == LaunchMode::Open => None,
+                    None => {
+                        return Err(StepError::fatal(
+                            ErrorCode::DdiNotMounted,
+                            DEBUG_PROXY_MISSING.to_string(),
+                        ))
+                    }
+                };
+
+                let (pid, adapter) = self
+                    .options
+                    .provider
+                    .launch(
+                        adapter,
+                        self.bundle_id.clone(),
+                        self.options.mode,
+                        &self.options.process,
+                    )
+                    .await
+                    .map_err(|e| StepError::fatal(ErrorCode::LaunchFailed, e))?;
+                self.tunnel = debug_proxy_port.map(|p| (adapter, p));
+                self.progress.send(LaunchEvent::Launched { pid });
+                LaunchStage::Launched { pid }
+            }
+            LaunchStage::Launched { pid } if self.options.mode == LaunchMode::Open => {
+                LaunchStage::Done { pid }
+            }
+            LaunchStage::Launched { pid } => {
+                let adapter = match self.tunnel.take() {
+                    Some((mut adapter, port)) => {
+                        info!("Connecting to debug proxy port: {port}");
+                        if let Err(e) = adapter.connect(port).await {
+                            warn!("Failed to connect to debug proxy port: {e:?}");
+                            return Err(StepError::transient(
+                                ErrorCode::TunnelFailed,
+                                "Failed to connect to debug proxy port".to_string(),
+                            ));
+                        }
+                        adapter
+                    }
+                    None => {
+                        rsd::connect_service(
+                            self.target,
+                            idevice::debug_proxy::SERVICE_NAME,
+                            DEBUG_PROXY_MISSING,
+                            self.progress,
+                        )
+                        .await
+                        .map_err(tunnel_error)?
+                        .0
+                    }
+                };
+                attach(adapter, pid, self.options.mode, self.progress).await?;
+                LaunchStage::Done { pid }
+            }
+            LaunchStage::Done { pid } => LaunchStage::Done { pid },
+        };
+        Ok(())
+    }
+}
+
+/// A missing service won't appear by retrying, anything else is the tunnel dropping
+fn tunnel_error(e: String) -> StepError {
+    if e == DVT_MISSING || e == DEBUG_PROXY_MISSING {
+        StepError::fatal(ErrorCode::DdiNotMounted, e)
+    } else {
+        StepError::transient(ErrorCode::TunnelFailed, e)
+    }
+}
+
+/// Attaches over a debugserver connection made without a tunnel, for the lockdown method
+pub async fn attach_direct<R: ReadWrite>(
+    socket: R,
+    pid: u64,
+    mode: LaunchMode,
+    progress: &Progress,
+) -> Result<(), JitError> {
+    attach(socket, pid, mode, progress)
+        .await
+        .map_err(|e| e.error)
+}
+
+/// Attaches debugserver to the process, then either detaches right away or, in
+/// continue mode, resumes it while attached to catch an immediate crash
+#[tracing::instrument(name = "debug_proxy", skip(socket, progress))]
+async fn attach<R: ReadWrite>(
+    socket: R,
+    pid: u64,
+    mode: LaunchMode,
+    progress: &Progress,
+) -> Result<(), StepError> {
+    let mut dp = DebugProxyClient::new(socket);
+    if let Some(res) = send(&mut dp, format!("vAttach;{pid:02X}"))
+        .await?
+        .filter(|r| r.starts_with('E'))
+    {
+        return Err(StepError::fatal(
+            ErrorCode::AttachFailed,
+            format!("Failed to attach to {pid}: {res}"),
+        ));
+    }
+    progress.send(LaunchEvent::Attached { pid });
+
+    match mode {
+        LaunchMode::Continue => resume(&mut dp, pid).await?,
+        _ => {
+            for _ in 0..4 {
+                send(&mut dp, "D".to_string()).await?;
+            }
+        }
+    }
+    progress.send(LaunchEvent::Detached { pid });
+    Ok(())
+}
+
+/// Continues the attached process and watches it for a moment. If it doesn't stop,
+/// the connection is dropped and debugserver detaches, leaving JIT enabled.
+async fn resume<R: ReadWrite>(dp: &mut DebugProxyClient<R>, pid: u64) -> Result<(), StepError> {
+    // Without this, debugserver kills the process when the connection drops
+    send(dp, "QSetDetachOnError:1".to_string()).await?;
+    match tokio::time::timeout(CONTINUE_WATCH, send(dp, "vCont;c".to_string())).await {
+        // Still running
+        Err(_) => Ok(()),
+        Ok(Ok(Some(res))) if res.starts_with('W') || res.starts_with('X') => Err(StepError::fatal(
+            ErrorCode::LaunchFailed,
+            format!("The app exited right after launching: {res}"),
+        )),
+        Ok(Ok(Some(res))) => Err(StepError::fatal(
+            ErrorCode::LaunchFailed,
+            format!("The app stopped right after launching, it may have crashed: {res}"),
+        )),
+        Ok(Ok(None)) => Ok(()),
+        Ok(Err(e)) => {
+            info!("Lost debugserver while continuing {pid}");
+            Err(e)
+        }
+    }
+}
+
+async fn send<R: ReadWrite>(
+    dp: &mut DebugProxyClient<R>,
+    command: String,
+) -> Result<Option<String>, StepError> {
+    match dp.send_command(command.into()).await {
+        Ok(res) => {
+            debug!("command res: {res:?}");
+            Ok(res)
+        }
+        Err(e) => {
+            warn!("Failed to send command to debug server: {e:?}");
+            Err(StepError::transient(
+                ErrorCode::TunnelFailed,
+                format!("Failed to send command to debug server: {e:?}"),
+            ))
+        }
+    }
+}
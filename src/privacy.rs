@@ -0,0 +1,300 @@
+// Jackson Coxson
+// Privacy mode, replacing UDIDs and IP addresses with salted hashes in logs, stats and the
+// admin API so they can be shared without identifying devices
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    net::IpAddr,
+    sync::Arc,
+};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::header::{CONTENT_LENGTH, CONTENT_TYPE},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::Value;
+use sha2::Digest;
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::{bans::BanList, error::JitError, register, JitStreamerState};
+
+/// Admin answers bigger than this are refused rather than passed through unhashed
+const MAX_ADMIN_BODY: usize = 64 * 1024 * 1024;
+
+const HASH_PREFIX: &str = "anon-";
+/// The prefix and 8 bytes of hex
+const HASH_LEN: usize = HASH_PREFIX.len() + 16;
+
+/// A stand-in for the value that's the same everywhere it shows up, but can't be traced back
+/// to it without the salt
+fn hash(salt: &str, value: &str) -> String {
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(b"\0");
+    hasher.update(value.as_bytes());
+    let digest = hasher.finalize();
+    let hex = digest[..8]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+    format!("{HASH_PREFIX}{hex}")
+}
+
+/// Characters UDIDs and IP addresses are made of
+fn in_identifier(b: u8) -> bool {
+    b.is_ascii_hexdigit() || b == b':' || b == b'.' || b == b'-'
+}
+
+fn in_word(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_'
+}
+
+/// The hash of the UDID or IP address the token starts with, and how long that is. Ports
+/// after addresses and punctuation after either are kept.
+fn identifier(salt: &str, token: &str) -> Option<(usize, String)> {
+    let token = token.trim_end_matches(['.', ':', '-']);
+    let host = match token.rsplit_once(':') {
+        Some((host, port))
+            if !host.contains(':')
+                && !port.is_empty()
+                && port.bytes().all(|b| b.is_ascii_digit()) =>
+        {
+            host
+        }
+        _ => token,
+    };
+    if register::valid_udid(host) {
+        return Some((host.len(), hash(salt, host)));
+    }
+    match host.parse::<IpAddr>() {
+        // Listening addresses say nothing about devices
+        Ok(ip) if !ip.is_loopback() && !ip.is_unspecified() => {
+            Some((host.len(), hash(salt, &ip.to_canonical().to_string())))
+        }
+        _ => None,
+    }
+}
+
+/// Where each UDID and IP address in the text starts, how long it is and its hash
+fn identifiers(salt: &str, text: &str) -> Vec<(usize, usize, String)> {
+    let bytes = text.as_bytes();
+    let mut found = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if !in_identifier(bytes[i]) || (i > 0 && in_word(bytes[i - 1])) {
+            i += 1;
+            continue;
+        }
+        let mut end = i;
+        while end < bytes.len() && in_identifier(bytes[end]) {
+            end += 1;
+        }
+        // Part of a longer word, such as a name that starts with hex letters
+        if end < bytes.len() && in_word(bytes[end]) {
+            i = end;
+            continue;
+        }
+        if let Some((len, id)) = identifier(salt, &text[i..end]) {
+            found.push((i, len, id));
+        }
+        i = end;
+    }
+    found
+}
+
+/// Replaces every UDID and IP address in the text with its hash
+pub fn redact(salt: &str, text: &str) -> String {
+    let mut redacted = String::with_capacity(text.len());
+    let mut copied = 0;
+    for (start, len, id) in identifiers(salt, text) {
+        redacted.push_str(&text[copied..start]);
+        redacted.push_str(&id);
+        copied = start + len;
+    }
+    redacted.push_str(&text[copied..]);
+    redacted
+}
+
+/// The UDIDs and IP addresses the admin API lists, by their hashes
+async fn known(
+    state: &JitStreamerState,
+    salt: &str,
+) -> Result<HashMap<String, String>, sqlx::Error> {
+    let mut values = Vec::new();
+    let devices =
+        sqlx::query_as::<_, (String, String, Option<String>)>("SELECT udid, ip, ipv4 FROM devices")
+            .fetch_all(&state.db)
+            .await?;
+    for (udid, ip, ipv4) in devices {
+        values.extend([udid, ip]);
+        values.extend(ipv4);
+    }
+    let waitlist = sqlx::query_as::<_, (String, String)>("SELECT udid, ip FROM waitlist")
+        .fetch_all(&state.db)
+        .await?;
+    for (udid, ip) in waitlist {
+        values.extend([udid, ip]);
+    }
+    values.extend(BanList::list(&state.db).await?.into_iter().map(|b| b.value));
+    values.extend(state.flood_guard.list().await.into_iter().map(|b| b.value));
+    // Sessions can outlive the device's registration
+    values.extend(
+        state
+            .heartbeats
+            .list()
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .map(|h| h.udid),
+    );
+
+    Ok(values
+        .iter()
+        .flat_map(|value| {
+            identifiers(salt, value)
+                .into_iter()
+                .map(move |(start, len, id)| (id, value[start..start + len].to_string()))
+        })
+        .collect())
+}
+
+/// The value with each hash the admin API showed in privacy mode replaced by the UDID or
+/// IP address it stands for, so the listed devices can be acted on
+pub async fn reveal(state: &JitStreamerState, value: &str) -> String {
+    let Some(salt) = state.config().privacy_salt.clone() else {
+        return value.to_string();
+    };
+    if !value.contains(HASH_PREFIX) {
+        return value.to_string();
+    }
+    let known = match known(state, &salt).await {
+        Ok(k) => k,
+        Err(e) => {
+            tracing::warn!("Failed to look up hashed identifiers: {e:?}");
+            return value.to_string();
+        }
+    };
+    unhash(&known, value)
+}
+
+fn unhash(known: &HashMap<String, String>, value: &str) -> String {
+    let mut revealed = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find(HASH_PREFIX) {
+        revealed.push_str(&rest[..start]);
+        let end = (start + HASH_LEN).min(rest.len());
+        match rest.get(start..end).and_then(|id| known.get(id)) {
+            Some(raw) => {
+                revealed.push_str(raw);
+                rest = &rest[end..];
+            }
+            None => {
+                revealed.push_str(HASH_PREFIX);
+                rest = &rest[start + HASH_PREFIX.len()..];
+            }
+        }
+    }
+    revealed.push_str(rest);
+    revealed
+}
+
+/// Text as it's stored for others to read, such as an error in the stats, hashed in
+/// privacy mode
+pub fn text(state: &JitStreamerState, text: &str) -> String {
+    match &state.config().privacy_salt {
+        Some(salt) => redact(salt, text),
+        None => text.to_string(),
+    }
+}
+
+fn redact_value(salt: &str, value: &mut Value) {
+    match value {
+        Value::String(s) => *s = redact(salt, s),
+        Value::Array(values) => values.iter_mut().for_each(|v| redact_value(salt, v)),
+        // Some listings are keyed by UDID
+        Value::Object(map) => {
+            *map = std::mem::take(map)
+                .into_iter()
+                .map(|(k, mut v)| {
+                    redact_value(salt, &mut v);
+                    (redact(salt, &k), v)
+                })
+                .collect();
+        }
+        _ => {}
+    }
+}
+
+/// Middleware hashing the UDIDs and IP addresses in the admin API's JSON answers while
+/// PRIVACY_SALT is set. The action routes take the hashes back through [`reveal`].
+pub async fn admin(
+    State(state): State<JitStreamerState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let response = next.run(request).await;
+    let Some(salt) = state.config().privacy_salt.clone() else {
+        return response;
+    };
+    let is_json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|c| c.as_bytes().starts_with(b"application/json"));
+    if !is_json {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, MAX_ADMIN_BODY).await {
+        Ok(b) => b,
+        Err(e) => {
+            tracing::warn!("Failed to read admin response: {e:?}");
+            return JitError::internal("The response was too big to hash its identifiers")
+                .into_response();
+        }
+    };
+    let mut value = match serde_json::from_slice::<Value>(&bytes) {
+        Ok(v) => v,
+        Err(_) => return Response::from_parts(parts, Body::from(bytes)),
+    };
+    redact_value(&salt, &mut value);
+    parts.headers.remove(CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(serde_json::to_vec(&value).unwrap()))
+}
+
+/// Log output with every UDID and IP address replaced by its hash. Each event is written
+/// in one call, so an identifier is never split across writes.
+#[derive(Clone)]
+pub struct LogWriter {
+    salt: Arc<str>,
+}
+
+impl LogWriter {
+    pub fn new(salt: &str) -> Self {
+        Self { salt: salt.into() }
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogWriter {
+    type Writer = Self;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text = String::from_utf8_lossy(buf);
+        io::stdout().write_all(redact(&self.salt, &text).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
@@ -0,0 +1,180 @@
+// Jackson Coxson
+// The device's running processes, read from the DVT device info service
+
+use std::collections::HashMap;
+
+use idevice::{
+    dvt::{process_control::ProcessControlClient, remote_server::RemoteServerClient},
+    installation_proxy::InstallationProxyClient,
+    IdeviceService,
+};
+use serde::Serialize;
+use tracing::{debug, warn};
+
+use crate::{provider::DeviceProvider, tunnel::Tunnel};
+
+const DEVICE_INFO_CHANNEL: &str = "com.apple.instruments.server.services.deviceinfo";
+
+#[derive(Serialize, Clone, Debug)]
+pub struct RunningProcess {
+    pub pid: u64,
+    pub name: String,
+    /// Only known for installed apps, after `fill_bundle_ids`
+    pub bundle_id: Option<String>,
+    /// Apps, as opposed to daemons and extensions
+    pub is_application: bool,
+    /// The executable
+    #[serde(skip)]
+    path: Option<String>,
+}
+
+impl RunningProcess {
+    /// An app's process on a pretend device
+    #[cfg(feature = "mock")]
+    pub fn app(pid: u64, name: String, bundle_id: String) -> Self {
+        Self {
+            pid,
+            name,
+            bundle_id: Some(bundle_id),
+            is_application: true,
+            path: None,
+        }
+    }
+}
+
+/// Opens the instruments remote server over the tunnel connected to the DVT service
+pub async fn remote_server(adapter: Tunnel) -> Result<RemoteServerClient<Tunnel>, String> {
+    let mut rs_client = RemoteServerClient::new(adapter).map_err(|e| {
+        warn!("Failed to create remote server client: {e:?}");
+        format!("Failed to create remote server client: {e:?}")
+    })?;
+    rs_client.read_message(0).await.map_err(|e| {
+        warn!("Failed to read first message from remote server client: {e:?}");
+        format!("Failed to read first message from remote server client: {e:?}")
+    })?;
+    Ok(rs_client)
+}
+
+/// Lists running processes using the tunnel connected to the DVT service.
+/// Returns the tunnel with the service connection closed, like a launch.
+#[tracing::instrument(name = "dvt", skip_all)]
+pub async fn running(adapter: Tunnel) -> Result<(Vec<RunningProcess>, Tunnel), String> {
+    let mut rs_client = remote_server(adapter).await?;
+
+    let res = {
+        let mut channel = rs_client
+            .make_channel(DEVICE_INFO_CHANNEL)
+            .await
+            .map_err(|e| format!("Failed to open device info channel: {e:?}"))?;
+        channel
+            .call_method(Some("runningProcesses"), None, true)
+            .await
+            .map_err(|e| format!("Failed to request running processes: {e:?}"))?;
+        channel
+            .read_message()
+            .await
+            .map_err(|e| format!("Failed to read running processes: {e:?}"))?
+    };
+    let entries = match res.data {
+        Some(plist::Value::Array(a)) => a,
+        d => {
+            warn!("Unexpected running processes response: {d:?}");
+            return Err("Unexpected running processes response".to_string());
+        }
+    };
+
+    let processes = entries
+        .into_iter()
+        .filter_map(|e| {
+            let e = e.into_dictionary()?;
+            Some(RunningProcess {
+                pid: e.get("pid")?.as_unsigned_integer()?,
+                name: e.get("name")?.as_string()?.to_string(),
+                bundle_id: None,
+                is_application: e
+                    .get("isApplication")
+                    .and_then(|a| a.as_boolean())
+                    .unwrap_or(false),
+                path: e
+                    .get("realAppName")
+                    .and_then(|p| p.as_string())
+                    .map(|p| p.to_string()),
+            })
+        })
+        .collect::<Vec<RunningProcess>>();
+    debug!("Found {} running processes", processes.len());
+
+    let mut adapter = rs_client.into_inner();
+    if let Err(e) = adapter.close().await {
+        warn!("Failed to close DVT port: {e:?}");
+        return Err("Failed to close RemoteXPC port".to_string());
+    }
+    Ok((processes, adapter))
+}
+
+/// Lifts the jetsam memory limit of a running process, using the tunnel connected to
+/// the DVT service
+#[tracing::instrument(name = "dvt", skip(adapter))]
+pub async fn disable_memory_limit(adapter: Tunnel, pid: u64) -> Result<(), String> {
+    let mut rs_client = remote_server(adapter).await?;
+    let mut pc_client = ProcessControlClient::new(&mut rs_client)
+        .await
+        .map_err(|e| {
+            warn!("Failed to create process control client: {e:?}");
+            format!("Failed to create process control client: {e:?}")
+        })?;
+    pc_client.disable_memory_limit(pid).await.map_err(|e| {
+        warn!("Failed to disable memory limit: {e:?}");
+        format!("Failed to disable memory limit of {pid}: {e:?}")
+    })
+}
+
+/// Finds a process by name, ignoring case. Apps win over daemons of the same name.
+pub fn find<'a>(processes: &'a [RunningProcess], name: &str) -> Option<&'a RunningProcess> {
+    processes
+        .iter()
+        .filter(|p| p.name.eq_ignore_ascii_case(name))
+        .max_by_key(|p| p.is_application)
+}
+
+/// Fills in the bundle ID of processes whose executable is inside an installed app.
+/// Processes are left without one if the apps can't be listed.
+pub async fn fill_bundle_ids(provider: &DeviceProvider, processes: &mut [RunningProcess]) {
+    let apps = match InstallationProxyClient::connect(provider).await {
+        Ok(mut i) => i.get_apps(None, None).await,
+        Err(e) => Err(e),
+    };
+    let apps = match apps {
+        Ok(a) => a,
+        Err(e) => {
+            warn!("Failed to get apps for process bundle IDs: {e:?}");
+            return;
+        }
+    };
+
+    // The app bundle's path to its bundle ID
+    let bundles = apps
+        .into_iter()
+        .filter_map(|(bundle_id, app)| {
+            let path = app.as_dictionary()?.get("Path")?.as_string()?;
+            Some((normalize(path).to_string(), bundle_id))
+        })
+        .collect::<HashMap<String, String>>();
+
+    for process in processes.iter_mut() {
+        let path = match &process.path {
+            Some(p) => normalize(p),
+            None => continue,
+        };
+        // The executable is directly inside the .app
+        process.bundle_id = path
+            .rsplit_once('/')
+            .and_then(|(app, _)| bundles.get(app))
+            .cloned();
+    }
+}
+
+/// Paths are reported with or without the /private prefix
+fn normalize(path: &str) -> &str {
+    path.strip_prefix("/private").unwrap_or(path)
+}
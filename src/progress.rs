@@ -0,0 +1,139 @@
+// Jackson Coxson
+// Launch phases reported to clients as they complete, so a hang can be pinned on a phase
+
+use axum::extract::ws::Message;
+use serde::Serialize;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::error::JitError;
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "phase", rename_all = "snake_case")]
+pub enum LaunchEvent {
+    /// The device didn't answer, so it was nudged before connecting. Only sent when it was.
+    Nudge {
+        woke: bool,
+    },
+    /// The device is heartbeating, so lockdown is reachable
+    Heartbeat {
+        reused: bool,
+        woke: bool,
+    },
+    /// The CoreDevice tunnel is up
+    Tunnel,
+    /// The RemoteXPC service list is known
+    Xpc {
+        cached: bool,
+    },
+    /// The app is running, suspended if JIT was requested
+    Launched {
+        pid: u64,
+    },
+    /// Debugserver attached to the app
+    Attached {
+        pid: u64,
+    },
+    /// Debugserver detached, leaving JIT enabled
+    Detached {
+        pid: u64,
+    },
+    /// A phase failed and is being retried
+    Retrying {
+        attempt: u32,
+        error: String,
+    },
+    Done {
+        pid: u64,
+    },
+    Error {
+        #[serde(flatten)]
+        error: JitError,
+        busy: bool,
+    },
+}
+
+impl LaunchEvent {
+    pub fn to_ws_message(&self) -> Message {
+        Message::text(serde_json::to_string(&self).unwrap())
+    }
+
+    /// The `phase` tag the event is serialized with
+    pub fn phase(&self) -> &'static str {
+        match self {
+            LaunchEvent::Nudge { .. } => "nudge",
+            LaunchEvent::Heartbeat { .. } => "heartbeat",
+            LaunchEvent::Tunnel => "tunnel",
+            LaunchEvent::Xpc { .. } => "xpc",
+            LaunchEvent::Launched { .. } => "launched",
+            LaunchEvent::Attached { .. } => "attached",
+            LaunchEvent::Detached { .. } => "detached",
+            LaunchEvent::Retrying { .. } => "retrying",
+            LaunchEvent::Done { .. } => "done",
+            LaunchEvent::Error { .. } => "error",
+        }
+    }
+}
+
+/// When a phase completed, counted from the start of the request
+#[derive(Clone, Debug, Serialize)]
+pub struct PhaseTiming {
+    pub phase: &'static str,
+    pub elapsed_ms: u64,
+}
+
+/// How many milliseconds each phase of a launch took, so a slow launch can be pinned on a
+/// phase. Phases that didn't run, like the tunnel for iOS 16, are None. Retried phases add up.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct PhaseDurations {
+    /// Waking a device that didn't answer
+    pub nudge_ms: Option<u64>,
+    pub heartbeat_ms: Option<u64>,
+    pub tunnel_ms: Option<u64>,
+    pub xpc_ms: Option<u64>,
+    /// Starting the app through DVT process control
+    pub dvt_ms: Option<u64>,
+    /// Attaching and detaching
+    pub debugserver_ms: Option<u64>,
+}
+
+impl PhaseDurations {
+    /// Each phase took from the previous event until it completed
+    pub fn from_timings(timings: &[PhaseTiming]) -> Self {
+        let mut durations = Self::default();
+        let mut previous = 0;
+        for timing in timings {
+            let slot = match timing.phase {
+                "nudge" => Some(&mut durations.nudge_ms),
+                "heartbeat" => Some(&mut durations.heartbeat_ms),
+                "tunnel" => Some(&mut durations.tunnel_ms),
+                "xpc" => Some(&mut durations.xpc_ms),
+                "launched" => Some(&mut durations.dvt_ms),
+                "attached" | "detached" => Some(&mut durations.debugserver_ms),
+                _ => None,
+            };
+            if let Some(slot) = slot {
+                let took = timing.elapsed_ms.saturating_sub(previous);
+                *slot = Some(slot.unwrap_or(0) + took);
+            }
+            previous = timing.elapsed_ms;
+        }
+        durations
+    }
+}
+
+/// Where a launch reports its progress, if anyone is listening
+#[derive(Clone, Default)]
+pub struct Progress(Option<UnboundedSender<LaunchEvent>>);
+
+impl Progress {
+    pub fn channel() -> (Self, UnboundedReceiver<LaunchEvent>) {
+        let (sender, receiver) = unbounded_channel();
+        (Self(Some(sender)), receiver)
+    }
+
+    pub fn send(&self, event: LaunchEvent) {
+        if let Some(sender) = &self.0 {
+            sender.send(event).ok();
+        }
+    }
+}
@@ -0,0 +1,158 @@
+// Jackson Coxson
+// Picks how a device is reached, over the network or through usbmuxd when it's plugged in
+
+use std::{future::Future, net::IpAddr, pin::Pin};
+
+use idevice::{
+    pairing_file::PairingFile,
+    provider::{IdeviceProvider, TcpProvider},
+    Idevice, IdeviceError,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    common,
+    dependencies::{self, Dependency},
+    error::{ErrorCode, JitError},
+    heartbeat::{self, HeartbeatError, HeartbeatStart},
+    netmuxd::{self, MuxerError, UsbProvider},
+    retry, JitStreamerState,
+};
+
+#[derive(Debug)]
+pub enum DeviceProvider {
+    Tcp(TcpProvider),
+    Usb(UsbProvider),
+}
+
+impl DeviceProvider {
+    pub fn pairing_file(&self) -> &PairingFile {
+        match self {
+            Self::Tcp(p) => &p.pairing_file,
+            Self::Usb(p) => &p.pairing_file,
+        }
+    }
+}
+
+impl IdeviceProvider for DeviceProvider {
+    fn connect(
+        &self,
+        port: u16,
+    ) -> Pin<Box<dyn Future<Output = Result<Idevice, IdeviceError>> + Send>> {
+        match self {
+            Self::Tcp(p) => p.connect(port),
+            Self::Usb(p) => p.connect(port),
+        }
+    }
+
+    fn label(&self) -> &str {
+        match self {
+            Self::Tcp(p) => p.label(),
+            Self::Usb(p) => p.label(),
+        }
+    }
+
+    fn get_pairing_file(
+        &self,
+    ) -> Pin<Box<dyn Future<Output = Result<PairingFile, IdeviceError>> + Send>> {
+        match self {
+            Self::Tcp(p) => p.get_pairing_file(),
+            Self::Usb(p) => p.get_pairing_file(),
+        }
+    }
+}
+
+/// Reaches the device over USB if USB_DEVICES is on and it's plugged in. Otherwise it's
+/// reached at its IP, with a heartbeat to keep the connection up. USB needs no heartbeat.
+/// While the muxer is down the network is tried instead, see `dependencies`.
+/// Release the heartbeat when done either way, releasing one that doesn't exist is fine.
+/// In a cluster, the device is only reached over the network by the node holding its lease.
+/// Devices that keep failing to connect are refused for a while, see `breaker`.
+pub async fn start(
+    state: &JitStreamerState,
+    udid: &str,
+    ip: IpAddr,
+    pairing_file: PairingFile,
+) -> Result<(DeviceProvider, HeartbeatStart), JitError> {
+    let mut muxer_error = None;
+    if state.config().usb_devices {
+        let device_id = match state.dependencies.missing(Dependency::Usbmuxd).await {
+            Some(e) => Err(e),
+            None => netmuxd::usb_device_id(udid).await.or_else(|e| match e {
+                MuxerError::Unavailable(_) => Err(e.to_string()),
+                e => {
+                    warn!("Failed to list USB devices: {e}");
+                    Ok(None)
+                }
+            }),
+        };
+        match device_id {
+            Ok(Some(device_id)) => {
+                debug!("Reaching {udid} over USB");
+                let provider = UsbProvider {
+                    device_id,
+                    pairing_file,
+                    label: "JitStreamer-EB".to_string(),
+                };
+                return Ok((DeviceProvider::Usb(provider), HeartbeatStart::default()));
+            }
+            Ok(None) => {}
+            Err(e) => {
+                dependencies::record(state, Dependency::Usbmuxd, &Err(e.clone())).await;
+                muxer_error = Some(e);
+            }
+        }
+    }
+
+    state.circuit_breaker.check(udid).await?;
+    state.cluster.claim(udid).await?;
+    let config = state.config();
+    let start = common::timeout(config.device_timeouts.heartbeat, "connecting", async {
+        let mut failures = 0;
+        loop {
+            match heartbeat::ensure_heartbeat(&state.heartbeats, udid, ip, &pairing_file).await {
+                Ok(start) => break Ok(start),
+                Err(HeartbeatError::Connect(e))
+                    if retry::transient(&e) && config.retry.retries_left(failures + 1) =>
+                {
+                    failures += 1;
+                    let delay = config.retry.delay(failures);
+                    info!("Failed to heartbeat device, retrying in {delay:?}: {:?}", e);
+                    tokio::time::sleep(delay).await;
+                }
+                Err(HeartbeatError::Connect(e)) => {
+                    info!("Failed to heartbeat device: {:?}", e);
+                    break Err(JitError::heartbeat(e, &config, ip));
+                }
+                Err(e) => {
+                    warn!("Failed to keep a heartbeat for {udid}: {e}");
+                    break Err(e.into());
+                }
+            }
+        }
+    })
+    .await;
+    state
+        .circuit_breaker
+        .record(
+            udid,
+            start.as_ref().err(),
+            config.circuit_breaker_failures,
+            config.circuit_breaker_cooldown,
+        )
+        .await;
+    // The device may be plugged in, so the muxer is likelier to blame than the device
+    let start = start.map_err(|e| match muxer_error {
+        Some(muxer_error) if e.code != ErrorCode::PairingInvalid => JitError::new(
+            ErrorCode::MuxerUnavailable,
+            format!("{muxer_error}. The device couldn't be reached over the network either: {e}"),
+        ),
+        _ => e,
+    })?;
+    let provider = TcpProvider {
+        addr: ip,
+        pairing_file,
+        label: "JitStreamer-EB".to_string(),
+    };
+    Ok((DeviceProvider::Tcp(provider), start))
+}
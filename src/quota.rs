@@ -0,0 +1,55 @@
+// Jackson Coxson
+// Launch quotas per device, so heavy users of a public instance can't crowd out the rest
+
+use tracing::info;
+
+use crate::{
+    error::{ErrorCode, JitError},
+    stats, JitStreamerState,
+};
+
+/// A window launches are counted over, with its SQLite modifier
+const WINDOWS: [(&str, &str); 2] = [("hour", "1 hour"), ("day", "1 day")];
+
+/// Rejects the launch if the device has used up its successful launches for the last hour
+/// or day. The window slides, so the quota frees up as each launch ages out of it.
+pub async fn check(state: &JitStreamerState, udid: &str) -> Result<(), JitError> {
+    let config = state.config();
+    let limits = [
+        config.quota_launches_per_hour,
+        config.quota_launches_per_day,
+    ];
+    for ((window, modifier), limit) in WINDOWS.into_iter().zip(limits) {
+        if limit == 0 {
+            continue;
+        }
+        // The limit-th most recent launch in the window is the next to leave it
+        let resets_at = sqlx::query_scalar::<_, String>(
+            "SELECT datetime(at, ?) FROM launch_stats WHERE kind = 'launch' AND ok AND udid_hash = ? AND at > datetime('now', ?) ORDER BY at DESC LIMIT 1 OFFSET ?",
+        )
+        .bind(format!("+{modifier}"))
+        .bind(stats::hash_udid(udid))
+        .bind(format!("-{modifier}"))
+        .bind(limit - 1)
+        .fetch_optional(&state.db)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to query database: {e:?}");
+            JitError::internal("Failed to query database")
+        })?;
+
+        if let Some(resets_at) = resets_at {
+            info!("{udid} is over its quota of {limit} launches per {window}");
+            return Err(JitError {
+                resets_at: Some(resets_at.clone()),
+                ..JitError::new(
+                    ErrorCode::QuotaExceeded,
+                    format!(
+                        "This device can launch {limit} times per {window}, try again after {resets_at} UTC"
+                    ),
+                )
+            });
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,176 @@
+// Jackson Coxson
+// Per client IP rate limits for the expensive routes
+
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{header::RETRY_AFTER, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_client_ip::SecureClientIp;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::{
+    config::Config,
+    error::{ErrorCode, JitError},
+    JitStreamerState,
+};
+
+/// Buckets are only pruned once there are this many
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// A group of routes sharing a budget
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Budget {
+    Register,
+    Launch,
+    GetApps,
+    Capacity,
+}
+
+impl Budget {
+    /// Requests allowed per minute, 0 for unlimited
+    fn per_minute(self, config: &Config) -> u32 {
+        match self {
+            Budget::Register => config.rate_limit_register,
+            Budget::Launch => config.rate_limit_launch,
+            Budget::GetApps => config.rate_limit_get_apps,
+            Budget::Capacity => config.rate_limit_capacity,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets per budget and client, refilled continuously so a client can
+/// burst up to a minute's budget and then continue at the steady rate
+#[derive(Clone)]
+pub struct RateLimiter<K = (Budget, IpAddr)>(Arc<Mutex<HashMap<K, Bucket>>>);
+
+impl<K> Default for RateLimiter<K> {
+    fn default() -> Self {
+        Self(Arc::default())
+    }
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    /// Takes a token, or returns how long until one is available
+    pub async fn take(&self, key: K, per_minute: u32) -> Result<(), Duration> {
+        let capacity = per_minute as f64;
+        let per_second = capacity / 60.0;
+        let now = Instant::now();
+
+        let mut lock = self.0.lock().await;
+        if lock.len() >= PRUNE_THRESHOLD {
+            // A bucket untouched for a minute is full again, same as a missing one
+            lock.retain(|_, b| now.duration_since(b.updated) < Duration::from_secs(60));
+        }
+
+        let bucket = lock.entry(key).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        bucket.tokens = (bucket.tokens
+            + now.duration_since(bucket.updated).as_secs_f64() * per_second)
+            .min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+        }
+    }
+}
+
+/// When each client IP last did something it has to wait between, like registering a device
+#[derive(Clone, Default)]
+pub struct Cooldowns(Arc<Mutex<HashMap<IpAddr, Instant>>>);
+
+impl Cooldowns {
+    /// How long until the IP's cooldown is over, none if it is
+    pub async fn remaining(&self, ip: IpAddr, cooldown: Duration) -> Option<Duration> {
+        self.0
+            .lock()
+            .await
+            .get(&ip.to_canonical())
+            .map(|at| cooldown.saturating_sub(at.elapsed()))
+            .filter(|r| !r.is_zero())
+    }
+
+    /// Starts the IP's cooldown
+    pub async fn start(&self, ip: IpAddr, cooldown: Duration) {
+        let mut lock = self.0.lock().await;
+        if lock.len() >= PRUNE_THRESHOLD {
+            lock.retain(|_, at| at.elapsed() < cooldown);
+        }
+        lock.insert(ip.to_canonical(), Instant::now());
+    }
+}
+
+#[derive(serde::Serialize)]
+struct RateLimitedReturn {
+    ok: bool,
+    #[serde(flatten)]
+    error: JitError,
+}
+
+/// Takes a token from the client's budget, or returns the error telling it how long to
+/// wait along with the wait in seconds. `what` is the route or call being made, for the log.
+pub async fn check(
+    state: &JitStreamerState,
+    budget: Budget,
+    ip: IpAddr,
+    what: &str,
+) -> Result<(), (JitError, u64)> {
+    let per_minute = budget.per_minute(&state.config());
+    if per_minute == 0 {
+        return Ok(());
+    }
+
+    let ip = ip.to_canonical();
+    if let Err(wait) = state.rate_limiter.take((budget, ip), per_minute).await {
+        let retry_after = wait.as_secs() + 1;
+        warn!("Rate limiting {ip} on {what}, retry in {retry_after}s");
+        return Err((
+            JitError::new(
+                ErrorCode::RateLimited,
+                format!("Too many requests, try again in {retry_after} seconds"),
+            ),
+            retry_after,
+        ));
+    }
+    Ok(())
+}
+
+/// Middleware rejecting clients over the budget's limit with 429 and Retry-After
+pub async fn enforce(
+    State((state, budget)): State<(JitStreamerState, Budget)>,
+    ip: SecureClientIp,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Err((error, retry_after)) = check(&state, budget, ip.0, request.uri().path()).await {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(RETRY_AFTER, retry_after.to_string())],
+            Json(RateLimitedReturn { ok: false, error }),
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
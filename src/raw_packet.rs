@@ -1,13 +1,111 @@
 // jkcoxson -  excerpt from netmuxd
 
-use log::warn;
+use std::{
+    fmt,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::warn;
+
+/// The header is four little endian u32s: size, version, message and tag
+const HEADER_SIZE: usize = 16;
+/// Larger packets are refused instead of allocated, usbmuxd's are a few KB at most
+const MAX_PACKET_SIZE: usize = 1024 * 1024;
+
+/// How the packet's payload is encoded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Version {
+    /// The old binary structs, which only the oldest clients send
+    Binary = 0,
+    Plist = 1,
+}
+
+/// The packet's message type. Clients talking plists always send Plist, with the
+/// request itself in the MessageType key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Message {
+    Result = 1,
+    Connect = 2,
+    Listen = 3,
+    DeviceAdd = 4,
+    DeviceRemove = 5,
+    DevicePaired = 6,
+    Plist = 8,
+}
+
+/// Matches a response to its request, the muxer echoes the request's tag back
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tag(pub u32);
+
+impl Tag {
+    /// A tag no other request from this process has used
+    pub fn next() -> Self {
+        static NEXT: AtomicU32 = AtomicU32::new(1);
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+#[derive(Debug)]
+pub enum PacketError {
+    /// The connection failed or closed partway through a packet
+    Io(std::io::Error),
+    /// The header's size is smaller than the header or larger than we accept
+    Size(u32),
+    Version(u32),
+    Message(u32),
+    Plist(plist::Error),
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "failed to read packet: {e}"),
+            Self::Size(s) => write!(f, "invalid packet size {s}"),
+            Self::Version(v) => write!(f, "unknown packet version {v}"),
+            Self::Message(m) => write!(f, "unknown packet message {m}"),
+            Self::Plist(e) => write!(f, "failed to parse packet plist: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for PacketError {}
+
+impl TryFrom<u32> for Version {
+    type Error = PacketError;
+    fn try_from(version: u32) -> Result<Self, Self::Error> {
+        match version {
+            0 => Ok(Self::Binary),
+            1 => Ok(Self::Plist),
+            v => Err(PacketError::Version(v)),
+        }
+    }
+}
+
+impl TryFrom<u32> for Message {
+    type Error = PacketError;
+    fn try_from(message: u32) -> Result<Self, Self::Error> {
+        match message {
+            1 => Ok(Self::Result),
+            2 => Ok(Self::Connect),
+            3 => Ok(Self::Listen),
+            4 => Ok(Self::DeviceAdd),
+            5 => Ok(Self::DeviceRemove),
+            6 => Ok(Self::DevicePaired),
+            8 => Ok(Self::Plist),
+            m => Err(PacketError::Message(m)),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct RawPacket {
     pub size: u32,
-    pub version: u32,
-    pub message: u32,
-    pub tag: u32,
+    pub version: Version,
+    pub message: Message,
+    pub tag: Tag,
     pub plist: plist::Dictionary,
 }
 
@@ -19,10 +117,29 @@ fn plist_to_bytes(p: &plist::Dictionary) -> Vec<u8> {
     writer.into_inner().unwrap()
 }
 
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
 impl RawPacket {
-    pub fn new(plist: plist::Dictionary, version: u32, message: u32, tag: u32) -> RawPacket {
+    /// A plist request, which is what every message sent to the muxer is
+    pub fn request(plist: plist::Dictionary) -> RawPacket {
+        RawPacket::new(plist, Version::Plist, Message::Plist, Tag::next())
+    }
+
+    /// The response to this packet, with its tag so the client can match them up
+    pub fn reply(&self, plist: plist::Dictionary) -> RawPacket {
+        RawPacket::new(plist, Version::Plist, Message::Plist, self.tag)
+    }
+
+    pub fn new(
+        plist: plist::Dictionary,
+        version: Version,
+        message: Message,
+        tag: Tag,
+    ) -> RawPacket {
         let plist_bytes = plist_to_bytes(&plist);
-        let size = plist_bytes.len() as u32 + 16;
+        let size = (plist_bytes.len() + HEADER_SIZE) as u32;
         RawPacket {
             size,
             version,
@@ -31,93 +148,219 @@ impl RawPacket {
             plist,
         }
     }
+
+    /// Reads one packet, the header first and then exactly the size it gives, so
+    /// however the bytes arrive the next packet is left untouched
+    pub async fn read<R: AsyncRead + Unpin + ?Sized>(reader: &mut R) -> Result<Self, PacketError> {
+        let mut header = [0u8; HEADER_SIZE];
+        reader
+            .read_exact(&mut header)
+            .await
+            .map_err(PacketError::Io)?;
+        let size = read_u32(&header, 0);
+        if (size as usize) < HEADER_SIZE || size as usize > MAX_PACKET_SIZE {
+            return Err(PacketError::Size(size));
+        }
+
+        let mut packet = header.to_vec();
+        packet.resize(size as usize, 0);
+        reader
+            .read_exact(&mut packet[HEADER_SIZE..])
+            .await
+            .map_err(PacketError::Io)?;
+        packet.as_slice().try_into()
+    }
+
+    pub async fn write<W: AsyncWrite + Unpin + ?Sized>(
+        self,
+        writer: &mut W,
+    ) -> std::io::Result<()> {
+        let bytes: Vec<u8> = self.into();
+        writer.write_all(&bytes).await?;
+        writer.flush().await
+    }
 }
 
 impl From<RawPacket> for Vec<u8> {
     fn from(raw_packet: RawPacket) -> Vec<u8> {
         let mut packet = vec![];
         packet.extend_from_slice(&raw_packet.size.to_le_bytes());
-        packet.extend_from_slice(&raw_packet.version.to_le_bytes());
-        packet.extend_from_slice(&raw_packet.message.to_le_bytes());
-        packet.extend_from_slice(&raw_packet.tag.to_le_bytes());
+        packet.extend_from_slice(&(raw_packet.version as u32).to_le_bytes());
+        packet.extend_from_slice(&(raw_packet.message as u32).to_le_bytes());
+        packet.extend_from_slice(&raw_packet.tag.0.to_le_bytes());
         packet.extend_from_slice(&plist_to_bytes(&raw_packet.plist));
         packet
     }
 }
 
-impl TryFrom<&mut Vec<u8>> for RawPacket {
-    type Error = ();
-    fn try_from(packet: &mut Vec<u8>) -> Result<Self, Self::Error> {
-        let packet: &[u8] = packet;
-        packet.try_into()
-    }
-}
-
 impl TryFrom<&[u8]> for RawPacket {
-    type Error = ();
-    fn try_from(packet: &[u8]) -> Result<Self, ()> {
-        // Determine if we have enough data to parse
-        if packet.len() < 16 {
+    type Error = PacketError;
+    /// Parses one whole packet, the slice may hold more after it
+    fn try_from(packet: &[u8]) -> Result<Self, Self::Error> {
+        if packet.len() < HEADER_SIZE {
             warn!("Not enough data to parse a raw packet header");
-            return Err(());
+            return Err(PacketError::Size(packet.len() as u32));
         }
 
-        let packet_size = &packet[0..4];
-        let packet_size = u32::from_le_bytes(match packet_size.try_into() {
-            Ok(packet_size) => packet_size,
-            Err(_) => {
-                warn!("Failed to parse packet size");
-                return Err(());
-            }
-        });
-
-        // Determine if we have enough data to parse
-        if packet.len() < packet_size as usize {
+        let size = read_u32(packet, 0);
+        if (size as usize) < HEADER_SIZE || packet.len() < size as usize {
             warn!("Not enough data to parse a raw packet body");
-            return Err(());
+            return Err(PacketError::Size(size));
         }
 
-        let packet_version = &packet[4..8];
-        let packet_version = u32::from_le_bytes(match packet_version.try_into() {
-            Ok(packet_version) => packet_version,
-            Err(_) => {
-                warn!("Failed to parse packet version");
-                return Err(());
-            }
-        });
-
-        let message = &packet[8..12];
-        let message = u32::from_le_bytes(match message.try_into() {
-            Ok(message) => message,
-            Err(_) => {
-                warn!("Failed to parse packet message");
-                return Err(());
-            }
-        });
-
-        let packet_tag = &packet[12..16];
-        let packet_tag = u32::from_le_bytes(match packet_tag.try_into() {
-            Ok(packet_tag) => packet_tag,
-            Err(_) => {
-                warn!("Failed to parse packet tag");
-                return Err(());
-            }
-        });
-
-        let plist = &packet[16..packet_size as usize];
-        let plist = if let Ok(p) = plist::from_bytes(plist) {
-            p
-        } else {
+        let version = Version::try_from(read_u32(packet, 4))?;
+        let message = Message::try_from(read_u32(packet, 8))?;
+        let tag = Tag(read_u32(packet, 12));
+        let plist = plist::from_bytes(&packet[HEADER_SIZE..size as usize]).map_err(|e| {
             warn!("Failed to parse packet plist");
-            return Err(());
-        };
+            PacketError::Plist(e)
+        })?;
 
         Ok(RawPacket {
-            size: packet_size,
-            version: packet_version,
+            size,
+            version,
             message,
-            tag: packet_tag,
+            tag,
             plist,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use tokio::io::ReadBuf;
+
+    use super::*;
+
+    /// Hands out the bytes a few at a time, like a socket that's slow to fill
+    struct Trickle<'a> {
+        bytes: &'a [u8],
+        chunk: usize,
+    }
+
+    impl AsyncRead for Trickle<'_> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            let n = self.chunk.min(self.bytes.len()).min(buf.remaining());
+            buf.put_slice(&self.bytes[..n]);
+            self.bytes = &self.bytes[n..];
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn request(message_type: &str) -> RawPacket {
+        let mut plist = plist::Dictionary::new();
+        plist.insert("MessageType".into(), message_type.into());
+        RawPacket::request(plist)
+    }
+
+    fn header(size: u32) -> Vec<u8> {
+        [size, Version::Plist as u32, Message::Plist as u32, 1]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect()
+    }
+
+    fn message_type(packet: &RawPacket) -> Option<&str> {
+        packet.plist.get("MessageType").and_then(|v| v.as_string())
+    }
+
+    #[tokio::test]
+    async fn reads_header_and_body_split_across_reads() {
+        let sent = request("ListDevices");
+        let tag = sent.tag;
+        let bytes: Vec<u8> = sent.into();
+
+        let packet = RawPacket::read(&mut Trickle {
+            bytes: &bytes,
+            chunk: 3,
+        })
+        .await
+        .unwrap();
+        assert_eq!(packet.size as usize, bytes.len());
+        assert_eq!(packet.version, Version::Plist);
+        assert_eq!(packet.message, Message::Plist);
+        assert_eq!(packet.tag, tag);
+        assert_eq!(message_type(&packet), Some("ListDevices"));
+    }
+
+    #[tokio::test]
+    async fn rejects_size_smaller_than_header() {
+        let bytes = header(HEADER_SIZE as u32 - 1);
+        let res = RawPacket::read(&mut bytes.as_slice()).await;
+        assert!(matches!(res, Err(PacketError::Size(s)) if s as usize == HEADER_SIZE - 1));
+    }
+
+    #[tokio::test]
+    async fn rejects_size_larger_than_max() {
+        let bytes = header(MAX_PACKET_SIZE as u32 + 1);
+        let res = RawPacket::read(&mut bytes.as_slice()).await;
+        assert!(matches!(res, Err(PacketError::Size(s)) if s as usize == MAX_PACKET_SIZE + 1));
+    }
+
+    #[tokio::test]
+    async fn fails_on_eof_in_body() {
+        let bytes: Vec<u8> = request("ListDevices").into();
+        let truncated = &bytes[..bytes.len() - 10];
+
+        let res = RawPacket::read(&mut Trickle {
+            bytes: truncated,
+            chunk: 7,
+        })
+        .await;
+        assert!(matches!(
+            res,
+            Err(PacketError::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof
+        ));
+    }
+
+    #[tokio::test]
+    async fn reads_packets_back_to_back() {
+        let first = request("Listen");
+        let second = request("ListDevices");
+        let tags = (first.tag, second.tag);
+        let mut bytes: Vec<u8> = first.into();
+        bytes.extend(Vec::<u8>::from(second));
+
+        let mut reader = Trickle {
+            bytes: &bytes,
+            chunk: 5,
+        };
+        let first = RawPacket::read(&mut reader).await.unwrap();
+        let second = RawPacket::read(&mut reader).await.unwrap();
+        assert_eq!((first.tag, second.tag), tags);
+        assert_eq!(message_type(&first), Some("Listen"));
+        assert_eq!(message_type(&second), Some("ListDevices"));
+        assert!(matches!(
+            RawPacket::read(&mut reader).await,
+            Err(PacketError::Io(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn write_then_read_round_trips() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let sent = request("ReadBUID");
+        let (size, tag) = (sent.size, sent.tag);
+
+        let (written, read) = tokio::join!(sent.write(&mut client), RawPacket::read(&mut server));
+        written.unwrap();
+        let read = read.unwrap();
+        assert_eq!(read.size, size);
+        assert_eq!(read.tag, tag);
+        assert_eq!(message_type(&read), Some("ReadBUID"));
+
+        let reply = read.reply(plist::Dictionary::new());
+        let (written, read) = tokio::join!(reply.write(&mut server), RawPacket::read(&mut client));
+        written.unwrap();
+        assert_eq!(read.unwrap().tag, tag);
+    }
+}
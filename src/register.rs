@@ -1,353 +1,1557 @@
 // Jackson Coxson
 
-use axum::{body::Bytes, http::StatusCode, response::Html};
+use axum::{
+    body::{Body, Bytes},
+    extract::{Query, State},
+    http::{
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::{Html, IntoResponse, Response},
+    Json,
+};
 use axum_client_ip::SecureClientIp;
-use log::info;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use idevice::pairing_file::PairingFile;
 use plist::Dictionary;
+use serde::{Deserialize, Serialize};
 use sha2::Digest;
-use sqlite::State;
-use std::net::{IpAddr, Ipv6Addr};
-
-/// Check to make sure the Wireguard interface exists
-pub fn check_wireguard() {
-    let wireguard_config_name =
-        std::env::var("WIREGUARD_CONFIG_NAME").unwrap_or("jitstreamer".to_string());
-    let wireguard_conf = format!("/etc/wireguard/{wireguard_config_name}.conf");
-    let wireguard_port = std::env::var("WIREGUARD_PORT")
-        .unwrap_or("51869".to_string())
-        .parse::<u16>()
-        .unwrap_or(51869);
-    let wireguard_server_address =
-        std::env::var("WIREGUARD_SERVER_ADDRESS").unwrap_or("fd00::/128".to_string());
-
-    if !std::fs::exists(&wireguard_conf).unwrap() {
-        let key = wg_config::WgKey::generate_private_key().expect("failed to generate key");
-        let interface = wg_config::WgInterface::new(
-            key,
-            wireguard_server_address.parse().unwrap(),
-            Some(wireguard_port),
-            None,
-            None,
-            None,
-        )
-        .unwrap();
+use sqlx::Connection;
+use std::{
+    collections::HashSet,
+    convert::Infallible,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+use tracing::info;
+
+use crate::{
+    acl::{Allowlist, Cidr},
+    captcha,
+    common::{self, DeviceSelector, DEVICE_TOKEN_HEADER},
+    config::{Config, WireguardConfig},
+    db::{DbPool, Writer},
+    dependencies::Dependency,
+    error::{ErrorCode, JitError},
+    events::DeviceEvent,
+    identity, invites, liveness, mobileconfig, notify,
+    pairing_store::{self, PairingStore},
+    wireguard::{self, WireguardError},
+    JitStreamerState,
+};
 
-        wg_config::WgConf::create(wireguard_conf.as_str(), interface, None)
-            .expect("failed to create config");
+/// Writes the config file for a new interface, with a fresh key and no peers
+fn create_wireguard_conf(config: &WireguardConfig) -> Result<(), WireguardError> {
+    let key = wg_config::WgKey::generate_private_key()
+        .map_err(|e| WireguardError::Config(format!("failed to generate a key: {e:?}")))?;
+    let address = config.server_address.parse().map_err(|_| {
+        WireguardError::Config(format!(
+            "WIREGUARD_SERVER_ADDRESS {} is invalid",
+            config.server_address
+        ))
+    })?;
+    let interface = wg_config::WgInterface::new(key, address, Some(config.port), None, None, None)
+        .map_err(|e| WireguardError::Config(format!("invalid interface: {e:?}")))?;
+    std::fs::create_dir_all(&config.conf_dir).map_err(|e| {
+        WireguardError::Config(format!("failed to create {}: {e}", config.conf_dir))
+    })?;
+    wg_config::WgConf::create(config.conf_path().as_str(), interface, None)
+        .map_err(|e| WireguardError::Config(format!("failed to create it: {e:?}")))?;
+    info!("Created new Wireguard config");
+    Ok(())
+}
 
-        info!("Created new Wireguard config");
+/// Makes sure the Wireguard interface exists, and brings it up with its peers
+pub fn check_wireguard(config: &WireguardConfig) {
+    let wireguard_config_name = &config.config_name;
+
+    match std::fs::exists(config.conf_path()) {
+        Ok(true) => {}
+        Ok(false) => {
+            if let Err(e) = create_wireguard_conf(config) {
+                tracing::error!("Failed to create {}: {e}", config.conf_path());
+                return;
+            }
+        }
+        Err(e) => {
+            tracing::error!("Failed to check for {}: {e}", config.conf_path());
+            return;
+        }
+    }
 
-        // Run wg-quick up jitstreamer
-        let _ = std::process::Command::new("bash")
-            .arg("-c")
-            .arg(format!("wg-quick up {wireguard_config_name}"))
-            .output()
-            .expect("failed to execute process");
+    let ipv4 = config
+        .ipv4_subnet
+        .as_ref()
+        .map(|subnet| (IpAddr::V4(server_ipv4(subnet)), subnet.prefix()));
+    match wireguard::up(config, ipv4) {
+        Ok(()) => info!("Brought up Wireguard interface {wireguard_config_name}"),
+        Err(e) => tracing::error!("Failed to bring up {wireguard_config_name}: {e}"),
     }
 }
 
+#[derive(Deserialize, Default)]
+pub struct RegisterOptions {
+    /// Return the Wireguard config as a QR code PNG, to scan into the WireGuard app
+    #[serde(default)]
+    pub qr: bool,
+    /// Return the Wireguard config in a configuration profile, installed with one tap
+    #[serde(default)]
+    pub mobileconfig: bool,
+    /// The one-time code needed to register with ALLOW_REGISTRATION=3
+    pub invite: Option<String>,
+}
+
 /// Takes the plist in bytes, and returns either the pairing file in return or an error message
 pub async fn register(
     client_ip: SecureClientIp,
-    plist_bytes: Bytes,
-) -> Result<Bytes, (StatusCode, &'static str)> {
-    let plist = match plist::from_bytes::<Dictionary>(plist_bytes.as_ref()) {
-        Ok(plist) => plist,
-        Err(_) => return Err((StatusCode::BAD_REQUEST, "bad plist")),
+    Query(options): Query<RegisterOptions>,
+    State(state): State<JitStreamerState>,
+    request_headers: HeaderMap,
+    body: Body,
+) -> Result<(HeaderMap, Bytes), RegisterError> {
+    if options.qr && options.mobileconfig {
+        return Err((StatusCode::BAD_REQUEST, "pick one of qr and mobileconfig").into());
+    }
+    let body = axum::body::to_bytes(body, state.config().register_max_body)
+        .await
+        .map_err(|_| (StatusCode::PAYLOAD_TOO_LARGE, "the body is too large"))?;
+    let (plist_bytes, form_captcha) = pairing_file_body(&request_headers, body).await?;
+    let udid = validate_pairing_file(&plist_bytes).map_err(RegisterError::Invalid)?;
+    if state.bans.udid_banned(&udid).await {
+        info!("Refusing to register banned device {udid}");
+        return Err((StatusCode::FORBIDDEN, "This device has been banned").into());
+    }
+
+    let captcha_token = form_captcha.or_else(|| {
+        request_headers
+            .get(captcha::CAPTCHA_HEADER)
+            .and_then(|t| t.to_str().ok())
+            .map(|t| t.to_string())
+    });
+    let new_device = check_new_device(&state, client_ip.0, &udid, captcha_token.as_deref()).await?;
+
+    let invite = check_invite(&state, &udid, options.invite.as_deref()).await?;
+    let (mut headers, body) = match store_device(&state, client_ip.0, &udid, &plist_bytes).await {
+        Ok(r) => {
+            if new_device {
+                state
+                    .register_cooldowns
+                    .start(client_ip.0, state.config().register_cooldown)
+                    .await;
+            }
+            r
+        }
+        Err(e) => {
+            if let Some(invite) = invite {
+                invites::release(&state.db_writer, &invite).await;
+            }
+            return Err(e);
+        }
     };
-    let udid = match plist.get("UDID") {
-        Some(plist::Value::String(udid)) => udid,
-        _ => return Err((StatusCode::BAD_REQUEST, "no UDID")),
+    // Only a Wireguard config is worth installing, direct registration returns the IP
+    let config = state.config();
+    if !config.wireguard_registration() {
+        return Ok((headers, body));
+    }
+    if options.qr {
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("image/png"));
+        return Ok((headers, qr_png(&body)?.into()));
     }
-    .to_owned();
+    if options.mobileconfig {
+        let wg_config = String::from_utf8_lossy(&body);
+        let endpoint = &config.wireguard_interface(None).endpoint;
+        let mut profile = mobileconfig::profile(&wg_config, &udid, endpoint).map_err(|e| {
+            info!("Failed to build profile: {e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, "failed to build profile")
+        })?;
+        if let Some(signing) = &config.profile_signing {
+            profile = mobileconfig::sign(&profile, signing).await.map_err(|e| {
+                info!("{e}");
+                (StatusCode::INTERNAL_SERVER_ERROR, "failed to sign profile")
+            })?;
+        }
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/x-apple-aspen-config"),
+        );
+        headers.insert(
+            CONTENT_DISPOSITION,
+            HeaderValue::from_static("attachment; filename=\"jitstreamer.mobileconfig\""),
+        );
+        return Ok((headers, profile.into()));
+    }
+    Ok((headers, body))
+}
 
-    let cloned_udid = udid.clone();
-    // Reverse lookup the device to see if we already have an IP for it
-    let ip = match tokio::task::spawn_blocking(move || {
-        let db = match sqlite::open("jitstreamer.db") {
-            Ok(db) => db,
-            Err(e) => {
-                info!("Failed to open database: {:?}", e);
-                return None;
+/// Why registering failed. Plain text like the other registration routes, except for a
+/// full server, which is a JSON error with a code so clients can tell the user.
+#[derive(Debug, thiserror::Error)]
+pub enum RegisterError {
+    #[error("{1}")]
+    Text(StatusCode, &'static str),
+    #[error("The server is full and isn't taking new devices")]
+    Full,
+    #[error("{0}")]
+    Jit(JitError),
+    #[error("The pairing file is invalid: {}", field_errors(.0))]
+    Invalid(Vec<FieldError>),
+}
+
+/// What's wrong with one part of an uploaded pairing file
+#[derive(Debug, Serialize)]
+pub struct FieldError {
+    /// The plist key, or `pairing_file` for the file as a whole
+    field: &'static str,
+    error: String,
+}
+
+fn field_errors(errors: &[FieldError]) -> String {
+    errors
+        .iter()
+        .map(|e| format!("{} {}", e.field, e.error))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[derive(Serialize)]
+struct InvalidReturn {
+    ok: bool,
+    #[serde(flatten)]
+    error: JitError,
+    fields: Vec<FieldError>,
+}
+
+impl From<(StatusCode, &'static str)> for RegisterError {
+    fn from((status, message): (StatusCode, &'static str)) -> Self {
+        Self::Text(status, message)
+    }
+}
+
+impl RegisterError {
+    /// The plain text version, for routes that only answer in text
+    pub fn into_text(self) -> (StatusCode, String) {
+        match self {
+            Self::Text(status, message) => (status, message.to_string()),
+            Self::Full => (StatusCode::SERVICE_UNAVAILABLE, self.to_string()),
+            Self::Jit(e) => (e.code.status(), e.message),
+            Self::Invalid(_) => (StatusCode::BAD_REQUEST, self.to_string()),
+        }
+    }
+}
+
+impl IntoResponse for RegisterError {
+    fn into_response(self) -> Response {
+        match self {
+            Self::Text(status, message) => (status, message).into_response(),
+            Self::Full => JitError::new(ErrorCode::ServerFull, self.to_string()).into_response(),
+            Self::Jit(e) => e.into_response(),
+            Self::Invalid(fields) => {
+                let error = JitError::new(
+                    ErrorCode::BadRequest,
+                    format!("The pairing file is invalid: {}", field_errors(&fields)),
+                );
+                (
+                    StatusCode::BAD_REQUEST,
+                    Json(InvalidReturn {
+                        ok: false,
+                        error,
+                        fields,
+                    }),
+                )
+                    .into_response()
             }
-        };
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct JsonBody {
+    /// The pairing file, base64 encoded
+    #[serde(alias = "plist")]
+    pairing_file: String,
+}
 
-        // Get the device from the database
-        let query = "SELECT ip FROM devices WHERE udid = ?";
-        let mut statement = match crate::db::db_prepare(&db, query) {
-            Some(s) => s,
-            None => {
-                log::error!("Failed to prepare query!");
-                return None;
+/// Pulls the pairing file out of the body, which is either the raw file, a form with the
+/// file in it, or JSON with it base64 encoded. A form can carry a CAPTCHA widget's token too.
+async fn pairing_file_body(
+    headers: &HeaderMap,
+    body: Bytes,
+) -> Result<(Bytes, Option<String>), (StatusCode, &'static str)> {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|c| c.to_str().ok())
+        .unwrap_or_default();
+
+    if let Ok(boundary) = multer::parse_boundary(content_type) {
+        let stream = futures_util::stream::once(async move { Ok::<_, Infallible>(body) });
+        let mut form = multer::Multipart::new(stream, boundary);
+        let bad_form = |_| (StatusCode::BAD_REQUEST, "bad form");
+        let mut file = None;
+        let mut captcha_token = None;
+        while let Some(field) = form.next_field().await.map_err(bad_form)? {
+            if field
+                .name()
+                .is_some_and(|n| captcha::FORM_FIELDS.contains(&n))
+            {
+                captcha_token = Some(field.text().await.map_err(bad_form)?);
+            } else if file.is_none()
+                && (field.file_name().is_some()
+                    || matches!(field.name(), Some("pairing_file" | "file")))
+            {
+                // Whatever the form calls its file input
+                file = Some(field.bytes().await.map_err(bad_form)?);
             }
+        }
+        return match file {
+            Some(file) => Ok((file, captcha_token)),
+            None => Err((StatusCode::BAD_REQUEST, "no pairing file in the form")),
         };
-        statement
-            .bind((1, cloned_udid.to_string().as_str()))
-            .unwrap();
-        if let Some(State::Row) = crate::db::statement_next(&mut statement) {
-            let ip = statement.read::<String, _>("ip").unwrap();
-            info!("Found device with udid {} already in db", cloned_udid);
-
-            // Delete the device from the database
-            let query = "DELETE FROM devices WHERE udid = ?";
-            let mut statement = match crate::db::db_prepare(&db, query) {
-                Some(s) => s,
-                None => {
-                    log::error!("Failed to prepare query!");
-                    return None;
-                }
-            };
-            statement
-                .bind((1, cloned_udid.to_string().as_str()))
-                .unwrap();
-            if crate::db::statement_next(&mut statement).is_none() {
-                log::error!("Failed to enact the statement");
-            }
+    }
+
+    // A plist never starts with a brace, so JSON is recognized without the content type
+    if content_type.starts_with("application/json") || body.first() == Some(&b'{') {
+        let json = serde_json::from_slice::<JsonBody>(&body)
+            .map_err(|_| (StatusCode::BAD_REQUEST, "bad JSON, expected pairing_file"))?;
+        return BASE64_STANDARD
+            .decode(json.pairing_file.trim())
+            .map(|file| (Bytes::from(file), None))
+            .map_err(|_| (StatusCode::BAD_REQUEST, "pairing_file isn't base64"));
+    }
+    Ok((body, None))
+}
+
+/// Pairing files are a few kilobytes of certificates and keys, anything much smaller isn't one
+const MIN_PAIRING_FILE: usize = 1024;
+/// The certificates and keys a pairing file needs, each PEM encoded data
+const PAIRING_PEM_KEYS: [&str; 5] = [
+    "DeviceCertificate",
+    "HostCertificate",
+    "RootCertificate",
+    "HostPrivateKey",
+    "RootPrivateKey",
+];
+const PAIRING_STRING_KEYS: [&str; 2] = ["HostID", "SystemBUID"];
+
+/// UDIDs are 40 hex digits, or 8 and 16 separated by a dash on devices since the iPhone XS
+pub fn valid_udid(udid: &str) -> bool {
+    let hex = |s: &str, len: usize| s.len() == len && s.bytes().all(|b| b.is_ascii_hexdigit());
+    match udid.split_once('-') {
+        Some((a, b)) => hex(a, 8) && hex(b, 16),
+        None => hex(udid, 40),
+    }
+}
 
-            Some(ip)
-        } else {
+/// Checks the upload is a whole pairing file before anything is stored, returning its UDID
+/// or everything wrong with it
+fn validate_pairing_file(bytes: &[u8]) -> Result<String, Vec<FieldError>> {
+    let invalid = |field, error: &str| FieldError {
+        field,
+        error: error.to_string(),
+    };
+    if bytes.len() < MIN_PAIRING_FILE {
+        return Err(vec![invalid(
+            "pairing_file",
+            "is too small to be a pairing file",
+        )]);
+    }
+    let plist = plist::from_bytes::<Dictionary>(bytes)
+        .map_err(|_| vec![invalid("pairing_file", "isn't a plist")])?;
+
+    let mut errors = Vec::new();
+    let udid = match plist.get("UDID") {
+        Some(plist::Value::String(udid)) if valid_udid(udid) => Some(udid.to_owned()),
+        Some(plist::Value::String(_)) => {
+            errors.push(invalid("UDID", "isn't a device's UDID"));
             None
         }
-    })
-    .await
+        Some(_) => {
+            errors.push(invalid("UDID", "isn't a string"));
+            None
+        }
+        None => {
+            errors.push(invalid("UDID", "is missing"));
+            None
+        }
+    };
+    for key in PAIRING_PEM_KEYS {
+        match plist.get(key) {
+            Some(plist::Value::Data(d)) if d.trim_ascii_start().starts_with(b"-----BEGIN ") => {}
+            Some(plist::Value::Data(_)) => errors.push(invalid(key, "isn't PEM encoded")),
+            Some(_) => errors.push(invalid(key, "isn't data")),
+            None => errors.push(invalid(key, "is missing")),
+        }
+    }
+    for key in PAIRING_STRING_KEYS {
+        match plist.get(key) {
+            Some(plist::Value::String(s)) if !s.trim().is_empty() => {}
+            Some(_) => errors.push(invalid(key, "isn't a string")),
+            None => errors.push(invalid(key, "is missing")),
+        }
+    }
+    // Anything else idevice needs to connect with it
+    if errors.is_empty() {
+        if let Err(e) = PairingFile::from_bytes(bytes) {
+            errors.push(invalid("pairing_file", &e.to_string()));
+        }
+    }
+    match udid {
+        Some(udid) if errors.is_empty() => Ok(udid),
+        _ => Err(errors),
+    }
+}
+
+/// Makes each new device cost a bot something: a solved CAPTCHA when CAPTCHA_PROVIDER is
+/// set, and waiting REGISTER_COOLDOWN after the IP's last one. Devices that are already
+/// registered skip both. Returns whether the device is new.
+async fn check_new_device(
+    state: &JitStreamerState,
+    client_ip: IpAddr,
+    udid: &str,
+    captcha_token: Option<&str>,
+) -> Result<bool, RegisterError> {
+    let config = state.config();
+    if config.captcha.is_none() && config.register_cooldown.is_zero() {
+        return Ok(false);
+    }
+    let registered = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM devices WHERE udid = ?")
+        .bind(udid)
+        .fetch_one(&state.db)
+        .await
+        .map_err(|e| {
+            info!("Failed to look up {udid}: {e:?}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed to look up the device",
+            )
+        })?;
+    if registered > 0 {
+        return Ok(false);
+    }
+
+    if let Some(wait) = state
+        .register_cooldowns
+        .remaining(client_ip, config.register_cooldown)
+        .await
     {
-        Ok(ip) => ip,
+        info!("Refusing to register {udid}, {client_ip} registered a device recently");
+        return Err(RegisterError::Jit(JitError::new(
+            ErrorCode::RateLimited,
+            format!(
+                "A device was registered from your network recently, try again in {} seconds",
+                wait.as_secs() + 1
+            ),
+        )));
+    }
+    if let Some(captcha) = &config.captcha {
+        let token = captcha_token.ok_or_else(|| {
+            RegisterError::Jit(JitError::new(
+                ErrorCode::Forbidden,
+                "Registering needs a solved CAPTCHA, register from the upload page",
+            ))
+        })?;
+        captcha.verify(token, client_ip).await.map_err(|e| {
+            info!("Refusing to register {udid}: {e}");
+            RegisterError::Jit(JitError::new(ErrorCode::Forbidden, e))
+        })?;
+    }
+    Ok(true)
+}
+
+/// Redeems the invite code when registering needs one, returning the code redeemed.
+/// Devices that are already registered don't need a new one.
+async fn check_invite(
+    state: &JitStreamerState,
+    udid: &str,
+    invite: Option<&str>,
+) -> Result<Option<String>, (StatusCode, &'static str)> {
+    if state.config().allow_registration != 3 {
+        return Ok(None);
+    }
+    let registered = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM devices WHERE udid = ?")
+        .bind(udid)
+        .fetch_one(&state.db)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to query database: {e:?}");
+            (StatusCode::INTERNAL_SERVER_ERROR, "failed to check invite")
+        })?;
+    if registered > 0 {
+        return Ok(None);
+    }
+
+    let invite = match invite {
+        Some(i) if !i.trim().is_empty() => i,
+        _ => {
+            return Err((
+                StatusCode::FORBIDDEN,
+                "an invite code is required to register",
+            ))
+        }
+    };
+    match invites::redeem(&state.db_writer, invite, udid).await {
+        Ok(true) => {
+            info!("{udid} redeemed an invite");
+            Ok(Some(invite.to_string()))
+        }
+        Ok(false) => Err((
+            StatusCode::FORBIDDEN,
+            "the invite code is invalid, expired or already used",
+        )),
         Err(e) => {
-            info!("Failed to get IP from database: {:?}", e);
-            return Err((StatusCode::INTERNAL_SERVER_ERROR, "failed to get IP"));
+            tracing::error!("Failed to redeem invite: {e:?}");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "failed to check invite"))
         }
+    }
+}
+
+/// Renders the data as a QR code PNG
+fn qr_png(data: &[u8]) -> Result<Vec<u8>, (StatusCode, &'static str)> {
+    let code = qrcode::QrCode::new(data).map_err(|e| {
+        info!("Failed to encode QR code: {e:?}");
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "failed to encode QR code",
+        )
+    })?;
+    let image = code
+        .render::<image::Luma<u8>>()
+        .min_dimensions(512, 512)
+        .build();
+    let mut png = std::io::Cursor::new(Vec::new());
+    image
+        .write_to(&mut png, image::ImageFormat::Png)
+        .map_err(|e| {
+            info!("Failed to encode PNG: {e:?}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed to encode QR code",
+            )
+        })?;
+    Ok(png.into_inner())
+}
+
+/// Saves a device's pairing file and registers it, giving it a Wireguard peer or token
+/// depending on the registration mode. Returns the headers and body to respond with.
+pub async fn store_device(
+    state: &JitStreamerState,
+    client_ip: IpAddr,
+    udid: &str,
+    plist_bytes: &[u8],
+) -> Result<(HeaderMap, Bytes), RegisterError> {
+    let res = store(state, client_ip, udid, plist_bytes).await;
+    if res.is_ok() {
+        state.events.send(udid, client_ip, DeviceEvent::Registered);
+    }
+    res
+}
+
+async fn store(
+    state: &JitStreamerState,
+    client_ip: IpAddr,
+    udid: &str,
+    plist_bytes: &[u8],
+) -> Result<(HeaderMap, Bytes), RegisterError> {
+    check_capacity(state, client_ip, udid).await?;
+
+    let config = state.config();
+    if config.wireguard_registration() {
+        state
+            .dependencies
+            .require(Dependency::Wireguard)
+            .await
+            .map_err(RegisterError::Jit)?;
+    }
+    let db_error = |e: sqlx::Error| {
+        tracing::error!("Failed to enact the statement: {e:?}");
+        (StatusCode::INTERNAL_SERVER_ERROR, "failed to save device")
     };
 
-    let register_mode = std::env::var("ALLOW_REGISTRATION")
-        .unwrap_or("1".to_string())
-        .parse::<u8>()
-        .unwrap();
+    // Held until the new peer is applied, so a failed registration can put the config
+    // file back without losing another registration's peer
+    let _guard = match config.wireguard_registration() {
+        true => Some(WIREGUARD_LOCK.lock().await),
+        false => None,
+    };
 
     let client_config: Vec<u8>;
     let ip_final: Ipv6Addr;
+    let mut ipv4_final = None;
+    let mut wireguard = None;
 
-    if register_mode == 1 {
+    if config.wireguard_registration() {
         // register using wireguard
-        let wireguard_config_name =
-            std::env::var("WIREGUARD_CONFIG_NAME").unwrap_or("jitstreamer".to_string());
-        let wireguard_conf = format!("/etc/wireguard/{wireguard_config_name}.conf");
-        let wireguard_port = std::env::var("WIREGUARD_PORT")
-            .unwrap_or("51869".to_string())
-            .parse::<u16>()
-            .unwrap_or(51869);
-        let wireguard_server_address =
-            std::env::var("WIREGUARD_SERVER_ADDRESS").unwrap_or("fd00::/128".to_string());
-        let wireguard_endpoint =
-            std::env::var("WIREGUARD_ENDPOINT").unwrap_or("jitstreamer.jkcoxson.com".to_string());
-        let wireguard_server_allowed_ips =
-            std::env::var("WIREGUARD_SERVER_ALLOWED_IPS").unwrap_or("fd00::/64".to_string());
-
-        // Read the Wireguard config file
-        info!("Reading Wireguard server config");
-        let mut server_peer = match wg_config::WgConf::open(&wireguard_conf) {
-            Ok(conf) => conf,
-            Err(e) => {
-                info!("Failed to open Wireguard config: {:?}", e);
-                if let wg_config::WgConfError::NotFound(_) = e {
-                    // Generate a new one
-
-                    let key =
-                        wg_config::WgKey::generate_private_key().expect("failed to generate key");
-                    let interface = wg_config::WgInterface::new(
-                        key,
-                        wireguard_server_address.parse().unwrap(),
-                        Some(wireguard_port),
-                        None,
-                        None,
-                        None,
-                    )
-                    .unwrap();
-
-                    wg_config::WgConf::create(wireguard_conf.as_str(), interface, None)
-                        .expect("failed to create config");
-
-                    info!("Created new Wireguard config");
-
-                    wg_config::WgConf::open(wireguard_conf.as_str()).unwrap()
-                } else {
-                    return Err((
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        "failed to open server Wireguard config",
-                    ));
-                }
-            }
-        };
-        let mut public_ip = None;
-        if let Some(ip) = ip {
-            match server_peer.peers() {
-                Ok(peers) => {
-                    for peer in peers {
-                        let peer_ip = peer.allowed_ips();
-                        if ip.is_empty() {
-                            continue;
-                        }
-                        if peer_ip[0].to_string() == ip {
-                            info!("Found peer with IP {}", ip);
-
-                            public_ip = Some(peer.public_key().to_owned());
-                        }
-                    }
-                }
+        let allocation = allocate(&state.db_writer, &config, udid).await?;
+        let interface = allocation.interface;
+        ipv4_final = allocation.ipv4;
+        let snapshot = ConfSnapshot::take(interface);
+        (ip_final, client_config) =
+            match wireguard_peer(interface, allocation.ipv6, allocation.previous, ipv4_final) {
+                Ok(p) => p,
                 Err(e) => {
-                    info!("Failed to get peers: {:?}", e);
-                    return Err((StatusCode::INTERNAL_SERVER_ERROR, "failed to get peers"));
+                    snapshot.restore();
+                    state.notifier.alert(notify::Alert::WireguardPeer {
+                        udid: udid.to_string(),
+                        error: e.1.to_string(),
+                    });
+                    return Err(e.into());
                 }
-            }
-        }
-
-        if let Some(public_ip) = public_ip {
-            info!("Removing existing peer");
-            server_peer = server_peer.remove_peer_by_pub_key(&public_ip).unwrap();
+            };
+        wireguard = Some((interface, snapshot));
+    } else if config.address_registration() {
+        if let Some(tailnet) = &config.tailnet {
+            check_tailnet(tailnet, client_ip).await?;
         }
-
-        info!("Generating IPv6 from UDID");
-        let ip = generate_ipv6_from_udid(udid.as_str());
-        ip_final = ip;
-
-        // Generate a new peer for the device
-        info!("Generating peer");
-        client_config = match server_peer.generate_peer(
-            std::net::IpAddr::V6(ip),
-            wireguard_endpoint.parse().unwrap(),
-            vec![wireguard_server_allowed_ips.parse().unwrap()],
-            None,
-            true,
-            Some(20),
-        ) {
-            Ok(config) => config.to_string().as_bytes().to_vec(),
-            Err(e) => {
-                info!("Failed to generate peer: {:?}", e);
-                return Err((StatusCode::INTERNAL_SERVER_ERROR, "failed to generate peer"));
-            }
-        };
-    } else if register_mode == 2 {
         // register directly using request IP
-        ip_final = match client_ip.0 {
+        ip_final = match client_ip {
             IpAddr::V4(v4) => v4.to_ipv6_mapped(),
             IpAddr::V6(v6) => v6,
         };
         client_config = ip_final.to_string().as_bytes().to_vec();
     } else {
-        return Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Unknown registration mode",
-        ));
+        return Err((StatusCode::FORBIDDEN, "Registration is disabled").into());
     }
 
-    // Save the plist to the storage
-    let plist_storage_path = std::env::var("PLIST_STORAGE").unwrap_or(
-        match std::env::consts::OS {
-            "macos" => "/var/db/lockdown",
-            "linux" => "/var/lib/lockdown",
-            "windows" => "C:/ProgramData/Apple/Lockdown",
-            _ => panic!("Unsupported OS, specify a path"),
+    // Public IPs are shared behind NAT and change on mobile networks, so every device gets
+    // a token to identify itself with instead
+    let token = identity::issue(&config, udid);
+
+    let saved = async {
+        save_pairing_file(&config.pairing_store, udid, plist_bytes).await?;
+
+        // The writer is only taken for the save, the slow work above runs without it
+        let mut conn = state.db_writer.acquire().await.map_err(db_error)?;
+        let mut tx = conn.begin().await.map_err(db_error)?;
+        // Counted with the writer held, so registrations at the same time can't all take
+        // the last spot
+        if over_capacity(&mut *tx, config.max_devices, udid).await? {
+            return Err(RegisterError::Full);
         }
-        .to_string(),
+        // Save the IP to the database, replacing the device's old row
+        sqlx::query(
+            "INSERT OR REPLACE INTO devices (udid, ip, ipv4, wireguard_interface, last_used) VALUES (?, ?, ?, ?, CURRENT_TIMESTAMP)",
+        )
+        .bind(udid)
+        .bind(ip_final.to_string())
+        .bind(ipv4_final.map(|i| i.to_string()))
+        .bind(wireguard.as_ref().map(|(w, _)| w.config_name.clone()))
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+        // It got in, so it's no longer waiting
+        sqlx::query("DELETE FROM waitlist WHERE udid = ?")
+            .bind(udid)
+            .execute(&mut *tx)
+            .await
+            .map_err(db_error)?;
+        tx.commit().await.map_err(db_error)?;
+        Ok::<(), RegisterError>(())
+    }
+    .await;
+    let wireguard = match (saved, wireguard) {
+        (Ok(()), wireguard) => wireguard.map(|(w, _)| w),
+        (Err(e), wireguard) => {
+            // The old peer is still the one in the database
+            if let Some((_, snapshot)) = wireguard {
+                snapshot.restore();
+            }
+            if let RegisterError::Full = e {
+                // Another device took the last spot, this one never got in
+                if let Err(e) = config.pairing_store.remove(udid).await {
+                    tracing::error!("Failed to remove pairing file for {udid}: {e:?}");
+                }
+                return Err(turn_away(state, client_ip, udid).await);
+            }
+            return Err(e);
+        }
+    };
+
+    state
+        .udid_cache
+        .invalidate(udid, &ip_final.to_string())
+        .await;
+    state
+        .muxer
+        .add(udid, IpAddr::V6(ip_final).to_canonical())
+        .await;
+
+    if let Some(wireguard) = wireguard {
+        refresh_wireguard(wireguard, ip_final, ipv4_final).map_err(|e| {
+            tracing::error!("Failed to apply Wireguard config: {e}");
+            state.notifier.alert(notify::Alert::WireguardPeer {
+                udid: udid.to_string(),
+                error: e.to_string(),
+            });
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed to apply Wireguard config",
+            )
+        })?;
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        DEVICE_TOKEN_HEADER,
+        HeaderValue::from_str(&token).expect("token is ASCII"),
     );
 
-    // Create the folder if it doesn't exist
-    if let Err(e) = tokio::fs::create_dir_all(&plist_storage_path).await {
-        log::error!("Failed to create plist storage path: {e:?}");
+    Ok((headers, client_config.into()))
+}
+
+/// Addresses picked for a device, saved with its row once its peer is written
+struct Allocation<'a> {
+    interface: &'a WireguardConfig,
+    ipv6: Ipv6Addr,
+    ipv4: Option<Ipv4Addr>,
+    /// The address the device had, so its old peer can be found and replaced
+    previous: Option<String>,
+}
+
+/// Picks the device's interface and addresses in a short transaction on the writer, so
+/// they're checked against every saved registration. Addresses are only saved under
+/// WIREGUARD_LOCK, so holding it keeps these free until the device's row is written.
+async fn allocate<'a>(
+    writer: &Writer,
+    config: &'a Config,
+    udid: &str,
+) -> Result<Allocation<'a>, (StatusCode, &'static str)> {
+    let db_error = |e: sqlx::Error| {
+        tracing::error!("Failed to query database: {e:?}");
+        (StatusCode::INTERNAL_SERVER_ERROR, "failed to get IP")
+    };
+    let mut conn = writer.acquire().await.map_err(db_error)?;
+    let mut tx = conn.begin().await.map_err(db_error)?;
+
+    // Reverse lookup the device to see if we already have an IP for it
+    let (ip, ipv4, interface) = match sqlx::query_as::<_, (String, Option<String>, Option<String>)>(
+        "SELECT ip, ipv4, wireguard_interface FROM devices WHERE udid = ?",
+    )
+    .bind(udid)
+    .fetch_optional(&mut *tx)
+    .await
+    {
+        Ok(Some((ip, ipv4, interface))) => {
+            info!("Found device with udid {} already in db", udid);
+            (Some(ip), ipv4, interface)
+        }
+        Ok(None) => (None, None, None),
+        Err(e) => return Err(db_error(e)),
+    };
+
+    let interface = pick_interface(&mut *tx, config, interface.as_deref()).await?;
+    let ipv4 = match &interface.ipv4_subnet {
+        Some(subnet) => Some(allocate_ipv4(&mut *tx, subnet, udid, ipv4).await?),
+        None => None,
+    };
+    let ipv6 = allocate_ipv6(
+        &mut *tx,
+        interface,
+        config.ipv6_allocation,
+        udid,
+        ip.clone(),
+    )
+    .await?;
+    tx.commit().await.map_err(db_error)?;
+
+    Ok(Allocation {
+        interface,
+        ipv6,
+        ipv4,
+        previous: ip,
+    })
+}
+
+/// The server config file as it was before a registration changed it, put back if the
+/// registration fails so the file never has a peer the database doesn't
+struct ConfSnapshot {
+    path: String,
+    contents: Option<String>,
+}
+
+impl ConfSnapshot {
+    fn take(wireguard: &WireguardConfig) -> Self {
+        let path = wireguard.conf_path();
+        let contents = std::fs::read_to_string(&path).ok();
+        Self { path, contents }
+    }
+
+    fn restore(self) {
+        if let Some(contents) = self.contents {
+            info!("Restoring Wireguard config {}", self.path);
+            if let Err(e) = std::fs::write(&self.path, contents) {
+                tracing::error!("Failed to restore Wireguard config: {e:?}");
+            }
+        }
     }
+}
 
-    tokio::fs::write(
-        format!("{plist_storage_path}/{udid}.plist"),
-        &plist_bytes.to_vec(),
+/// Turns away new devices once MAX_DEVICES are registered, before any work is done for
+/// them. Counted again when the device is saved, which is what holds the limit.
+async fn check_capacity(
+    state: &JitStreamerState,
+    client_ip: IpAddr,
+    udid: &str,
+) -> Result<(), RegisterError> {
+    match over_capacity(&state.db, state.config().max_devices, udid).await? {
+        true => Err(turn_away(state, client_ip, udid).await),
+        false => Ok(()),
+    }
+}
+
+/// Whether MAX_DEVICES are registered and the device isn't one of them. Registered devices
+/// can always register again.
+async fn over_capacity(
+    db: impl sqlx::SqliteExecutor<'_>,
+    max_devices: usize,
+    udid: &str,
+) -> Result<bool, (StatusCode, &'static str)> {
+    if max_devices == 0 {
+        return Ok(false);
+    }
+    let (registered, known) = sqlx::query_as::<_, (i64, bool)>(
+        "SELECT COUNT(*), COALESCE(SUM(udid = ?), 0) > 0 FROM devices",
     )
+    .bind(udid)
+    .fetch_one(db)
     .await
     .map_err(|e| {
+        tracing::error!("Failed to query database: {e:?}");
+        (StatusCode::INTERNAL_SERVER_ERROR, "failed to count devices")
+    })?;
+    if known || (registered as usize) < max_devices {
+        return Ok(false);
+    }
+    info!("Turning away {udid}, {registered} devices are registered");
+    Ok(true)
+}
+
+/// Puts a device turned away for capacity on the waitlist if it's on
+async fn turn_away(state: &JitStreamerState, client_ip: IpAddr, udid: &str) -> RegisterError {
+    if !state.config().waitlist {
+        return RegisterError::Full;
+    }
+    // Saying the server is full would have them wait on a list they aren't on
+    match state
+        .db_writer
+        .execute(
+            sqlx::query(
+                "INSERT OR IGNORE INTO waitlist (udid, ip, created_at) VALUES (?, ?, CURRENT_TIMESTAMP)",
+            )
+            .bind(udid)
+            .bind(client_ip.to_string()),
+        )
+        .await
+    {
+        Ok(_) => RegisterError::Full,
+        Err(e) => {
+            tracing::error!("Failed to add {udid} to the waitlist: {e:?}");
+            (StatusCode::INTERNAL_SERVER_ERROR, "failed to join the waitlist").into()
+        }
+    }
+}
+
+/// Makes sure a device registering over Tailscale is calling from its tailnet address, and
+/// that the server can reach it there
+async fn check_tailnet(
+    tailnet: &Allowlist,
+    client_ip: IpAddr,
+) -> Result<(), (StatusCode, &'static str)> {
+    if !tailnet.contains(client_ip) {
+        info!("Refusing to register {client_ip}, it's not on the tailnet");
+        return Err((
+            StatusCode::FORBIDDEN,
+            "connect to the server over Tailscale to register",
+        ));
+    }
+    if !liveness::lockdown_reachable(client_ip).await {
+        info!("Refusing to register {client_ip}, it's not reachable over the tailnet");
+        return Err((
+            StatusCode::BAD_GATEWAY,
+            "the server can't reach your device over Tailscale, check that it's connected",
+        ));
+    }
+    Ok(())
+}
+
+/// Saves a device's pairing file to the configured store
+pub async fn save_pairing_file(
+    store: &pairing_store::Backend,
+    udid: &str,
+    plist_bytes: &[u8],
+) -> Result<(), (StatusCode, &'static str)> {
+    store.put(udid, plist_bytes).await.map_err(|e| {
         info!("Failed to save plist: {:?}", e);
         (StatusCode::INTERNAL_SERVER_ERROR, "failed to save plist")
-    })?;
+    })
+}
 
-    // Save the IP to the database
-    tokio::task::spawn_blocking(move || {
-        let db = match sqlite::open("jitstreamer.db") {
-            Ok(db) => db,
-            Err(e) => {
-                info!("Failed to open database: {:?}", e);
-                return;
+/// Serializes edits to the Wireguard config file
+static WIREGUARD_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Replaces the device's Wireguard peer, found by its previous address, with a freshly
+/// generated one at `address`. Returns the device's address and the client config.
+fn wireguard_peer(
+    wireguard: &WireguardConfig,
+    address: Ipv6Addr,
+    ip: Option<String>,
+    ipv4: Option<Ipv4Addr>,
+) -> Result<(Ipv6Addr, Vec<u8>), (StatusCode, &'static str)> {
+    let wireguard_conf = wireguard.conf_path();
+    let wireguard_endpoint = &wireguard.endpoint;
+    let wireguard_server_allowed_ips = &wireguard.server_allowed_ips;
+
+    // Read the Wireguard config file
+    info!("Reading Wireguard server config");
+    let mut server_peer = match wg_config::WgConf::open(&wireguard_conf) {
+        Ok(conf) => conf,
+        Err(e) => {
+            info!("Failed to open Wireguard config: {:?}", e);
+            if let wg_config::WgConfError::NotFound(_) = e {
+                // Generate a new one
+                let failed = (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "failed to create server Wireguard config",
+                );
+                create_wireguard_conf(wireguard).map_err(|e| {
+                    tracing::error!("Failed to create {wireguard_conf}: {e}");
+                    failed
+                })?;
+                wg_config::WgConf::open(wireguard_conf.as_str()).map_err(|e| {
+                    tracing::error!("Failed to open the new {wireguard_conf}: {e:?}");
+                    failed
+                })?
+            } else {
+                return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "failed to open server Wireguard config",
+                ));
             }
-        };
+        }
+    };
+    let mut public_ip = None;
+    if let Some(ip) = ip {
+        match server_peer.peers() {
+            Ok(peers) => {
+                for peer in peers {
+                    let peer_ip = peer.allowed_ips();
+                    if ip.is_empty() {
+                        continue;
+                    }
+                    if peer_ip.first().is_some_and(|p| p.to_string() == ip) {
+                        info!("Found peer with IP {}", ip);
 
-        // Insert the device into the database
-        let query = "INSERT INTO devices (udid, ip, last_used) VALUES (?, ?, CURRENT_TIMESTAMP)";
-        let mut statement = match crate::db::db_prepare(&db, query) {
-            Some(s) => s,
-            None => {
-                log::error!("Failed to prepare query!");
-                return;
+                        public_ip = Some(peer.public_key().to_owned());
+                    }
+                }
+            }
+            Err(e) => {
+                info!("Failed to get peers: {:?}", e);
+                return Err((StatusCode::INTERNAL_SERVER_ERROR, "failed to get peers"));
             }
-        };
-        statement
-            .bind(&[(1, udid.as_str()), (2, ip_final.to_string().as_str())][..])
-            .unwrap();
-        if crate::db::statement_next(&mut statement).is_none() {
-            log::error!("Failed to enact the statement");
         }
-    });
+    }
 
-    if register_mode == 1 {
-        refresh_wireguard(ip_final.to_string());
+    if let Some(public_ip) = public_ip {
+        info!("Removing existing peer");
+        server_peer = server_peer
+            .remove_peer_by_pub_key(&public_ip)
+            .map_err(|e| {
+                info!("Failed to remove peer: {:?}", e);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "failed to remove the old peer",
+                )
+            })?;
     }
 
-    Ok(client_config.into())
+    let ip = address;
+
+    // Both are settings, so a bad one fails every registration until it's fixed
+    let endpoint = wireguard_endpoint.parse().map_err(|_| {
+        tracing::error!("WIREGUARD_ENDPOINT {wireguard_endpoint} is invalid");
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "invalid WIREGUARD_ENDPOINT",
+        )
+    })?;
+    let allowed_ips = wireguard_server_allowed_ips.parse().map_err(|_| {
+        tracing::error!("WIREGUARD_SERVER_ALLOWED_IPS {wireguard_server_allowed_ips} is invalid");
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "invalid WIREGUARD_SERVER_ALLOWED_IPS",
+        )
+    })?;
+
+    // Generate a new peer for the device
+    info!("Generating peer");
+    match server_peer.generate_peer(
+        std::net::IpAddr::V6(ip),
+        endpoint,
+        vec![allowed_ips],
+        None,
+        true,
+        Some(20),
+    ) {
+        Ok(config) => {
+            let config = config.to_string();
+            let config = match (ipv4, &wireguard.ipv4_subnet) {
+                (Some(ipv4), Some(subnet)) => {
+                    add_peer_address(&wireguard_conf, ip, ipv4)?;
+                    dual_stack_client_config(&config, ipv4, server_ipv4(subnet))
+                }
+                _ => config,
+            };
+            Ok((ip, config.as_bytes().to_vec()))
+        }
+        Err(e) => {
+            info!("Failed to generate peer: {:?}", e);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "failed to generate peer"))
+        }
+    }
 }
 
-const UPLOAD_HTML: &str = include_str!("../src/upload.html");
+/// Keeps a re-registering device on its interface, and puts new ones on the interface
+/// with the fewest peers
+async fn pick_interface<'a>(
+    db: impl sqlx::SqliteExecutor<'_>,
+    config: &'a Config,
+    previous: Option<&str>,
+) -> Result<&'a WireguardConfig, (StatusCode, &'static str)> {
+    if let Some(previous) = previous {
+        if let Some(w) = config.wireguard.iter().find(|w| w.config_name == previous) {
+            return Ok(w);
+        }
+    }
+    if config.wireguard.len() == 1 {
+        return Ok(&config.wireguard[0]);
+    }
 
-pub async fn upload() -> Result<Html<&'static str>, (StatusCode, &'static str)> {
-    Ok(Html(UPLOAD_HTML))
+    let counts = sqlx::query_as::<_, (Option<String>, i64)>(
+        "SELECT wireguard_interface, COUNT(*) FROM devices GROUP BY wireguard_interface",
+    )
+    .fetch_all(db)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to query database: {e:?}");
+        (StatusCode::INTERNAL_SERVER_ERROR, "failed to get IP")
+    })?;
+    let load = |w: &WireguardConfig| {
+        counts
+            .iter()
+            .filter(|(i, _)| config.wireguard_interface(i.as_deref()).config_name == w.config_name)
+            .map(|(_, c)| c)
+            .sum::<i64>()
+    };
+    let interface = config
+        .wireguard
+        .iter()
+        .min_by_key(|w| load(w))
+        .expect("there's always a first interface");
+    info!("Putting the new peer on {}", interface.config_name);
+    Ok(interface)
+}
+
+/// The server's own address in the IPv4 subnet, its first
+fn server_ipv4(subnet: &Cidr) -> Ipv4Addr {
+    match subnet.addr() {
+        IpAddr::V4(net) => Ipv4Addr::from(u32::from(net) + 1),
+        IpAddr::V6(_) => unreachable!("the config only accepts IPv4 subnets"),
+    }
 }
 
-fn generate_ipv6_from_udid(udid: &str) -> std::net::Ipv6Addr {
-    // Hash the UDID using SHA-256
+/// Picks a free IPv4 address for the device, keeping the one it had if it's still free.
+/// Others start at a spot picked from the UDID.
+async fn allocate_ipv4(
+    db: impl sqlx::SqliteExecutor<'_>,
+    subnet: &Cidr,
+    udid: &str,
+    previous: Option<String>,
+) -> Result<Ipv4Addr, (StatusCode, &'static str)> {
+    let taken = sqlx::query_scalar::<_, String>(
+        "SELECT ipv4 FROM devices WHERE ipv4 IS NOT NULL AND udid != ?",
+    )
+    .bind(udid)
+    .fetch_all(db)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to query database: {e:?}");
+        (StatusCode::INTERNAL_SERVER_ERROR, "failed to get IP")
+    })?
+    .into_iter()
+    .filter_map(|i| i.parse::<Ipv4Addr>().ok())
+    .collect::<HashSet<Ipv4Addr>>();
+
+    let network = u32::from(server_ipv4(subnet)) - 1;
+    let size = 1u64 << (32 - subnet.prefix());
+    let in_subnet = |ip: Ipv4Addr| subnet.contains(IpAddr::V4(ip));
+    if let Some(previous) = previous.and_then(|p| p.parse::<Ipv4Addr>().ok()) {
+        if in_subnet(previous) && !taken.contains(&previous) {
+            return Ok(previous);
+        }
+    }
+
+    // Skips the network, the server and the broadcast address
+    let hosts = size - 3;
     let mut hasher = sha2::Sha256::new();
     hasher.update(udid.as_bytes());
-    let hash = hasher.finalize();
-
-    // Use the first 64 bits of the hash for the interface ID
-    let interface_id = u64::from_be_bytes(hash[0..8].try_into().unwrap());
-
-    // Set the first 64 bits to the `fd00::/8` range (locally assigned address)
-    let mut segments = [0u16; 8];
-    segments[0] = 0xfd00; // First segment in the `fd00::/8` range
-    (1..8).for_each(|i| {
-        let shift = (7 - i) * 16;
-        segments[i] = if shift < 64 {
-            ((interface_id >> shift) & 0xFFFF) as u16
-        } else {
-            0
-        };
-    });
+    let start = u64::from_be_bytes(hasher.finalize()[0..8].try_into().unwrap()) % hosts;
+    (0..hosts)
+        .map(|i| Ipv4Addr::from(network + 2 + ((start + i) % hosts) as u32))
+        .find(|ip| !taken.contains(ip))
+        .ok_or((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "no IPv4 addresses are left",
+        ))
+}
 
-    std::net::Ipv6Addr::from(segments)
+/// How new devices get their IPv6 address, from WIREGUARD_IPV6_ALLOCATION
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ipv6Allocation {
+    /// A random interface ID, retried until it's one no other device has
+    Random,
+    /// The lowest interface ID no other device has, so addresses stay short
+    Sequential,
 }
 
-fn refresh_wireguard(ip: String) {
-    let wireguard_config_name =
-        std::env::var("WIREGUARD_CONFIG_NAME").unwrap_or("jitstreamer".to_string());
+impl FromStr for Ipv6Allocation {
+    type Err = ();
 
-    // wg syncconf jitstreamer <(wg-quick strip jitstreamer)
-    let output = std::process::Command::new("bash")
-        .arg("-c")
-        .arg(format!(
-            "wg syncconf jitstreamer <(wg-quick strip {wireguard_config_name})"
-        ))
-        .output()
-        .expect("failed to execute process");
-    info!("Refreshing Wireguard: {:?}", output);
-
-    // ip route add fd00::b36d:f867:9391:fb0a dev jitstreamer
-    let output = std::process::Command::new("bash")
-        .arg("-c")
-        .arg(format!("ip route add {ip} dev {wireguard_config_name}"))
-        .output()
-        .expect("failed to add IP route");
-    info!("Adding route: {:?}", output);
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "random" => Ok(Self::Random),
+            "sequential" => Ok(Self::Sequential),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Random interface IDs tried before giving up, a collision is already unlikely
+const RANDOM_IPV6_ATTEMPTS: usize = 16;
+
+/// Picks a free IPv6 address in the interface's network for the device, keeping the one it
+/// had if it's still free. Every device's address is checked, so two never share one.
+async fn allocate_ipv6(
+    db: impl sqlx::SqliteExecutor<'_>,
+    wireguard: &WireguardConfig,
+    allocation: Ipv6Allocation,
+    udid: &str,
+    previous: Option<String>,
+) -> Result<Ipv6Addr, (StatusCode, &'static str)> {
+    let mut taken = sqlx::query_scalar::<_, String>("SELECT ip FROM devices WHERE udid != ?")
+        .bind(udid)
+        .fetch_all(db)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to query database: {e:?}");
+            (StatusCode::INTERNAL_SERVER_ERROR, "failed to get IP")
+        })?
+        .into_iter()
+        .filter_map(|i| i.parse::<Ipv6Addr>().ok())
+        .collect::<HashSet<Ipv6Addr>>();
+    if let Some(server) = wireguard
+        .server_address
+        .split('/')
+        .next()
+        .and_then(|a| a.parse::<Ipv6Addr>().ok())
+    {
+        taken.insert(server);
+    }
+
+    let network = u128::from(wireguard.ipv6_network()) & !(u64::MAX as u128);
+    let address = |interface_id: u64| Ipv6Addr::from(network | interface_id as u128);
+    let is_free = |ip: &Ipv6Addr| !taken.contains(ip);
+    if let Some(previous) = previous.and_then(|p| p.parse::<Ipv6Addr>().ok()) {
+        if u128::from(previous) & !(u64::MAX as u128) == network && is_free(&previous) {
+            return Ok(previous);
+        }
+    }
+
+    // The all zero interface ID is the subnet-router anycast address
+    let ip = match allocation {
+        Ipv6Allocation::Random => (0..RANDOM_IPV6_ATTEMPTS)
+            .map(|_| address(rand::random::<u64>()))
+            .find(|ip| u128::from(*ip) != network && is_free(ip)),
+        Ipv6Allocation::Sequential => (1..=u64::MAX).map(address).find(is_free),
+    };
+    ip.ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        "no IPv6 addresses are left",
+    ))
+}
+
+/// Adds the IPv4 address to the server's peer for the device, so its traffic is accepted
+fn add_peer_address(
+    conf_path: &str,
+    ip: Ipv6Addr,
+    ipv4: Ipv4Addr,
+) -> Result<(), (StatusCode, &'static str)> {
+    let conf = std::fs::read_to_string(conf_path).map_err(|e| {
+        info!("Failed to read Wireguard config: {:?}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "failed to open server Wireguard config",
+        )
+    })?;
+    let ip = ip.to_string();
+    let is_peer = |l: &str| {
+        l.trim_start().starts_with("AllowedIPs")
+            && l.split(['=', ','])
+                .skip(1)
+                .any(|a| a.trim().split('/').next() == Some(ip.as_str()))
+    };
+    let conf = conf
+        .lines()
+        .map(|l| match is_peer(l) {
+            true => format!("{l}, {ipv4}/32"),
+            false => l.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    std::fs::write(conf_path, conf + "\n").map_err(|e| {
+        info!("Failed to write Wireguard config: {:?}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "failed to save server Wireguard config",
+        )
+    })
+}
+
+/// Gives the client its IPv4 address, and routes the server's IPv4 address through the tunnel
+fn dual_stack_client_config(config: &str, ipv4: Ipv4Addr, server: Ipv4Addr) -> String {
+    config
+        .lines()
+        .map(|l| {
+            let key = l.trim_start();
+            if key.starts_with("Address") {
+                format!("{l}, {ipv4}/32")
+            } else if key.starts_with("AllowedIPs") {
+                format!("{l}, {server}/32")
+            } else {
+                l.to_string()
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+        + "\n"
+}
+
+/// Issues a registered device a new Wireguard peer, returning the new client config
+pub async fn regenerate_config(
+    db: &DbPool,
+    writer: &Writer,
+    config: &Config,
+    udid: &str,
+) -> Result<String, String> {
+    if !config.wireguard_registration() {
+        return Err("Config regeneration requires Wireguard registration".to_string());
+    }
+
+    let (ip, ipv4, interface) = match sqlx::query_as::<_, (String, Option<String>, Option<String>)>(
+        "SELECT ip, ipv4, wireguard_interface FROM devices WHERE udid = ?",
+    )
+    .bind(udid)
+    .fetch_optional(db)
+    .await
+    {
+        Ok(Some(ip)) => ip,
+        Ok(None) => return Err(format!("Device {udid} is not registered")),
+        Err(e) => {
+            tracing::error!("Failed to query database: {e:?}");
+            return Err("Failed to query database".to_string());
+        }
+    };
+    let wireguard = config.wireguard_interface(interface.as_deref());
+
+    // Held until the new addresses are saved, so another registration can't take them
+    let _guard = WIREGUARD_LOCK.lock().await;
+    let write_error = |e: sqlx::Error| {
+        tracing::error!("Failed to reach the database writer: {e:?}");
+        "Failed to save device".to_string()
+    };
+    // Released before the config file is touched, the lock keeps the addresses free
+    let mut conn = writer.acquire().await.map_err(write_error)?;
+    let ipv4 = match &wireguard.ipv4_subnet {
+        Some(subnet) => Some(
+            allocate_ipv4(&mut *conn, subnet, udid, ipv4)
+                .await
+                .map_err(|(_, e)| e.to_string())?,
+        ),
+        None => None,
+    };
+    let ipv6 = allocate_ipv6(
+        &mut *conn,
+        wireguard,
+        config.ipv6_allocation,
+        udid,
+        Some(ip.clone()),
+    )
+    .await
+    .map_err(|(_, e)| e.to_string())?;
+    drop(conn);
+    let snapshot = ConfSnapshot::take(wireguard);
+    let (ip, client_config) = match wireguard_peer(wireguard, ipv6, Some(ip), ipv4) {
+        Ok(p) => p,
+        Err((_, e)) => {
+            snapshot.restore();
+            return Err(e.to_string());
+        }
+    };
+    if let Err(e) = writer
+        .execute(
+            sqlx::query(
+                "UPDATE devices SET ip = ?, ipv4 = ?, wireguard_interface = ? WHERE udid = ?",
+            )
+            .bind(ip.to_string())
+            .bind(ipv4.map(|i| i.to_string()))
+            .bind(&wireguard.config_name)
+            .bind(udid),
+        )
+        .await
+    {
+        tracing::error!("Failed to enact the statement: {e:?}");
+        snapshot.restore();
+        return Err("Failed to save device".to_string());
+    }
+    refresh_wireguard(wireguard, ip, ipv4).map_err(|e| e.to_string())?;
+
+    Ok(String::from_utf8_lossy(&client_config).to_string())
+}
+
+/// Removes the caller's registration so they can cleanly leave the service
+pub async fn unregister(
+    client_ip: SecureClientIp,
+    selector: DeviceSelector,
+    State(state): State<JitStreamerState>,
+) -> Result<&'static str, (StatusCode, String)> {
+    if state.config().allow_registration == 0 {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Registration is disabled".to_string(),
+        ));
+    }
+    let udid = common::get_udid(&state, client_ip.0.to_string(), &selector)
+        .await
+        .map_err(|e| (StatusCode::NOT_FOUND, e.into()))?;
+
+    remove_device(&state, &udid)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    Ok("unregistered")
+}
+
+/// Deletes the device's row, pairing file and Wireguard peer, and stops its heartbeat
+pub async fn remove_device(state: &JitStreamerState, udid: &str) -> Result<(), String> {
+    let ip = remove_registration(&state.db_writer, &state.config(), udid).await?;
+
+    state.heartbeats.kill(udid).await.ok();
+    state.heartbeats.forget(udid);
+    state.udid_cache.invalidate(udid, &ip).await;
+    state.rsd_cache.invalidate(udid).await;
+    state.muxer.remove(udid).await;
+    state.apps_cache.invalidate(udid).await;
+    state.device_info_cache.lock().await.remove(udid);
+    state.mount_cache.lock().await.remove(udid);
+    state.launch_checkpoints.lock().await.remove(udid);
+    state.latency.remove(udid).await;
+    state.circuit_breaker.reset(udid).await;
+    Ok(())
+}
+
+/// Deletes the device's row, waitlist entry, pairing file and Wireguard peer, without
+/// touching a running server's state. Returns the device's address.
+pub async fn remove_registration(
+    db: &Writer,
+    config: &Config,
+    udid: &str,
+) -> Result<String, String> {
+    info!("Removing device {udid}");
+    let db_error = |e: sqlx::Error| {
+        tracing::error!("Failed to enact the statement: {e:?}");
+        "Failed to remove device from the database".to_string()
+    };
+    let mut conn = db.acquire().await.map_err(db_error)?;
+    let mut tx = conn.begin().await.map_err(db_error)?;
+    let (ip, ipv4, interface) = sqlx::query_as::<_, (String, Option<String>, Option<String>)>(
+        "DELETE FROM devices WHERE udid = ? RETURNING ip, ipv4, wireguard_interface",
+    )
+    .bind(udid)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(db_error)?
+    .ok_or_else(|| format!("Device {udid} is not registered"))?;
+    sqlx::query("DELETE FROM waitlist WHERE udid = ?")
+        .bind(udid)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    sqlx::query("DELETE FROM device_usage WHERE udid = ?")
+        .bind(udid)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    sqlx::query("DELETE FROM favorites WHERE udid = ?")
+        .bind(udid)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    sqlx::query("DELETE FROM subscriptions WHERE udid = ?")
+        .bind(udid)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    tx.commit().await.map_err(db_error)?;
+    // Removing the peer takes the Wireguard lock, which is taken before the writer elsewhere
+    drop(conn);
+
+    if let Err(e) = config.pairing_store.remove(udid).await {
+        tracing::error!("Failed to remove pairing file for {udid}: {e:?}");
+    }
+
+    if config.wireguard_registration() {
+        let _guard = WIREGUARD_LOCK.lock().await;
+        let wireguard = config.wireguard_interface(interface.as_deref());
+        remove_wireguard_peer(wireguard, &ip, ipv4.as_deref())?;
+    }
+    Ok(ip)
+}
+
+const UPLOAD_HTML: &str = include_str!("../src/upload.html");
+
+pub async fn upload(
+    State(state): State<JitStreamerState>,
+) -> Result<Html<String>, (StatusCode, &'static str)> {
+    let config = state.config();
+    if !config.address_registration() {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "Uploading requires direct registration",
+        ));
+    }
+    let widget = config
+        .captcha
+        .as_ref()
+        .map(|c| c.widget())
+        .unwrap_or_default();
+    Ok(Html(UPLOAD_HTML.replace("<!-- CAPTCHA -->", &widget)))
+}
+
+/// Applies the config file to the interface and routes the peer's addresses through it
+fn refresh_wireguard(
+    wireguard: &WireguardConfig,
+    ip: Ipv6Addr,
+    ipv4: Option<Ipv4Addr>,
+) -> Result<(), WireguardError> {
+    wireguard::sync(wireguard)?;
+    wireguard::add_route(&wireguard.config_name, &ip.to_string())?;
+    if let Some(ipv4) = ipv4 {
+        wireguard::add_route(&wireguard.config_name, &ipv4.to_string())?;
+    }
+    Ok(())
+}
+
+/// Removes the Wireguard peer with the given address, and its routes
+fn remove_wireguard_peer(
+    wireguard: &WireguardConfig,
+    ip: &str,
+    ipv4: Option<&str>,
+) -> Result<(), String> {
+    let server_peer = wg_config::WgConf::open(&wireguard.conf_path())
+        .map_err(|e| format!("Failed to open Wireguard config: {e:?}"))?;
+    let peers = server_peer
+        .peers()
+        .map_err(|e| format!("Failed to get peers: {e:?}"))?;
+    let public_key = peers
+        .iter()
+        .find(|p| p.allowed_ips().first().map(|a| a.to_string()).as_deref() == Some(ip))
+        .map(|p| p.public_key().to_owned());
+
+    if let Some(public_key) = public_key {
+        info!("Removing peer with IP {ip}");
+        server_peer
+            .remove_peer_by_pub_key(&public_key)
+            .map_err(|e| format!("Failed to remove peer: {e:?}"))?;
+        wireguard::sync(wireguard).map_err(|e| e.to_string())?;
+
+        for ip in std::iter::once(ip).chain(ipv4) {
+            if let Err(e) = wireguard::remove_route(&wireguard.config_name, ip) {
+                // The route goes away with the peer on some kernels
+                info!("Failed to remove route: {e}");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Removes the peers in the Wireguard configs that no registered device has, such as ones
+/// left behind when removing a device failed partway. Run by the wireguard_peers job.
+pub async fn remove_orphan_peers(state: &JitStreamerState) -> Result<String, String> {
+    let config = state.config();
+    if !config.wireguard_registration() {
+        return Ok("Wireguard registration is off".to_string());
+    }
+    // Held while comparing, so a registration can't add a peer in between
+    let _guard = WIREGUARD_LOCK.lock().await;
+    let registered = sqlx::query_scalar::<_, String>("SELECT ip FROM devices")
+        .fetch_all(&state.db)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to query database: {e:?}");
+            "Failed to query database".to_string()
+        })?
+        .into_iter()
+        .filter_map(|ip| ip.parse::<IpAddr>().ok())
+        .collect::<HashSet<IpAddr>>();
+
+    let mut removed = 0;
+    for wireguard in &config.wireguard {
+        let peers = match wg_config::WgConf::open(&wireguard.conf_path()) {
+            Ok(conf) => conf
+                .peers()
+                .map_err(|e| format!("Failed to get peers: {e:?}"))?,
+            // The first registration creates it
+            Err(wg_config::WgConfError::NotFound(_)) => continue,
+            Err(e) => return Err(format!("Failed to open Wireguard config: {e:?}")),
+        };
+        for peer in peers {
+            let allowed_ips = peer
+                .allowed_ips()
+                .iter()
+                .map(|a| a.to_string())
+                .collect::<Vec<String>>();
+            let Some(ip) = allowed_ips.first() else {
+                continue;
+            };
+            // Compared as addresses, the config may write them with a prefix length.
+            // Anything unexpected is left alone.
+            match ip.split('/').next().unwrap_or(ip).parse::<IpAddr>() {
+                Ok(address) if !registered.contains(&address) => {}
+                _ => continue,
+            }
+            info!(
+                "Removing orphaned peer with IP {ip} from {}",
+                wireguard.config_name
+            );
+            remove_wireguard_peer(wireguard, ip, allowed_ips.get(1).map(|i| i.as_str()))?;
+            removed += 1;
+        }
+    }
+    Ok(format!("Removed {removed} orphaned Wireguard peers"))
+}
+
+/// How long after its last handshake a Wireguard peer is considered disconnected
+pub const HANDSHAKE_TIMEOUT: u64 = 180;
+
+/// Whether the Wireguard peer routing to the address has completed a handshake recently,
+/// on whichever interface it's on.
+/// Returns true if it can't be determined, so a missing interface isn't blamed on the VPN.
+pub fn recent_handshake(interfaces: &[WireguardConfig], ip: IpAddr) -> bool {
+    interfaces
+        .iter()
+        .find_map(|w| interface_handshake(w, ip))
+        .unwrap_or(true)
+}
+
+/// None if the interface has no peer for the address, or it can't be checked
+fn interface_handshake(wireguard: &WireguardConfig, ip: IpAddr) -> Option<bool> {
+    let handshake = wireguard::last_handshake(wireguard, ip)?;
+    Some(handshake.is_some_and(|h| h.as_secs() < HANDSHAKE_TIMEOUT))
 }
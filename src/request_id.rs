@@ -0,0 +1,86 @@
+// Jackson Coxson
+// Tags every request with an ID that shows up in its logs and error responses
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header::CONTENT_TYPE, HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use tracing::{info_span, warn, Instrument};
+
+use crate::cluster::Cluster;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// JSON bodies larger than this are passed through without a request ID
+const MAX_JSON_BODY: usize = 1024 * 1024;
+
+/// Runs the request in a span carrying its ID, and returns the ID in the
+/// `X-Request-Id` header and in the body of JSON error responses.
+/// An ID sent by a reverse proxy is reused so logs line up across both.
+/// The span also names the node, to tell servers in a cluster apart.
+pub async fn middleware(State(cluster): State<Cluster>, request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .filter(|h| !h.is_empty() && h.len() <= 64 && h.bytes().all(|b| b.is_ascii_graphic()))
+        .map(|h| h.to_string())
+        .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+
+    let span = info_span!(
+        "request",
+        id = %id,
+        node = %cluster.node(),
+        method = %request.method(),
+        path = %request.uri().path()
+    );
+    let response = next.run(request).instrument(span).await;
+    let mut response = tag_error_body(response, &id).await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+    response
+}
+
+/// Adds `request_id` to JSON object bodies that report an error
+async fn tag_error_body(response: Response, id: &str) -> Response {
+    let is_json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|c| c.as_bytes().starts_with(b"application/json"));
+    if !is_json {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, MAX_JSON_BODY).await {
+        Ok(b) => b,
+        Err(e) => {
+            warn!("Failed to read response body: {e:?}");
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+
+    let mut value = match serde_json::from_slice::<serde_json::Value>(&bytes) {
+        Ok(serde_json::Value::Object(v)) => v,
+        _ => return Response::from_parts(parts, Body::from(bytes)),
+    };
+    let failed = value.get("error").is_some_and(|e| !e.is_null())
+        || value.get("success") == Some(&serde_json::Value::Bool(false));
+    if !failed {
+        return Response::from_parts(parts, Body::from(bytes));
+    }
+
+    value.insert(
+        "request_id".to_string(),
+        serde_json::Value::String(id.to_string()),
+    );
+    let body = serde_json::to_vec(&value).unwrap_or_else(|_| bytes.to_vec());
+    parts.headers.remove(axum::http::header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(body))
+}
@@ -0,0 +1,53 @@
+// Jackson Coxson
+// Removes devices that haven't launched anything in a while, freeing their Wireguard peers
+
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use crate::{register, JitStreamerState};
+
+/// The devices unused for longer than `retention`, and when they were last used
+pub async fn stale(
+    state: &JitStreamerState,
+    retention: Duration,
+) -> Result<Vec<(String, String)>, sqlx::Error> {
+    sqlx::query_as::<_, (String, String)>(
+        "SELECT udid, CAST(last_used AS TEXT) FROM devices WHERE last_used < datetime('now', ?) ORDER BY last_used",
+    )
+    .bind(format!("-{} seconds", retention.as_secs()))
+    .fetch_all(&state.db)
+    .await
+}
+
+/// Removes devices unused for longer than DEVICE_RETENTION_DAYS, forever. Everything
+/// kept for them goes too, see `register::remove_device`. Run by the stale_devices job.
+pub async fn sweep(state: &JitStreamerState) -> Result<String, String> {
+    let retention = state.config().device_retention;
+    if retention.is_zero() {
+        return Ok("DEVICE_RETENTION_DAYS is 0, devices are kept forever".to_string());
+    }
+    let stale = stale(state, retention).await.map_err(|e| {
+        tracing::error!("Failed to query database: {e:?}");
+        "Failed to query database".to_string()
+    })?;
+    if stale.is_empty() {
+        return Ok("No devices are stale".to_string());
+    }
+
+    info!("Removing {} devices unused for {retention:?}", stale.len());
+    let mut removed = 0;
+    for (udid, _) in &stale {
+        match register::remove_device(state, udid).await {
+            Ok(()) => removed += 1,
+            Err(e) => warn!("Failed to remove stale device {udid}: {e}"),
+        }
+    }
+    match removed == stale.len() {
+        true => Ok(format!("Removed {removed} stale devices")),
+        false => Err(format!(
+            "Removed {removed} of {} stale devices, see the logs",
+            stale.len()
+        )),
+    }
+}
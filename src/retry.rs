@@ -0,0 +1,41 @@
+// Jackson Coxson
+// Retries for device operations that often fail once, like the first connection over Wi-Fi
+
+use std::time::Duration;
+
+use idevice::IdeviceError;
+
+/// The longest wait between two attempts, however many there have been
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// How many times a transient failure is retried, and how long to wait in between
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first. 1 never retries.
+    pub attempts: u32,
+    /// The wait before the first retry, doubled for each one after
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// The wait before retrying after `failures` failed attempts. Half of it is random, so
+    /// devices that dropped together don't all retry at the same moment.
+    pub fn delay(&self, failures: u32) -> Duration {
+        let delay = self
+            .backoff
+            .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+            .min(MAX_BACKOFF);
+        delay / 2 + delay.mul_f64(rand::random::<f64>() / 2.0)
+    }
+
+    /// Whether another attempt is allowed after `failures` failed attempts
+    pub fn retries_left(&self, failures: u32) -> bool {
+        failures < self.attempts
+    }
+}
+
+/// Whether connecting again may succeed, such as after a refused or reset connection.
+/// A rejected pairing file fails the same way every time.
+pub fn transient(e: &IdeviceError) -> bool {
+    matches!(e, IdeviceError::Socket(_))
+}
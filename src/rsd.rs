@@ -0,0 +1,282 @@
+// Jackson Coxson
+// Caches the RemoteXPC service directory so launches can skip the RSD handshake
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Mutex;
+use tracing::{debug, info, info_span, warn, Instrument};
+
+use crate::{
+    progress::{LaunchEvent, Progress},
+    provider::DeviceProvider,
+    tunnel::{Tunnel, TunnelProvider},
+};
+
+/// The device to tunnel to, how, and where its service list is cached
+#[derive(Clone, Copy)]
+pub struct TunnelTarget<'a> {
+    pub provider: &'a DeviceProvider,
+    pub udid: &'a str,
+    pub tunnels: &'a dyn TunnelProvider,
+    pub cache: &'a RsdCache,
+}
+
+/// How long a prewarmed tunnel waits for the launch it was opened for
+const WARM_TUNNEL_TTL: Duration = Duration::from_secs(60);
+
+/// The service name to port map returned by the RSD handshake
+#[derive(Clone, Debug)]
+pub struct RsdServices {
+    pub ports: HashMap<String, u16>,
+    pub from_cache: bool,
+}
+
+#[derive(Clone)]
+struct CachedServices {
+    ports: HashMap<String, u16>,
+    fetched: Instant,
+}
+
+/// A tunnel opened ahead of a launch, see `prewarm`
+struct WarmTunnel {
+    tunnel: Tunnel,
+    ports: HashMap<String, u16>,
+    opened: Instant,
+}
+
+#[derive(Clone)]
+pub struct RsdCache {
+    inner: Arc<Mutex<HashMap<String, CachedServices>>>,
+    warm: Arc<Mutex<HashMap<String, WarmTunnel>>>,
+    ttl: Duration,
+}
+
+impl RsdCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(HashMap::new())),
+            warm: Arc::new(Mutex::new(HashMap::new())),
+            ttl,
+        }
+    }
+
+    /// Takes the device's prewarmed tunnel if it's fresh
+    async fn take_warm(&self, udid: &str) -> Option<(Tunnel, HashMap<String, u16>)> {
+        let mut warm = self.warm.lock().await;
+        // Idle tunnels hold a connection to the device, so don't let them pile up
+        warm.retain(|_, w| w.opened.elapsed() < WARM_TUNNEL_TTL);
+        warm.remove(udid).map(|w| (w.tunnel, w.ports))
+    }
+
+    async fn has_warm(&self, udid: &str) -> bool {
+        self.warm
+            .lock()
+            .await
+            .get(udid)
+            .is_some_and(|w| w.opened.elapsed() < WARM_TUNNEL_TTL)
+    }
+
+    async fn get(&self, udid: &str) -> Option<HashMap<String, u16>> {
+        let mut lock = self.inner.lock().await;
+        match lock.get(udid) {
+            Some(c) if c.fetched.elapsed() < self.ttl => Some(c.ports.clone()),
+            Some(_) => {
+                lock.remove(udid);
+                None
+            }
+            None => None,
+        }
+    }
+
+    async fn insert(&self, udid: &str, ports: HashMap<String, u16>) {
+        self.inner.lock().await.insert(
+            udid.to_string(),
+            CachedServices {
+                ports,
+                fetched: Instant::now(),
+            },
+        );
+    }
+
+    pub async fn invalidate(&self, udid: &str) {
+        self.inner.lock().await.remove(udid);
+        self.warm.lock().await.remove(udid);
+    }
+
+    /// The devices with a cached handshake, and its age in seconds
+    pub async fn entries(&self) -> Vec<(String, u64)> {
+        self.inner
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, c)| c.fetched.elapsed() < self.ttl)
+            .map(|(udid, c)| (udid.clone(), c.fetched.elapsed().as_secs()))
+            .collect()
+    }
+}
+
+impl RsdServices {
+    pub fn port(&self, service_name: &str) -> Option<u16> {
+        self.ports.get(service_name).copied()
+    }
+}
+
+/// Creates a tunnel to the device and resolves the RSD service list,
+/// skipping the XPC handshake if the cached list hasn't expired.
+/// A tunnel opened by `prewarm` is used instead of a new one when there is one.
+/// The returned tunnel is not connected to any port.
+#[tracing::instrument(name = "tunnel", skip_all, fields(provider = %target.tunnels.kind()))]
+pub async fn tunnel(
+    target: TunnelTarget<'_>,
+    use_cache: bool,
+    progress: &Progress,
+) -> Result<(Tunnel, RsdServices), String> {
+    if use_cache {
+        if let Some((adapter, ports)) = target.cache.take_warm(target.udid).await {
+            debug!("Using the tunnel prewarmed for {}", target.udid);
+            progress.send(LaunchEvent::Tunnel);
+            progress.send(LaunchEvent::Xpc { cached: true });
+            // Marked cached so a dead one is replaced by a fresh tunnel
+            return Ok((
+                adapter,
+                RsdServices {
+                    ports,
+                    from_cache: true,
+                },
+            ));
+        }
+    }
+    open(target, use_cache, progress).await
+}
+
+/// Opens a tunnel and does the RSD handshake ahead of a launch, keeping both for
+/// `WARM_TUNNEL_TTL` so the launch's first tunnel is already there
+pub async fn prewarm(target: TunnelTarget<'_>) -> Result<(), String> {
+    if target.cache.has_warm(target.udid).await {
+        return Ok(());
+    }
+    let (tunnel, services) = open(target, true, &Progress::default()).await?;
+    debug!("Prewarmed a tunnel for {}", target.udid);
+    target.cache.warm.lock().await.insert(
+        target.udid.to_string(),
+        WarmTunnel {
+            tunnel,
+            ports: services.ports,
+            opened: Instant::now(),
+        },
+    );
+    Ok(())
+}
+
+async fn open(
+    target: TunnelTarget<'_>,
+    use_cache: bool,
+    progress: &Progress,
+) -> Result<(Tunnel, RsdServices), String> {
+    let TunnelTarget {
+        provider,
+        udid,
+        tunnels,
+        cache,
+    } = target;
+    let (mut adapter, rsd_port) = tunnels.open(provider, udid).await?;
+    progress.send(LaunchEvent::Tunnel);
+
+    if use_cache {
+        if let Some(ports) = cache.get(udid).await {
+            debug!("Using cached RSD services for {udid}");
+            progress.send(LaunchEvent::Xpc { cached: true });
+            return Ok((
+                adapter,
+                RsdServices {
+                    ports,
+                    from_cache: true,
+                },
+            ));
+        }
+    }
+
+    if let Err(e) = adapter.connect(rsd_port).await {
+        info!("Failed to connect to RemoteXPC port: {:?}", e);
+        return Err(format!("Failed to connect to RemoteXPC port: {e}"));
+    }
+
+    let xpc_client = match idevice::xpc::XPCDevice::new(adapter)
+        .instrument(info_span!("xpc"))
+        .await
+    {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Failed to connect to RemoteXPC: {e:?}");
+            return Err("Failed to connect to RemoteXPC".to_string());
+        }
+    };
+
+    let ports = xpc_client
+        .services
+        .iter()
+        .map(|(name, service)| (name.clone(), service.port))
+        .collect::<HashMap<String, u16>>();
+
+    let mut adapter = xpc_client.into_inner();
+    if let Err(e) = adapter.close().await {
+        warn!("Failed to close RemoteXPC port: {e:?}");
+        return Err("Failed to close RemoteXPC port".to_string());
+    }
+
+    cache.insert(udid, ports.clone()).await;
+    progress.send(LaunchEvent::Xpc { cached: false });
+    Ok((
+        adapter,
+        RsdServices {
+            ports,
+            from_cache: false,
+        },
+    ))
+}
+
+/// Creates a tunnel and connects it to the given service.
+/// If the port came from the cache and the connection fails, the cache is dropped
+/// and a fresh handshake is performed.
+pub async fn connect_service(
+    target: TunnelTarget<'_>,
+    service_name: &str,
+    missing_message: &str,
+    progress: &Progress,
+) -> Result<(Tunnel, RsdServices), String> {
+    let TunnelTarget { udid, cache, .. } = target;
+    let mut use_cache = true;
+    loop {
+        let (mut adapter, services) = tunnel(target, use_cache, progress).await?;
+        let port = match services.port(service_name) {
+            Some(p) => p,
+            None => {
+                if services.from_cache {
+                    cache.invalidate(udid).await;
+                    use_cache = false;
+                    continue;
+                }
+                return Err(missing_message.to_string());
+            }
+        };
+
+        info!("Connecting to {service_name} port: {port}");
+        match adapter.connect(port).await {
+            Ok(_) => return Ok((adapter, services)),
+            Err(e) => {
+                if services.from_cache {
+                    debug!("Cached port for {service_name} failed, refreshing RSD services: {e:?}");
+                    cache.invalidate(udid).await;
+                    use_cache = false;
+                    continue;
+                }
+                warn!("Failed to connect to {service_name} port: {e:?}");
+                return Err(format!("Failed to connect to {service_name} port"));
+            }
+        }
+    }
+}
@@ -0,0 +1,260 @@
+// Jackson Coxson
+// Runs the periodic jobs on their own intervals, keeping track of how each last went
+
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use crate::{
+    invites, mount, pipeline, register, retention, stats, subscriptions, JitStreamerState,
+};
+
+/// The longest the scheduler sleeps, so intervals changed by a reload are picked up
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Job {
+    /// Removes devices unused for DEVICE_RETENTION_DAYS
+    StaleDevices,
+    /// Removes Wireguard peers that no registered device has
+    WireguardPeers,
+    /// Forgets expired unfinished launches, old mounts and expired invite codes
+    QueueGc,
+    /// Folds launch stats older than STATS_RETENTION_DAYS into daily counts
+    StatsRollup,
+    /// Launches subscribed apps that are due, or whose device came back
+    Subscriptions,
+}
+
+impl Job {
+    pub const ALL: [Job; 5] = [
+        Job::StaleDevices,
+        Job::WireguardPeers,
+        Job::QueueGc,
+        Job::StatsRollup,
+        Job::Subscriptions,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Job::StaleDevices => "stale_devices",
+            Job::WireguardPeers => "wireguard_peers",
+            Job::QueueGc => "queue_gc",
+            Job::StatsRollup => "stats_rollup",
+            Job::Subscriptions => "subscriptions",
+        }
+    }
+
+    /// The setting with the seconds between runs
+    pub fn var(&self) -> &'static str {
+        match self {
+            Job::StaleDevices => "JOB_STALE_DEVICES",
+            Job::WireguardPeers => "JOB_WIREGUARD_PEERS",
+            Job::QueueGc => "JOB_QUEUE_GC",
+            Job::StatsRollup => "JOB_STATS_ROLLUP",
+            Job::Subscriptions => "JOB_SUBSCRIPTIONS",
+        }
+    }
+
+    /// Peers added by hand would be removed too, so that one is opt in
+    pub fn default_interval(&self) -> u64 {
+        match self {
+            Job::StaleDevices => 60 * 60,
+            Job::WireguardPeers => 0,
+            Job::QueueGc => 5 * 60,
+            Job::StatsRollup => 24 * 60 * 60,
+            Job::Subscriptions => 60,
+        }
+    }
+
+    /// Removing devices and peers needs the Wireguard interface, which only nodes that
+    /// register have
+    pub fn needs_registering(&self) -> bool {
+        matches!(self, Job::StaleDevices | Job::WireguardPeers)
+    }
+
+    /// Launching needs to reach devices, which nodes that only register don't
+    pub fn needs_serving(&self) -> bool {
+        matches!(self, Job::Subscriptions)
+    }
+
+    /// Returns what it did
+    async fn run(self, state: &JitStreamerState) -> Result<String, String> {
+        let role = state.config().role;
+        if self.needs_registering() && !role.registers() {
+            return Err(format!(
+                "This node's ROLE is {role}, run it on a node that registers devices"
+            ));
+        }
+        if self.needs_serving() && !role.serves_devices() {
+            return Err(format!(
+                "This node's ROLE is {role}, run it on a node that serves devices"
+            ));
+        }
+        match self {
+            Job::StaleDevices => retention::sweep(state).await,
+            Job::WireguardPeers => register::remove_orphan_peers(state).await,
+            Job::QueueGc => queue_gc(state).await,
+            Job::StatsRollup => stats::rollup(state).await,
+            Job::Subscriptions => subscriptions::launch_due(state).await,
+        }
+    }
+}
+
+impl FromStr for Job {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Job::ALL.into_iter().find(|j| j.as_str() == s).ok_or(())
+    }
+}
+
+#[derive(Default)]
+struct JobState {
+    running: bool,
+    /// Due right away when unset
+    next_run: Option<Instant>,
+    last_run: Option<Instant>,
+    last_duration: Duration,
+    last_result: Option<Result<String, String>>,
+}
+
+#[derive(Serialize)]
+pub struct JobStatus {
+    name: Job,
+    /// Seconds between runs, 0 when it only runs when asked to
+    interval_secs: u64,
+    running: bool,
+    /// Null if it hasn't run since the server started
+    last_run_secs_ago: Option<u64>,
+    last_duration_ms: Option<u64>,
+    /// What the last run did
+    last_result: Option<String>,
+    last_error: Option<String>,
+    /// Null when it only runs when asked to
+    next_run_in_secs: Option<u64>,
+}
+
+#[derive(Clone, Default)]
+pub struct Scheduler(Arc<Mutex<HashMap<Job, JobState>>>);
+
+impl Scheduler {
+    /// Every job, whether it's on or not
+    pub async fn status(&self, state: &JitStreamerState) -> Vec<JobStatus> {
+        let config = state.config();
+        let jobs = self.0.lock().await;
+        let now = Instant::now();
+        Job::ALL
+            .into_iter()
+            .map(|job| {
+                let interval = config.job_interval(job);
+                let job_state = jobs.get(&job);
+                let last_run = job_state.and_then(|s| s.last_run);
+                let last_result = job_state.and_then(|s| s.last_result.clone());
+                JobStatus {
+                    name: job,
+                    interval_secs: interval.as_secs(),
+                    running: job_state.is_some_and(|s| s.running),
+                    last_run_secs_ago: last_run.map(|l| now.duration_since(l).as_secs()),
+                    last_duration_ms: job_state
+                        .filter(|s| s.last_run.is_some())
+                        .map(|s| s.last_duration.as_millis() as u64),
+                    last_result: last_result.clone().and_then(|r| r.ok()),
+                    last_error: last_result.and_then(|r| r.err()),
+                    next_run_in_secs: match interval.is_zero() {
+                        true => None,
+                        false => Some(
+                            job_state
+                                .and_then(|s| s.next_run)
+                                .map(|n| n.saturating_duration_since(now).as_secs())
+                                .unwrap_or(0),
+                        ),
+                    },
+                }
+            })
+            .collect()
+    }
+
+    /// Starts the job now, even if it's off. Returns false if it's already running.
+    pub async fn run_now(&self, state: &JitStreamerState, job: Job) -> bool {
+        let mut jobs = self.0.lock().await;
+        let job_state = jobs.entry(job).or_default();
+        if job_state.running {
+            return false;
+        }
+        job_state.running = true;
+        tokio::spawn(run_job(state.clone(), job));
+        true
+    }
+}
+
+/// Starts each job when it's due, until the server stops
+pub async fn run(state: JitStreamerState) {
+    loop {
+        let config = state.config();
+        let now = Instant::now();
+        let mut wake = now + MAX_SLEEP;
+        {
+            let mut jobs = state.scheduler.0.lock().await;
+            for job in Job::ALL {
+                let interval = config.job_interval(job);
+                let job_state = jobs.entry(job).or_default();
+                if interval.is_zero() || job_state.running {
+                    continue;
+                }
+                match job_state.next_run {
+                    Some(next_run) if next_run > now => wake = wake.min(next_run),
+                    _ => {
+                        job_state.running = true;
+                        tokio::spawn(run_job(state.clone(), job));
+                    }
+                }
+            }
+        }
+        tokio::time::sleep_until(wake.into()).await;
+    }
+}
+
+async fn run_job(state: JitStreamerState, job: Job) {
+    debug!("Running the {} job", job.as_str());
+    let started = Instant::now();
+    let task_state = state.clone();
+    // A panicking job would otherwise stay running forever
+    let res = match tokio::spawn(async move { job.run(&task_state).await }).await {
+        Ok(res) => res,
+        Err(e) => Err(format!("The job panicked: {e}")),
+    };
+    match &res {
+        Ok(r) => info!("The {} job finished: {r}", job.as_str()),
+        Err(e) => warn!("The {} job failed: {e}", job.as_str()),
+    }
+
+    let interval = state.config().job_interval(job);
+    let mut jobs = state.scheduler.0.lock().await;
+    let job_state = jobs.entry(job).or_default();
+    job_state.running = false;
+    job_state.next_run = Some(started + interval);
+    job_state.last_run = Some(started);
+    job_state.last_duration = started.elapsed();
+    job_state.last_result = Some(res);
+}
+
+async fn queue_gc(state: &JitStreamerState) -> Result<String, String> {
+    let launches = pipeline::prune(&state.launch_checkpoints).await;
+    let mounts = mount::prune(&state.mount_cache).await;
+    let invites = invites::prune(&state.db_writer).await.map_err(|e| {
+        tracing::error!("Failed to delete expired invites: {e:?}");
+        "Failed to delete expired invites".to_string()
+    })?;
+    Ok(format!(
+        "Forgot {launches} expired unfinished launches, {mounts} old mounts and {invites} expired invite codes"
+    ))
+}
@@ -0,0 +1,36 @@
+// Jackson Coxson
+// Captures the device's screen over DVT, to check an app actually launched
+
+use tracing::{debug, warn};
+
+use crate::{processes, tunnel::Tunnel};
+
+const SCREENSHOT_CHANNEL: &str = "com.apple.instruments.server.services.screenshot";
+
+/// Takes a PNG screenshot using the tunnel connected to the DVT service
+#[tracing::instrument(name = "dvt", skip_all)]
+pub async fn capture(adapter: Tunnel) -> Result<Vec<u8>, String> {
+    let mut rs_client = processes::remote_server(adapter).await?;
+    let mut channel = rs_client
+        .make_channel(SCREENSHOT_CHANNEL)
+        .await
+        .map_err(|e| format!("Failed to open screenshot channel: {e:?}"))?;
+    channel
+        .call_method(Some("takeScreenshot"), None, true)
+        .await
+        .map_err(|e| format!("Failed to request screenshot: {e:?}"))?;
+    let res = channel
+        .read_message()
+        .await
+        .map_err(|e| format!("Failed to read screenshot: {e:?}"))?;
+    match res.data {
+        Some(plist::Value::Data(png)) => {
+            debug!("Captured a {} byte screenshot", png.len());
+            Ok(png)
+        }
+        d => {
+            warn!("Unexpected screenshot response: {d:?}");
+            Err("Unexpected screenshot response".to_string())
+        }
+    }
+}
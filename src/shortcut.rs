@@ -0,0 +1,43 @@
+// Jackson Coxson
+// GET /shortcut, so the shortcut can check for its own updates
+
+use axum::{extract::State, Json};
+use serde::Serialize;
+
+use crate::{ClientType, JitStreamerState};
+
+/// The latest release of the shortcut, from SHORTCUT_VERSION, SHORTCUT_URL and
+/// SHORTCUT_CHANGELOG
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShortcutRelease {
+    pub version: String,
+    /// Where to install it, usually an iCloud link
+    pub url: String,
+    pub changelog: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ShortcutReturn {
+    ok: bool,
+    /// The latest release, null if the server doesn't publish one
+    version: Option<String>,
+    url: Option<String>,
+    changelog: Option<String>,
+    /// Older shortcuts don't work with this server
+    minimum_version: String,
+}
+
+/// The latest shortcut, for it to compare against its own version
+pub async fn handler(State(state): State<JitStreamerState>) -> Json<ShortcutReturn> {
+    let release = state.config().shortcut.clone();
+    Json(ShortcutReturn {
+        ok: true,
+        version: release.as_ref().map(|r| r.version.clone()),
+        url: release.as_ref().map(|r| r.url.clone()),
+        changelog: release.and_then(|r| r.changelog),
+        minimum_version: ClientType::Shortcut
+            .minimum_version()
+            .map(|v| v.to_string())
+            .join("."),
+    })
+}
@@ -0,0 +1,132 @@
+// Jackson Coxson
+// SideJITServer's routes on top of the usual launch, so apps made for it work unchanged
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use axum_client_ip::SecureClientIp;
+use serde::Serialize;
+use tracing::info;
+
+use crate::{
+    client::{self, CLIENT_HEADER},
+    common,
+    error::ErrorCode,
+    jit, launcher, rate_limit, resolve_app_name, JitStreamerState,
+};
+
+/// Recorded in stats for callers that don't send X-Client themselves
+const CLIENT_NAME: &str = "SideJITServer";
+
+/// The routes as SideJITServer has them. The UDID in the path picks one of the devices
+/// registered from the caller's IP, like the X-JitStreamer-Device header.
+pub fn router(state: &JitStreamerState) -> Router<JitStreamerState> {
+    Router::new()
+        .route("/ver", get(version))
+        .route(
+            "/{udid}/",
+            get(apps).layer(axum::middleware::from_fn_with_state(
+                (state.clone(), rate_limit::Budget::GetApps),
+                rate_limit::enforce,
+            )),
+        )
+        .route(
+            "/{udid}/{app}/",
+            get(launch).layer(axum::middleware::from_fn_with_state(
+                (state.clone(), rate_limit::Budget::Launch),
+                rate_limit::enforce,
+            )),
+        )
+}
+
+fn selector(selector: common::DeviceSelector, udid: String) -> common::DeviceSelector {
+    common::DeviceSelector {
+        device: Some(udid),
+        ..selector
+    }
+}
+
+fn client(headers: &axum::http::HeaderMap) -> client::Client {
+    match headers.contains_key(CLIENT_HEADER) {
+        true => client::Client::from_headers(headers),
+        false => client::Client::new(Some(CLIENT_NAME)),
+    }
+}
+
+#[derive(Serialize)]
+struct Version {
+    version: &'static str,
+}
+
+async fn version() -> Json<Version> {
+    Json(Version {
+        version: env!("CARGO_PKG_VERSION"),
+    })
+}
+
+#[derive(Serialize)]
+struct App {
+    name: String,
+    bundle: String,
+}
+
+/// The device's debuggable apps
+async fn apps(
+    ip: SecureClientIp,
+    headers: axum::http::HeaderMap,
+    device_selector: common::DeviceSelector,
+    Path(udid): Path<String>,
+    State(state): State<JitStreamerState>,
+) -> Result<Json<Vec<App>>, (StatusCode, String)> {
+    let res = crate::get_apps(
+        ip,
+        selector(device_selector, udid),
+        client(&headers),
+        Query(Default::default()),
+        State(state),
+    )
+    .await
+    .0;
+    match (res.bundle_ids, res.error) {
+        (Some(bundle_ids), None) => {
+            let mut apps = bundle_ids
+                .into_iter()
+                .map(|(name, bundle)| App { name, bundle })
+                .collect::<Vec<App>>();
+            apps.sort_by(|a, b| a.name.cmp(&b.name));
+            Ok(Json(apps))
+        }
+        (_, error) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            error.map(|e| e.to_string()).unwrap_or_default(),
+        )),
+    }
+}
+
+/// Launches with JIT by the app's name or bundle ID, answering in text like SideJITServer
+async fn launch(
+    ip: SecureClientIp,
+    headers: axum::http::HeaderMap,
+    device_selector: common::DeviceSelector,
+    Path((udid, app)): Path<(String, String)>,
+    State(state): State<JitStreamerState>,
+) -> Result<String, (StatusCode, String)> {
+    info!("Got SideJITServer request to launch {app} from {:?}", ip.0);
+    let selector = selector(device_selector, udid);
+    let fail = |e: crate::error::JitError| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    let bundle_id = match resolve_app_name(ip.0, &selector, &app, &state).await {
+        Ok(b) => b,
+        // SideJITServer takes bundle IDs in the same place
+        Err(e) if e.code == ErrorCode::AppNotFound && app.contains('.') => app.clone(),
+        Err(e) => return Err(fail(e)),
+    };
+    jit::JitSession::new(&state, ip.0, &selector)
+        .client(client(&headers).name())
+        .launch(bundle_id, launcher::LaunchOptions::default())
+        .await
+        .map_err(fail)?;
+    Ok(format!("Enabled JIT for '{app}'!"))
+}
@@ -0,0 +1,15 @@
+-- Devices are keyed by UDID so several devices can share a public IP,
+-- those are told apart by a token issued at registration
+create table devices_new (
+  udid varchar(40) primary key,
+  ip varchar(45) not null,
+  token varchar(64) unique,
+  last_used datetime not null
+);
+
+insert or replace into devices_new (udid, ip, last_used)
+  select udid, ip, last_used from devices;
+
+drop table devices;
+alter table devices_new rename to devices;
+create index devices_ip on devices (ip);
@@ -0,0 +1,8 @@
+-- Devices and networks that are refused service
+create table bans (
+  kind varchar(8) not null, -- udid or ip
+  value varchar(64) not null,
+  reason varchar(255),
+  created_at datetime not null,
+  primary key (kind, value)
+);
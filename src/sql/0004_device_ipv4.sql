@@ -0,0 +1,3 @@
+-- Dual stack Wireguard peers also get an IPv4 address, devices are looked up by either
+alter table devices add column ipv4 varchar(15);
+create unique index devices_ipv4 on devices (ipv4);
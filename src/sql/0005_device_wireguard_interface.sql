@@ -0,0 +1,2 @@
+-- Which Wireguard interface a device's peer is on, the first configured one when null
+alter table devices add column wireguard_interface varchar(15);
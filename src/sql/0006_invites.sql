@@ -0,0 +1,9 @@
+-- One-time codes required to register with ALLOW_REGISTRATION=3
+create table invites (
+  code varchar(32) primary key,
+  note varchar(255),
+  created_at datetime not null,
+  expires_at datetime, -- never when null
+  used_by varchar(64), -- the UDID that registered with it
+  used_at datetime
+);
@@ -0,0 +1,6 @@
+-- Devices that tried to register while the server was full
+create table waitlist (
+  udid varchar(64) primary key,
+  ip varchar(64) not null,
+  created_at datetime not null
+);
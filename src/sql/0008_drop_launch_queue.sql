@@ -0,0 +1,2 @@
+-- Launches run in-process, the Python runners that read this queue are gone
+drop table if exists launch_queue;
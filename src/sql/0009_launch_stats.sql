@@ -0,0 +1,12 @@
+-- Every launch and attach attempt, for /stats and /admin/stats
+create table launch_stats (
+  at datetime not null,
+  kind varchar(16) not null, -- launch or attach
+  udid_hash varchar(64), -- null when the device couldn't be found
+  bundle_id varchar(255),
+  ok boolean not null,
+  code varchar(32), -- the error code when it failed
+  duration_ms integer not null,
+  ios_version varchar(32)
+);
+create index launch_stats_at on launch_stats (at);
@@ -0,0 +1,10 @@
+-- Keys clients send as a bearer token, required with REQUIRE_API_KEY
+create table api_keys (
+  id integer primary key autoincrement,
+  key_hash varchar(64) not null unique, -- sha256 of the key, which is only shown when minted
+  name varchar(255) not null,
+  scopes varchar(64) not null, -- comma separated, launch or admin
+  rate_limit integer not null default 0, -- requests per minute, 0 for unlimited
+  created_at datetime not null,
+  last_used datetime
+);
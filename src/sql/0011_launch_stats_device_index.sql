@@ -0,0 +1,2 @@
+-- Quotas and /history look up a device's launches
+create index launch_stats_udid_hash on launch_stats (udid_hash, at);
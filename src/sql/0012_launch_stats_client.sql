@@ -0,0 +1,2 @@
+-- The X-Client header of the app that asked, to tell clients apart in the stats
+alter table launch_stats add column client varchar(64);
@@ -0,0 +1,14 @@
+-- Hashed IPv6 addresses could collide, and the newer registration took over the peer.
+-- The older devices lost their tunnel, so they're removed and have to register again.
+delete from devices
+where wireguard_interface is not null
+  and exists (
+    select 1 from devices newer
+    where newer.ip = devices.ip
+      and newer.wireguard_interface is not null
+      and (newer.last_used > devices.last_used
+        or (newer.last_used = devices.last_used and newer.rowid > devices.rowid))
+  );
+
+-- Catches a collision the allocator missed, devices registered by address may share an IP
+create unique index devices_wireguard_ip on devices (ip) where wireguard_interface is not null;
@@ -0,0 +1,7 @@
+-- Notes about outages shown on the public status page, posted through the admin API
+create table incidents (
+  id integer primary key autoincrement,
+  message text not null,
+  created_at datetime not null,
+  resolved_at datetime -- null while ongoing
+);
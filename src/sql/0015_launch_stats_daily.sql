@@ -0,0 +1,8 @@
+-- Launch stats older than STATS_RETENTION_DAYS, counted by day
+create table launch_stats_daily (
+  day date not null,
+  kind varchar(16) not null, -- launch or attach
+  ok boolean not null,
+  attempts integer not null,
+  primary key (day, kind, ok)
+);
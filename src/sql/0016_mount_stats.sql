@@ -0,0 +1,11 @@
+-- Every developer disk image mount, for /admin/stats
+create table mount_stats (
+  at datetime not null,
+  udid_hash varchar(64) not null,
+  ios_version varchar(32),
+  ddi_build varchar(32), -- the ProductBuildVersion of the image the server mounts
+  ok boolean not null,
+  error text, -- what went wrong when it failed
+  duration_ms integer not null
+);
+create index mount_stats_at on mount_stats (at);
@@ -0,0 +1,2 @@
+-- The failed attempt's error message, so /launch_status can repeat it
+alter table launch_stats add column error text;
@@ -0,0 +1,8 @@
+-- Launches per device by hour of the day in UTC, to keep devices warm when they're usually used
+create table device_usage (
+  udid varchar(64) not null,
+  hour integer not null, -- 0 to 23
+  launches integer not null,
+  last_launch datetime not null,
+  primary key (udid, hour)
+);
@@ -0,0 +1,7 @@
+-- Apps marked as favorites per device, which the shortcut shows before the full app list
+create table favorites (
+  udid varchar(64) not null,
+  bundle_id varchar(255) not null,
+  added_at datetime not null,
+  primary key (udid, bundle_id)
+);
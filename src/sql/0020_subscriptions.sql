@@ -0,0 +1,14 @@
+-- Apps launched by the server on their own, on a schedule or when the device comes back
+create table subscriptions (
+  id integer primary key autoincrement,
+  udid varchar(64) not null,
+  bundle_id varchar(255) not null,
+  every_minutes integer, -- null when it's only launched on reconnect
+  on_reconnect boolean not null,
+  device_online boolean not null default 0, -- whether the device answered the last check
+  pending boolean not null default 0, -- the device came back and the launch hasn't worked yet
+  last_run datetime,
+  last_error text,
+  created_at datetime not null
+);
+create index subscriptions_udid on subscriptions (udid);
@@ -0,0 +1,18 @@
+-- Tokens are signed with the server's secret and verified without the database,
+-- the stored ones were never read. SQLite can't drop a unique column, so the table is rebuilt.
+create table devices_new (
+  udid varchar(40) primary key,
+  ip varchar(45) not null,
+  last_used datetime not null,
+  ipv4 varchar(15),
+  wireguard_interface varchar(15)
+);
+
+insert into devices_new (udid, ip, last_used, ipv4, wireguard_interface)
+  select udid, ip, last_used, ipv4, wireguard_interface from devices;
+
+drop table devices;
+alter table devices_new rename to devices;
+create index devices_ip on devices (ip);
+create unique index devices_ipv4 on devices (ipv4);
+create unique index devices_wireguard_ip on devices (ip) where wireguard_interface is not null;
@@ -0,0 +1,87 @@
+// Jackson Coxson
+// Keeps the heartbeats and tunnels of the busiest devices up during the hours they're usually
+// used, so their launches skip connecting
+
+use std::{net::IpAddr, str::FromStr, time::Duration};
+
+use tracing::debug;
+
+use crate::JitStreamerState;
+
+/// The most time between refreshes, under the lifetime of a warm tunnel
+const MAX_TICK: Duration = Duration::from_secs(30);
+const MIN_TICK: Duration = Duration::from_secs(5);
+
+/// Counts a launch towards the device's usual hours
+pub async fn record(state: &JitStreamerState, udid: &str) {
+    if let Err(e) = state
+        .db_writer
+        .execute(
+            sqlx::query(
+                "INSERT INTO device_usage (udid, hour, launches, last_launch) VALUES (?, CAST(strftime('%H', 'now') AS INTEGER), 1, CURRENT_TIMESTAMP) ON CONFLICT (udid, hour) DO UPDATE SET launches = launches + 1, last_launch = CURRENT_TIMESTAMP",
+            )
+            .bind(udid),
+        )
+        .await
+    {
+        tracing::error!("Failed to record usage of {udid}: {e:?}");
+    }
+}
+
+/// The devices launched from at least STANDBY_MIN_LAUNCHES times at this hour of the day,
+/// most launched first. Hours unused for STANDBY_HISTORY_DAYS no longer count.
+async fn candidates(state: &JitStreamerState) -> Result<Vec<(String, IpAddr)>, sqlx::Error> {
+    let config = state.config();
+    let rows = sqlx::query_as::<_, (String, String)>(
+        "SELECT d.udid, d.ip FROM device_usage u JOIN devices d ON d.udid = u.udid WHERE u.hour = CAST(strftime('%H', 'now') AS INTEGER) AND u.launches >= ? AND u.last_launch >= datetime('now', ?) ORDER BY u.launches DESC LIMIT ?",
+    )
+    .bind(config.standby_min_launches)
+    .bind(format!("-{} days", config.standby_history_days))
+    .bind(config.standby_devices as i64)
+    .fetch_all(&state.db)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|(udid, ip)| IpAddr::from_str(&ip).ok().map(|ip| (udid, ip)))
+        .collect())
+}
+
+/// Connects to the device and opens its tunnel, or keeps the ones it has. Devices in use
+/// are left to their requests.
+async fn warm(state: JitStreamerState, udid: String, ip: IpAddr) {
+    if state.device_queues.queued(&udid).await > 0 {
+        return;
+    }
+    let Ok((_turn, _)) = state.device_queues.wait(&udid).await else {
+        return;
+    };
+    if let Err(e) = state.backend.prewarm(&state, &udid, ip).await {
+        debug!("Failed to keep {udid} on standby: {e}");
+    }
+    // Kept for the grace period, the next refresh comes before it runs out
+    state.heartbeats.release(&udid).await.ok();
+}
+
+/// Refreshes the standby devices until the server stops. Off while STANDBY_DEVICES is 0.
+pub async fn run(state: JitStreamerState) {
+    loop {
+        let config = state.config();
+        let tick = (config.heartbeat.grace_period / 2).clamp(MIN_TICK, MAX_TICK);
+        if config.standby_devices > 0 && config.role.serves_devices() {
+            match candidates(&state).await {
+                Ok(devices) => {
+                    debug!("Keeping {} devices on standby", devices.len());
+                    let tasks = devices
+                        .into_iter()
+                        .map(|(udid, ip)| tokio::spawn(warm(state.clone(), udid, ip)))
+                        .collect::<Vec<_>>();
+                    for task in tasks {
+                        task.await.ok();
+                    }
+                }
+                Err(e) => tracing::error!("Failed to read device usage: {e:?}"),
+            }
+        }
+        tokio::time::sleep(tick).await;
+    }
+}
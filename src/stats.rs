@@ -0,0 +1,492 @@
+// Jackson Coxson
+// Counts of every launch and attach, public at /stats and /capacity and broken down for admins
+
+use std::time::Duration;
+
+use axum::{extract::State, Json};
+use axum_client_ip::SecureClientIp;
+use serde::Serialize;
+use sha2::Digest;
+use sqlx::Connection;
+use tracing::warn;
+
+use crate::{
+    common::{self, DeviceSelector},
+    error::JitError,
+    privacy, JitStreamerState,
+};
+
+/// How many bundle IDs the admin breakdown lists
+const TOP_BUNDLES: i64 = 20;
+/// How many attempts /history returns
+const HISTORY_LENGTH: i64 = 50;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Kind {
+    Launch,
+    Attach,
+}
+
+impl Kind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Kind::Launch => "launch",
+            Kind::Attach => "attach",
+        }
+    }
+}
+
+/// UDIDs aren't stored, so the stats can't be tied back to a device
+pub fn hash_udid(udid: &str) -> String {
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(udid.as_bytes());
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Records an attempt. `udid` is `None` when the caller's device couldn't be found, `client`
+/// when the caller didn't send X-Client.
+pub async fn record(
+    state: &JitStreamerState,
+    kind: Kind,
+    udid: Option<&str>,
+    bundle_id: Option<&str>,
+    error: Option<&JitError>,
+    duration: Duration,
+    client: Option<&str>,
+) {
+    let ios_version = match udid {
+        Some(udid) => state
+            .device_info_cache
+            .lock()
+            .await
+            .get(udid)
+            .and_then(|i| i.product_version.clone()),
+        None => None,
+    };
+    let code = error.and_then(|e| {
+        serde_json::to_value(e.code)
+            .ok()
+            .and_then(|v| v.as_str().map(|v| v.to_string()))
+    });
+
+    if let Err(e) = state
+        .db_writer
+        .execute(
+            sqlx::query(
+                "INSERT INTO launch_stats (at, kind, udid_hash, bundle_id, ok, code, error, duration_ms, ios_version, client) VALUES (CURRENT_TIMESTAMP, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            )
+            .bind(kind.as_str())
+            .bind(udid.map(hash_udid))
+            .bind(bundle_id)
+            .bind(error.is_none())
+            .bind(code)
+            .bind(error.map(|e| privacy::text(state, &e.message)))
+            .bind(duration.as_millis() as i64)
+            .bind(ios_version)
+            .bind(client),
+        )
+        .await
+    {
+        warn!("Failed to record {} stats: {e:?}", kind.as_str());
+    }
+}
+
+/// Records a developer disk image mount, which was of the `ddi_build` image
+pub async fn record_mount(
+    state: &JitStreamerState,
+    udid: &str,
+    ddi_build: Option<&str>,
+    error: Option<&str>,
+    duration: Duration,
+) {
+    let ios_version = state
+        .device_info_cache
+        .lock()
+        .await
+        .get(udid)
+        .and_then(|i| i.product_version.clone());
+    if let Err(e) = state
+        .db_writer
+        .execute(
+            sqlx::query(
+                "INSERT INTO mount_stats (at, udid_hash, ios_version, ddi_build, ok, error, duration_ms) VALUES (CURRENT_TIMESTAMP, ?, ?, ?, ?, ?, ?)",
+            )
+            .bind(hash_udid(udid))
+            .bind(ios_version)
+            .bind(ddi_build)
+            .bind(error.is_none())
+            .bind(error.map(|e| privacy::text(state, e)))
+            .bind(duration.as_millis() as i64),
+        )
+        .await
+    {
+        warn!("Failed to record mount stats: {e:?}");
+    }
+}
+
+#[derive(Serialize, Default)]
+pub struct Counts {
+    total: i64,
+    succeeded: i64,
+    failed: i64,
+    /// Attempts in the last 24 hours
+    last_day: i64,
+}
+
+#[derive(Serialize, Default)]
+pub struct StatsReturn {
+    ok: bool,
+    launches: Counts,
+    attaches: Counts,
+    /// Distinct devices that have launched or attached
+    devices: i64,
+    error: Option<String>,
+}
+
+async fn counts(state: &JitStreamerState) -> Result<StatsReturn, sqlx::Error> {
+    let rows = sqlx::query_as::<_, (String, i64, i64, i64)>(
+        "SELECT kind, COUNT(*), SUM(ok), SUM(at > datetime('now', '-1 day')) FROM launch_stats GROUP BY kind",
+    )
+    .fetch_all(&state.db)
+    .await?;
+    let devices =
+        sqlx::query_scalar::<_, i64>("SELECT COUNT(DISTINCT udid_hash) FROM launch_stats")
+            .fetch_one(&state.db)
+            .await?;
+    let rolled_up = sqlx::query_as::<_, (String, i64, i64)>(
+        "SELECT kind, SUM(attempts), SUM(ok * attempts) FROM launch_stats_daily GROUP BY kind",
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    let mut res = StatsReturn {
+        ok: true,
+        devices,
+        ..Default::default()
+    };
+    for (kind, total, succeeded, last_day) in rows {
+        let counts = Counts {
+            total,
+            succeeded,
+            failed: total - succeeded,
+            last_day,
+        };
+        match kind.as_str() {
+            "launch" => res.launches = counts,
+            "attach" => res.attaches = counts,
+            _ => {}
+        }
+    }
+    for (kind, total, succeeded) in rolled_up {
+        let counts = match kind.as_str() {
+            "launch" => &mut res.launches,
+            "attach" => &mut res.attaches,
+            _ => continue,
+        };
+        counts.total += total;
+        counts.succeeded += succeeded;
+        counts.failed += total - succeeded;
+    }
+    Ok(res)
+}
+
+/// Folds attempts older than STATS_RETENTION_DAYS into daily counts of successes and
+/// failures, dropping everything else about them, and deletes mounts that old.
+/// Run by the stats_rollup job.
+pub async fn rollup(state: &JitStreamerState) -> Result<String, String> {
+    let retention = state.config().stats_retention;
+    if retention.is_zero() {
+        return Ok("STATS_RETENTION_DAYS is 0, every attempt is kept".to_string());
+    }
+    let before = format!("-{} seconds", retention.as_secs());
+    let db_error = |e: sqlx::Error| {
+        tracing::error!("Failed to roll up launch stats: {e:?}");
+        "Failed to roll up launch stats".to_string()
+    };
+    let mut conn = state.db_writer.acquire().await.map_err(db_error)?;
+    let mut tx = conn.begin().await.map_err(db_error)?;
+    sqlx::query(
+        "INSERT INTO launch_stats_daily (day, kind, ok, attempts) SELECT date(at), kind, ok, COUNT(*) FROM launch_stats WHERE at < datetime('now', ?) GROUP BY date(at), kind, ok ON CONFLICT (day, kind, ok) DO UPDATE SET attempts = attempts + excluded.attempts",
+    )
+    .bind(&before)
+    .execute(&mut *tx)
+    .await
+    .map_err(db_error)?;
+    let res = sqlx::query("DELETE FROM launch_stats WHERE at < datetime('now', ?)")
+        .bind(&before)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    let mounts = sqlx::query("DELETE FROM mount_stats WHERE at < datetime('now', ?)")
+        .bind(&before)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    tx.commit().await.map_err(db_error)?;
+    Ok(format!(
+        "Rolled up {} attempts and deleted {} mounts",
+        res.rows_affected(),
+        mounts.rows_affected()
+    ))
+}
+
+/// Aggregate counts of every launch and attach, public like the original JitStreamer's
+pub async fn handler(State(state): State<JitStreamerState>) -> Json<StatsReturn> {
+    match counts(&state).await {
+        Ok(res) => Json(res),
+        Err(e) => {
+            tracing::error!("Failed to query database: {e:?}");
+            Json(StatsReturn {
+                error: Some("Failed to query database".to_string()),
+                ..Default::default()
+            })
+        }
+    }
+}
+
+#[derive(Serialize, Default)]
+pub struct CapacityReturn {
+    ok: bool,
+    /// Devices registered on the server
+    registered: i64,
+    /// MAX_DEVICES, `null` when there's no limit
+    max_devices: Option<usize>,
+    /// Whether new devices are turned away
+    full: bool,
+    /// Successful launches in the last hour
+    launches_last_hour: i64,
+    /// How long they took on average, `null` without any
+    avg_launch_ms: Option<u64>,
+    error: Option<String>,
+}
+
+async fn capacity_counts(state: &JitStreamerState) -> Result<CapacityReturn, sqlx::Error> {
+    let registered = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM devices")
+        .fetch_one(&state.db)
+        .await?;
+    let (launches_last_hour, avg_launch_ms) = sqlx::query_as::<_, (i64, Option<f64>)>(
+        "SELECT COUNT(*), AVG(duration_ms) FROM launch_stats WHERE kind = 'launch' AND ok AND at > datetime('now', '-1 hour')",
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    let max_devices = Some(state.config().max_devices).filter(|m| *m > 0);
+    Ok(CapacityReturn {
+        ok: true,
+        registered,
+        max_devices,
+        full: max_devices.is_some_and(|m| registered as usize >= m),
+        launches_last_hour,
+        avg_launch_ms: avg_launch_ms.map(|a| a.round() as u64),
+        error: None,
+    })
+}
+
+/// How full the server is and how quickly it launches, for anyone deciding whether to
+/// register with it
+pub async fn capacity(State(state): State<JitStreamerState>) -> Json<CapacityReturn> {
+    match capacity_counts(&state).await {
+        Ok(res) => Json(res),
+        Err(e) => {
+            tracing::error!("Failed to query database: {e:?}");
+            Json(CapacityReturn {
+                error: Some("Failed to query database".to_string()),
+                ..Default::default()
+            })
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct CodeCount {
+    kind: String,
+    code: String,
+    count: i64,
+}
+
+#[derive(Serialize)]
+pub struct GroupCount {
+    /// The bundle ID, iOS version or client, `null` when it wasn't known
+    key: Option<String>,
+    total: i64,
+    failed: i64,
+    average_duration_ms: f64,
+}
+
+#[derive(Serialize)]
+pub struct MountCount {
+    /// `null` when it wasn't known
+    ios_version: Option<String>,
+    /// The build of the developer disk image that was mounted
+    ddi_build: Option<String>,
+    total: i64,
+    failed: i64,
+    average_duration_ms: f64,
+    /// The most recent failure's error
+    last_error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Breakdown {
+    /// Failures by error code, most common first
+    failures: Vec<CodeCount>,
+    /// The most launched bundle IDs
+    bundle_ids: Vec<GroupCount>,
+    ios_versions: Vec<GroupCount>,
+    /// Launches by X-Client, most common first
+    clients: Vec<GroupCount>,
+    /// Developer disk image mounts by iOS version and image build
+    mounts: Vec<MountCount>,
+}
+
+fn groups(rows: Vec<(Option<String>, i64, i64, f64)>) -> Vec<GroupCount> {
+    rows.into_iter()
+        .map(|(key, total, failed, average_duration_ms)| GroupCount {
+            key,
+            total,
+            failed,
+            average_duration_ms,
+        })
+        .collect()
+}
+
+/// Detailed counts for attempts in the last `days` days
+pub async fn breakdown(state: &JitStreamerState, days: u32) -> Result<Breakdown, sqlx::Error> {
+    let since = format!("-{days} days");
+    let failures = sqlx::query_as::<_, (String, String, i64)>(
+        "SELECT kind, code, COUNT(*) AS count FROM launch_stats WHERE NOT ok AND code IS NOT NULL AND at > datetime('now', ?) GROUP BY kind, code ORDER BY count DESC",
+    )
+    .bind(&since)
+    .fetch_all(&state.db)
+    .await?;
+    let bundle_ids = sqlx::query_as::<_, (Option<String>, i64, i64, f64)>(
+        "SELECT bundle_id, COUNT(*) AS total, SUM(NOT ok), AVG(duration_ms) FROM launch_stats WHERE kind = 'launch' AND at > datetime('now', ?) GROUP BY bundle_id ORDER BY total DESC LIMIT ?",
+    )
+    .bind(&since)
+    .bind(TOP_BUNDLES)
+    .fetch_all(&state.db)
+    .await?;
+    let ios_versions = sqlx::query_as::<_, (Option<String>, i64, i64, f64)>(
+        "SELECT ios_version, COUNT(*), SUM(NOT ok), AVG(duration_ms) FROM launch_stats WHERE at > datetime('now', ?) GROUP BY ios_version ORDER BY ios_version",
+    )
+    .bind(&since)
+    .fetch_all(&state.db)
+    .await?;
+    let clients = sqlx::query_as::<_, (Option<String>, i64, i64, f64)>(
+        "SELECT client, COUNT(*) AS total, SUM(NOT ok), AVG(duration_ms) FROM launch_stats WHERE kind = 'launch' AND at > datetime('now', ?) GROUP BY client ORDER BY total DESC",
+    )
+    .bind(&since)
+    .fetch_all(&state.db)
+    .await?;
+    let mounts = sqlx::query_as::<_, (Option<String>, Option<String>, i64, i64, f64, Option<String>)>(
+        "SELECT ios_version, ddi_build, COUNT(*), SUM(NOT ok), AVG(duration_ms), (SELECT error FROM mount_stats f WHERE NOT f.ok AND f.ios_version IS m.ios_version AND f.ddi_build IS m.ddi_build AND f.at > datetime('now', ?) ORDER BY f.at DESC LIMIT 1) FROM mount_stats m WHERE at > datetime('now', ?) GROUP BY ios_version, ddi_build ORDER BY ios_version, ddi_build",
+    )
+    .bind(&since)
+    .bind(&since)
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Breakdown {
+        failures: failures
+            .into_iter()
+            .map(|(kind, code, count)| CodeCount { kind, code, count })
+            .collect(),
+        bundle_ids: groups(bundle_ids),
+        ios_versions: groups(ios_versions),
+        clients: groups(clients),
+        mounts: mounts
+            .into_iter()
+            .map(
+                |(ios_version, ddi_build, total, failed, average_duration_ms, last_error)| {
+                    MountCount {
+                        ios_version,
+                        ddi_build,
+                        total,
+                        failed,
+                        average_duration_ms,
+                        last_error,
+                    }
+                },
+            )
+            .collect(),
+    })
+}
+
+#[derive(Serialize)]
+pub struct HistoryEntry {
+    at: String,
+    kind: String,
+    bundle_id: Option<String>,
+    ok: bool,
+    /// The error code when it failed
+    code: Option<String>,
+    duration_ms: i64,
+    /// What asked for it, `subscription` for launches by a subscription
+    client: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct HistoryReturn {
+    ok: bool,
+    /// Newest first
+    history: Vec<HistoryEntry>,
+    #[serde(flatten)]
+    error: Option<JitError>,
+}
+
+/// The caller's device's recent launches and attaches, to check what the server actually did
+pub async fn history(
+    ip: SecureClientIp,
+    selector: DeviceSelector,
+    State(state): State<JitStreamerState>,
+) -> Json<HistoryReturn> {
+    let res = async {
+        let udid = common::get_udid(&state, ip.0.to_string(), &selector)
+            .await?;
+        sqlx::query_as::<
+            _,
+            (String, String, Option<String>, bool, Option<String>, i64, Option<String>),
+        >(
+            "SELECT CAST(at AS TEXT), kind, bundle_id, ok, code, duration_ms, client FROM launch_stats WHERE udid_hash = ? ORDER BY at DESC LIMIT ?",
+        )
+        .bind(hash_udid(&udid))
+        .bind(HISTORY_LENGTH)
+        .fetch_all(&state.db)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to query database: {e:?}");
+            JitError::internal("Failed to query database")
+        })
+    }
+    .await;
+
+    match res {
+        Ok(rows) => Json(HistoryReturn {
+            ok: true,
+            history: rows
+                .into_iter()
+                .map(
+                    |(at, kind, bundle_id, ok, code, duration_ms, client)| HistoryEntry {
+                        at,
+                        kind,
+                        bundle_id,
+                        ok,
+                        code,
+                        duration_ms,
+                        client,
+                    },
+                )
+                .collect(),
+            error: None,
+        }),
+        Err(e) => Json(HistoryReturn {
+            ok: false,
+            history: Vec::new(),
+            error: Some(e),
+        }),
+    }
+}
@@ -0,0 +1,147 @@
+// Jackson Coxson
+// A public status page, so users of an instance can tell whether a problem is on their end
+
+use axum::{extract::State, Json};
+use serde::Serialize;
+
+use crate::{
+    db::{DbPool, Writer},
+    heartbeat::HeartbeatStatus,
+    JitStreamerState,
+};
+
+/// How many incidents the status page shows
+const RECENT_INCIDENTS: i64 = 10;
+/// Resolved incidents drop off the status page after this long
+const RESOLVED_SHOWN_FOR: &str = "-7 days";
+
+#[derive(Serialize)]
+pub struct Incident {
+    pub id: i64,
+    pub message: String,
+    pub created_at: String,
+    /// Null while it's ongoing
+    pub resolved_at: Option<String>,
+}
+
+type IncidentRow = (i64, String, String, Option<String>);
+
+fn incident((id, message, created_at, resolved_at): IncidentRow) -> Incident {
+    Incident {
+        id,
+        message,
+        created_at,
+        resolved_at,
+    }
+}
+
+/// Records an ongoing incident, returning its ID
+pub async fn post(db: &Writer, message: &str) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar::<_, i64>(
+        "INSERT INTO incidents (message, created_at) VALUES (?, CURRENT_TIMESTAMP) RETURNING id",
+    )
+    .bind(message)
+    .fetch_one(&mut *db.acquire().await?)
+    .await
+}
+
+/// Marks an incident resolved, returning whether there was an ongoing one
+pub async fn resolve(db: &Writer, id: i64) -> Result<bool, sqlx::Error> {
+    let res = db
+        .execute(
+            sqlx::query(
+                "UPDATE incidents SET resolved_at = CURRENT_TIMESTAMP WHERE id = ? AND resolved_at IS NULL",
+            )
+            .bind(id),
+        )
+        .await?;
+    Ok(res.rows_affected() > 0)
+}
+
+/// Deletes an incident, such as one posted by mistake, returning whether there was one
+pub async fn remove(db: &Writer, id: i64) -> Result<bool, sqlx::Error> {
+    let res = db
+        .execute(sqlx::query("DELETE FROM incidents WHERE id = ?").bind(id))
+        .await?;
+    Ok(res.rows_affected() > 0)
+}
+
+/// Every incident, newest first
+pub async fn list(db: &DbPool) -> Result<Vec<Incident>, sqlx::Error> {
+    let rows = sqlx::query_as::<_, IncidentRow>(
+        "SELECT id, message, CAST(created_at AS TEXT), CAST(resolved_at AS TEXT) FROM incidents ORDER BY id DESC",
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(rows.into_iter().map(incident).collect())
+}
+
+/// Ongoing incidents and recently resolved ones, newest first
+async fn recent(db: &DbPool) -> Result<Vec<Incident>, sqlx::Error> {
+    let rows = sqlx::query_as::<_, IncidentRow>(
+        "SELECT id, message, CAST(created_at AS TEXT), CAST(resolved_at AS TEXT) FROM incidents WHERE resolved_at IS NULL OR resolved_at > datetime('now', ?) ORDER BY id DESC LIMIT ?",
+    )
+    .bind(RESOLVED_SHOWN_FOR)
+    .bind(RECENT_INCIDENTS)
+    .fetch_all(db)
+    .await?;
+    Ok(rows.into_iter().map(incident).collect())
+}
+
+#[derive(Serialize)]
+pub struct ServerStatusReturn {
+    ok: bool,
+    version: &'static str,
+    uptime_secs: u64,
+    /// Devices the server holds a connection to
+    heartbeats: usize,
+    /// Devices with a cached tunnel
+    tunnels: usize,
+    /// Developer disk images being mounted
+    mounting: usize,
+    /// Launches running now, out of LAUNCH_CONCURRENCY
+    launches_running: usize,
+    launch_capacity: usize,
+    /// Ongoing incidents, and ones resolved in the last week
+    incidents: Vec<Incident>,
+    error: Option<String>,
+}
+
+/// What the status page shows, without anything identifying a device
+pub async fn handler(State(state): State<JitStreamerState>) -> Json<ServerStatusReturn> {
+    let (launches_running, launch_capacity) = state.launch_limiter.load();
+    let (incidents, error) = match recent(&state.db).await {
+        Ok(i) => (i, None),
+        Err(e) => {
+            tracing::error!("Failed to query database: {e:?}");
+            (Vec::new(), Some("Failed to query database".to_string()))
+        }
+    };
+    Json(ServerStatusReturn {
+        ok: error.is_none(),
+        version: env!("CARGO_PKG_VERSION"),
+        uptime_secs: state.started.elapsed().as_secs(),
+        heartbeats: state
+            .heartbeats
+            .list()
+            .await
+            .map(|h| {
+                h.iter()
+                    .filter(|h| !matches!(h.status, HeartbeatStatus::Failed(_)))
+                    .count()
+            })
+            .unwrap_or(0),
+        tunnels: state.rsd_cache.entries().await.len(),
+        mounting: state
+            .mount_cache
+            .lock()
+            .await
+            .values()
+            .filter(|m| !m.ended())
+            .count(),
+        launches_running,
+        launch_capacity,
+        incidents,
+        error,
+    })
+}
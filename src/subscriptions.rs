@@ -0,0 +1,386 @@
+// Jackson Coxson
+// Apps the server launches with JIT on its own, on a schedule or when the device comes back
+// after a reboot, run by the subscriptions job
+
+use std::{collections::HashMap, net::IpAddr, str::FromStr};
+
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use axum_client_ip::SecureClientIp;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use crate::{
+    apps,
+    common::DeviceSelector,
+    error::{ErrorCode, JitError},
+    jit,
+    launcher::LaunchOptions,
+    liveness, JitStreamerState,
+};
+
+/// The client launches by a subscription are recorded as in the stats and history
+const CLIENT: &str = "subscription";
+const MAX_SUBSCRIPTIONS: i64 = 10;
+/// Each launch restarts the app, interrupting whatever it was doing, so schedules can't be
+/// tighter than this
+const MIN_EVERY_MINUTES: u32 = 15;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SubscriptionInfo {
+    id: i64,
+    bundle_id: String,
+    /// Minutes between launches, null when it's only launched on reconnect
+    every_minutes: Option<i64>,
+    on_reconnect: bool,
+    /// The device came back and the launch hasn't worked yet, such as while it's locked
+    pending: bool,
+    /// When the server last launched it, in UTC
+    last_run: Option<String>,
+    /// Why that launch failed
+    last_error: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct SubscriptionRequest {
+    bundle_id: String,
+    every_minutes: Option<u32>,
+    #[serde(default)]
+    on_reconnect: bool,
+}
+
+#[derive(Serialize, Default)]
+pub struct SubscriptionsReturn {
+    ok: bool,
+    subscriptions: Vec<SubscriptionInfo>,
+    #[serde(flatten)]
+    error: Option<JitError>,
+}
+
+impl SubscriptionsReturn {
+    fn fail(error: JitError) -> Self {
+        Self {
+            error: Some(error),
+            ..Default::default()
+        }
+    }
+}
+
+fn db_error(e: sqlx::Error) -> JitError {
+    tracing::error!("Failed to read or write subscriptions: {e:?}");
+    JitError::internal("Failed to read or write subscriptions")
+}
+
+async fn listed(state: &JitStreamerState, udid: &str) -> Result<SubscriptionsReturn, JitError> {
+    let rows = sqlx::query_as::<
+        _,
+        (i64, String, Option<i64>, bool, bool, Option<String>, Option<String>),
+    >(
+        "SELECT id, bundle_id, every_minutes, on_reconnect, pending, CAST(last_run AS TEXT), last_error FROM subscriptions WHERE udid = ? ORDER BY id",
+    )
+    .bind(udid)
+    .fetch_all(&state.db)
+    .await
+    .map_err(db_error)?;
+    Ok(SubscriptionsReturn {
+        ok: true,
+        subscriptions: rows
+            .into_iter()
+            .map(
+                |(id, bundle_id, every_minutes, on_reconnect, pending, last_run, last_error)| {
+                    SubscriptionInfo {
+                        id,
+                        bundle_id,
+                        every_minutes,
+                        on_reconnect,
+                        pending,
+                        last_run,
+                        last_error,
+                    }
+                },
+            )
+            .collect(),
+        error: None,
+    })
+}
+
+async fn udid(
+    state: &JitStreamerState,
+    ip: SecureClientIp,
+    selector: &DeviceSelector,
+) -> Result<String, JitError> {
+    jit::JitSession::new(state, ip.0, selector)
+        .device()
+        .await
+        .map(|(udid, _)| udid)
+}
+
+/// Lists the caller's subscriptions and how their last launches went
+pub async fn list(
+    ip: SecureClientIp,
+    selector: DeviceSelector,
+    State(state): State<JitStreamerState>,
+) -> Json<SubscriptionsReturn> {
+    let res = match udid(&state, ip, &selector).await {
+        Ok(udid) => listed(&state, &udid).await,
+        Err(e) => Err(e),
+    };
+    Json(res.unwrap_or_else(SubscriptionsReturn::fail))
+}
+
+/// Subscribes the caller's device to launches of the app, returning its subscriptions
+pub async fn create(
+    ip: SecureClientIp,
+    selector: DeviceSelector,
+    State(state): State<JitStreamerState>,
+    Json(request): Json<SubscriptionRequest>,
+) -> Json<SubscriptionsReturn> {
+    info!(
+        "Got request to subscribe to {} from {:?}",
+        request.bundle_id, ip.0
+    );
+    let res = match udid(&state, ip, &selector).await {
+        Ok(udid) => match insert(&state, &udid, request).await {
+            Ok(()) => listed(&state, &udid).await,
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    };
+    Json(res.unwrap_or_else(SubscriptionsReturn::fail))
+}
+
+async fn insert(
+    state: &JitStreamerState,
+    udid: &str,
+    request: SubscriptionRequest,
+) -> Result<(), JitError> {
+    if !apps::valid_bundle_id(&request.bundle_id) {
+        return Err(JitError::new(
+            ErrorCode::BadRequest,
+            format!("{} isn't a bundle ID", request.bundle_id),
+        ));
+    }
+    match request.every_minutes {
+        None if !request.on_reconnect => {
+            return Err(JitError::new(
+                ErrorCode::BadRequest,
+                "Set every_minutes, on_reconnect or both",
+            ))
+        }
+        Some(m) if m < MIN_EVERY_MINUTES => {
+            return Err(JitError::new(
+                ErrorCode::BadRequest,
+                format!("every_minutes can't be under {MIN_EVERY_MINUTES}"),
+            ))
+        }
+        _ => {}
+    }
+
+    // The writer is only ours until it's dropped, so the count can't change under us
+    let mut conn = state.db_writer.acquire().await.map_err(db_error)?;
+    let count = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM subscriptions WHERE udid = ?")
+        .bind(udid)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(db_error)?;
+    if count >= MAX_SUBSCRIPTIONS {
+        return Err(JitError::new(
+            ErrorCode::BadRequest,
+            format!("A device can have up to {MAX_SUBSCRIPTIONS} subscriptions, remove one first"),
+        ));
+    }
+    // Counted as online, so the first launch on reconnect is after the device next comes back
+    sqlx::query(
+        "INSERT INTO subscriptions (udid, bundle_id, every_minutes, on_reconnect, device_online, created_at) VALUES (?, ?, ?, ?, 1, CURRENT_TIMESTAMP)",
+    )
+    .bind(udid)
+    .bind(&request.bundle_id)
+    .bind(request.every_minutes)
+    .bind(request.on_reconnect)
+    .execute(&mut *conn)
+    .await
+    .map_err(db_error)?;
+    Ok(())
+}
+
+/// Removes one of the caller's subscriptions, returning what's left
+pub async fn remove(
+    ip: SecureClientIp,
+    selector: DeviceSelector,
+    Path(id): Path<i64>,
+    State(state): State<JitStreamerState>,
+) -> Json<SubscriptionsReturn> {
+    info!("Got request to remove subscription {id} from {:?}", ip.0);
+    let res = match udid(&state, ip, &selector).await {
+        Ok(udid) => {
+            let deleted = state
+                .db_writer
+                .execute(
+                    sqlx::query("DELETE FROM subscriptions WHERE id = ? AND udid = ?")
+                        .bind(id)
+                        .bind(&udid),
+                )
+                .await
+                .map_err(db_error);
+            match deleted {
+                Ok(r) if r.rows_affected() == 0 => Err(JitError::new(
+                    ErrorCode::BadRequest,
+                    format!("The device has no subscription {id}"),
+                )),
+                Ok(_) => listed(&state, &udid).await,
+                Err(e) => Err(e),
+            }
+        }
+        Err(e) => Err(e),
+    };
+    Json(res.unwrap_or_else(SubscriptionsReturn::fail))
+}
+
+struct Due {
+    id: i64,
+    bundle_id: String,
+    on_reconnect: bool,
+    device_online: bool,
+    pending: bool,
+    /// Its schedule's time has come
+    scheduled: bool,
+}
+
+#[derive(Default)]
+struct Outcome {
+    launched: usize,
+    failed: usize,
+    /// Due, but the device is offline, locked or busy
+    waiting: usize,
+}
+
+/// Errors the device gets over by itself, such as being locked until it's first unlocked
+/// after a reboot, so the launch is tried again on the next run
+fn retryable(error: &JitError) -> bool {
+    matches!(
+        error.code,
+        ErrorCode::DeviceLocked
+            | ErrorCode::DeviceUnreachable
+            | ErrorCode::DeviceTimeout
+            | ErrorCode::Busy
+            | ErrorCode::RateLimited
+    )
+}
+
+/// Checks whether the device is back and launches its subscriptions that are due
+async fn device(state: JitStreamerState, udid: String, ip: IpAddr, subs: Vec<Due>) -> Outcome {
+    let alive = liveness::probe(&state.heartbeats, &udid, ip).await.alive;
+    // Devices in use are left to their requests, launches wait for the next run
+    let free = alive && state.device_queues.queued(&udid).await == 0;
+    let mut turn = None;
+    let mut outcome = Outcome::default();
+
+    for sub in subs {
+        let pending = sub.on_reconnect && (sub.pending || (alive && !sub.device_online));
+        let mut res = None;
+        if pending || sub.scheduled {
+            if free && turn.is_none() {
+                turn = state.device_queues.wait(&udid).await.ok();
+            }
+            if turn.is_some() {
+                debug!("Launching subscribed {} on {udid}", sub.bundle_id);
+                let selector = DeviceSelector {
+                    device: Some(udid.clone()),
+                    ..Default::default()
+                };
+                res = Some(
+                    jit::JitSession::new(&state, ip, &selector)
+                        .client(Some(CLIENT))
+                        .launch(sub.bundle_id.clone(), LaunchOptions::default())
+                        .await,
+                );
+            }
+        }
+
+        let done = match &res {
+            Some(Ok(_)) => {
+                outcome.launched += 1;
+                true
+            }
+            Some(Err(e)) if !retryable(e) => {
+                outcome.failed += 1;
+                true
+            }
+            _ => {
+                if pending || sub.scheduled {
+                    outcome.waiting += 1;
+                }
+                false
+            }
+        };
+        let query = match done {
+            true => sqlx::query(
+                "UPDATE subscriptions SET device_online = ?, pending = 0, last_run = CURRENT_TIMESTAMP, last_error = ? WHERE id = ?",
+            )
+            .bind(alive)
+            .bind(res.and_then(|r| r.err()).map(|e| e.to_string())),
+            false => sqlx::query(
+                "UPDATE subscriptions SET device_online = ?, pending = ? WHERE id = ?",
+            )
+            .bind(alive)
+            .bind(pending),
+        };
+        if let Err(e) = state.db_writer.execute(query.bind(sub.id)).await {
+            tracing::error!("Failed to update subscription {}: {e:?}", sub.id);
+        }
+    }
+    outcome
+}
+
+/// Launches the subscriptions that are due, returning what it did. Run by the
+/// subscriptions job.
+pub async fn launch_due(state: &JitStreamerState) -> Result<String, String> {
+    let rows = sqlx::query_as::<_, (i64, String, String, bool, bool, bool, bool, String)>(
+        "SELECT s.id, s.udid, s.bundle_id, s.on_reconnect, s.device_online, s.pending, s.every_minutes IS NOT NULL AND (s.last_run IS NULL OR s.last_run <= datetime('now', '-' || s.every_minutes || ' minutes')), d.ip FROM subscriptions s JOIN devices d ON d.udid = s.udid ORDER BY s.id",
+    )
+    .fetch_all(&state.db)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to read subscriptions: {e:?}");
+        "Failed to read subscriptions".to_string()
+    })?;
+
+    let mut devices: HashMap<String, (IpAddr, Vec<Due>)> = HashMap::new();
+    for (id, udid, bundle_id, on_reconnect, device_online, pending, scheduled, ip) in rows {
+        let Ok(ip) = IpAddr::from_str(&ip) else {
+            continue;
+        };
+        devices
+            .entry(udid)
+            .or_insert_with(|| (ip, Vec::new()))
+            .1
+            .push(Due {
+                id,
+                bundle_id,
+                on_reconnect,
+                device_online,
+                pending,
+                scheduled,
+            });
+    }
+
+    let count = devices.len();
+    let tasks = devices
+        .into_iter()
+        .map(|(udid, (ip, subs))| tokio::spawn(device(state.clone(), udid, ip, subs)))
+        .collect::<Vec<_>>();
+    let mut outcome = Outcome::default();
+    for task in tasks {
+        if let Ok(o) = task.await {
+            outcome.launched += o.launched;
+            outcome.failed += o.failed;
+            outcome.waiting += o.waiting;
+        }
+    }
+    Ok(format!(
+        "Checked {count} devices, launched {} subscribed apps, {} failed and {} are waiting for their device",
+        outcome.launched, outcome.failed, outcome.waiting
+    ))
+}
@@ -0,0 +1,181 @@
+// Jackson Coxson
+// Streams the device's syslog to a web console, optionally for a single process
+
+use axum::extract::{
+    ws::{Message, WebSocket},
+    Query, State, WebSocketUpgrade,
+};
+use axum_client_ip::SecureClientIp;
+use idevice::{
+    installation_proxy::InstallationProxyClient, syslog_relay::SyslogRelayClient, IdeviceService,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::{
+    common::DeviceSelector,
+    error::{ErrorCode, JitError},
+    i18n::Language,
+    provider::DeviceProvider,
+    JitStreamerState,
+};
+
+#[derive(Deserialize, Debug)]
+pub struct SyslogFilter {
+    /// Only lines from this process, matched ignoring case
+    process: Option<String>,
+    /// Only lines from this app's executable
+    bundle_id: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum SyslogEvent {
+    Line {
+        line: String,
+    },
+    Error {
+        #[serde(flatten)]
+        error: JitError,
+    },
+}
+
+impl SyslogEvent {
+    fn to_ws_message(&self) -> Message {
+        Message::text(serde_json::to_string(&self).unwrap())
+    }
+}
+
+pub async fn handler(
+    ws: WebSocketUpgrade,
+    ip: SecureClientIp,
+    selector: DeviceSelector,
+    Query(filter): Query<SyslogFilter>,
+    language: Language,
+    State(state): State<JitStreamerState>,
+) -> axum::response::Response {
+    ws.on_upgrade(move |mut socket| async move {
+        info!("Got request to stream syslog from {:?}", ip.0);
+        let (udid, provider) = match crate::jit::JitSession::new(&state, ip.0, &selector)
+            .connect()
+            .await
+        {
+            Ok(d) => d,
+            Err(e) => {
+                let event = SyslogEvent::Error {
+                    error: language.localize(e),
+                };
+                socket.send(event.to_ws_message()).await.ok();
+                return;
+            }
+        };
+
+        if let Err(e) = stream(&provider, filter, &mut socket).await {
+            let event = SyslogEvent::Error {
+                error: language.localize(e),
+            };
+            socket.send(event.to_ws_message()).await.ok();
+        }
+        socket.close().await.ok();
+
+        state.heartbeats.release(&udid).await.ok();
+    })
+}
+
+/// Forwards matching lines until the client leaves
+async fn stream(
+    provider: &DeviceProvider,
+    filter: SyslogFilter,
+    socket: &mut WebSocket,
+) -> Result<(), JitError> {
+    let process = match (filter.process, filter.bundle_id) {
+        (Some(p), _) => Some(p),
+        (None, Some(b)) => Some(executable(provider, &b).await?),
+        (None, None) => None,
+    };
+
+    let mut client = SyslogRelayClient::connect(provider).await.map_err(|e| {
+        warn!("Failed to start syslog relay: {e:?}");
+        JitError::new(
+            ErrorCode::ServiceFailed,
+            format!("Failed to start syslog relay: {e:?}"),
+        )
+    })?;
+
+    loop {
+        // Keep the same read going across client messages, dropping it could lose a line
+        let line = {
+            let next = client.next();
+            tokio::pin!(next);
+            loop {
+                tokio::select! {
+                    line = &mut next => break line,
+                    msg = socket.recv() => {
+                        if let Some(Ok(Message::Close(_))) | Some(Err(_)) | None = msg {
+                            debug!("Syslog client left");
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+        };
+        let line = line.map_err(|e| {
+            JitError::new(
+                ErrorCode::DeviceUnreachable,
+                format!("Syslog relay failed: {e:?}"),
+            )
+        })?;
+
+        if let Some(p) = &process {
+            if !process_name(&line).is_some_and(|n| n.eq_ignore_ascii_case(p)) {
+                continue;
+            }
+        }
+        if socket
+            .send(SyslogEvent::Line { line }.to_ws_message())
+            .await
+            .is_err()
+        {
+            return Ok(());
+        }
+    }
+}
+
+/// Looks up the name an app's process runs under
+async fn executable(provider: &DeviceProvider, bundle_id: &str) -> Result<String, JitError> {
+    let mut client = InstallationProxyClient::connect(provider)
+        .await
+        .map_err(|e| {
+            JitError::new(
+                ErrorCode::ServiceFailed,
+                format!("Failed to start instproxy: {e:?}"),
+            )
+        })?;
+    let apps = client
+        .get_apps(None, Some(vec![bundle_id.to_string()]))
+        .await
+        .map_err(|e| {
+            JitError::new(
+                ErrorCode::ServiceFailed,
+                format!("Failed to get apps: {e:?}"),
+            )
+        })?;
+    apps.get(bundle_id)
+        .and_then(|a| a.as_dictionary())
+        .and_then(|a| a.get("CFBundleExecutable"))
+        .and_then(|e| e.as_string())
+        .map(|e| e.to_string())
+        .ok_or_else(|| {
+            JitError::new(
+                ErrorCode::ProcessNotFound,
+                format!("{bundle_id} is not installed"),
+            )
+        })
+}
+
+/// The process of a line like `Oct 16 10:00:00 iPhone SpringBoard(FrontBoard)[55] <Notice>: ...`
+fn process_name(line: &str) -> Option<&str> {
+    line.split(' ')
+        .find(|t| t.ends_with(']') && t.contains('['))
+        .and_then(|t| t.split(['[', '(']).next())
+}
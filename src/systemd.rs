@@ -0,0 +1,61 @@
+// Jackson Coxson
+// Readiness, watchdog and socket activation when running under systemd.
+// Everything here is a no-op when not started by systemd.
+
+use std::time::Duration;
+
+use tracing::{debug, info, warn};
+
+/// Takes the listening socket passed by systemd socket activation, if there is one
+pub fn listener() -> Option<std::net::TcpListener> {
+    #[cfg(unix)]
+    {
+        use std::os::fd::FromRawFd;
+
+        let fd = sd_notify::listen_fds().ok()?.next()?;
+        info!("Using socket from systemd socket activation");
+        // Safety: systemd hands the process ownership of the passed file descriptors
+        let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        if let Err(e) = listener.set_nonblocking(true) {
+            warn!("Failed to make systemd socket non-blocking: {e:?}");
+            return None;
+        }
+        Some(listener)
+    }
+    #[cfg(not(unix))]
+    None
+}
+
+/// Tells systemd the server is accepting requests, and starts pinging the watchdog if enabled
+pub fn ready() {
+    #[cfg(unix)]
+    {
+        if let Err(e) = sd_notify::notify(false, &[sd_notify::NotifyState::Ready]) {
+            debug!("Failed to notify systemd: {e:?}");
+        }
+
+        let mut usec = 0;
+        if sd_notify::watchdog_enabled(false, &mut usec) {
+            // Ping at twice the required rate so a slow tick doesn't trip it
+            let period = Duration::from_micros(usec / 2);
+            info!("Pinging the systemd watchdog every {period:?}");
+            tokio::task::spawn(async move {
+                let mut interval = tokio::time::interval(period);
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = sd_notify::notify(false, &[sd_notify::NotifyState::Watchdog]) {
+                        warn!("Failed to ping the systemd watchdog: {e:?}");
+                    }
+                }
+            });
+        }
+    }
+}
+
+/// Tells systemd the server is shutting down
+pub fn stopping() {
+    #[cfg(unix)]
+    if let Err(e) = sd_notify::notify(false, &[sd_notify::NotifyState::Stopping]) {
+        debug!("Failed to notify systemd: {e:?}");
+    }
+}
@@ -0,0 +1,56 @@
+// Jackson Coxson
+// Log output, and optional span export to an OpenTelemetry collector
+
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::WithExportConfig;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+use crate::privacy::LogWriter;
+
+/// Installs the logger. With an OTLP endpoint, request spans are also exported
+/// so the phases of a launch can be viewed in Jaeger, Tempo, etc. With a privacy salt,
+/// UDIDs and IP addresses are hashed in the log lines.
+pub fn init(otlp_endpoint: Option<&str>, node_id: &str, privacy_salt: Option<&str>) {
+    let otel = otlp_endpoint.and_then(|endpoint| {
+        let exporter = match opentelemetry_otlp::SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build()
+        {
+            Ok(e) => e,
+            Err(e) => {
+                eprintln!("Failed to create OTLP exporter for {endpoint}: {e:?}");
+                return None;
+            }
+        };
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+            .with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio)
+            .with_resource(opentelemetry_sdk::Resource::new(vec![
+                opentelemetry::KeyValue::new("service.name", "jitstreamer-eb"),
+                opentelemetry::KeyValue::new("service.instance.id", node_id.to_string()),
+            ]))
+            .build();
+        let tracer = provider.tracer("jitstreamer-eb");
+        opentelemetry::global::set_tracer_provider(provider);
+        Some(tracing_opentelemetry::layer().with_tracer(tracer))
+    });
+
+    let (plain, private) = match privacy_salt {
+        Some(salt) => (
+            None,
+            Some(tracing_subscriber::fmt::layer().with_writer(LogWriter::new(salt))),
+        ),
+        None => (Some(tracing_subscriber::fmt::layer()), None),
+    };
+    tracing_subscriber::registry()
+        .with(EnvFilter::from_default_env())
+        .with(plain)
+        .with(private)
+        .with(otel)
+        .init();
+}
+
+/// Flushes spans that haven't been exported yet
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
@@ -0,0 +1,71 @@
+// Jackson Coxson
+// Serves HTTPS directly, for deployments without a reverse proxy
+
+use std::{future::Future, net::SocketAddr, time::Duration};
+
+use axum::{extract::connect_info::IntoMakeServiceWithConnectInfo, Router};
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use futures_util::StreamExt;
+use rustls_acme::{caches::DirCache, AcmeConfig};
+use tracing::{info, warn};
+
+use crate::config::TlsConfig;
+
+/// Serves the app over TLS until `shutdown` resolves, then gives requests `drain` to finish
+pub async fn serve(
+    listener: std::net::TcpListener,
+    app: IntoMakeServiceWithConnectInfo<Router, SocketAddr>,
+    tls: TlsConfig,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+    drain: Duration,
+) -> std::io::Result<()> {
+    let handle = Handle::new();
+    tokio::task::spawn({
+        let handle = handle.clone();
+        async move {
+            shutdown.await;
+            handle.graceful_shutdown(Some(drain));
+        }
+    });
+
+    match tls {
+        TlsConfig::Files { cert, key } => {
+            let config = RustlsConfig::from_pem_file(&cert, &key).await?;
+            info!("Serving HTTPS with {}", cert.display());
+            axum_server::from_tcp_rustls(listener, config)
+                .handle(handle)
+                .serve(app)
+                .await
+        }
+        TlsConfig::Acme {
+            domains,
+            email,
+            cache,
+            staging,
+        } => {
+            info!("Serving HTTPS for {} with ACME", domains.join(", "));
+            let mut state = AcmeConfig::new(domains)
+                .contact(email.iter().map(|e| format!("mailto:{e}")))
+                .cache(DirCache::new(cache))
+                .directory_lets_encrypt(!staging)
+                .state();
+            let acceptor = state.axum_acceptor(state.default_rustls_config());
+
+            // Drives issuing and renewing the certificate
+            tokio::task::spawn(async move {
+                while let Some(event) = state.next().await {
+                    match event {
+                        Ok(e) => info!("ACME: {e:?}"),
+                        Err(e) => warn!("ACME failed: {e:?}"),
+                    }
+                }
+            });
+
+            axum_server::from_tcp(listener)
+                .acceptor(acceptor)
+                .handle(handle)
+                .serve(app)
+                .await
+        }
+    }
+}
@@ -0,0 +1,533 @@
+// Jackson Coxson
+// The ways a tunnel to the device's developer services can be made, picked per iOS version
+
+use std::{
+    fmt::Display,
+    future::Future,
+    io,
+    net::IpAddr,
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use idevice::{core_device_proxy::CoreDeviceProxy, tcp::adapter::Adapter, IdeviceService};
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+    task::JoinHandle,
+};
+use tracing::{debug, info};
+
+use crate::{
+    config::Config, dependencies::Dependency, device, error::JitError, provider::DeviceProvider,
+    JitStreamerState,
+};
+
+/// The port pymobiledevice3's tunneld listens on
+pub const DEFAULT_TUNNELD_URL: &str = "http://127.0.0.1:49151";
+/// tunneld runs on this host, so it answers at once or not at all
+const TUNNELD_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TunnelKind {
+    /// CoreDeviceProxy with idevice's TCP stack, needs nothing from the host
+    Software,
+    /// A tunnel already made by tunneld, looked up by UDID
+    Tunneld,
+    /// CoreDeviceProxy into a TUN interface, so the kernel's TCP stack is used. Linux only,
+    /// and needs CAP_NET_ADMIN.
+    Kernel,
+}
+
+impl Display for TunnelKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            TunnelKind::Software => "software",
+            TunnelKind::Tunneld => "tunneld",
+            TunnelKind::Kernel => "kernel",
+        })
+    }
+}
+
+impl FromStr for TunnelKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "software" => Ok(TunnelKind::Software),
+            "tunneld" => Ok(TunnelKind::Tunneld),
+            "kernel" => Ok(TunnelKind::Kernel),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A TUNNEL_PROVIDER_BY_VERSION entry, such as `18=kernel` for every iOS 18 release
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VersionTunnel {
+    pub major: u32,
+    pub kind: TunnelKind,
+}
+
+impl FromStr for VersionTunnel {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (major, kind) = s.split_once('=').ok_or(())?;
+        Ok(Self {
+            major: major.trim().parse().map_err(|_| ())?,
+            kind: kind.parse()?,
+        })
+    }
+}
+
+/// Makes tunnels to devices. Implement this to add a way of reaching the developer services.
+pub trait TunnelProvider: Send + Sync {
+    fn kind(&self) -> TunnelKind;
+
+    /// Opens a tunnel to the device, returning it unconnected and the port of its RSD
+    fn open<'a>(
+        &'a self,
+        provider: &'a DeviceProvider,
+        udid: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<(Tunnel, u16), String>> + Send + 'a>>;
+}
+
+/// Whether TUNNEL_PROVIDER or TUNNEL_PROVIDER_BY_VERSION sends any device through tunneld
+pub fn uses_tunneld(config: &Config) -> bool {
+    config.tunnel_provider == TunnelKind::Tunneld
+        || config
+            .tunnel_provider_by_version
+            .iter()
+            .any(|o| o.kind == TunnelKind::Tunneld)
+}
+
+/// Whether tunneld answers at its URL, whichever tunnels it has
+pub async fn tunneld_reachable(url: &str) -> Result<(), String> {
+    reqwest::Client::new()
+        .get(url)
+        .timeout(TUNNELD_CHECK_TIMEOUT)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map(|_| ())
+        .map_err(|e| format!("Failed to reach tunneld at {url}: {e}"))
+}
+
+/// Picks the provider for the device from TUNNEL_PROVIDER_BY_VERSION, falling back to
+/// TUNNEL_PROVIDER. The device's version is only looked up when there are overrides.
+/// Refused while tunneld is missing, if that's the one picked.
+pub async fn for_device(
+    state: &JitStreamerState,
+    udid: &str,
+    provider: &DeviceProvider,
+) -> Result<Box<dyn TunnelProvider>, JitError> {
+    let config = state.config();
+    let mut kind = config.tunnel_provider;
+    if !config.tunnel_provider_by_version.is_empty() {
+        let major = device::get_device_info(&state.device_info_cache, udid, provider)
+            .await
+            .ok()
+            .and_then(|i| i.product_version)
+            .and_then(|v| v.split('.').next()?.parse::<u32>().ok());
+        if let Some(o) = config
+            .tunnel_provider_by_version
+            .iter()
+            .find(|o| Some(o.major) == major)
+        {
+            kind = o.kind;
+        }
+    }
+    if kind == TunnelKind::Tunneld {
+        state.dependencies.require(Dependency::Tunneld).await?;
+    }
+    debug!("Tunneling to {udid} with the {kind} provider");
+    Ok(new_provider(kind, &config))
+}
+
+fn new_provider(kind: TunnelKind, config: &Config) -> Box<dyn TunnelProvider> {
+    match kind {
+        TunnelKind::Software => Box::new(SoftwareTunnel),
+        TunnelKind::Tunneld => Box::new(Tunneld {
+            url: config.tunneld_url.clone(),
+        }),
+        TunnelKind::Kernel => Box::new(KernelTun),
+    }
+}
+
+/// A tunnel to the device, connected to one of its ports at a time like idevice's adapter
+#[derive(Debug)]
+pub enum Tunnel {
+    Software(Adapter),
+    /// Reached through the host's network stack, at the device's tunnel address
+    Kernel(KernelConnection),
+}
+
+#[derive(Debug)]
+pub struct KernelConnection {
+    addr: IpAddr,
+    stream: Option<TcpStream>,
+    /// Moves the packets for tunnels this server made, None for tunneld's
+    _pump: Option<PumpTask>,
+}
+
+/// Stops moving packets once the tunnel is dropped, which removes its TUN interface
+#[derive(Debug)]
+struct PumpTask(JoinHandle<()>);
+
+impl Drop for PumpTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+impl Tunnel {
+    fn kernel(addr: IpAddr, pump: Option<PumpTask>) -> Self {
+        Tunnel::Kernel(KernelConnection {
+            addr,
+            stream: None,
+            _pump: pump,
+        })
+    }
+
+    /// Connects to the port, closing the previous connection
+    pub async fn connect(&mut self, port: u16) -> io::Result<()> {
+        match self {
+            Tunnel::Software(a) => a.connect(port).await,
+            Tunnel::Kernel(k) => {
+                k.stream = Some(TcpStream::connect((k.addr, port)).await?);
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn close(&mut self) -> io::Result<()> {
+        match self {
+            Tunnel::Software(a) => a.close().await,
+            Tunnel::Kernel(k) => match k.stream.take() {
+                Some(mut s) => s.shutdown().await,
+                None => Ok(()),
+            },
+        }
+    }
+}
+
+fn not_connected() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "the tunnel isn't connected")
+}
+
+impl AsyncRead for Tunnel {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Tunnel::Software(a) => Pin::new(a).poll_read(cx, buf),
+            Tunnel::Kernel(k) => match &mut k.stream {
+                Some(s) => Pin::new(s).poll_read(cx, buf),
+                None => Poll::Ready(Err(not_connected())),
+            },
+        }
+    }
+}
+
+impl AsyncWrite for Tunnel {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Tunnel::Software(a) => Pin::new(a).poll_write(cx, buf),
+            Tunnel::Kernel(k) => match &mut k.stream {
+                Some(s) => Pin::new(s).poll_write(cx, buf),
+                None => Poll::Ready(Err(not_connected())),
+            },
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Tunnel::Software(a) => Pin::new(a).poll_flush(cx),
+            Tunnel::Kernel(k) => match &mut k.stream {
+                Some(s) => Pin::new(s).poll_flush(cx),
+                None => Poll::Ready(Ok(())),
+            },
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Tunnel::Software(a) => Pin::new(a).poll_shutdown(cx),
+            Tunnel::Kernel(k) => match &mut k.stream {
+                Some(s) => Pin::new(s).poll_shutdown(cx),
+                None => Poll::Ready(Ok(())),
+            },
+        }
+    }
+}
+
+async fn core_device_proxy(provider: &DeviceProvider) -> Result<CoreDeviceProxy, String> {
+    CoreDeviceProxy::connect(provider).await.map_err(|e| {
+        info!("Failed to proxy device: {:?}", e);
+        format!("Failed to start core device proxy: {e}")
+    })
+}
+
+pub struct SoftwareTunnel;
+
+impl TunnelProvider for SoftwareTunnel {
+    fn kind(&self) -> TunnelKind {
+        TunnelKind::Software
+    }
+
+    fn open<'a>(
+        &'a self,
+        provider: &'a DeviceProvider,
+        _udid: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<(Tunnel, u16), String>> + Send + 'a>> {
+        Box::pin(async move {
+            let proxy = core_device_proxy(provider).await?;
+            let rsd_port = proxy.handshake.server_rsd_port;
+            let adapter = proxy.create_software_tunnel().map_err(|e| {
+                info!("Failed to create software tunnel: {:?}", e);
+                format!("Failed to create software tunnel: {e}")
+            })?;
+            Ok((Tunnel::Software(adapter), rsd_port))
+        })
+    }
+}
+
+/// A tunnel listed by tunneld, from pymobiledevice3 or go-ios
+#[derive(Deserialize)]
+struct TunneldEntry {
+    #[serde(rename = "tunnel-address")]
+    address: IpAddr,
+    #[serde(rename = "tunnel-port")]
+    port: u16,
+}
+
+/// Uses the tunnels tunneld keeps to every device it can see, so it has to run on this
+/// host and reach the devices itself
+pub struct Tunneld {
+    pub url: String,
+}
+
+impl TunnelProvider for Tunneld {
+    fn kind(&self) -> TunnelKind {
+        TunnelKind::Tunneld
+    }
+
+    fn open<'a>(
+        &'a self,
+        _provider: &'a DeviceProvider,
+        udid: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<(Tunnel, u16), String>> + Send + 'a>> {
+        Box::pin(async move {
+            let tunnels = reqwest::get(&self.url)
+                .await
+                .and_then(|r| r.error_for_status())
+                .map_err(|e| {
+                    info!("Failed to reach tunneld: {e:?}");
+                    format!("Failed to reach tunneld at {}", self.url)
+                })?
+                .json::<std::collections::HashMap<String, Vec<TunneldEntry>>>()
+                .await
+                .map_err(|e| format!("Failed to read tunneld's tunnels: {e}"))?;
+            let entry = tunnels
+                .get(udid)
+                .and_then(|t| t.first())
+                .ok_or_else(|| format!("tunneld has no tunnel to {udid}"))?;
+            Ok((Tunnel::kernel(entry.address, None), entry.port))
+        })
+    }
+}
+
+pub struct KernelTun;
+
+impl TunnelProvider for KernelTun {
+    fn kind(&self) -> TunnelKind {
+        TunnelKind::Kernel
+    }
+
+    fn open<'a>(
+        &'a self,
+        provider: &'a DeviceProvider,
+        _udid: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<(Tunnel, u16), String>> + Send + 'a>> {
+        Box::pin(async move {
+            let proxy = core_device_proxy(provider).await?;
+            kernel::open(proxy)
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod kernel {
+    use std::{
+        fs::File,
+        io::{Read, Write},
+        net::Ipv6Addr,
+        os::fd::{AsRawFd, FromRawFd},
+        sync::atomic::{AtomicU32, Ordering},
+    };
+
+    use boringtun::device::tun::TunSocket;
+    use idevice::core_device_proxy::CoreDeviceProxy;
+    use tokio::io::unix::AsyncFd;
+    use tracing::{debug, warn};
+
+    use super::{PumpTask, Tunnel};
+
+    /// Interfaces are named jse0, jse1 and so on
+    static NEXT_INTERFACE: AtomicU32 = AtomicU32::new(0);
+    /// The fixed part of an IPv6 header, which the payload length doesn't count
+    const IPV6_HEADER_LEN: usize = 40;
+
+    /// Creates a TUN interface with the address the device gave us and moves packets
+    /// between it and the proxy until the tunnel is dropped
+    pub fn open(proxy: CoreDeviceProxy) -> Result<(Tunnel, u16), String> {
+        let handshake = &proxy.handshake;
+        let parse = |a: &str| {
+            a.parse::<Ipv6Addr>()
+                .map_err(|_| format!("The device sent an invalid tunnel address {a}"))
+        };
+        let client = parse(&handshake.client_parameters.address)?;
+        let prefix = u128::from(parse(&handshake.client_parameters.netmask)?).count_ones();
+        let server = parse(&handshake.server_address)?;
+        let mtu = handshake.client_parameters.mtu.to_string();
+        let rsd_port = handshake.server_rsd_port;
+
+        let name = format!(
+            "jse{}",
+            NEXT_INTERFACE.fetch_add(1, Ordering::Relaxed) % 10000
+        );
+        let tun = TunSocket::new(&name)
+            .and_then(|t| t.set_non_blocking())
+            .map_err(|e| {
+                warn!("Failed to create TUN interface {name}: {e:?}");
+                "Failed to create a TUN interface, the kernel provider needs CAP_NET_ADMIN"
+                    .to_string()
+            })?;
+        ip(&[
+            "-6",
+            "address",
+            "add",
+            &format!("{client}/{prefix}"),
+            "dev",
+            &name,
+        ])?;
+        ip(&["link", "set", "dev", &name, "mtu", &mtu, "up"])?;
+        debug!("Created {name} with {client}/{prefix} for a tunnel to {server}");
+
+        // The interface is removed when the file is closed. The socket is forgotten so only
+        // the file closes the descriptor.
+        let fd = tun.as_raw_fd();
+        std::mem::forget(tun);
+        let file = unsafe { File::from_raw_fd(fd) };
+        let tun = AsyncFd::new(file).map_err(|e| format!("Failed to poll {name}: {e}"))?;
+
+        let pump = PumpTask(tokio::spawn(pump(proxy, tun)));
+        Ok((Tunnel::kernel(server.into(), Some(pump)), rsd_port))
+    }
+
+    /// Runs iproute2 directly, there's no shell involved
+    fn ip(args: &[&str]) -> Result<(), String> {
+        let output = std::process::Command::new("ip")
+            .args(args)
+            .output()
+            .map_err(|e| format!("failed to run ip: {e}"))?;
+        match output.status.success() {
+            true => Ok(()),
+            false => Err(format!(
+                "ip {}: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )),
+        }
+    }
+
+    async fn pump(mut proxy: CoreDeviceProxy, tun: AsyncFd<File>) {
+        let mut outgoing = vec![0u8; u16::MAX as usize];
+        // The device sends packets back to back, not one per read
+        let mut incoming = Vec::new();
+        loop {
+            tokio::select! {
+                data = proxy.recv() => {
+                    let data = match data {
+                        Ok(d) => d,
+                        Err(e) => {
+                            debug!("Tunnel closed by the device: {e:?}");
+                            return;
+                        }
+                    };
+                    incoming.extend_from_slice(&data);
+                    while incoming.len() >= 6 {
+                        if incoming[0] >> 4 != 6 {
+                            warn!("Dropping a packet that isn't IPv6 from the tunnel");
+                            incoming.clear();
+                            break;
+                        }
+                        let len = IPV6_HEADER_LEN
+                            + u16::from_be_bytes([incoming[4], incoming[5]]) as usize;
+                        if incoming.len() < len {
+                            break;
+                        }
+                        let packet = incoming.drain(..len).collect::<Vec<u8>>();
+                        if let Err(e) = write_packet(&tun, &packet).await {
+                            warn!("Failed to write to the TUN interface: {e:?}");
+                            return;
+                        }
+                    }
+                }
+                guard = tun.readable() => {
+                    let mut guard = match guard {
+                        Ok(g) => g,
+                        Err(e) => {
+                            warn!("Failed to poll the TUN interface: {e:?}");
+                            return;
+                        }
+                    };
+                    match guard.try_io(|f| f.get_ref().read(&mut outgoing)) {
+                        Ok(Ok(n)) => {
+                            if let Err(e) = proxy.send(&outgoing[..n]).await {
+                                debug!("Tunnel closed while sending: {e:?}");
+                                return;
+                            }
+                        }
+                        Ok(Err(e)) => {
+                            warn!("Failed to read from the TUN interface: {e:?}");
+                            return;
+                        }
+                        // Not readable after all
+                        Err(_) => continue,
+                    }
+                }
+            }
+        }
+    }
+
+    async fn write_packet(tun: &AsyncFd<File>, packet: &[u8]) -> std::io::Result<()> {
+        loop {
+            let mut guard = tun.writable().await?;
+            if let Ok(res) = guard.try_io(|f| f.get_ref().write(packet)) {
+                return res.map(|_| ());
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod kernel {
+    use idevice::core_device_proxy::CoreDeviceProxy;
+
+    use super::Tunnel;
+
+    pub fn open(_proxy: CoreDeviceProxy) -> Result<(Tunnel, u16), String> {
+        Err("The kernel tunnel provider is only supported on Linux".to_string())
+    }
+}
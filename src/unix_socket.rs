@@ -0,0 +1,31 @@
+// Jackson Coxson
+// Serves the API on a Unix socket, for reverse proxies on the same machine
+
+use std::{
+    net::{Ipv6Addr, SocketAddr},
+    os::unix::fs::PermissionsExt,
+    path::Path,
+};
+
+use axum::{extract::ConnectInfo, Extension, Router};
+use tokio::net::UnixListener;
+
+/// Binds the socket, replacing one left behind by a previous run
+pub fn bind(path: &Path, mode: u32) -> std::io::Result<UnixListener> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+/// Unix sockets have no peer address, so clients appear to connect from localhost like
+/// they would through a proxy on TCP
+pub fn app(app: Router) -> Router {
+    app.layer(Extension(ConnectInfo(SocketAddr::from((
+        Ipv6Addr::LOCALHOST,
+        0,
+    )))))
+}
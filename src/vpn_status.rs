@@ -0,0 +1,138 @@
+// Jackson Coxson
+// Reports the caller's Wireguard peer, so an unreachable device can be told apart from a VPN that's down
+
+use std::{net::IpAddr, str::FromStr};
+
+use axum::{extract::State, Json};
+use axum_client_ip::SecureClientIp;
+use serde::Serialize;
+
+use crate::{
+    common::{self, DeviceSelector},
+    error::{ErrorCode, JitError},
+    register::HANDSHAKE_TIMEOUT,
+    wireguard::{self, PeerStatus},
+    JitStreamerState,
+};
+
+#[derive(Serialize, Default)]
+pub struct VpnStatusReturn {
+    /// Whether the VPN is connected, a recent handshake
+    ok: bool,
+    udid: Option<String>,
+    /// The device's address inside the VPN
+    ip: Option<String>,
+    interface: Option<String>,
+    /// Whether the interface has a peer for the device. Without one, register again.
+    peer: bool,
+    /// Null if the peer never completed a handshake
+    last_handshake_secs_ago: Option<u64>,
+    /// Bytes received from and sent to the device since the interface came up
+    rx_bytes: u64,
+    tx_bytes: u64,
+    /// Where the device last connected from
+    endpoint: Option<String>,
+    #[serde(flatten)]
+    error: Option<JitError>,
+}
+
+impl VpnStatusReturn {
+    fn fail(error: JitError) -> Self {
+        Self {
+            error: Some(error),
+            ..Default::default()
+        }
+    }
+}
+
+/// Reads the caller's peer from the Wireguard interface
+pub async fn handler(
+    ip: SecureClientIp,
+    selector: DeviceSelector,
+    State(state): State<JitStreamerState>,
+) -> Json<VpnStatusReturn> {
+    let config = state.config();
+    if !config.wireguard_registration() {
+        return Json(VpnStatusReturn::fail(JitError::new(
+            ErrorCode::Forbidden,
+            "This server doesn't register devices with Wireguard",
+        )));
+    }
+    let udid = match common::get_device(&state, ip.0, &selector, config.allow_udid_override).await {
+        Ok((udid, _)) => udid,
+        Err(e) => return Json(VpnStatusReturn::fail(e)),
+    };
+
+    let (device_ip, interface) = match sqlx::query_as::<_, (String, Option<String>)>(
+        "SELECT ip, wireguard_interface FROM devices WHERE udid = ?",
+    )
+    .bind(&udid)
+    .fetch_optional(&state.db)
+    .await
+    {
+        Ok(Some(row)) => row,
+        Ok(None) => {
+            return Json(VpnStatusReturn::fail(JitError::new(
+                ErrorCode::NotRegistered,
+                format!("Device {udid} is not registered"),
+            )))
+        }
+        Err(e) => {
+            tracing::error!("Failed to query database: {e:?}");
+            return Json(VpnStatusReturn::fail(JitError::internal(
+                "Failed to query database",
+            )));
+        }
+    };
+    let address = match IpAddr::from_str(&device_ip) {
+        Ok(a) => a,
+        Err(_) => {
+            return Json(VpnStatusReturn::fail(JitError::internal(format!(
+                "Device {udid} has an invalid registered IP"
+            ))))
+        }
+    };
+    let wireguard = config.wireguard_interface(interface.as_deref());
+    let status = match wireguard::peer_status(wireguard, address) {
+        Ok(s) => s,
+        Err(e) => {
+            tracing::error!("Failed to read {}: {e}", wireguard.config_name);
+            return Json(VpnStatusReturn::fail(JitError::internal(
+                "Failed to read the Wireguard interface",
+            )));
+        }
+    };
+
+    let mut res = VpnStatusReturn {
+        udid: Some(udid),
+        ip: Some(address.to_canonical().to_string()),
+        interface: Some(wireguard.config_name.clone()),
+        ..Default::default()
+    };
+    let Some(PeerStatus {
+        last_handshake,
+        rx_bytes,
+        tx_bytes,
+        endpoint,
+    }) = status
+    else {
+        res.error = Some(JitError::new(
+            ErrorCode::NotRegistered,
+            "The VPN has no peer for this device, register again to get a new config",
+        ));
+        return Json(res);
+    };
+    res.peer = true;
+    res.last_handshake_secs_ago = last_handshake.map(|h| h.as_secs());
+    res.rx_bytes = rx_bytes;
+    res.tx_bytes = tx_bytes;
+    res.endpoint = endpoint.map(|e| e.to_string());
+    res.ok = last_handshake.is_some_and(|h| h.as_secs() < HANDSHAKE_TIMEOUT);
+    if !res.ok {
+        res.error = Some(JitError::new(
+            ErrorCode::VpnNoHandshake,
+            "The VPN isn't connected, turn on the JitStreamer tunnel in the WireGuard app.",
+        ));
+    }
+    Json(res)
+}
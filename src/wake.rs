@@ -0,0 +1,114 @@
+// Jackson Coxson
+// Nudges sleeping devices that have dropped their VPN connection
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::{net::UdpSocket, time::Instant};
+use tracing::{debug, info};
+
+use crate::{dependencies::Dependency, liveness, netmuxd, JitStreamerState};
+
+const MDNS_PORT: u16 = 5353;
+/// How long a device that didn't answer the heartbeat is nudged for
+const WAKE_LIMIT: Duration = Duration::from_secs(10);
+const WAKE_DELAY: Duration = Duration::from_millis(500);
+
+/// Builds a DNS query for the device's remote pairing service, asking for a unicast response
+fn mdns_query() -> Vec<u8> {
+    let mut packet = vec![
+        0, 0, // ID
+        0, 0, // flags
+        0, 1, // questions
+        0, 0, // answers
+        0, 0, // authority
+        0, 0, // additional
+    ];
+    for label in ["_apple-mobdev2", "_tcp", "local"] {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&12u16.to_be_bytes()); // PTR
+    packet.extend_from_slice(&0x8001u16.to_be_bytes()); // IN, unicast response
+    packet
+}
+
+async fn send_mdns_burst(ip: IpAddr) {
+    let bind: SocketAddr = match ip {
+        IpAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+        IpAddr::V6(_) => "[::]:0".parse().unwrap(),
+    };
+    let socket = match UdpSocket::bind(bind).await {
+        Ok(s) => s,
+        Err(e) => {
+            debug!("Failed to bind mDNS socket: {e:?}");
+            return;
+        }
+    };
+    let query = mdns_query();
+    for _ in 0..3 {
+        if let Err(e) = socket.send_to(&query, SocketAddr::new(ip, MDNS_PORT)).await {
+            debug!("Failed to send mDNS query to {ip}: {e:?}");
+            return;
+        }
+    }
+}
+
+/// Repeatedly pokes lockdown and sends mDNS queries until the device answers.
+/// Returns true if the device became reachable.
+pub async fn wake(ip: IpAddr) -> bool {
+    wake_for(ip, WAKE_LIMIT).await
+}
+
+/// Like `wake`, giving up once `limit` has passed
+pub async fn wake_for(ip: IpAddr, limit: Duration) -> bool {
+    debug!("Attempting to wake {ip}");
+    let deadline = Instant::now() + limit;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        send_mdns_burst(ip).await;
+        if liveness::lockdown_reachable(ip).await {
+            debug!("{ip} woke after {attempt} attempts");
+            return true;
+        }
+        debug!("Wake attempt {attempt} for {ip} failed");
+        if Instant::now() + WAKE_DELAY >= deadline {
+            return false;
+        }
+        tokio::time::sleep(WAKE_DELAY).await;
+    }
+}
+
+/// Wakes the device before a launch connects to it, since devices asleep on Wi-Fi often
+/// miss the first connection. None if it answered right away, is reached over USB, or is
+/// being refused for now anyway. Otherwise whether it woke within WAKE_TIMEOUT, the launch
+/// goes on either way.
+pub async fn nudge(state: &JitStreamerState, udid: &str, ip: IpAddr) -> Option<bool> {
+    let config = state.config();
+    if config.wake_timeout.is_zero()
+        || state.circuit_breaker.check(udid).await.is_err()
+        || liveness::probe(&state.heartbeats, udid, ip).await.alive
+    {
+        return None;
+    }
+    if config.usb_devices
+        && state
+            .dependencies
+            .missing(Dependency::Usbmuxd)
+            .await
+            .is_none()
+        && matches!(netmuxd::usb_device_id(udid).await, Ok(Some(_)))
+    {
+        return None;
+    }
+    info!("{udid} didn't answer, nudging it awake before connecting");
+    let woke = wake_for(ip, config.wake_timeout).await;
+    if !woke {
+        info!("{udid} didn't wake within {:?}", config.wake_timeout);
+    }
+    Some(woke)
+}
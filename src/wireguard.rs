@@ -0,0 +1,279 @@
+// Jackson Coxson
+// Brings up Wireguard interfaces and applies their peers over netlink, without wg-quick
+
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use boringtun::device::{DeviceConfig, DeviceHandle};
+use tracing::{error, info};
+
+use wireguard_control::{Backend, Device, DeviceUpdate, InterfaceName, Key, PeerConfigBuilder};
+
+use crate::{acl::Cidr, config::WireguardConfig};
+
+#[derive(Debug, thiserror::Error)]
+pub enum WireguardError {
+    /// Not a name Linux accepts for an interface
+    #[error("invalid interface name {0}")]
+    InvalidName(String),
+    /// The server config file couldn't be read or has an invalid line
+    #[error("bad Wireguard config: {0}")]
+    Config(String),
+    /// The kernel refused the change
+    #[error("failed to configure Wireguard: {0}")]
+    Netlink(std::io::Error),
+    /// Setting an address or route failed
+    #[error("failed to configure the interface: {0}")]
+    Ip(String),
+    /// The embedded interface couldn't be started
+    #[error("failed to start embedded Wireguard: {0}")]
+    Embedded(String),
+}
+
+fn interface_name(name: &str) -> Result<InterfaceName, WireguardError> {
+    name.parse()
+        .map_err(|_| WireguardError::InvalidName(name.to_string()))
+}
+
+/// Kernel interfaces are configured over netlink, embedded ones over boringtun's UAPI socket
+fn backend(wireguard: &WireguardConfig) -> Backend {
+    match wireguard.embedded {
+        true => Backend::Userspace,
+        false => Backend::Kernel,
+    }
+}
+
+/// Embedded interfaces that are running, they live as long as the process
+static EMBEDDED: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+
+/// Starts a boringtun device for the interface on its own threads, unless it's running.
+/// It creates a TUN device, so /dev/net/tun and CAP_NET_ADMIN are still needed, but not
+/// the kernel module.
+fn start_embedded(name: &str) -> Result<(), WireguardError> {
+    // Claimed before starting so it's only started once, without holding the lock while
+    // the device comes up
+    if !EMBEDDED
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashSet::new)
+        .insert(name.to_string())
+    {
+        return Ok(());
+    }
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let thread_name = name.to_string();
+    std::thread::spawn(move || {
+        let mut handle = match DeviceHandle::new(&thread_name, DeviceConfig::default()) {
+            Ok(h) => h,
+            Err(e) => {
+                tx.send(Err(format!("{e:?}"))).ok();
+                return;
+            }
+        };
+        tx.send(Ok(())).ok();
+        handle.wait();
+        error!("Embedded Wireguard interface {thread_name} stopped");
+        // So bringing it up again starts a new one
+        if let Some(running) = EMBEDDED.lock().unwrap().as_mut() {
+            running.remove(&thread_name);
+        }
+    });
+    let res = rx
+        .recv()
+        .map_err(|_| WireguardError::Embedded("the device thread panicked".to_string()))
+        .and_then(|r| r.map_err(WireguardError::Embedded));
+    if res.is_err() {
+        if let Some(running) = EMBEDDED.lock().unwrap().as_mut() {
+            running.remove(name);
+        }
+        return res;
+    }
+
+    info!("Started embedded Wireguard interface {name}");
+    Ok(())
+}
+
+/// Creates the interface if it doesn't exist, applies the config file, and sets its
+/// addresses and peer routes, like `wg-quick up`
+pub fn up(wireguard: &WireguardConfig, ipv4: Option<(IpAddr, u8)>) -> Result<(), WireguardError> {
+    let name = &wireguard.config_name;
+    if wireguard.embedded {
+        start_embedded(name)?;
+    }
+    let peers = sync(wireguard)?;
+
+    ip(&["address", "replace", &wireguard.server_address, "dev", name])?;
+    if let Some((addr, prefix)) = ipv4 {
+        ip(&[
+            "address",
+            "replace",
+            &format!("{addr}/{prefix}"),
+            "dev",
+            name,
+        ])?;
+    }
+    ip(&["link", "set", "up", "dev", name])?;
+    for addr in peers {
+        add_route(name, &addr.to_string())?;
+    }
+    Ok(())
+}
+
+/// Replaces the interface's key, port and peers with the ones in its config file, like
+/// `wg syncconf`. Returns the addresses of the peers.
+pub fn sync(wireguard: &WireguardConfig) -> Result<Vec<IpAddr>, WireguardError> {
+    let conf = std::fs::read_to_string(wireguard.conf_path())
+        .map_err(|e| WireguardError::Config(e.to_string()))?;
+    let (update, addresses) = parse_conf(&conf)?;
+    update
+        .apply(&interface_name(&wireguard.config_name)?, backend(wireguard))
+        .map_err(WireguardError::Netlink)?;
+    Ok(addresses)
+}
+
+/// Builds the update for a wg-quick style config, ignoring the keys only wg-quick reads
+fn parse_conf(conf: &str) -> Result<(DeviceUpdate, Vec<IpAddr>), WireguardError> {
+    let bad = |line: &str| WireguardError::Config(format!("invalid line {line:?}"));
+    let key = |line: &str, value: &str| Key::from_base64(value).map_err(|_| bad(line));
+
+    let mut update = DeviceUpdate::new().replace_peers();
+    let mut addresses = Vec::new();
+    // Each peer's lines, which may come in any order
+    let mut peers: Vec<Vec<&str>> = Vec::new();
+    let mut in_peer = false;
+    for line in conf.lines().map(|l| l.trim()) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') {
+            in_peer = line.eq_ignore_ascii_case("[Peer]");
+            if in_peer {
+                peers.push(Vec::new());
+            }
+            continue;
+        }
+        if let Some(peer) = peers.last_mut().filter(|_| in_peer) {
+            peer.push(line);
+            continue;
+        }
+        let (k, value) = line.split_once('=').ok_or_else(|| bad(line))?;
+        let value = value.trim();
+        match k.trim() {
+            "PrivateKey" => update = update.set_private_key(key(line, value)?),
+            "ListenPort" => update = update.set_listen_port(value.parse().map_err(|_| bad(line))?),
+            _ => {}
+        }
+    }
+
+    for lines in peers {
+        let fields = lines
+            .iter()
+            .map(|line| {
+                let (k, value) = line.split_once('=').ok_or_else(|| bad(line))?;
+                Ok((*line, k.trim(), value.trim()))
+            })
+            .collect::<Result<Vec<(&str, &str, &str)>, WireguardError>>()?;
+        let (line, _, public_key) = fields
+            .iter()
+            .find(|(_, k, _)| *k == "PublicKey")
+            .ok_or_else(|| WireguardError::Config("a peer has no public key".to_string()))?;
+        let mut peer = PeerConfigBuilder::new(&key(line, public_key)?).replace_allowed_ips();
+        for (line, k, value) in fields {
+            match k {
+                "PresharedKey" => peer = peer.set_preshared_key(key(line, value)?),
+                "Endpoint" => {
+                    peer = peer.set_endpoint(value.parse::<SocketAddr>().map_err(|_| bad(line))?)
+                }
+                "PersistentKeepalive" => {
+                    let interval = value.parse::<u16>().map_err(|_| bad(line))?;
+                    peer = peer.set_persistent_keepalive_interval(interval);
+                }
+                "AllowedIPs" => {
+                    for allowed in value.split(',').map(|a| a.trim()).filter(|a| !a.is_empty()) {
+                        let cidr = allowed.parse::<Cidr>().map_err(|_| bad(line))?;
+                        addresses.push(cidr.addr());
+                        peer = peer.add_allowed_ip(cidr.addr(), cidr.prefix());
+                    }
+                }
+                _ => {}
+            }
+        }
+        update = update.add_peer(peer);
+    }
+    Ok((update, addresses))
+}
+
+pub fn add_route(name: &str, addr: &str) -> Result<(), WireguardError> {
+    ip(&["route", "replace", addr, "dev", name])
+}
+
+pub fn remove_route(name: &str, addr: &str) -> Result<(), WireguardError> {
+    ip(&["route", "del", addr, "dev", name])
+}
+
+/// Runs iproute2 directly, there's no shell involved
+fn ip(args: &[&str]) -> Result<(), WireguardError> {
+    let output = std::process::Command::new("ip")
+        .args(args)
+        .output()
+        .map_err(|e| WireguardError::Ip(format!("failed to run ip: {e}")))?;
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(WireguardError::Ip(format!(
+            "ip {}: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))),
+    }
+}
+
+/// What the interface knows about a peer
+pub struct PeerStatus {
+    /// How long ago it last completed a handshake, None if it never has
+    pub last_handshake: Option<Duration>,
+    /// Bytes received from and sent to the peer since the interface came up
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    /// Where the peer last connected from
+    pub endpoint: Option<SocketAddr>,
+}
+
+/// The peer routing to the address, None if the interface has no peer for it
+pub fn peer_status(
+    wireguard: &WireguardConfig,
+    ip: IpAddr,
+) -> Result<Option<PeerStatus>, WireguardError> {
+    let name = interface_name(&wireguard.config_name)?;
+    let device = Device::get(&name, backend(wireguard)).map_err(WireguardError::Netlink)?;
+    let ip = ip.to_canonical();
+    let peer = device.peers.iter().find(|p| {
+        p.config
+            .allowed_ips
+            .iter()
+            .any(|a| a.address.to_canonical() == ip)
+    });
+    Ok(peer.map(|peer| PeerStatus {
+        last_handshake: peer
+            .stats
+            .last_handshake_time
+            .filter(|t| *t != SystemTime::UNIX_EPOCH)
+            .map(|t| t.elapsed().unwrap_or_default()),
+        rx_bytes: peer.stats.rx_bytes,
+        tx_bytes: peer.stats.tx_bytes,
+        endpoint: peer.config.endpoint,
+    }))
+}
+
+/// When the peer routing to the address last completed a handshake, None if the interface
+/// has no peer for it or can't be read. The inner None means it never has.
+pub fn last_handshake(wireguard: &WireguardConfig, ip: IpAddr) -> Option<Option<Duration>> {
+    peer_status(wireguard, ip)
+        .ok()
+        .flatten()
+        .map(|p| p.last_handshake)
+}